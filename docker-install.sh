@@ -2,7 +2,6 @@ DEBIAN_FRONTEND=noninteractive apt-get update
 DEBIAN_FRONTEND=noninteractive apt-get install -y --no-install-recommends \
     bash=5.1-6ubuntu1.1 \
     wget=1.21.2-2ubuntu1.1 \
-    socat=1.7.4.1-3ubuntu4 \
     ipset=7.15-1build1 \
     unzip=6.0-26ubuntu3.2 \
     iproute2=5.15.0-1ubuntu2 \
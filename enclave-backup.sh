@@ -39,7 +39,7 @@ set -e
 # remove device
 losetup -d $(losetup -j /run/disk.img | cut -d: -f1)
 
-# start socat
+# start forward
 ./supervisord -c supervisord.conf &
 SUPERVISOR_PID=$!
 
@@ -47,7 +47,7 @@ sleep 1
 
 ./supervisord-ctl.sh status
 
-./supervisord-ctl.sh start socat-rclone
+./supervisord-ctl.sh start forward-rclone
 
 # rclone config for backup
 cat > /enclaved/rclone.conf <<EOF
@@ -6,15 +6,14 @@ set -e
 update-alternatives --set iptables /usr/sbin/iptables-legacy
 update-alternatives --set ip6tables /usr/sbin/ip6tables-legacy
 
-ip=`./node_modules/.bin/tsx src/index.ts cli parent_get_ip | tail -n 1`
-
-# take IP from parent
+# take IP from parent, it's announced by the parent's
+# vsock proxy when the outgoing link is set up, with the
+# link flags of the supervised proxy, --secure, --parent-key
+# and --transport have to match the parent's
+args=`sed -n 's|^command=/enclaved/ip-to-vsock-raw-outgoing ||p' supervisord.conf`
+ip=`./ip-to-vsock-raw-outgoing $args --print-ip | tail -n 1`
 echo "IP $ip"
 
-# required by vsock utils
-echo $ip > ip.txt
-grep $ip ip.txt
-
 # add TUN device to proxy through vsock,
 # TUN instead of bridge required so that we could
 # pass incoming packets through network stack
@@ -6,7 +6,7 @@ echo "Recover data from parent"
 set -Eeuo pipefail
 #set -e 
 
-./supervisord-ctl.sh start socat-rclone
+./supervisord-ctl.sh start forward-rclone
 
 # rclone config for backup
 cat > /enclaved/rclone.conf <<EOF
@@ -75,16 +75,16 @@ sleep 1
 # start chrony to sync time
 ./supervisord-ctl.sh start chronyd
 
-# start proxy to parent, otherwise we can't get IP to setup the network
-./supervisord-ctl.sh start socat-parent
+# start forward to the parent API
+./supervisord-ctl.sh start forward-parent
 
-# setup network (after we started socat and asked parent for our IP)
+# setup network, the IP is announced by the parent's vsock proxy
 ./enclave-network-setup.sh
 
 # start proxies
 ./supervisord-ctl.sh start ip-to-vsock-raw-outgoing
 ./supervisord-ctl.sh start vsock-to-ip-raw-incoming
-./supervisord-ctl.sh start dnsproxy
+./supervisord-ctl.sh start dns-forwarder
 
 # wait for them to start
 sleep 1
@@ -109,12 +109,12 @@ echo "status"
 ./supervisord-ctl.sh status
 
 # start proxies
-./supervisord-ctl.sh start socat-parent
+./supervisord-ctl.sh start forward-parent
 ./supervisord-ctl.sh start ip-to-vsock-raw-outgoing
 ./supervisord-ctl.sh start vsock-to-ip-raw-incoming
 
-# start dnsproxy
-./supervisord-ctl.sh start dnsproxy
+# start dns
+./supervisord-ctl.sh start dns-forwarder
 
 # Start the Docker daemon
 ./supervisord-ctl.sh start docker
@@ -55,13 +55,14 @@ echo "status"
 # start proxies
 ./build/supervisord ctl -c supervisord-parent.conf start vsock-to-ip-raw-outgoing
 ./build/supervisord ctl -c supervisord-parent.conf start ip-to-vsock-raw-incoming
+./build/supervisord ctl -c supervisord-parent.conf start dns-resolver
 
 # start parent
-./build/supervisord ctl -c supervisord-parent.conf start socat-parent
+./build/supervisord ctl -c supervisord-parent.conf start forward-parent
 ./build/supervisord ctl -c supervisord-parent.conf start parent
 
 # start rclone
-./build/supervisord ctl -c supervisord-parent.conf start socat-rclone
+./build/supervisord ctl -c supervisord-parent.conf start forward-rclone
 ./build/supervisord ctl -c supervisord-parent.conf start rclone
 
 wait $SUPERVISOR_PID
\ No newline at end of file
@@ -47,12 +47,6 @@ async function ping({
   console.log("ping", Date.now() - start, "ms");
 }
 
-async function parentGetIP({ port }: { port: number }) {
-  const client = new ParentClient({ port });
-  const r = await client.getIP();
-  console.log(r.ip);
-}
-
 async function hasBackup(port: number) {
   const client = new ParentClient({ port });
   const r = await client.hasBackup();
@@ -178,10 +172,6 @@ export function mainCli(argv: string[]) {
       const port = Number(argv?.[2]) || 2080;
       return log(message, port);
     }
-    case "parent_get_ip": {
-      const port = Number(argv[1]) || 2080;
-      return parentGetIP({ port });
-    }
     case "docker_inspect": {
       const dockerUrl = argv[1];
       return dockerInspect(dockerUrl);
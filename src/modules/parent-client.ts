@@ -23,10 +23,6 @@ export class ParentClient extends WSClient {
     return this.call<void>("log", [s]);
   }
 
-  getIP() {
-    return this.call<{ ip: string }>("get_ip", []);
-  }
-
   hasBackup() {
     return this.call<{ has_backup: boolean }>("has_backup", []);
   }
@@ -4,7 +4,6 @@ import { validateEvent, verifyEvent, Event } from "nostr-tools";
 import { nsmParseAttestation } from "../modules/nsm";
 import { verifyBuild, verifyInstance, verifyRelease } from "../modules/aws";
 import { fetchOutboxRelays } from "../cli/utils";
-import { getIP } from "../modules/utils";
 import { WSServer, Rep, Req } from "../modules/ws-server";
 import { DEFAULT_RELAYS } from "../modules/nostr";
 
@@ -116,14 +115,6 @@ class ParentServer extends WSServer {
     };
   }
 
-  private async getIP() {
-    const ip = getIP();
-    if (!ip) throw new Error("Failed to get IP");
-    return {
-      ip,
-    };
-  }
-
   private async hasBackup() {
     const has_backup = !!fs.statSync(this.dir + "/data/disk.img.age");
     return {
@@ -138,9 +129,6 @@ class ParentServer extends WSServer {
   protected async handle(req: Req, rep: Rep) {
     try {
       switch (req.method) {
-        case "get_ip":
-          rep.result = await this.getIP();
-          break;
         case "get_meta":
           rep.result = await this.getMeta(req.params);
           break;
@@ -34,7 +34,7 @@ sleep 2
 echo "enclave => docker"
 curl -v localhost:3000
 
-./dnsproxy/linux-amd64/dnsproxy -u https://1.1.1.1/dns-query &
+./dns-forwarder --vsock-addr 3:1053 --answer-vsock-addr $ENCLAVE_CID:1053 &
 
 sleep 3
 
@@ -46,7 +46,7 @@ cp ./phoenix-cli /home/phoenix/
 
 # wallet state sync for debugging 
 RCLONE_PORT=2022
-socat TCP4-LISTEN:${RCLONE_PORT},reuseaddr,fork,forever,keepalive VSOCK-CONNECT:3:${RCLONE_PORT} &
+./vsock-stream-forward --map tcp:0.0.0.0:${RCLONE_PORT}=vsock:3:${RCLONE_PORT} &
 mkdir -p /home/phoenix/.config/rclone
 mkdir /home/phoenix/.phoenix
 cat > /home/phoenix/.config/rclone/rclone.conf << EOF
@@ -95,8 +95,8 @@ sleep 200
 
 runuser -u phoenix -- /home/phoenix/phoenix-cli payinvoice --invoice=lnbc500n1pnlvvaepp5c95n94hva9r88eqxdwx4c7vhgcm74vtpndayk7yjvl0dtypmp38qdqqcqzzsxqyz5vqsp5d7p6vp0eja5mxwzn5092rfcaha60fumn4v0lzh86szxagsg4hu0s9qxpqysgq9y64vyrg9xgjkk02zssreszuuyukqcaymg8xah3pxge0gn6dk3eh7zn5qkqd3lffth828x405fl6dmkck5lejvukld0g0v9mjyp8qyqqel7ala
 
-# start dnsproxy
-#/app/supervisord ctl -c /etc/supervisord.conf start dnsproxy
+# start dns
+#/app/supervisord ctl -c /etc/supervisord.conf start dns-forwarder
 
 # generate identity key
 #/app/keygen-x25519 --secret /app/id.sec --public /app/id.pub
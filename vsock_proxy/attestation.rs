@@ -0,0 +1,374 @@
+// Nitro attestation of the enclave's link key
+//
+// The enclave asks the Nitro Secure Module for an attestation document
+// with its static Noise key as public_key. The document is a COSE_Sign1
+// structure signed with ES384 by a certificate chaining up to the AWS
+// Nitro Enclaves root, and lists the PCRs of the running image. The
+// parent checks the chain against the root embedded here, see
+// https://docs.aws.amazon.com/enclaves/latest/user/verify-root.html,
+// the signature, the expected PCRs, and returns the attested key for the
+// handshake to compare with the one it authenticated.
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use aws_nitro_enclaves_nsm_api::api::{AttestationDoc, Request, Response};
+use aws_nitro_enclaves_nsm_api::driver::{nsm_exit, nsm_init, nsm_process_request};
+use ring::signature::{UnparsedPublicKey, ECDSA_P384_SHA384_FIXED};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+use thiserror::Error;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::prelude::{parse_x509_certificate, ASN1Time};
+
+/// AWS Nitro Enclaves root certificate, SHA-256 fingerprint
+/// 64:1A:03:21:A3:E2:44:EF:E4:56:46:31:95:D6:06:31:7E:D7:CD:CC:3C:17:56:E0:98:93:F3:C6:8F:79:BB:5B
+pub const AWS_NITRO_ROOT_PEM: &[u8] = include_bytes!("aws_nitro_root.pem");
+
+// COSE algorithm of the document signature
+const COSE_ALG: i128 = 1;
+const COSE_ES384: i128 = -35;
+
+#[derive(Error, Debug)]
+pub enum AttestationError {
+    #[error("failed to open the nitro secure module")]
+    NsmOpenError,
+    #[error("nitro secure module failed: {0}")]
+    NsmError(String),
+    #[error("invalid attestation document: {0}")]
+    FormatError(String),
+    #[error("invalid certificate chain: {0}")]
+    ChainError(String),
+    #[error("invalid attestation signature")]
+    SignatureError,
+    #[error("pcr{0} does not match")]
+    PcrError(usize),
+    #[error("attestation document has no public key")]
+    NoPublicKey,
+}
+
+/// Get an attestation document for public_key from the Nitro Secure Module
+pub fn attest(public_key: &[u8]) -> Result<Vec<u8>, AttestationError> {
+    let fd = nsm_init();
+    if fd < 0 {
+        return Err(AttestationError::NsmOpenError);
+    }
+
+    let response = nsm_process_request(
+        fd,
+        Request::Attestation {
+            user_data: None,
+            nonce: None,
+            public_key: Some(ByteBuf::from(public_key)),
+        },
+    );
+    nsm_exit(fd);
+
+    match response {
+        Response::Attestation { document } => Ok(document),
+        Response::Error(code) => Err(AttestationError::NsmError(format!("{code:?}"))),
+        _ => Err(AttestationError::NsmError("unexpected response".to_owned())),
+    }
+}
+
+/// What the parent accepts in an attestation document
+#[derive(Clone, Debug)]
+pub struct AttestationPolicy {
+    /// DER of the root the document has to chain up to
+    pub root: Vec<u8>,
+    /// expected PCR values by index, others are not checked
+    pub pcrs: BTreeMap<usize, Vec<u8>>,
+}
+
+impl Default for AttestationPolicy {
+    fn default() -> Self {
+        let (_, pem) = x509_parser::pem::parse_x509_pem(AWS_NITRO_ROOT_PEM).unwrap();
+        AttestationPolicy {
+            root: pem.contents,
+            pcrs: BTreeMap::new(),
+        }
+    }
+}
+
+fn format_error(reason: &str) -> AttestationError {
+    AttestationError::FormatError(reason.to_owned())
+}
+
+fn parse_certificate(der: &[u8]) -> Result<X509Certificate<'_>, AttestationError> {
+    parse_x509_certificate(der)
+        .map(|(_, certificate)| certificate)
+        .map_err(|e| AttestationError::ChainError(e.to_string()))
+}
+
+// the cabundle starts at the root, every certificate signs the next and
+// the last one signs the document certificate, all valid now
+fn verify_chain(doc: &AttestationDoc, policy: &AttestationPolicy) -> Result<(), AttestationError> {
+    let chain_error = |reason: &str| AttestationError::ChainError(reason.to_owned());
+
+    if doc.cabundle.first().map(|root| root.as_slice()) != Some(&policy.root[..]) {
+        return Err(chain_error("unknown root"));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| chain_error("clock before epoch"))?;
+    let now =
+        ASN1Time::from_timestamp(now.as_secs() as i64).map_err(|_| chain_error("invalid time"))?;
+
+    let mut issuer: Option<X509Certificate> = None;
+    for der in doc.cabundle.iter().chain([&doc.certificate]) {
+        let certificate = parse_certificate(der)?;
+        if !certificate.validity().is_valid_at(now) {
+            return Err(chain_error("certificate expired or not yet valid"));
+        }
+        // the root signs itself
+        let issuer_key = issuer.as_ref().unwrap_or(&certificate).public_key();
+        certificate
+            .verify_signature(Some(issuer_key))
+            .map_err(|e| AttestationError::ChainError(e.to_string()))?;
+        issuer = Some(certificate);
+    }
+
+    Ok(())
+}
+
+/// Verify an attestation document as per policy, returns the public key it attests
+pub fn verify_attestation(
+    document: &[u8],
+    policy: &AttestationPolicy,
+) -> Result<Vec<u8>, AttestationError> {
+    // COSE_Sign1: [protected, unprotected, payload, signature]
+    let cose: Value = serde_cbor::from_slice(document).map_err(|_| format_error("not cbor"))?;
+    let (protected, payload, signature) = match cose {
+        Value::Array(items) => match &items[..] {
+            [Value::Bytes(protected), _, Value::Bytes(payload), Value::Bytes(signature)] => {
+                (protected.clone(), payload.clone(), signature.clone())
+            }
+            _ => return Err(format_error("not COSE_Sign1")),
+        },
+        _ => return Err(format_error("not COSE_Sign1")),
+    };
+
+    let header: BTreeMap<Value, Value> =
+        serde_cbor::from_slice(&protected).map_err(|_| format_error("invalid protected header"))?;
+    if header.get(&Value::Integer(COSE_ALG)) != Some(&Value::Integer(COSE_ES384)) {
+        return Err(format_error("unsupported signature algorithm"));
+    }
+
+    let doc = AttestationDoc::from_binary(&payload).map_err(|_| format_error("invalid payload"))?;
+    verify_chain(&doc, policy)?;
+
+    // Sig_structure: ["Signature1", protected, external_aad, payload]
+    let signed = serde_cbor::to_vec(&Value::Array(vec![
+        Value::Text("Signature1".to_owned()),
+        Value::Bytes(protected),
+        Value::Bytes(Vec::new()),
+        Value::Bytes(payload),
+    ]))
+    .map_err(|_| format_error("cannot encode signed data"))?;
+    let certificate = parse_certificate(&doc.certificate)?;
+    let key = &certificate.public_key().subject_public_key.data;
+    UnparsedPublicKey::new(&ECDSA_P384_SHA384_FIXED, key)
+        .verify(&signed, &signature)
+        .map_err(|_| AttestationError::SignatureError)?;
+
+    for (index, expected) in &policy.pcrs {
+        if doc.pcrs.get(index).map(|pcr| pcr.as_slice()) != Some(&expected[..]) {
+            return Err(AttestationError::PcrError(*index));
+        }
+    }
+
+    doc.public_key
+        .map(ByteBuf::into_vec)
+        .ok_or(AttestationError::NoPublicKey)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use aws_nitro_enclaves_nsm_api::api::Digest;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, IsCa, KeyPair, PKCS_ECDSA_P384_SHA384,
+    };
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, ECDSA_P384_SHA384_FIXED_SIGNING};
+
+    use super::*;
+
+    pub(crate) const PCR0: [u8; 48] = [0xa0; 48];
+
+    fn ca_params(name: &str) -> CertificateParams {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+    }
+
+    /// Document attesting public_key with PCR0 signed under a test root
+    /// and intermediate like the NSM does, and the policy trusting that
+    /// root and expecting PCR0
+    pub(crate) fn signed_document(public_key: Option<&[u8]>) -> (Vec<u8>, AttestationPolicy) {
+        let key = || KeyPair::generate_for(&PKCS_ECDSA_P384_SHA384).unwrap();
+        let (root_key, intermediate_key, leaf_key) = (key(), key(), key());
+        let root = ca_params("test root").self_signed(&root_key).unwrap();
+        let intermediate = ca_params("test intermediate")
+            .signed_by(&intermediate_key, &root, &root_key)
+            .unwrap();
+        let mut leaf_params = CertificateParams::new(Vec::new()).unwrap();
+        leaf_params
+            .distinguished_name
+            .push(DnType::CommonName, "test enclave");
+        let leaf = leaf_params
+            .signed_by(&leaf_key, &intermediate, &intermediate_key)
+            .unwrap();
+
+        let doc = AttestationDoc::new(
+            "test-module".to_owned(),
+            Digest::SHA384,
+            0,
+            BTreeMap::from([(0, PCR0.to_vec()), (1, vec![0xa1; 48])]),
+            leaf.der().to_vec(),
+            vec![root.der().to_vec(), intermediate.der().to_vec()],
+            None,
+            None,
+            public_key.map(<[u8]>::to_vec),
+        );
+        let payload = doc.to_binary();
+        let protected = serde_cbor::to_vec(&BTreeMap::from([(
+            Value::Integer(COSE_ALG),
+            Value::Integer(COSE_ES384),
+        )]))
+        .unwrap();
+        let signed = serde_cbor::to_vec(&Value::Array(vec![
+            Value::Text("Signature1".to_owned()),
+            Value::Bytes(protected.clone()),
+            Value::Bytes(Vec::new()),
+            Value::Bytes(payload.clone()),
+        ]))
+        .unwrap();
+        let rng = SystemRandom::new();
+        let signer = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P384_SHA384_FIXED_SIGNING,
+            &leaf_key.serialize_der(),
+            &rng,
+        )
+        .unwrap();
+        let signature = signer.sign(&rng, &signed).unwrap();
+
+        let document = serde_cbor::to_vec(&Value::Array(vec![
+            Value::Bytes(protected),
+            Value::Map(BTreeMap::new()),
+            Value::Bytes(payload),
+            Value::Bytes(signature.as_ref().to_vec()),
+        ]))
+        .unwrap();
+        let policy = AttestationPolicy {
+            root: root.der().to_vec(),
+            pcrs: BTreeMap::from([(0, PCR0.to_vec())]),
+        };
+        (document, policy)
+    }
+
+    #[test]
+    fn verifies_signed_document() {
+        let (document, policy) = signed_document(Some(&[7; 32]));
+        assert_eq!(verify_attestation(&document, &policy).unwrap(), [7; 32]);
+
+        // PCRs not in the policy are not checked
+        let any_image = AttestationPolicy {
+            pcrs: BTreeMap::new(),
+            ..policy.clone()
+        };
+        assert_eq!(verify_attestation(&document, &any_image).unwrap(), [7; 32]);
+    }
+
+    #[test]
+    fn rejects_pcr_mismatch() {
+        let (document, mut policy) = signed_document(Some(&[7; 32]));
+        policy.pcrs.insert(1, vec![0xb1; 48]);
+        assert!(matches!(
+            verify_attestation(&document, &policy),
+            Err(AttestationError::PcrError(1))
+        ));
+
+        // a PCR the document does not have
+        policy.pcrs = BTreeMap::from([(8, vec![0xa8; 48])]);
+        assert!(matches!(
+            verify_attestation(&document, &policy),
+            Err(AttestationError::PcrError(8))
+        ));
+    }
+
+    #[test]
+    fn binds_public_key() {
+        // the key is part of the signed payload, it cannot be swapped
+        let (document, policy) = signed_document(Some(&[7; 32]));
+        let mut tampered = document.clone();
+        let at = tampered
+            .windows(32)
+            .position(|window| window == [7; 32])
+            .unwrap();
+        tampered[at] ^= 1;
+        assert!(matches!(
+            verify_attestation(&tampered, &policy),
+            Err(AttestationError::SignatureError)
+        ));
+
+        let (document, policy) = signed_document(None);
+        assert!(matches!(
+            verify_attestation(&document, &policy),
+            Err(AttestationError::NoPublicKey)
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_root() {
+        let (document, _) = signed_document(Some(&[7; 32]));
+        let (_, other) = signed_document(Some(&[7; 32]));
+        assert!(matches!(
+            verify_attestation(&document, &other),
+            Err(AttestationError::ChainError(_))
+        ));
+        assert!(matches!(
+            verify_attestation(&document, &AttestationPolicy::default()),
+            Err(AttestationError::ChainError(_))
+        ));
+    }
+
+    #[test]
+    fn embedded_root_is_self_signed() {
+        let policy = AttestationPolicy::default();
+        let root = parse_certificate(&policy.root).unwrap();
+        assert!(root.verify_signature(None).is_ok());
+        assert_eq!(
+            root.subject().to_string(),
+            "C=US, O=Amazon, OU=AWS, CN=aws.nitro-enclaves"
+        );
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let policy = AttestationPolicy::default();
+        assert!(matches!(
+            verify_attestation(b"garbage", &policy),
+            Err(AttestationError::FormatError(_))
+        ));
+
+        // well formed COSE_Sign1 with a payload that is no attestation document
+        let protected = serde_cbor::to_vec(&BTreeMap::from([(
+            Value::Integer(COSE_ALG),
+            Value::Integer(COSE_ES384),
+        )]))
+        .unwrap();
+        let document = serde_cbor::to_vec(&Value::Array(vec![
+            Value::Bytes(protected),
+            Value::Map(BTreeMap::new()),
+            Value::Bytes(b"payload".to_vec()),
+            Value::Bytes(vec![0; 96]),
+        ]))
+        .unwrap();
+        assert!(matches!(
+            verify_attestation(&document, &policy),
+            Err(AttestationError::FormatError(_))
+        ));
+    }
+}
@@ -0,0 +1,515 @@
+// Retry policy for (re)connecting sockets
+//
+// run_with_backoff used to double a whole number of seconds up to a
+// cap, retry forever and print every error. All proxies restarting at
+// once then retried in lockstep, and callers had no way to give up,
+// cancel or count attempts. Backoff keeps the exponential delay but
+// spreads it with jitter, optionally gives up after a max elapsed time,
+// starts over after a success, reports every attempt to a hook and
+// stops sleeping as soon as a shutdown is requested.
+//
+// Time goes through the Clock trait so that the policy can be tested
+// without actually sleeping.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::shutdown;
+use crate::ProxyError;
+
+// how often a sleeping retry checks for cancellation
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+impl<C: Clock> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        (**self).sleep(duration)
+    }
+}
+
+/// What happened to an operation run with backoff, passed to the hook
+#[derive(Debug)]
+pub enum BackoffEvent {
+    /// attempt failed, the next one starts after delay
+    Retry {
+        attempt: u32,
+        delay: Duration,
+        error: ProxyError,
+    },
+    /// attempt succeeded after earlier failures
+    Recovered { attempts: u32, elapsed: Duration },
+    /// max elapsed time reached, no more attempts
+    GaveUp { attempts: u32, elapsed: Duration },
+    /// shutdown requested while retrying
+    Cancelled { attempts: u32 },
+}
+
+#[derive(Error, Debug)]
+pub enum BackoffError {
+    #[error("gave up after {attempts} attempts in {elapsed:?}")]
+    GaveUp {
+        attempts: u32,
+        elapsed: Duration,
+        #[source]
+        source: ProxyError,
+    },
+    #[error("cancelled after {attempts} attempts")]
+    Cancelled { attempts: u32 },
+}
+
+type Hook = Box<dyn FnMut(BackoffEvent) + Send>;
+type Cancel = Box<dyn Fn() -> bool + Send>;
+
+pub struct Backoff<C: Clock = SystemClock> {
+    initial: Duration,
+    max: Duration,
+    // fraction of each delay that is randomized, 0 - none, 1 - full jitter
+    jitter: f64,
+    max_elapsed: Option<Duration>,
+    clock: C,
+    hook: Option<Hook>,
+    cancel: Cancel,
+    rng: u64,
+    // state of the current run of failures
+    attempts: u32,
+    delay: Duration,
+    started: Option<Instant>,
+}
+
+impl Backoff {
+    /// Delays start at initial and double up to max, with 20% jitter,
+    /// retrying until a shutdown is requested
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self::with_clock(initial, max, SystemClock)
+    }
+}
+
+impl<C: Clock> Backoff<C> {
+    pub fn with_clock(initial: Duration, max: Duration, clock: C) -> Self {
+        Backoff {
+            initial,
+            max: max.max(initial),
+            jitter: 0.2,
+            max_elapsed: None,
+            clock,
+            hook: None,
+            cancel: Box::new(shutdown::shutdown_requested),
+            rng: seed(),
+            attempts: 0,
+            delay: initial,
+            started: None,
+        }
+    }
+
+    /// Randomize this fraction of each delay, clamped to 0..=1
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Give up once failures keep going for this long
+    pub fn max_elapsed(mut self, max_elapsed: Duration) -> Self {
+        self.max_elapsed = Some(max_elapsed);
+        self
+    }
+
+    /// Called on every retry, recovery, give up and cancellation
+    pub fn hook<F: FnMut(BackoffEvent) + Send + 'static>(mut self, hook: F) -> Self {
+        self.hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Stop retrying once cancel returns true, defaults to the shutdown signal
+    pub fn cancel_when<F: Fn() -> bool + Send + 'static>(mut self, cancel: F) -> Self {
+        self.cancel = Box::new(cancel);
+        self
+    }
+
+    /// Fixed jitter seed, for reproducible delays
+    pub fn seed(mut self, seed: u64) -> Self {
+        // xorshift gets stuck at 0
+        self.rng = seed | 1;
+        self
+    }
+
+    /// Failed attempts since the last success
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Start over with the initial delay, done after every success
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.delay = self.initial;
+        self.started = None;
+    }
+
+    /// Record a failure and get the delay before the next attempt,
+    /// None once the max elapsed time is reached
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        let now = self.clock.now();
+        let started = *self.started.get_or_insert(now);
+        self.attempts += 1;
+
+        let elapsed = now.saturating_duration_since(started);
+        if let Some(max_elapsed) = self.max_elapsed {
+            if elapsed >= max_elapsed {
+                return None;
+            }
+        }
+
+        // take off up to jitter of the delay so that the max still holds
+        let jitter = self.delay.mul_f64(self.jitter * self.next_random());
+        let mut delay = self.delay - jitter;
+        self.delay = (self.delay * 2).min(self.max);
+
+        // never sleep past the deadline
+        if let Some(max_elapsed) = self.max_elapsed {
+            delay = delay.min(max_elapsed - elapsed);
+        }
+
+        Some(delay)
+    }
+
+    /// Run f until it succeeds, the max elapsed time is reached or
+    /// retrying is cancelled
+    pub fn retry<R, F: FnMut() -> Result<R, ProxyError>>(
+        &mut self,
+        mut f: F,
+    ) -> Result<R, BackoffError> {
+        loop {
+            let err = match f() {
+                Ok(r) => {
+                    if self.attempts > 0 {
+                        let event = BackoffEvent::Recovered {
+                            attempts: self.attempts,
+                            elapsed: self.elapsed(),
+                        };
+                        self.emit(event);
+                    }
+                    self.reset();
+                    return Ok(r);
+                }
+                Err(err) => err,
+            };
+
+            // nothing is in flight while (re)connecting, stop right away
+            if (self.cancel)() {
+                return Err(self.cancelled());
+            }
+
+            let Some(delay) = self.next_delay() else {
+                let (attempts, elapsed) = (self.attempts, self.elapsed());
+                self.emit(BackoffEvent::GaveUp { attempts, elapsed });
+                self.reset();
+                return Err(BackoffError::GaveUp {
+                    attempts,
+                    elapsed,
+                    source: err,
+                });
+            };
+
+            self.emit(BackoffEvent::Retry {
+                attempt: self.attempts,
+                delay,
+                error: err,
+            });
+
+            if !self.sleep(delay) {
+                return Err(self.cancelled());
+            }
+        }
+    }
+
+    // sleep in slices so that a shutdown is noticed quickly,
+    // returns false if cancelled
+    fn sleep(&self, delay: Duration) -> bool {
+        let deadline = self.clock.now() + delay;
+        loop {
+            if (self.cancel)() {
+                return false;
+            }
+
+            let left = deadline.saturating_duration_since(self.clock.now());
+            if left.is_zero() {
+                return true;
+            }
+            self.clock.sleep(left.min(CANCEL_POLL));
+        }
+    }
+
+    fn cancelled(&mut self) -> BackoffError {
+        let attempts = self.attempts;
+        self.emit(BackoffEvent::Cancelled { attempts });
+        self.reset();
+        BackoffError::Cancelled { attempts }
+    }
+
+    fn elapsed(&self) -> Duration {
+        match self.started {
+            Some(started) => self.clock.now().saturating_duration_since(started),
+            None => Duration::ZERO,
+        }
+    }
+
+    fn emit(&mut self, event: BackoffEvent) {
+        if let Some(hook) = &mut self.hook {
+            hook(event);
+        }
+    }
+
+    // xorshift64, uniform in 0..1, jitter does not need a real rng
+    fn next_random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// differs between processes so that restarts do not retry in lockstep
+fn seed() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    (nanos ^ (u64::from(std::process::id()) << 32)) | 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    use crate::SocketError;
+
+    struct MockClock {
+        start: Instant,
+        offset: Mutex<Duration>,
+        slept: Mutex<Vec<Duration>>,
+    }
+
+    impl MockClock {
+        fn new() -> Arc<Self> {
+            Arc::new(MockClock {
+                start: Instant::now(),
+                offset: Mutex::new(Duration::ZERO),
+                slept: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.offset.lock().unwrap() += duration;
+        }
+
+        fn total_slept(&self) -> Duration {
+            self.slept.lock().unwrap().iter().sum()
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.start + *self.offset.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.slept.lock().unwrap().push(duration);
+            self.advance(duration);
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn failure() -> ProxyError {
+        ProxyError::VsockError(SocketError::EofError)
+    }
+
+    fn backoff(clock: &Arc<MockClock>) -> Backoff<Arc<MockClock>> {
+        Backoff::with_clock(secs(1), secs(8), clock.clone())
+            .jitter(0.0)
+            .cancel_when(|| false)
+    }
+
+    #[test]
+    fn doubles_up_to_max() {
+        let clock = MockClock::new();
+        let mut backoff = backoff(&clock);
+
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay().unwrap()).collect();
+        assert_eq!(
+            delays,
+            [secs(1), secs(2), secs(4), secs(8), secs(8), secs(8)]
+        );
+        assert_eq!(backoff.attempts(), 6);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let clock = MockClock::new();
+        let mut backoff = backoff(&clock).jitter(0.5).seed(42);
+
+        let mut base = secs(1);
+        let mut distinct = std::collections::HashSet::new();
+        for _ in 0..100 {
+            let delay = backoff.next_delay().unwrap();
+            assert!(delay <= base && delay >= base / 2, "{delay:?} for {base:?}");
+            distinct.insert(delay);
+            base = (base * 2).min(secs(8));
+        }
+        assert!(distinct.len() > 10);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let clock = MockClock::new();
+        let mut backoff = backoff(&clock);
+
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), Some(secs(1)));
+    }
+
+    #[test]
+    fn gives_up_after_max_elapsed() {
+        let clock = MockClock::new();
+        let mut backoff = backoff(&clock).max_elapsed(secs(10));
+
+        let mut attempts = 0;
+        let result: Result<(), _> = backoff.retry(|| {
+            attempts += 1;
+            Err(failure())
+        });
+
+        // 1 + 2 + 4, then cut to the 3s left of the deadline
+        assert_eq!(clock.total_slept(), secs(10));
+        assert_eq!(attempts, 5);
+        match result {
+            Err(BackoffError::GaveUp {
+                attempts, elapsed, ..
+            }) => {
+                assert_eq!(attempts, 5);
+                assert_eq!(elapsed, secs(10));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn success_resets_and_reports_recovery() {
+        let clock = MockClock::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let mut backoff = backoff(&clock).hook(move |event| {
+            recorded.lock().unwrap().push(match event {
+                BackoffEvent::Retry { attempt, delay, .. } => format!("retry {attempt} {delay:?}"),
+                BackoffEvent::Recovered { attempts, elapsed } => {
+                    format!("recovered {attempts} {elapsed:?}")
+                }
+                BackoffEvent::GaveUp { attempts, .. } => format!("gave up {attempts}"),
+                BackoffEvent::Cancelled { attempts } => format!("cancelled {attempts}"),
+            })
+        });
+
+        let mut failures = 2;
+        let result = backoff.retry(|| {
+            if failures > 0 {
+                failures -= 1;
+                Err(failure())
+            } else {
+                Ok(7)
+            }
+        });
+
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["retry 1 1s", "retry 2 2s", "recovered 2 3s"]
+        );
+
+        // the next run of failures starts from the initial delay again
+        assert_eq!(backoff.next_delay(), Some(secs(1)));
+    }
+
+    #[test]
+    fn no_events_on_first_success() {
+        let clock = MockClock::new();
+        let called = Arc::new(AtomicBool::new(false));
+        let flag = called.clone();
+        let mut backoff = backoff(&clock).hook(move |_| flag.store(true, Ordering::Relaxed));
+
+        assert_eq!(backoff.retry(|| Ok(1)).unwrap(), 1);
+        assert!(!called.load(Ordering::Relaxed));
+        assert_eq!(clock.total_slept(), Duration::ZERO);
+    }
+
+    #[test]
+    fn cancel_interrupts_sleep() {
+        let clock = MockClock::new();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        let sleeper = clock.clone();
+        let mut backoff = Backoff::with_clock(secs(60), secs(60), clock.clone())
+            .jitter(0.0)
+            .cancel_when(move || {
+                // cancel half a second into the first sleep
+                if sleeper.total_slept() >= Duration::from_millis(500) {
+                    flag.store(true, Ordering::Relaxed);
+                }
+                flag.load(Ordering::Relaxed)
+            });
+
+        let result: Result<(), _> = backoff.retry(|| Err(failure()));
+
+        assert!(matches!(
+            result,
+            Err(BackoffError::Cancelled { attempts: 1 })
+        ));
+        assert!(cancel.load(Ordering::Relaxed));
+        assert_eq!(clock.total_slept(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn cancelled_before_first_sleep() {
+        let clock = MockClock::new();
+        let mut backoff = backoff(&clock).cancel_when(|| true);
+
+        let result: Result<(), _> = backoff.retry(|| Err(failure()));
+
+        assert!(matches!(
+            result,
+            Err(BackoffError::Cancelled { attempts: 0 })
+        ));
+        assert_eq!(clock.total_slept(), Duration::ZERO);
+    }
+}
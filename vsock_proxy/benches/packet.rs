@@ -0,0 +1,107 @@
+// Micro benchmarks of the per packet work done by the proxies
+
+use std::hint::black_box;
+use std::net::Ipv4Addr;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use oyster_raw_proxy::packet::{
+    checksum_ip4, checksum_tcp4, is_reserved_addr, modify_packet, ChecksumMode, TCP,
+};
+
+const SRC_IP: Ipv4Addr = Ipv4Addr::new(172, 17, 0, 2);
+const DST_IP: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+const NAT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+// IPv4 + TCP headers without options, followed by payload
+fn tcp_packet(size: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; size];
+    buf[0] = 0x45;
+    buf[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+    buf[8] = 64;
+    buf[9] = TCP;
+    buf[12..16].copy_from_slice(&SRC_IP.octets());
+    buf[16..20].copy_from_slice(&DST_IP.octets());
+    buf[20..22].copy_from_slice(&40000u16.to_be_bytes());
+    buf[22..24].copy_from_slice(&443u16.to_be_bytes());
+    buf[32] = 5 << 4;
+    for (i, byte) in buf[40..].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    buf
+}
+
+const SIZES: [usize; 3] = [40, 576, 1500];
+
+fn bench_checksum_ip4(c: &mut Criterion) {
+    let packet = tcp_packet(40);
+    c.bench_function("checksum_ip4", |b| {
+        b.iter(|| checksum_ip4(black_box(&packet[..20])))
+    });
+}
+
+fn bench_checksum_tcp4(c: &mut Criterion) {
+    let mut group = c.benchmark_group("checksum_tcp4");
+    for size in SIZES {
+        let packet = tcp_packet(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &packet, |b, packet| {
+            b.iter(|| checksum_tcp4(black_box(&packet[20..]), SRC_IP, DST_IP))
+        });
+    }
+    group.finish();
+}
+
+fn bench_modify_packet(c: &mut Criterion) {
+    for mode in [
+        ChecksumMode::Incremental,
+        ChecksumMode::Verify,
+        ChecksumMode::Full,
+    ] {
+        let name = format!("modify_packet/{mode:?}").to_lowercase();
+        let mut group = c.benchmark_group(name);
+        for size in SIZES {
+            // rewriting is idempotent apart from the ttl, which stops at 1
+            let mut packet = tcp_packet(size);
+            modify_packet(&mut packet, NAT_IP, DST_IP, ChecksumMode::Full);
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_function(BenchmarkId::from_parameter(size), |b| {
+                b.iter(|| modify_packet(black_box(&mut packet), NAT_IP, DST_IP, mode))
+            });
+        }
+        group.finish();
+    }
+}
+
+fn bench_is_reserved_addr(c: &mut Criterion) {
+    // public addresses go through every check, reserved ones stop early or late
+    let addrs: Vec<u32> = [
+        Ipv4Addr::new(1, 1, 1, 1),
+        Ipv4Addr::new(8, 8, 8, 8),
+        Ipv4Addr::new(142, 250, 74, 46),
+        Ipv4Addr::new(10, 1, 2, 3),
+        Ipv4Addr::new(192, 168, 1, 1),
+        Ipv4Addr::new(255, 255, 255, 255),
+    ]
+    .iter()
+    .map(|addr| u32::from(*addr))
+    .collect();
+
+    c.bench_function("is_reserved_addr", |b| {
+        b.iter(|| {
+            addrs
+                .iter()
+                .filter(|addr| is_reserved_addr(black_box(**addr)))
+                .count()
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_checksum_ip4,
+    bench_checksum_tcp4,
+    bench_modify_packet,
+    bench_is_reserved_addr
+);
+criterion_main!(benches);
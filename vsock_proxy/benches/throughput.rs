@@ -0,0 +1,244 @@
+// End to end throughput of a proxy pair over Unix socket transports
+//
+//   generator -> dgram -> sender -> stream -> receiver -> dgram -> sink
+//
+// The generator stands in for nfqueue and writes synthetic TCP packets
+// stamped with their send time. The sender takes batches of them,
+// rewrites the source address and writes frames to a Unix stream
+// standing in for vsock, like ip-to-vsock-raw-outgoing. The receiver
+// reads frames, filters them and sends the packets out with sendmmsg,
+// like vsock-to-ip-raw-outgoing. The sink measures the latency of every
+// packet.
+//
+// cargo bench --bench throughput -- --packets 1000000 --size 1400 --rate 0
+
+use std::net::Ipv4Addr;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use socket2::{SockAddr, Socket};
+
+use oyster_raw_proxy::frame::{is_control_frame, write_frames, FrameReader};
+use oyster_raw_proxy::packet::{is_reserved_addr, modify_packet, ChecksumMode, TCP};
+use oyster_raw_proxy::{send_ip_batch, SocketError, MAX_BATCH};
+
+const SRC_IP: Ipv4Addr = Ipv4Addr::new(172, 17, 0, 2);
+const NAT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const DST_IP: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+
+// send time in ns since the start of the run, right after the TCP header
+const STAMP_OFFSET: usize = 40;
+
+#[derive(Parser)]
+struct Cli {
+    /// packets to push through
+    #[clap(long, value_parser, default_value_t = 1_000_000)]
+    packets: usize,
+    /// packet size in bytes
+    #[clap(long, value_parser = clap::value_parser!(u16).range(48..), default_value_t = 1400)]
+    size: u16,
+    /// packets per second offered by the generator, 0 sends as fast as possible
+    #[clap(long, value_parser, default_value_t = 0)]
+    rate: u64,
+    /// passed by cargo bench
+    #[clap(long, hide = true)]
+    bench: bool,
+}
+
+fn tcp_packet(size: u16) -> Vec<u8> {
+    let mut buf = vec![0u8; size.into()];
+    buf[0] = 0x45;
+    buf[2..4].copy_from_slice(&size.to_be_bytes());
+    buf[8] = 64;
+    buf[9] = TCP;
+    buf[12..16].copy_from_slice(&SRC_IP.octets());
+    buf[16..20].copy_from_slice(&DST_IP.octets());
+    buf[20..22].copy_from_slice(&40000u16.to_be_bytes());
+    buf[22..24].copy_from_slice(&443u16.to_be_bytes());
+    buf[32] = 5 << 4;
+    buf
+}
+
+fn stamp(buf: &[u8]) -> u64 {
+    u64::from_be_bytes(buf[STAMP_OFFSET..STAMP_OFFSET + 8].try_into().unwrap())
+}
+
+fn generator(out: UnixDatagram, cli: &Cli, start: Instant) -> std::io::Result<()> {
+    let mut packet = tcp_packet(cli.size);
+    let interval = match cli.rate {
+        0 => Duration::ZERO,
+        rate => Duration::from_secs(1) / rate as u32,
+    };
+
+    for i in 0..cli.packets {
+        let due = interval * i as u32;
+        let now = start.elapsed();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+
+        let sent = start.elapsed().as_nanos() as u64;
+        packet[STAMP_OFFSET..STAMP_OFFSET + 8].copy_from_slice(&sent.to_be_bytes());
+        out.send(&packet)?;
+    }
+
+    Ok(())
+}
+
+// recv without waiting, false once nothing is queued
+fn recv_ready(socket: &UnixDatagram, buf: &mut [u8]) -> std::io::Result<Option<usize>> {
+    let size = unsafe {
+        libc::recv(
+            socket.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if size < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() == std::io::ErrorKind::WouldBlock {
+            return Ok(None);
+        }
+        return Err(e);
+    }
+
+    Ok(Some(size as usize))
+}
+
+fn sender(input: UnixDatagram, link: Socket, packets: usize) -> std::io::Result<()> {
+    let mut bufs = vec![vec![0u8; 65536]; MAX_BATCH];
+    let mut sizes = [0usize; MAX_BATCH];
+    let mut left = packets;
+
+    while left > 0 {
+        // like recv_nfq_batch, wait for one then take what is queued
+        sizes[0] = input.recv(&mut bufs[0])?;
+        let mut count = 1;
+        while count < MAX_BATCH.min(left) {
+            match recv_ready(&input, &mut bufs[count])? {
+                Some(size) => sizes[count] = size,
+                None => break,
+            }
+            count += 1;
+        }
+
+        for (buf, size) in bufs.iter_mut().zip(sizes).take(count) {
+            modify_packet(&mut buf[..size], NAT_IP, DST_IP, ChecksumMode::Incremental);
+        }
+        let frames: Vec<&[u8]> = bufs
+            .iter()
+            .zip(sizes)
+            .take(count)
+            .map(|(buf, size)| &buf[..size])
+            .collect();
+        write_frames(&link, &frames).map_err(|(_, e)| std::io::Error::other(e))?;
+        left -= count;
+    }
+
+    Ok(())
+}
+
+// same checks as vsock-to-ip-raw-outgoing
+fn should_forward(buf: &[u8]) -> bool {
+    if is_control_frame(buf) || buf.len() < 24 {
+        return false;
+    }
+
+    let src_addr = Ipv4Addr::from(u32::from_be_bytes(buf[12..16].try_into().unwrap()));
+    let dst_addr = u32::from_be_bytes(buf[16..20].try_into().unwrap());
+    let src_port = u16::from_be_bytes(buf[20..22].try_into().unwrap());
+
+    src_addr == NAT_IP
+        && !is_reserved_addr(dst_addr)
+        && (src_port == 80 || src_port == 443 || (1024..=61439).contains(&src_port))
+}
+
+fn receiver(mut link: Socket, out: Socket, dest: SockAddr) -> Result<(), SocketError> {
+    let mut reader = FrameReader::new();
+    loop {
+        let frames = match reader.read_frames(&mut link) {
+            Ok(frames) => frames,
+            Err(SocketError::EofError) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let packets: Vec<&[u8]> = frames.filter(|frame| should_forward(frame)).collect();
+        send_ip_batch(&out, &packets, &dest)?;
+    }
+}
+
+fn sink(input: UnixDatagram, packets: usize, start: Instant) -> std::io::Result<Vec<u64>> {
+    let mut buf = vec![0u8; 65536];
+    let mut latencies = Vec::with_capacity(packets);
+    for _ in 0..packets {
+        let size = input.recv(&mut buf)?;
+        let received = start.elapsed().as_nanos() as u64;
+        latencies.push(received - stamp(&buf[..size]));
+    }
+
+    Ok(latencies)
+}
+
+fn percentile(sorted: &[u64], p: f64) -> Duration {
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    Duration::from_nanos(sorted[index])
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let (gen_out, sender_in) = UnixDatagram::pair()?;
+    let (link_tx, link_rx) = UnixStream::pair()?;
+
+    let sink_path: PathBuf =
+        std::env::temp_dir().join(format!("throughput-bench-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&sink_path);
+    let sink_in = UnixDatagram::bind(&sink_path)?;
+    let dest = SockAddr::unix(&sink_path)?;
+    let receiver_out: Socket = UnixDatagram::unbound()?.into();
+
+    let packets = cli.packets;
+    let start = Instant::now();
+
+    let sink = std::thread::spawn(move || sink(sink_in, packets, start));
+    let receiver = std::thread::spawn(move || receiver(link_rx.into(), receiver_out, dest));
+    let sender = std::thread::spawn(move || {
+        let link: Socket = link_tx.into();
+        sender(sender_in, link.try_clone()?, packets)?;
+        // eof ends the receiver
+        link.shutdown(std::net::Shutdown::Write)
+    });
+    generator(gen_out, &cli, start)?;
+
+    let mut latencies = sink.join().unwrap()?;
+    let elapsed = start.elapsed();
+    sender.join().unwrap()?;
+    receiver.join().unwrap()?;
+    let _ = std::fs::remove_file(&sink_path);
+
+    latencies.sort_unstable();
+    let pps = packets as f64 / elapsed.as_secs_f64();
+    println!(
+        "{} packets of {} bytes in {:.2?}",
+        packets, cli.size, elapsed
+    );
+    println!(
+        "throughput: {:.0} packets/s, {:.2} Gbit/s",
+        pps,
+        pps * f64::from(cli.size) * 8.0 / 1e9
+    );
+    println!(
+        "latency: p50 {:.1?}, p90 {:.1?}, p99 {:.1?}, p99.9 {:.1?}, max {:.1?}",
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.9),
+        percentile(&latencies, 0.99),
+        percentile(&latencies, 0.999),
+        percentile(&latencies, 1.0)
+    );
+
+    Ok(())
+}
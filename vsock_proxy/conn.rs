@@ -0,0 +1,186 @@
+// A connected vsock link, plain or secure
+//
+// Connections are set up in lib.rs, with --secure the handshake runs
+// first (see secure.rs). Everything after goes through VsockConn, so
+// the frame reader, the hello and the link writer do not care which
+// kind of link they are on. Written frames are sealed into records of
+// their own, a failed batch still tells how many frames went out whole.
+//
+// Links are stream sockets by default, with --transport seqpacket they
+// are seqpacket sockets that keep message boundaries, one frame per
+// message (see frame.rs). Kernels without seqpacket vsock fall back to
+// stream, the transport of a connection follows its socket type.
+
+use std::io::Read;
+
+use clap::ValueEnum;
+use socket2::{Socket, Type};
+
+use crate::frame::{read_frame, read_message, write_frames, write_messages, FrameReader, Frames};
+use crate::secure::SecureChannel;
+use crate::SocketError;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Transport {
+    /// frames delimited by their length in a byte stream
+    #[default]
+    Stream,
+    /// one frame per message, needs seqpacket vsock support in the kernel
+    Seqpacket,
+}
+
+impl Transport {
+    pub fn socket_type(self) -> Type {
+        match self {
+            Transport::Stream => Type::STREAM,
+            Transport::Seqpacket => Type::SEQPACKET,
+        }
+    }
+
+    // transport of a connected socket
+    fn of(socket: &Socket) -> Self {
+        match socket.r#type() {
+            Ok(Type::SEQPACKET) => Transport::Seqpacket,
+            _ => Transport::Stream,
+        }
+    }
+}
+
+pub struct VsockConn {
+    socket: Socket,
+    channel: Option<SecureChannel>,
+    transport: Transport,
+}
+
+impl VsockConn {
+    pub fn plain(socket: Socket) -> Self {
+        VsockConn {
+            transport: Transport::of(&socket),
+            socket,
+            channel: None,
+        }
+    }
+
+    pub fn secure(socket: Socket, channel: SecureChannel) -> Self {
+        VsockConn {
+            transport: Transport::of(&socket),
+            socket,
+            channel: Some(channel),
+        }
+    }
+
+    pub fn socket(&self) -> &Socket {
+        &self.socket
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.channel.is_some()
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Write whole frames, see frame::write_frames and frame::write_messages
+    pub fn write_frames(&mut self, frames: &[&[u8]]) -> Result<(), (usize, SocketError)> {
+        let Some(channel) = &mut self.channel else {
+            return match self.transport {
+                Transport::Stream => write_frames(&self.socket, frames),
+                Transport::Seqpacket => write_messages(&self.socket, frames),
+            };
+        };
+
+        let mut sealed = Vec::with_capacity(frames.len());
+        for frame in frames {
+            let mut buf = Vec::new();
+            channel.seal(frame, &mut buf).map_err(|e| (0, e))?;
+            sealed.push(buf);
+        }
+        let sealed: Vec<&[u8]> = sealed.iter().map(Vec::as_slice).collect();
+        write_frames(&self.socket, &sealed)
+    }
+
+    /// Write a whole frame
+    pub fn write_frame(&mut self, buf: &[u8]) -> Result<(), SocketError> {
+        self.write_frames(&[buf]).map_err(|(_, err)| err)
+    }
+
+    /// Read a single frame into buf, returns its total size
+    pub fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, SocketError> {
+        match self.transport {
+            Transport::Stream => read_frame(self, buf),
+            Transport::Seqpacket => read_message(self, buf),
+        }
+    }
+
+    /// Read whole frames through reader, see FrameReader::read_frames
+    pub fn read_frames<'a>(
+        &mut self,
+        reader: &'a mut FrameReader,
+    ) -> Result<Frames<'a>, SocketError> {
+        match self.transport {
+            Transport::Stream => reader.read_frames(self),
+            Transport::Seqpacket => reader.read_messages(self),
+        }
+    }
+}
+
+impl Read for VsockConn {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.channel {
+            Some(channel) => channel.read(&mut &self.socket, buf),
+            None => (&self.socket).read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use socket2::Domain;
+
+    use super::*;
+    use crate::frame::{gso_frame, heartbeat_frame};
+
+    fn seqpacket_pair() -> (VsockConn, VsockConn) {
+        let (a, b) = Socket::pair(Domain::UNIX, Type::SEQPACKET, None).unwrap();
+        (VsockConn::plain(a), VsockConn::plain(b))
+    }
+
+    #[test]
+    fn seqpacket_keeps_frames_apart() {
+        let (mut tx, mut rx) = seqpacket_pair();
+        assert_eq!(tx.transport(), Transport::Seqpacket);
+
+        let mut packet = vec![0x45; 60];
+        packet[2..4].copy_from_slice(&60u16.to_be_bytes());
+        let gso = gso_frame(1400, &vec![0x45; 3000]);
+        let frames: [&[u8]; 3] = [&packet, &heartbeat_frame(), &gso];
+        tx.write_frames(&frames).unwrap();
+
+        // one frame per read
+        let mut reader = FrameReader::new();
+        for frame in frames {
+            let read: Vec<&[u8]> = rx.read_frames(&mut reader).unwrap().collect();
+            assert_eq!(read, [frame]);
+        }
+    }
+
+    #[test]
+    fn seqpacket_rejects_bad_lengths() {
+        let (tx, mut rx) = seqpacket_pair();
+
+        // claims 40 bytes, carries 60
+        let mut packet = vec![0x45; 60];
+        packet[2..4].copy_from_slice(&40u16.to_be_bytes());
+        tx.socket().send(&packet).unwrap();
+        // the next message is read as is
+        tx.socket().send(&heartbeat_frame()).unwrap();
+
+        let mut buf = vec![0; 100];
+        assert!(matches!(
+            rx.read_frame(&mut buf),
+            Err(SocketError::FrameError(_))
+        ));
+        assert_eq!(rx.read_frame(&mut buf).unwrap(), 4);
+    }
+}
@@ -0,0 +1,481 @@
+// Names of the addresses containers talk to, learned from DNS answers
+//
+// Relays behind CDNs change addresses all the time, so egress policies
+// (policy.rs) may name domains instead. The addresses they stand for are
+// learned from the answers dns-forwarder gets to its own queries, and kept
+// for their TTL, at least MIN_TTL so that the connection right after a
+// short lived answer still finds it. Answers that merely go by on the
+// download path are not trusted, anyone may send a packet from port 53
+// and have its addresses allowed, so containers have to resolve through
+// dns-forwarder.
+//
+// The enforcing proxy is another process, so the cache is shared as a
+// file, one `<expires> <ip> <name>` line per mapping with the expiry in
+// unix seconds. The forwarder writes it as soon as it learns a new
+// mapping, merged with what is in the file already so that mappings of a
+// previous run are kept, and ip-to-vsock-raw-outgoing reloads it on
+// change. Files are replaced by rename, readers never see half of one.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::reload::{watch_file, ConfigError};
+
+/// Seconds a learned mapping is kept at least
+pub const MIN_TTL: u32 = 60;
+/// Max mappings kept, answers beyond it are not learned until some expire
+pub const MAX_ENTRIES: usize = 1 << 16;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const CLASS_IN: u16 = 1;
+// CNAME chains longer than this are not followed
+const MAX_CHAIN: usize = 8;
+
+/// An address a name resolved to, for ttl seconds
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Answer {
+    pub name: String,
+    pub addr: Ipv4Addr,
+    pub ttl: u32,
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// name at offset at of msg, lowercase without the trailing dot, and the
+// offset after it, compression pointers are followed
+pub(crate) fn read_name(msg: &[u8], mut at: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // every pointer has to go back, so a loop cannot go on forever
+    let mut limit = at;
+    loop {
+        let length = *msg.get(at)? as usize;
+        match length {
+            0 => break,
+            length if length & 0xc0 == 0xc0 => {
+                let pointer = (length & 0x3f) << 8 | *msg.get(at + 1)? as usize;
+                if pointer >= limit {
+                    return None;
+                }
+                end.get_or_insert(at + 2);
+                limit = pointer;
+                at = pointer;
+            }
+            length if length & 0xc0 == 0 => {
+                let label = msg.get(at + 1..at + 1 + length)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.extend(label.iter().map(|c| c.to_ascii_lowercase() as char));
+                at += 1 + length;
+            }
+            _ => return None,
+        }
+    }
+    Some((name, end.unwrap_or(at + 1)))
+}
+
+pub(crate) fn read_u16(msg: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(msg.get(at..at + 2)?.try_into().ok()?))
+}
+
+/// Addresses of a DNS response, each under the name it has and the names
+/// pointing to it through CNAMEs, None if msg is no successful response
+pub fn parse_response(msg: &[u8]) -> Option<Vec<Answer>> {
+    const QR: u16 = 0x8000;
+    const RCODE: u16 = 0x000f;
+
+    let flags = read_u16(msg, 2)?;
+    if msg.len() < HEADER_LEN || flags & QR == 0 || flags & RCODE != 0 {
+        return None;
+    }
+    let questions = read_u16(msg, 4)?;
+    let records = read_u16(msg, 6)?;
+
+    let mut at = HEADER_LEN;
+    for _ in 0..questions {
+        at = read_name(msg, at)?.1 + 4;
+    }
+
+    let mut addrs = Vec::new();
+    // alias, target and ttl
+    let mut cnames = Vec::new();
+    for _ in 0..records {
+        let (name, next) = read_name(msg, at)?;
+        let record_type = read_u16(msg, next)?;
+        let class = read_u16(msg, next + 2)?;
+        let ttl = u32::from_be_bytes(msg.get(next + 4..next + 8)?.try_into().ok()?);
+        let data_length = read_u16(msg, next + 8)? as usize;
+        let data = next + 10;
+        let rdata = msg.get(data..data + data_length)?;
+        match (record_type, class) {
+            (TYPE_A, CLASS_IN) if data_length == 4 => {
+                let addr = Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]);
+                addrs.push(Answer { name, addr, ttl });
+            }
+            (TYPE_CNAME, CLASS_IN) => cnames.push((name, read_name(msg, data)?.0, ttl)),
+            _ => {}
+        }
+        at = data + data_length;
+    }
+
+    let mut answers = Vec::new();
+    for answer in addrs {
+        let mut name = answer.name.clone();
+        let mut ttl = answer.ttl;
+        answers.push(answer.clone());
+        for _ in 0..MAX_CHAIN {
+            let Some((alias, _, alias_ttl)) = cnames.iter().find(|(_, target, _)| *target == name)
+            else {
+                break;
+            };
+            ttl = ttl.min(*alias_ttl);
+            name = alias.clone();
+            answers.push(Answer {
+                name: name.clone(),
+                addr: answer.addr,
+                ttl,
+            });
+        }
+    }
+    Some(answers)
+}
+
+/// A mapping of the cache file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub expires: u64,
+    pub addr: Ipv4Addr,
+    pub name: String,
+}
+
+/// Parse a cache file, one `<expires> <ip> <name>` per line
+pub fn parse_cache(content: &str) -> Result<Vec<CacheEntry>, (usize, String)> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            let invalid = || (index + 1, "expected <expires> <ip> <name>".to_owned());
+            let [expires, addr, name] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(invalid());
+            };
+            Ok(CacheEntry {
+                expires: expires.parse().map_err(|_| invalid())?,
+                addr: addr.parse().map_err(|_| invalid())?,
+                name: name.to_ascii_lowercase(),
+            })
+        })
+        .collect()
+}
+
+/// Read a cache file, a missing one is empty
+pub fn read_cache_file(path: &Path) -> Result<Vec<CacheEntry>, ConfigError> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(ConfigError::ReadError {
+                path: path.display().to_string(),
+                source: e,
+            })
+        }
+    };
+    parse_cache(&content).map_err(|(line, reason)| ConfigError::RuleError {
+        path: path.display().to_string(),
+        line,
+        reason,
+    })
+}
+
+fn write_cache_file(path: &Path, entries: &[CacheEntry]) -> std::io::Result<()> {
+    let content: String = entries
+        .iter()
+        .map(|entry| format!("{} {} {}\n", entry.expires, entry.addr, entry.name))
+        .collect();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(&tmp, path)
+}
+
+struct CacheState {
+    // names of an address with when they expire
+    entries: HashMap<Ipv4Addr, Vec<(String, u64)>>,
+    len: usize,
+    // a mapping was learned that is not in the file yet
+    dirty: bool,
+}
+
+impl CacheState {
+    fn prune(&mut self, now: u64) {
+        for names in self.entries.values_mut() {
+            names.retain(|(_, expires)| *expires > now);
+        }
+        self.entries.retain(|_, names| !names.is_empty());
+        self.len = self.entries.values().map(Vec::len).sum();
+    }
+
+    // whether the mapping is new, known ones only get the later expiry
+    fn insert(&mut self, entry: CacheEntry, now: u64) -> bool {
+        if let Some((_, expires)) = self
+            .entries
+            .get_mut(&entry.addr)
+            .and_then(|names| names.iter_mut().find(|(name, _)| *name == entry.name))
+        {
+            *expires = (*expires).max(entry.expires);
+            return false;
+        }
+
+        if self.len >= MAX_ENTRIES {
+            self.prune(now);
+            if self.len >= MAX_ENTRIES {
+                return false;
+            }
+        }
+        self.entries
+            .entry(entry.addr)
+            .or_default()
+            .push((entry.name, entry.expires));
+        self.len += 1;
+        true
+    }
+
+    fn to_entries(&self, now: u64) -> Vec<CacheEntry> {
+        self.entries
+            .iter()
+            .flat_map(|(addr, names)| {
+                names.iter().map(|(name, expires)| CacheEntry {
+                    expires: *expires,
+                    addr: *addr,
+                    name: name.clone(),
+                })
+            })
+            .filter(|entry| entry.expires > now)
+            .collect()
+    }
+}
+
+struct CacheInner {
+    state: Mutex<CacheState>,
+    learned: Condvar,
+}
+
+/// Names addresses were resolved for, shared with the threads keeping
+/// the cache file up to date
+#[derive(Clone)]
+pub struct DnsCache(Arc<CacheInner>);
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        DnsCache(Arc::new(CacheInner {
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                len: 0,
+                dirty: false,
+            }),
+            learned: Condvar::new(),
+        }))
+    }
+}
+
+impl DnsCache {
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        // a panic while holding the lock aborts the process anyway
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Names addr was resolved for that have not expired at now
+    pub fn names(&self, addr: Ipv4Addr, now: u64) -> Vec<String> {
+        self.lock().entries.get(&addr).map_or(Vec::new(), |names| {
+            names
+                .iter()
+                .filter(|(_, expires)| *expires > now)
+                .map(|(name, _)| name.clone())
+                .collect()
+        })
+    }
+
+    /// Remember answers received at now
+    pub fn learn(&self, answers: &[Answer], now: u64) {
+        let mut state = self.lock();
+        let mut learned = false;
+        for answer in answers {
+            let entry = CacheEntry {
+                expires: now + u64::from(answer.ttl.max(MIN_TTL)),
+                addr: answer.addr,
+                name: answer.name.clone(),
+            };
+            learned |= state.insert(entry, now);
+        }
+        if learned {
+            state.dirty = true;
+            self.0.learned.notify_one();
+        }
+    }
+
+    /// Replace all mappings with entries
+    pub fn replace(&self, entries: Vec<CacheEntry>) {
+        let now = now_secs();
+        let mut state = self.lock();
+        state.entries.clear();
+        state.len = 0;
+        for entry in entries.into_iter().filter(|entry| entry.expires > now) {
+            state.insert(entry, now);
+        }
+    }
+}
+
+/// Spawn a thread reloading cache from path on file change or SIGHUP
+pub fn watch_cache_file(path: PathBuf, cache: DnsCache) -> Result<JoinHandle<()>, ConfigError> {
+    match read_cache_file(&path) {
+        Ok(entries) => cache.replace(entries),
+        Err(err) => println!("{:?}", anyhow::Error::from(err)),
+    }
+    let watched = path.clone();
+    watch_file(watched, move || match read_cache_file(&path) {
+        Ok(entries) => cache.replace(entries),
+        Err(err) => {
+            // keep the old mappings, the file might have been edited by hand
+            println!("{:?}", anyhow::Error::from(err));
+        }
+    })
+}
+
+/// Spawn a thread writing what cache learns to path, merged with the
+/// mappings in the file, right away for new mappings and expired ones
+/// are dropped at least every minute
+pub fn spawn_cache_writer(path: PathBuf, cache: DnsCache) -> JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        let mut state = cache.lock();
+        if !state.dirty {
+            state = cache
+                .0
+                .learned
+                .wait_timeout(state, Duration::from_secs(60))
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        state.dirty = false;
+        let now = now_secs();
+        state.prune(now);
+        let mut entries = state.to_entries(now);
+        drop(state);
+
+        match read_cache_file(&path) {
+            Ok(known) => entries.extend(known.into_iter().filter(|entry| entry.expires > now)),
+            Err(err) => println!("{:?}", anyhow::Error::from(err)),
+        }
+        entries.sort_by(|a, b| (a.addr, &a.name, b.expires).cmp(&(b.addr, &b.name, a.expires)));
+        entries.dedup_by(|a, b| a.addr == b.addr && a.name == b.name);
+        if let Err(e) = write_cache_file(&path, &entries) {
+            let err = ConfigError::ReadError {
+                path: path.display().to_string(),
+                source: e,
+            };
+            println!("{:?}", anyhow::Error::from(err));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut buf: Vec<u8> = name
+            .split('.')
+            .flat_map(|label| [&[label.len() as u8][..], label.as_bytes()].concat())
+            .collect();
+        buf.push(0);
+        buf
+    }
+
+    fn record(owner: &[u8], record_type: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = owner.to_vec();
+        buf.extend_from_slice(&record_type.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    // response to relay.example.com, a CNAME to cdn.net with two addresses,
+    // the owners of the later records are pointers to earlier names
+    fn response() -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 0];
+        msg.extend(name("Relay.Example.com"));
+        msg.extend_from_slice(&[0, 1, 0, 1]);
+        let cdn = msg.len() + 12;
+        msg.extend(record(&[0xc0, 12], TYPE_CNAME, 300, &name("cdn.net")));
+        msg.extend(record(&[0xc0, cdn as u8], TYPE_A, 30, &[203, 0, 113, 7]));
+        msg.extend(record(&[0xc0, cdn as u8], TYPE_A, 600, &[203, 0, 113, 8]));
+        msg
+    }
+
+    #[test]
+    fn parses_responses() {
+        let answer = |name: &str, last: u8, ttl: u32| Answer {
+            name: name.to_owned(),
+            addr: Ipv4Addr::new(203, 0, 113, last),
+            ttl,
+        };
+        assert_eq!(
+            parse_response(&response()).unwrap(),
+            [
+                answer("cdn.net", 7, 30),
+                answer("relay.example.com", 7, 30),
+                answer("cdn.net", 8, 600),
+                answer("relay.example.com", 8, 300),
+            ]
+        );
+
+        // queries, errors, pointer loops and short messages
+        let mut query = response();
+        query[2] = 0x01;
+        assert_eq!(parse_response(&query), None);
+        let mut error = response();
+        error[3] = 0x83;
+        assert_eq!(parse_response(&error), None);
+        let mut looped = response();
+        looped[12..14].copy_from_slice(&[0xc0, 12]);
+        assert_eq!(parse_response(&looped), None);
+        let msg = response();
+        assert_eq!(parse_response(&msg[..msg.len() - 1]), None);
+    }
+
+    #[test]
+    fn expires_and_shares_mappings() {
+        let cache = DnsCache::default();
+        let addr = Ipv4Addr::new(203, 0, 113, 7);
+        cache.learn(&parse_response(&response()).unwrap(), 1000);
+        // at least MIN_TTL
+        assert_eq!(cache.names(addr, 1059), ["cdn.net", "relay.example.com"]);
+        assert!(cache.names(addr, 1060).is_empty());
+        assert_eq!(cache.names(Ipv4Addr::new(203, 0, 113, 8), 1299).len(), 2);
+
+        let entries = cache.lock().to_entries(1100);
+        let content: String = entries
+            .iter()
+            .map(|entry| format!("{} {} {}\n", entry.expires, entry.addr, entry.name))
+            .collect();
+        assert_eq!(parse_cache(&content).unwrap(), entries);
+        assert_eq!(parse_cache("1 2 3\n").unwrap_err().0, 1);
+
+        cache.replace(parse_cache(&format!("{} {addr} Other.org\n", u64::MAX)).unwrap());
+        assert_eq!(cache.names(addr, now_secs()), ["other.org"]);
+    }
+}
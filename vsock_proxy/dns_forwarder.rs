@@ -0,0 +1,627 @@
+// DNS server of the enclave, see resolver.rs
+//
+// Serves DNS over UDP and TCP on --listen, answers from its cache or
+// forwards the query to dns-resolver on the parent. Queries go out on a
+// link to --vsock-addr, the parent connects back to --answer-vsock-addr
+// with the answers, every link carries frames one way only.
+
+use std::borrow::Cow;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use clap::Parser;
+use socket2::SockAddr;
+
+use oyster_raw_proxy::conn::{Transport, VsockConn};
+use oyster_raw_proxy::dns::{now_secs, parse_response, spawn_cache_writer, DnsCache};
+use oyster_raw_proxy::frame::{dns_frame, parse_dns_frame, FrameReader};
+use oyster_raw_proxy::health::{spawn_health_reporters, Counter, Health};
+use oyster_raw_proxy::heartbeat::{spawn_heartbeat, Liveness};
+use oyster_raw_proxy::link::LinkWriter;
+use oyster_raw_proxy::replay::{ReplayConfig, ReplayPolicy};
+use oyster_raw_proxy::resolver::{
+    parse_query, truncate, AnswerCache, Pending, QueryKey, MIN_UDP_SIZE,
+};
+use oyster_raw_proxy::secure::EnclaveSecureArgs;
+use oyster_raw_proxy::shutdown::{
+    exit_status, log_shutdown, or_shutdown, register_shutdown, shutdown_requested,
+};
+use oyster_raw_proxy::{
+    accept_vsock_conn_recv_hello_with_backoff, new_vsock_server_with_backoff,
+    new_vsock_socket_recv_hello_with_backoff, ProxyError, SocketError, VsockAddrParser,
+};
+
+// TCP clients idle or not reading for longer are disconnected
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    /// address to serve DNS on over UDP and TCP <ip:port>
+    #[clap(long, value_parser, default_value = "0.0.0.0:53")]
+    listen: SocketAddr,
+    /// vsock address of dns-resolver to send queries to <cid:port>
+    #[clap(short, long, value_parser = VsockAddrParser{})]
+    vsock_addr: SockAddr,
+    /// vsock address to take the answers of dns-resolver on <cid:port>
+    #[clap(long, value_parser = VsockAddrParser{})]
+    answer_vsock_addr: SockAddr,
+    /// max answers cached, 0 disables the cache
+    #[clap(long, value_parser, default_value_t = 4096)]
+    cache_size: usize,
+    /// seconds to wait for the answer of the parent, the client is left to retry after
+    #[clap(long, value_parser, default_value_t = 5)]
+    query_timeout: u64,
+    /// max TCP clients served at once, more are disconnected right away
+    #[clap(long, value_parser, default_value_t = 64)]
+    max_tcp_clients: usize,
+    /// file to share the addresses domains resolve to in with
+    /// ip-to-vsock-raw-outgoing --dns-cache-file, learned from the answers
+    #[clap(long, value_parser)]
+    dns_cache_file: Option<PathBuf>,
+    /// seconds of idle link before a heartbeat is sent, 0 disables
+    #[clap(long, value_parser, default_value_t = 5)]
+    heartbeat_interval: u64,
+    /// seconds without frames, heartbeats included, before the peer is considered dead, 0 disables
+    #[clap(long, value_parser, default_value_t = 15)]
+    peer_timeout: u64,
+    /// file to report the forwarder state to for health checks
+    #[clap(long, value_parser)]
+    health_file: Option<PathBuf>,
+    /// address to serve the forwarder state on over HTTP <ip:port>, 503 when unhealthy
+    #[clap(long, value_parser)]
+    health_addr: Option<SocketAddr>,
+    /// vsock socket type, seqpacket sends every frame as a message of its own,
+    /// has to match the parent
+    #[clap(long, value_enum, default_value_t = Transport::Stream)]
+    transport: Transport,
+    #[clap(flatten)]
+    secure: EnclaveSecureArgs,
+}
+
+// where the answer to a query goes
+enum Client {
+    Udp(SocketAddr),
+    Tcp(Arc<Mutex<TcpStream>>),
+}
+
+struct Waiting {
+    // None for queries that are not cached
+    key: Option<QueryKey>,
+    udp_size: usize,
+    client: Client,
+}
+
+struct Forwarder {
+    udp: UdpSocket,
+    writer: Arc<LinkWriter>,
+    pending: Pending<Waiting>,
+    cache: AnswerCache,
+    dns: Option<DnsCache>,
+    queries: Counter,
+    cache_hits: Counter,
+    truncated: Counter,
+    dropped: Counter,
+}
+
+impl Forwarder {
+    // answer query msg of client from the cache or send it to the parent
+    fn query(&self, msg: &[u8], client: Client) {
+        self.queries.inc();
+
+        let query = parse_query(msg);
+        let udp_size = query.as_ref().map_or(MIN_UDP_SIZE, |query| query.udp_size);
+        let cached = query
+            .as_ref()
+            .and_then(|query| self.cache.get(msg, query, now_secs()));
+        if let Some(answer) = cached {
+            self.cache_hits.inc();
+            self.reply(&client, udp_size, &answer);
+            return;
+        }
+
+        let waiting = Waiting {
+            key: query.map(|query| query.key),
+            udp_size,
+            client,
+        };
+        let Some(tag) = self.pending.insert(waiting) else {
+            self.dropped.inc();
+            return;
+        };
+        let Some(frame) = dns_frame(tag, msg) else {
+            self.pending.take(tag);
+            self.dropped.inc();
+            return;
+        };
+        // buffered while the link is re-established
+        if let Err(err) = self.writer.write_frame(&frame) {
+            println!("{:?}", anyhow::Error::from(err));
+        }
+    }
+
+    // answer from the parent to the query sent under tag
+    fn answer(&self, tag: u32, answer: &[u8]) {
+        // the client was given up on already
+        let Some(waiting) = self.pending.take(tag) else {
+            return;
+        };
+
+        let now = now_secs();
+        if let Some(key) = waiting.key {
+            self.cache.insert(key, answer, now);
+        }
+        if let Some(dns) = &self.dns {
+            if let Some(answers) = parse_response(answer) {
+                dns.learn(&answers, now);
+            }
+        }
+        self.reply(&waiting.client, waiting.udp_size, answer);
+    }
+
+    fn reply(&self, client: &Client, udp_size: usize, answer: &[u8]) {
+        let result = match client {
+            Client::Udp(addr) => {
+                // the client retries over TCP
+                let answer = match answer.len() > udp_size {
+                    true => {
+                        self.truncated.inc();
+                        Cow::Owned(truncate(answer))
+                    }
+                    false => Cow::Borrowed(answer),
+                };
+                self.udp.send_to(&answer, addr).map(|_| ())
+            }
+            Client::Tcp(stream) => {
+                let mut buf = (answer.len() as u16).to_be_bytes().to_vec();
+                buf.extend_from_slice(answer);
+                // a panic while holding the lock aborts the process anyway
+                let mut stream = stream.lock().unwrap_or_else(|e| e.into_inner());
+                stream.write_all(&buf)
+            }
+        };
+        if let Err(e) = result {
+            let err = ProxyError::IpError(SocketError::WriteError(e));
+            println!("{:?}", anyhow::Error::from(err));
+        }
+    }
+}
+
+// serve the queries of a TCP client, each with a length prefix
+fn serve_tcp(forwarder: &Forwarder, stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let mut reader = stream.try_clone()?;
+    let writer = Arc::new(Mutex::new(stream));
+
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let mut length = [0u8; 2];
+        match reader.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let msg = &mut buf[..u16::from_be_bytes(length).into()];
+        reader.read_exact(msg)?;
+        forwarder.query(msg, Client::Tcp(writer.clone()));
+    }
+}
+
+fn spawn_tcp_server(forwarder: Arc<Forwarder>, listener: TcpListener, max_clients: usize) {
+    let clients = Arc::new(AtomicUsize::new(0));
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let err = ProxyError::IpError(SocketError::ReadError(e));
+                    println!("{:?}", anyhow::Error::from(err));
+                    continue;
+                }
+            };
+            if clients.fetch_add(1, Ordering::Relaxed) >= max_clients {
+                clients.fetch_sub(1, Ordering::Relaxed);
+                forwarder.dropped.inc();
+                continue;
+            }
+
+            let forwarder = forwarder.clone();
+            let clients = clients.clone();
+            std::thread::spawn(move || {
+                // idle and broken clients simply go away
+                let _ = serve_tcp(&forwarder, stream);
+                clients.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    });
+}
+
+fn handle_answers(
+    conn: &mut VsockConn,
+    forwarder: &Forwarder,
+    health: &Health,
+) -> Result<(), ProxyError> {
+    let mut reader = FrameReader::new();
+
+    loop {
+        let frames = conn
+            .read_frames(&mut reader)
+            .map_err(ProxyError::VsockError)
+            .map_err(or_shutdown)?;
+        // back to waiting with the next read, the watchdog sees a stall otherwise
+        let _busy = health.busy();
+        health.touch_vsock();
+
+        // heartbeats and anything else are skipped
+        for (tag, answer) in frames.filter_map(parse_dns_frame) {
+            forwarder.answer(tag, answer);
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    exit_status(run(Cli::parse()))
+}
+
+fn run(cli: Cli) -> anyhow::Result<()> {
+    // stop between queries on SIGTERM/SIGINT
+    register_shutdown()?;
+
+    let udp = UdpSocket::bind(cli.listen)
+        .with_context(|| format!("could not listen on udp {}", cli.listen))?;
+    let tcp = TcpListener::bind(cli.listen)
+        .with_context(|| format!("could not listen on tcp {}", cli.listen))?;
+
+    let health = Health::new();
+    spawn_health_reporters(&health, cli.health_file.clone(), cli.health_addr)?;
+    // queries go out on link 0, answers come in on link 1
+    health.set_vsock_links(2);
+    health.for_link(1).set_vsock_listening();
+
+    // records of the secure channel are not framed for seqpacket yet
+    if cli.secure.secure && cli.transport == Transport::Seqpacket {
+        anyhow::bail!("--secure is not supported with --transport seqpacket");
+    }
+    let secure = cli.secure.config()?;
+    let liveness = Liveness::from_secs(cli.heartbeat_interval, cli.peer_timeout);
+
+    let dns = cli.dns_cache_file.clone().map(|dns_cache_file| {
+        let dns = DnsCache::default();
+        spawn_cache_writer(dns_cache_file, dns.clone());
+        dns
+    });
+
+    // answers are read by a thread of their own, it takes the connection
+    // of the parent while ours for queries is set up, with --secure both
+    // handshakes have to run at the same time
+    let (forwarder_tx, forwarder_rx) = mpsc::channel::<Arc<Forwarder>>();
+    let answer_vsock_addr = cli.answer_vsock_addr.clone();
+    let answer_socket = new_vsock_server_with_backoff(&answer_vsock_addr, cli.transport)?;
+    let answer_health = health.for_link(1);
+    let answer_secure = secure.clone();
+    std::thread::spawn(move || {
+        let accept = || {
+            let (conn, _) = accept_vsock_conn_recv_hello_with_backoff(
+                (&answer_vsock_addr, &answer_socket),
+                &liveness,
+                answer_secure.as_deref(),
+            )?;
+            answer_health.set_vsock_connected(true);
+            Ok::<_, ProxyError>(conn)
+        };
+        // stop on shutdown, the main thread exits on its own
+        let Ok(mut conn) = accept() else {
+            return;
+        };
+        let Ok(forwarder) = forwarder_rx.recv() else {
+            return;
+        };
+        loop {
+            // on errors, simply reset the erroring socket
+            let err = match handle_answers(&mut conn, &forwarder, &answer_health) {
+                Ok(_) => unreachable!("connection handler exited without error"),
+                Err(ProxyError::Shutdown) => return,
+                Err(err) => err,
+            };
+            println!("{:?}", anyhow::Error::from(err));
+            answer_health.set_vsock_listening();
+            let Ok(new_conn) = accept() else {
+                return;
+            };
+            conn = new_conn;
+        }
+    });
+
+    // get the link for queries, reconnected in the background on write errors
+    let connect = {
+        let vsock_addr = cli.vsock_addr.clone();
+        let transport = cli.transport;
+        move || {
+            let (conn, _) =
+                new_vsock_socket_recv_hello_with_backoff(&vsock_addr, transport, secure.as_deref())?;
+            Ok(conn)
+        }
+    };
+    // queries lost on a reconnect are retried by their clients soon anyway
+    let replay = ReplayConfig {
+        capacity: 1 << 16,
+        policy: ReplayPolicy::DropOldest,
+    };
+    let writer = LinkWriter::new(
+        connect()?,
+        connect,
+        liveness,
+        replay,
+        health.for_link(0),
+        false,
+    );
+    spawn_heartbeat(writer.clone());
+
+    let forwarder = Arc::new(Forwarder {
+        udp: udp.try_clone().context("could not clone udp socket")?,
+        writer,
+        pending: Pending::new(Duration::from_secs(cli.query_timeout)),
+        cache: AnswerCache::new(cli.cache_size),
+        dns,
+        queries: health.counter("dns_queries"),
+        cache_hits: health.counter("dns_cache_hits"),
+        truncated: health.counter("dns_truncated"),
+        dropped: health.counter("dns_dropped"),
+    });
+    forwarder_tx.send(forwarder.clone())?;
+
+    // give up on queries the parent did not answer in time
+    let timeouts = health.counter("dns_timeouts");
+    let sweeper = forwarder.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(1));
+        timeouts.add(sweeper.pending.expire(Instant::now()) as u64);
+    });
+
+    spawn_tcp_server(forwarder.clone(), tcp, cli.max_tcp_clients);
+
+    let mut buf = vec![0u8; 65535];
+    while !shutdown_requested() {
+        match udp.recv_from(&mut buf) {
+            Ok((size, addr)) => forwarder.query(&buf[..size], Client::Udp(addr)),
+            Err(e) => match or_shutdown(ProxyError::IpError(SocketError::ReadError(e))) {
+                ProxyError::Shutdown => break,
+                err => println!("{:?}", anyhow::Error::from(err)),
+            },
+        }
+    }
+
+    // clients retry whatever is still in flight
+    log_shutdown();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use socket2::{Domain, Socket, Type};
+
+    use super::*;
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut buf: Vec<u8> = name
+            .split('.')
+            .flat_map(|label| [&[label.len() as u8][..], label.as_bytes()].concat())
+            .collect();
+        buf.push(0);
+        buf
+    }
+
+    // query for the A records of owner, without EDNS
+    fn query(id: u16, owner: &str) -> Vec<u8> {
+        let mut msg = id.to_be_bytes().to_vec();
+        msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        msg.extend(name(owner));
+        msg.extend_from_slice(&[0, 1, 0, 1]);
+        msg
+    }
+
+    // answer to query with count addresses 203.0.113.x
+    fn answer(query: &[u8], count: u8) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2..4].copy_from_slice(&[0x81, 0x80]);
+        msg[6..8].copy_from_slice(&u16::from(count).to_be_bytes());
+        for host in 0..count {
+            msg.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4]);
+            msg.extend_from_slice(&[203, 0, 113, host]);
+        }
+        msg
+    }
+
+    // forwarder sending queries on a link to the returned end
+    fn forwarder(query_timeout: Duration, health: &Health) -> (Forwarder, VsockConn) {
+        let (conn, rx) = Socket::pair(Domain::UNIX, Type::STREAM, None).unwrap();
+        let replay = ReplayConfig {
+            capacity: 0,
+            policy: ReplayPolicy::DropOldest,
+        };
+        let writer = LinkWriter::new(
+            VsockConn::plain(conn),
+            || Err(ProxyError::Shutdown),
+            Liveness::from_secs(0, 0),
+            replay,
+            health.clone(),
+            false,
+        );
+        let forwarder = Forwarder {
+            udp: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            writer,
+            pending: Pending::new(query_timeout),
+            cache: AnswerCache::new(16),
+            dns: Some(DnsCache::default()),
+            queries: health.counter("dns_queries"),
+            cache_hits: health.counter("dns_cache_hits"),
+            truncated: health.counter("dns_truncated"),
+            dropped: health.counter("dns_dropped"),
+        };
+        (forwarder, VsockConn::plain(rx))
+    }
+
+    fn udp_client() -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        client
+    }
+
+    fn recv(client: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buf = vec![0u8; 65535];
+        let size = client.recv(&mut buf).ok()?;
+        Some(buf[..size].to_vec())
+    }
+
+    // connected TCP client, the accepted end and the client end
+    fn tcp_client() -> (Arc<Mutex<TcpStream>>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        (Arc::new(Mutex::new(accepted)), client)
+    }
+
+    fn recv_tcp(client: &mut TcpStream) -> Vec<u8> {
+        let mut length = [0u8; 2];
+        client.read_exact(&mut length).unwrap();
+        let mut msg = vec![0u8; u16::from_be_bytes(length).into()];
+        client.read_exact(&mut msg).unwrap();
+        msg
+    }
+
+    // tags and messages of the queries sent to the parent
+    fn sent(rx: &mut VsockConn, count: usize) -> Vec<(u32, Vec<u8>)> {
+        let mut reader = FrameReader::new();
+        let mut sent = Vec::new();
+        while sent.len() < count {
+            let frames = rx.read_frames(&mut reader).unwrap();
+            sent.extend(
+                frames
+                    .filter_map(parse_dns_frame)
+                    .map(|(tag, msg)| (tag, msg.to_vec())),
+            );
+        }
+        sent
+    }
+
+    #[test]
+    fn routes_answers_by_tag() {
+        let health = Health::new();
+        let (forwarder, mut rx) = forwarder(Duration::from_secs(5), &health);
+        let udp = udp_client();
+        let (tcp, mut tcp_client) = tcp_client();
+
+        let (udp_query, tcp_query) = (query(1, "a.example"), query(2, "b.example"));
+        forwarder.query(&udp_query, Client::Udp(udp.local_addr().unwrap()));
+        forwarder.query(&tcp_query, Client::Tcp(tcp));
+        let sent = sent(&mut rx, 2);
+        assert_eq!(sent[0].1, udp_query);
+        assert_eq!(sent[1].1, tcp_query);
+
+        // answers come back in any order, each goes to its client
+        let (tx, answers) = Socket::pair(Domain::UNIX, Type::STREAM, None).unwrap();
+        let mut tx = VsockConn::plain(tx);
+        let (udp_answer, tcp_answer) = (answer(&udp_query, 1), answer(&tcp_query, 1));
+        tx.write_frame(&dns_frame(sent[1].0, &tcp_answer).unwrap())
+            .unwrap();
+        tx.write_frame(&dns_frame(sent[0].0, &udp_answer).unwrap())
+            .unwrap();
+        // an answer nobody waits for is skipped
+        tx.write_frame(&dns_frame(sent[0].0, &udp_answer).unwrap())
+            .unwrap();
+        drop(tx);
+        let result = handle_answers(&mut VsockConn::plain(answers), &forwarder, &health);
+        assert!(matches!(result, Err(ProxyError::VsockError(_))));
+
+        assert_eq!(recv_tcp(&mut tcp_client), tcp_answer);
+        assert_eq!(recv(&udp).unwrap(), udp_answer);
+        assert_eq!(recv(&udp), None);
+        assert_eq!(health.counter("dns_queries").get(), 2);
+    }
+
+    #[test]
+    fn caches_answers() {
+        let health = Health::new();
+        let (forwarder, mut rx) = forwarder(Duration::from_secs(5), &health);
+        let udp = udp_client();
+        let addr = udp.local_addr().unwrap();
+
+        let first = query(1, "relay.example");
+        forwarder.query(&first, Client::Udp(addr));
+        let (tag, _) = sent(&mut rx, 1)[0];
+        forwarder.answer(tag, &answer(&first, 2));
+        assert_eq!(recv(&udp).unwrap(), answer(&first, 2));
+
+        // the addresses are learned for the egress policy
+        let dns = forwarder.dns.as_ref().unwrap();
+        for host in 0..2 {
+            let names = dns.names(Ipv4Addr::new(203, 0, 113, host), now_secs());
+            assert_eq!(names, ["relay.example"]);
+        }
+
+        // and the next query is answered without the parent
+        forwarder.query(&query(2, "relay.example"), Client::Udp(addr));
+        assert_eq!(recv(&udp).unwrap()[..2], [0, 2]);
+        assert_eq!(health.counter("dns_cache_hits").get(), 1);
+        rx.socket().set_nonblocking(true).unwrap();
+        assert!(rx.read_frames(&mut FrameReader::new()).is_err());
+    }
+
+    #[test]
+    fn truncates_udp_answers() {
+        let health = Health::new();
+        let (forwarder, mut rx) = forwarder(Duration::from_secs(5), &health);
+        let udp = udp_client();
+        let (tcp, mut tcp_client) = tcp_client();
+
+        // 40 addresses are beyond the 512 bytes of a client without EDNS
+        let msg = query(1, "many.example");
+        let full = answer(&msg, 40);
+        assert!(full.len() > MIN_UDP_SIZE);
+        forwarder.query(&msg, Client::Udp(udp.local_addr().unwrap()));
+        forwarder.query(&msg, Client::Tcp(tcp));
+        let sent = sent(&mut rx, 2);
+        forwarder.answer(sent[0].0, &full);
+        forwarder.answer(sent[1].0, &full);
+
+        // the UDP client gets the question with TC set and retries over TCP
+        let truncated = recv(&udp).unwrap();
+        assert_eq!(truncated, truncate(&full));
+        assert_ne!(truncated[2] & 0x02, 0);
+        assert_eq!(recv_tcp(&mut tcp_client), full);
+        assert_eq!(health.counter("dns_truncated").get(), 1);
+    }
+
+    #[test]
+    fn drops_clients_on_timeout() {
+        let health = Health::new();
+        let (forwarder, mut rx) = forwarder(Duration::from_millis(10), &health);
+        let udp = udp_client();
+
+        let msg = query(1, "slow.example");
+        forwarder.query(&msg, Client::Udp(udp.local_addr().unwrap()));
+        let (tag, _) = sent(&mut rx, 1)[0];
+        assert_eq!(forwarder.pending.expire(Instant::now()), 0);
+        assert_eq!(
+            forwarder
+                .pending
+                .expire(Instant::now() + Duration::from_millis(20)),
+            1
+        );
+
+        // a late answer has nobody to go to
+        forwarder.answer(tag, &answer(&msg, 1));
+        assert_eq!(recv(&udp), None);
+    }
+}
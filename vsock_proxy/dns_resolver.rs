@@ -0,0 +1,356 @@
+// Resolver stub of the parent for dns-forwarder in the enclave, see resolver.rs
+//
+// Takes queries on --vsock-addr, asks --upstream and sends the answers
+// over a link of its own to --answer-vsock-addr. Every query is resolved
+// in a thread of its own, at most --max-inflight at once, so that a slow
+// answer does not hold up the others. Queries that fail upstream get a
+// server failure back, the client does not have to wait for its timeout.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use socket2::SockAddr;
+
+use oyster_raw_proxy::conn::{Transport, VsockConn};
+use oyster_raw_proxy::frame::{
+    dns_frame, parse_dns_frame, FrameReader, Hello, MAX_DNS_MESSAGE_LEN,
+};
+use oyster_raw_proxy::health::{spawn_health_reporters, Counter, Health};
+use oyster_raw_proxy::heartbeat::{spawn_heartbeat, Liveness};
+use oyster_raw_proxy::link::LinkWriter;
+use oyster_raw_proxy::replay::{ReplayConfig, ReplayPolicy};
+use oyster_raw_proxy::resolver::{resolve, server_failure};
+use oyster_raw_proxy::secure::ParentSecureArgs;
+use oyster_raw_proxy::shutdown::{
+    exit_status, log_shutdown, or_shutdown, register_shutdown, shutdown_requested,
+};
+use oyster_raw_proxy::{
+    accept_vsock_conn_with_backoff, new_vsock_server_with_backoff, new_vsock_socket_with_backoff,
+    ProxyError, VsockAddrParser,
+};
+
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    /// vsock address to take the queries of dns-forwarder on <cid:port>
+    #[clap(short, long, value_parser = VsockAddrParser{})]
+    vsock_addr: SockAddr,
+    /// vsock address of dns-forwarder to send answers to <cid:port>
+    #[clap(long, value_parser = VsockAddrParser{})]
+    answer_vsock_addr: SockAddr,
+    /// DNS server to resolve with <ip:port>
+    #[clap(long, value_parser, default_value = "1.1.1.1:53")]
+    upstream: SocketAddr,
+    /// seconds to wait for the upstream server, below dns-forwarder --query-timeout
+    #[clap(long, value_parser, default_value_t = 4)]
+    query_timeout: u64,
+    /// max queries resolved at once, more are dropped
+    #[clap(long, value_parser, default_value_t = 256)]
+    max_inflight: usize,
+    /// seconds of idle link before a heartbeat is sent, 0 disables
+    #[clap(long, value_parser, default_value_t = 5)]
+    heartbeat_interval: u64,
+    /// seconds without frames, heartbeats included, before the peer is considered dead, 0 disables
+    #[clap(long, value_parser, default_value_t = 15)]
+    peer_timeout: u64,
+    /// file to report the resolver state to for health checks
+    #[clap(long, value_parser)]
+    health_file: Option<PathBuf>,
+    /// address to serve the resolver state on over HTTP <ip:port>, 503 when unhealthy
+    #[clap(long, value_parser)]
+    health_addr: Option<SocketAddr>,
+    /// vsock socket type, seqpacket sends every frame as a message of its own,
+    /// has to match the enclave
+    #[clap(long, value_enum, default_value_t = Transport::Stream)]
+    transport: Transport,
+    #[clap(flatten)]
+    secure: ParentSecureArgs,
+}
+
+struct Resolver {
+    upstream: SocketAddr,
+    timeout: Duration,
+    writer: Arc<LinkWriter>,
+    inflight: AtomicUsize,
+    max_inflight: usize,
+    failed: Counter,
+    dropped: Counter,
+}
+
+impl Resolver {
+    // resolve query and send the answer under tag
+    fn resolve(&self, tag: u32, query: &[u8]) {
+        let answer = match resolve(self.upstream, query, self.timeout) {
+            Ok(answer) => answer,
+            Err(e) => {
+                self.failed.inc();
+                let err = anyhow::Error::from(e);
+                println!(
+                    "{:?}",
+                    err.context(format!("could not resolve with {}", self.upstream))
+                );
+                server_failure(query)
+            }
+        };
+
+        // the query came in a frame, so does a failure cut from it
+        let answer = match answer.len() <= MAX_DNS_MESSAGE_LEN {
+            true => answer,
+            false => {
+                self.failed.inc();
+                server_failure(query)
+            }
+        };
+        let frame = dns_frame(tag, &answer).expect("answer does not fit a frame");
+        if let Err(err) = self.writer.write_frame(&frame) {
+            println!("{:?}", anyhow::Error::from(err));
+        }
+    }
+}
+
+fn handle_conn(
+    conn: &mut VsockConn,
+    resolver: &Arc<Resolver>,
+    health: &Health,
+) -> Result<(), ProxyError> {
+    let mut reader = FrameReader::new();
+
+    loop {
+        let frames = conn
+            .read_frames(&mut reader)
+            .map_err(ProxyError::VsockError)
+            .map_err(or_shutdown)?;
+        // back to waiting with the next read, the watchdog sees a stall otherwise
+        let _busy = health.busy();
+        health.touch_vsock();
+
+        // heartbeats and anything else are skipped
+        for (tag, query) in frames.filter_map(parse_dns_frame) {
+            if resolver.inflight.fetch_add(1, Ordering::Relaxed) >= resolver.max_inflight {
+                resolver.inflight.fetch_sub(1, Ordering::Relaxed);
+                resolver.dropped.inc();
+                continue;
+            }
+
+            let resolver = resolver.clone();
+            let query = query.to_vec();
+            std::thread::spawn(move || {
+                resolver.resolve(tag, &query);
+                resolver.inflight.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    exit_status(run(Cli::parse()))
+}
+
+fn run(cli: Cli) -> anyhow::Result<()> {
+    // stop at a frame boundary on SIGTERM/SIGINT
+    register_shutdown()?;
+
+    let health = Health::new();
+    spawn_health_reporters(&health, cli.health_file.clone(), cli.health_addr)?;
+    // queries come in on link 0, answers go out on link 1
+    health.set_vsock_links(2);
+    health.for_link(0).set_vsock_listening();
+
+    // records of the secure channel are not framed for seqpacket yet
+    if cli.secure.secure && cli.transport == Transport::Seqpacket {
+        anyhow::bail!("--secure is not supported with --transport seqpacket");
+    }
+    let secure = cli.secure.config()?;
+    let liveness = Liveness::from_secs(cli.heartbeat_interval, cli.peer_timeout);
+
+    // set up the vsock socket for queries first, the enclave connects
+    // for queries while we connect for answers
+    let vsock_addr = &cli.vsock_addr;
+    let vsock_socket = new_vsock_server_with_backoff(vsock_addr, cli.transport)?;
+
+    // get the link for answers, reconnected in the background on write errors
+    let connect = {
+        let answer_vsock_addr = cli.answer_vsock_addr.clone();
+        let transport = cli.transport;
+        let secure = secure.clone();
+        move || {
+            new_vsock_socket_with_backoff(
+                &answer_vsock_addr,
+                transport,
+                &Hello::default(),
+                secure.as_deref(),
+            )
+        }
+    };
+    // answers the forwarder gave up on are of no use, keep a few
+    let replay = ReplayConfig {
+        capacity: 1 << 16,
+        policy: ReplayPolicy::DropOldest,
+    };
+    let writer = LinkWriter::new(
+        connect()?,
+        connect,
+        liveness,
+        replay,
+        health.for_link(1),
+        false,
+    );
+    spawn_heartbeat(writer.clone());
+
+    let resolver = Arc::new(Resolver {
+        upstream: cli.upstream,
+        timeout: Duration::from_secs(cli.query_timeout),
+        writer,
+        inflight: AtomicUsize::new(0),
+        max_inflight: cli.max_inflight,
+        failed: health.counter("dns_failed"),
+        dropped: health.counter("dns_dropped"),
+    });
+
+    let query_health = health.for_link(0);
+    while !shutdown_requested() {
+        // the link parameters are not used on this link
+        let mut conn = accept_vsock_conn_with_backoff(
+            (vsock_addr, &vsock_socket),
+            &Hello::default(),
+            &liveness,
+            secure.as_deref(),
+        )?;
+        query_health.set_vsock_connected(true);
+
+        // on errors, simply reset the erroring socket
+        match handle_conn(&mut conn, &resolver, &query_health) {
+            Ok(_) => unreachable!("connection handler exited without error"),
+            Err(ProxyError::Shutdown) => break,
+            Err(err) => println!("{:?}", anyhow::Error::from(err)),
+        }
+        query_health.set_vsock_listening();
+    }
+
+    // queries in flight are retried by their clients
+    log_shutdown();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use socket2::{Domain, Socket, Type};
+
+    use super::*;
+
+    // query for the A records of example.com
+    fn query(id: u16) -> Vec<u8> {
+        let mut msg = id.to_be_bytes().to_vec();
+        msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        msg.extend_from_slice(b"\x07example\x03com\x00");
+        msg.extend_from_slice(&[0, 1, 0, 1]);
+        msg
+    }
+
+    fn answer(query: &[u8]) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2..4].copy_from_slice(&[0x81, 0x80]);
+        msg[7] = 1;
+        msg.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4, 203, 0, 113, 7]);
+        msg
+    }
+
+    // resolver asking upstream, sending answers on a link to the returned end
+    fn resolver(upstream: SocketAddr, health: &Health) -> (Resolver, VsockConn) {
+        let (conn, rx) = Socket::pair(Domain::UNIX, Type::STREAM, None).unwrap();
+        let replay = ReplayConfig {
+            capacity: 0,
+            policy: ReplayPolicy::DropOldest,
+        };
+        let writer = LinkWriter::new(
+            VsockConn::plain(conn),
+            || Err(ProxyError::Shutdown),
+            Liveness::from_secs(0, 0),
+            replay,
+            health.clone(),
+            false,
+        );
+        let resolver = Resolver {
+            upstream,
+            timeout: Duration::from_millis(200),
+            writer,
+            inflight: AtomicUsize::new(0),
+            max_inflight: 1,
+            failed: health.counter("dns_failed"),
+            dropped: health.counter("dns_dropped"),
+        };
+        (resolver, VsockConn::plain(rx))
+    }
+
+    // tag and message of the next answer sent to the forwarder
+    fn sent(rx: &mut VsockConn) -> (u32, Vec<u8>) {
+        let mut reader = FrameReader::new();
+        let mut frames = rx.read_frames(&mut reader).unwrap();
+        let (tag, msg) = frames.find_map(parse_dns_frame).unwrap();
+        (tag, msg.to_vec())
+    }
+
+    #[test]
+    fn sends_answers_under_tag() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let health = Health::new();
+        let (resolver, mut rx) = resolver(upstream.local_addr().unwrap(), &health);
+
+        let server = std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (size, client) = upstream.recv_from(&mut buf).unwrap();
+            upstream.send_to(&answer(&buf[..size]), client).unwrap();
+        });
+        resolver.resolve(7, &query(1));
+        server.join().unwrap();
+
+        assert_eq!(sent(&mut rx), (7, answer(&query(1))));
+        assert_eq!(health.counter("dns_failed").get(), 0);
+    }
+
+    #[test]
+    fn fails_queries_upstream_does_not_answer() {
+        // bound, but never answers
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let health = Health::new();
+        let (resolver, mut rx) = resolver(upstream.local_addr().unwrap(), &health);
+
+        resolver.resolve(9, &query(1));
+
+        let (tag, failure) = sent(&mut rx);
+        assert_eq!(tag, 9);
+        assert_eq!(failure, server_failure(&query(1)));
+        // id and question kept, SERVFAIL
+        assert_eq!(failure[..2], [0, 1]);
+        assert_eq!(failure[3] & 0x0f, 2);
+        assert_eq!(health.counter("dns_failed").get(), 1);
+    }
+
+    #[test]
+    fn drops_queries_beyond_max_inflight() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let health = Health::new();
+        let (resolver, _rx) = resolver(upstream.local_addr().unwrap(), &health);
+        let resolver = Arc::new(resolver);
+
+        // two queries in one read, only one may be resolved at once
+        let (tx, conn) = Socket::pair(Domain::UNIX, Type::STREAM, None).unwrap();
+        let (mut tx, mut conn) = (VsockConn::plain(tx), VsockConn::plain(conn));
+        let frames = [
+            dns_frame(1, &query(1)).unwrap(),
+            dns_frame(2, &query(2)).unwrap(),
+        ];
+        tx.write_frames(&[&frames[0], &frames[1]]).unwrap();
+        drop(tx);
+        let result = handle_conn(&mut conn, &resolver, &health);
+        assert!(matches!(result, Err(ProxyError::VsockError(_))));
+        assert_eq!(health.counter("dns_dropped").get(), 1);
+    }
+}
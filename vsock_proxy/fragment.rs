@@ -0,0 +1,163 @@
+// Forwarding decisions for IPv4 fragments
+//
+// Only the first fragment of a datagram carries the transport header,
+// later ones start with payload, so port checks on them read garbage.
+// Reassembly would hold whole datagrams in memory only to send them out
+// fragmented again. Instead the decision made on the first fragment is
+// remembered under (src, dst, proto, id) and applied to the rest, which
+// the enclave sends after it over the same ordered link. Later fragments
+// without a forwarded first one are dropped.
+//
+// Entries go away with the last fragment or after a timeout, and the
+// table is bounded like the kernel's ipfrag_* limits, when it is full
+// new datagrams are refused until entries time out.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::packet::{get_fragment, get_proto};
+
+/// Max datagrams tracked at once
+pub const MAX_DATAGRAMS: usize = 4096;
+/// How long fragments of a datagram may trail its first one, like ipfrag_time
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// src, dst, proto, id
+type DatagramKey = (u32, u32, u8, u16);
+
+fn datagram_key(buf: &[u8]) -> DatagramKey {
+    (
+        BigEndian::read_u32(&buf[12..16]),
+        BigEndian::read_u32(&buf[16..20]),
+        get_proto(buf),
+        BigEndian::read_u16(&buf[4..6]),
+    )
+}
+
+pub struct FragmentTable {
+    // datagrams whose first fragment was forwarded, with when it was seen
+    forwarded: HashMap<DatagramKey, Instant>,
+    max_datagrams: usize,
+    timeout: Duration,
+}
+
+impl Default for FragmentTable {
+    fn default() -> Self {
+        Self::new(MAX_DATAGRAMS, FRAGMENT_TIMEOUT)
+    }
+}
+
+impl FragmentTable {
+    pub fn new(max_datagrams: usize, timeout: Duration) -> Self {
+        FragmentTable {
+            forwarded: HashMap::new(),
+            max_datagrams,
+            timeout,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.forwarded.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.forwarded.is_empty()
+    }
+
+    /// Remember that the first fragment in buf was forwarded, returns
+    /// false if the table is full and the datagram cannot be tracked
+    pub fn insert(&mut self, buf: &[u8], now: Instant) -> bool {
+        let key = datagram_key(buf);
+        if self.forwarded.len() >= self.max_datagrams && !self.forwarded.contains_key(&key) {
+            let timeout = self.timeout;
+            self.forwarded
+                .retain(|_, seen| now.saturating_duration_since(*seen) < timeout);
+            if self.forwarded.len() >= self.max_datagrams {
+                return false;
+            }
+        }
+
+        self.forwarded.insert(key, now);
+        true
+    }
+
+    /// Whether the first fragment of the datagram of the later fragment
+    /// in buf was forwarded, the last fragment ends the datagram
+    pub fn is_forwarded(&mut self, buf: &[u8], now: Instant) -> bool {
+        let key = datagram_key(buf);
+        let Some(seen) = self.forwarded.get(&key) else {
+            return false;
+        };
+        let forwarded = now.saturating_duration_since(*seen) < self.timeout;
+
+        let (_, more_fragments) = get_fragment(buf);
+        if !forwarded || !more_fragments {
+            self.forwarded.remove(&key);
+        }
+
+        forwarded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // fragment of datagram id at offset in 8 byte units
+    fn fragment(id: u16, offset: u16, more_fragments: bool) -> Vec<u8> {
+        let mut buf = vec![0u8; 28];
+        buf[0] = 0x45;
+        buf[4..6].copy_from_slice(&id.to_be_bytes());
+        let field = offset | if more_fragments { 0x2000 } else { 0 };
+        buf[6..8].copy_from_slice(&field.to_be_bytes());
+        buf[9] = 17;
+        buf[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        buf[16..20].copy_from_slice(&[1, 1, 1, 1]);
+        buf
+    }
+
+    #[test]
+    fn follows_first_fragment() {
+        let now = Instant::now();
+        let mut table = FragmentTable::default();
+
+        assert!(!table.is_forwarded(&fragment(1, 1, true), now));
+
+        assert!(table.insert(&fragment(2, 0, true), now));
+        assert!(table.is_forwarded(&fragment(2, 1, true), now));
+        assert!(!table.is_forwarded(&fragment(3, 1, true), now));
+        // the last fragment ends the datagram
+        assert!(table.is_forwarded(&fragment(2, 2, false), now));
+        assert!(table.is_empty());
+        assert!(!table.is_forwarded(&fragment(2, 2, false), now));
+    }
+
+    #[test]
+    fn forgets_after_timeout() {
+        let now = Instant::now();
+        let mut table = FragmentTable::new(8, Duration::from_secs(30));
+
+        assert!(table.insert(&fragment(1, 0, true), now));
+        let later = now + Duration::from_secs(30);
+        assert!(!table.is_forwarded(&fragment(1, 1, true), later));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn bounded() {
+        let now = Instant::now();
+        let mut table = FragmentTable::new(2, Duration::from_secs(30));
+
+        assert!(table.insert(&fragment(1, 0, true), now));
+        assert!(table.insert(&fragment(2, 0, true), now));
+        assert!(!table.insert(&fragment(3, 0, true), now));
+        assert_eq!(table.len(), 2);
+
+        // room again once the oldest have timed out
+        let later = now + Duration::from_secs(30);
+        assert!(table.insert(&fragment(3, 0, true), later));
+        assert_eq!(table.len(), 1);
+    }
+}
@@ -0,0 +1,707 @@
+// Framing on the vsock links
+//
+// Data frames are raw IPv4 packets, delimited by the total length
+// field in the IP header. Control frames reuse the same layout so that
+// a single reader handles both:
+//
+// byte 0     - 0x00, IPv4 packets always start with 0x4X
+// byte 1     - control frame type
+// bytes 2..4 - total frame length, big endian, same offset as IPv4 total length
+// bytes 4..  - frame body
+//
+// The hello frame body is a list of TLVs (type u8, len u8, value) so
+// that new link parameters can be added without breaking older peers,
+// unknown types are skipped.
+//
+// GSO frames carry a TCP packet larger than the MTU, to be segmented by
+// the receiver. With their header the packet no longer fits a u16
+// length, they have a u32 length instead:
+//
+// bytes 0..4   - 0x00, 0x03, 0x00, 0x00, older peers fail on the zero length
+// bytes 4..8   - total frame length, big endian
+// bytes 8..10  - max TCP payload per segment, big endian, 0 leaves it to the receiver
+// bytes 10..12 - zero
+// bytes 12..   - IPv4 TCP packet
+//
+// Sequenced frames wrap any other frame with a sequence number and the
+// send time, so that the receiver can tell frames lost or duplicated
+// around reconnects and measure one-way latency (see sequence.rs). They
+// use the same u32 length:
+//
+// bytes 0..4   - 0x00, 0x04, 0x00, 0x00
+// bytes 4..8   - total frame length, big endian
+// bytes 8..12  - stream id, new on every sender start
+// bytes 12..20 - sequence number in the stream, big endian
+// bytes 20..28 - send time, us since the unix epoch, big endian
+// bytes 28..   - inner frame
+//
+// DNS frames carry a DNS message between dns-forwarder in the enclave
+// and dns-resolver on the parent (see resolver.rs), queries on one link
+// and answers on another, matched by a tag picked by the forwarder:
+//
+// bytes 0..4 - 0x00, 0x05, total frame length, big endian
+// bytes 4..8 - tag, the answer carries the tag of its query
+// bytes 8..  - DNS message as sent over TCP, without the length prefix
+//
+// Frames are read through FrameReader, which fills a large buffer per
+// read and hands out every whole frame in it, and written in batches
+// with writev, so that a busy link costs a couple of syscalls per batch
+// instead of several per packet.
+//
+// On seqpacket links every frame is a message of its own, written in
+// batches with sendmmsg. A read returns a single message, which has to
+// hold exactly one frame of the size in its header, so a bad length is
+// caught right away and can never shift where the next frame starts.
+
+use std::io::{ErrorKind, IoSlice, Read, Write};
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::os::fd::AsRawFd;
+
+use socket2::Socket;
+
+use crate::shutdown::shutdown_requested;
+use crate::{SocketError, MAX_BATCH};
+
+pub const FRAME_HEADER_LEN: usize = 4;
+pub const MAX_FRAME_LEN: usize = 65535;
+
+pub const CONTROL_MARKER: u8 = 0x00;
+
+pub const CONTROL_HELLO: u8 = 0x01;
+pub const CONTROL_HEARTBEAT: u8 = 0x02;
+pub const CONTROL_GSO: u8 = 0x03;
+pub const CONTROL_SEQ: u8 = 0x04;
+pub const CONTROL_DNS: u8 = 0x05;
+
+pub const GSO_HEADER_LEN: usize = 12;
+pub const MAX_GSO_FRAME_LEN: usize = GSO_HEADER_LEN + 65535;
+
+pub const SEQ_HEADER_LEN: usize = 28;
+pub const MAX_SEQ_FRAME_LEN: usize = SEQ_HEADER_LEN + MAX_GSO_FRAME_LEN;
+
+pub const DNS_HEADER_LEN: usize = 8;
+/// Largest DNS message a DNS frame carries
+pub const MAX_DNS_MESSAGE_LEN: usize = MAX_FRAME_LEN - DNS_HEADER_LEN;
+
+const HELLO_IPV4: u8 = 0x01;
+const HELLO_GSO: u8 = 0x02;
+const HELLO_MTU: u8 = 0x03;
+const HELLO_SEQ: u8 = 0x04;
+const HELLO_STREAMS: u8 = 0x05;
+
+// room for a few max size frames, a read never has to stop short of one
+const READ_BUF_LEN: usize = 4 * MAX_FRAME_LEN;
+
+// like read_exact, but a shutdown request interrupting the read
+// at a frame boundary is reported, a partial frame is always completed
+pub(crate) fn read_frame_part<R: Read>(
+    reader: &mut R,
+    mut buf: &mut [u8],
+    frame_start: bool,
+) -> Result<(), SocketError> {
+    let mut started = !frame_start;
+    while !buf.is_empty() {
+        match reader.read(buf) {
+            Ok(0) => return Err(SocketError::EofError),
+            Ok(size) => {
+                started = true;
+                buf = &mut buf[size..];
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {
+                if !started && shutdown_requested() {
+                    return Err(SocketError::ReadError(e));
+                }
+            }
+            Err(e) => return Err(SocketError::ReadError(e)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a single frame into buf, returns its total size
+pub fn read_frame<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, SocketError> {
+    // read till total size
+    read_frame_part(reader, &mut buf[0..FRAME_HEADER_LEN], true)?;
+
+    let size: usize = u16::from_be_bytes(buf[2..4].try_into().unwrap()).into();
+    if size < FRAME_HEADER_LEN {
+        return Err(SocketError::FrameError(format!(
+            "invalid frame size {size}"
+        )));
+    }
+
+    // read till full frame
+    read_frame_part(reader, &mut buf[FRAME_HEADER_LEN..size], false)?;
+
+    Ok(size)
+}
+
+/// Write a whole frame, signals never leave a partial frame behind
+pub fn write_frame(socket: &Socket, buf: &[u8]) -> Result<(), SocketError> {
+    let mut total_sent = 0;
+    while total_sent < buf.len() {
+        match socket.send(&buf[total_sent..]) {
+            Ok(size) => total_sent += size,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(SocketError::WriteError(e)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a single frame from a seqpacket link into buf, returns its total size
+pub fn read_message<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, SocketError> {
+    loop {
+        match reader.read(buf) {
+            Ok(0) => return Err(SocketError::EofError),
+            Ok(size) => return check_message(&buf[..size]).map(|_| size),
+            // messages arrive whole, every read starts at a frame boundary
+            Err(e) if e.kind() == ErrorKind::Interrupted => {
+                if shutdown_requested() {
+                    return Err(SocketError::ReadError(e));
+                }
+            }
+            Err(e) => return Err(SocketError::ReadError(e)),
+        }
+    }
+}
+
+// a message has to be exactly one frame
+fn check_message(buf: &[u8]) -> Result<(), SocketError> {
+    match frame_size(buf)? {
+        Some(size) if size == buf.len() => Ok(()),
+        _ => Err(SocketError::FrameError(format!(
+            "message of {} bytes is not a single frame",
+            buf.len()
+        ))),
+    }
+}
+
+/// Write whole frames as one message each, batched with sendmmsg, on error
+/// returns how many frames were written
+pub fn write_messages(socket: &Socket, frames: &[&[u8]]) -> Result<(), (usize, SocketError)> {
+    let mut iovecs: Vec<libc::iovec> = frames
+        .iter()
+        .map(|frame| libc::iovec {
+            iov_base: frame.as_ptr() as *mut libc::c_void,
+            iov_len: frame.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .map(|iovec| {
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    // messages go out whole or not at all, a short count only means
+    // the rest still has to go
+    let mut sent = 0;
+    while sent < msgs.len() {
+        let count = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs[sent..].as_mut_ptr(),
+                (msgs.len() - sent).min(MAX_BATCH) as libc::c_uint,
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        if count < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err((sent, SocketError::WriteError(e)));
+        }
+        sent += count as usize;
+    }
+
+    Ok(())
+}
+
+/// Write whole frames with a single writev where possible, on error
+/// returns how many frames were written completely
+pub fn write_frames(socket: &Socket, frames: &[&[u8]]) -> Result<(), (usize, SocketError)> {
+    // next frame to write and how much of it is already written
+    let (mut frame, mut offset) = (0, 0);
+    let mut slices = Vec::with_capacity(frames.len().min(MAX_BATCH));
+    while frame < frames.len() {
+        slices.clear();
+        slices.push(IoSlice::new(&frames[frame][offset..]));
+        slices.extend(
+            frames[frame + 1..]
+                .iter()
+                .take(MAX_BATCH - 1)
+                .map(|buf| IoSlice::new(buf)),
+        );
+
+        let mut size = match socket.send_vectored(&slices) {
+            Ok(size) => size,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err((frame, SocketError::WriteError(e))),
+        };
+
+        // skip over what was written, a frame may be cut anywhere
+        while frame < frames.len() && size >= frames[frame].len() - offset {
+            size -= frames[frame].len() - offset;
+            frame += 1;
+            offset = 0;
+        }
+        offset += size;
+    }
+
+    Ok(())
+}
+
+/// Buffered reader handing out all whole frames of a read at once
+pub struct FrameReader {
+    buf: Box<[u8]>,
+    // buffered bytes not handed out yet
+    start: usize,
+    end: usize,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        FrameReader {
+            buf: vec![0u8; READ_BUF_LEN].into_boxed_slice(),
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Drop everything buffered, for reading a new connection
+    pub fn clear(&mut self) {
+        (self.start, self.end) = (0, 0);
+    }
+
+    // end of the whole frames buffered from start
+    fn whole_frames_end(&self) -> Result<usize, SocketError> {
+        let mut pos = self.start;
+        while let Some(size) = frame_size(&self.buf[pos..self.end])? {
+            if self.end - pos < size {
+                break;
+            }
+            pos += size;
+        }
+
+        Ok(pos)
+    }
+
+    /// Wait for at least one whole frame and return all whole frames
+    /// buffered so far. A shutdown request interrupting the read at a
+    /// frame boundary is reported, a partial frame is always completed.
+    pub fn read_frames<R: Read>(&mut self, reader: &mut R) -> Result<Frames<'_>, SocketError> {
+        let range = self.read_frames_with(|buf| reader.read(buf))?;
+        Ok(self.frames(range))
+    }
+
+    /// Like read_frames with a custom read into the free part of the
+    /// buffer, returns where the frames are in the buffer
+    pub fn read_frames_with<F: FnMut(&mut [u8]) -> std::io::Result<usize>>(
+        &mut self,
+        mut read: F,
+    ) -> Result<Range<usize>, SocketError> {
+        loop {
+            let end = self.whole_frames_end()?;
+            if end > self.start {
+                let start = std::mem::replace(&mut self.start, end);
+                return Ok(start..end);
+            }
+
+            // only a partial frame left, move it to the front once
+            // there might not be room for the rest of it
+            if self.start == self.end {
+                (self.start, self.end) = (0, 0);
+            } else if self.buf.len() - self.end < MAX_SEQ_FRAME_LEN {
+                self.buf.copy_within(self.start..self.end, 0);
+                (self.start, self.end) = (0, self.end - self.start);
+            }
+
+            match read(&mut self.buf[self.end..]) {
+                Ok(0) => return Err(SocketError::EofError),
+                Ok(size) => self.end += size,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {
+                    if self.start == self.end && shutdown_requested() {
+                        return Err(SocketError::ReadError(e));
+                    }
+                }
+                Err(e) => return Err(SocketError::ReadError(e)),
+            }
+        }
+    }
+
+    /// Same as read_frames for a seqpacket link, returns the frame of
+    /// a single message
+    pub fn read_messages<R: Read>(&mut self, reader: &mut R) -> Result<Frames<'_>, SocketError> {
+        let range = self.read_messages_with(|buf| reader.read(buf))?;
+        Ok(self.frames(range))
+    }
+
+    /// Like read_messages with a custom read into the buffer, returns
+    /// where the frame is in the buffer
+    pub fn read_messages_with<F: FnMut(&mut [u8]) -> std::io::Result<usize>>(
+        &mut self,
+        mut read: F,
+    ) -> Result<Range<usize>, SocketError> {
+        // nothing is ever left over from the last message
+        self.clear();
+        let size = read_message(&mut ReadFn(&mut read), &mut self.buf)?;
+        Ok(0..size)
+    }
+
+    /// Frames at range, as returned by read_frames_with
+    pub fn frames(&self, range: Range<usize>) -> Frames<'_> {
+        Frames {
+            buf: &self.buf[range],
+        }
+    }
+
+    /// Whole buffer, frames are read into and handed out from it
+    pub fn buffer(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+// Read over a closure, for read_message
+struct ReadFn<F>(F);
+
+impl<F: FnMut(&mut [u8]) -> std::io::Result<usize>> Read for ReadFn<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (self.0)(buf)
+    }
+}
+
+/// Whole frames returned by FrameReader::read_frames
+pub struct Frames<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        // sizes were validated when the frames were buffered
+        let size = frame_size(self.buf).unwrap().unwrap();
+        let (frame, rest) = self.buf.split_at(size);
+        self.buf = rest;
+        Some(frame)
+    }
+}
+
+// size of the frame at the start of buf, None until enough of it is there to tell
+fn frame_size(buf: &[u8]) -> Result<Option<usize>, SocketError> {
+    if buf.len() < FRAME_HEADER_LEN {
+        return Ok(None);
+    }
+
+    // frames with a u32 length
+    let long = match buf[..FRAME_HEADER_LEN] {
+        [CONTROL_MARKER, CONTROL_GSO, 0, 0] => Some((GSO_HEADER_LEN, MAX_GSO_FRAME_LEN)),
+        [CONTROL_MARKER, CONTROL_SEQ, 0, 0] => Some((SEQ_HEADER_LEN, MAX_SEQ_FRAME_LEN)),
+        _ => None,
+    };
+    let (size, min, max) = match long {
+        Some(_) if buf.len() < 8 => return Ok(None),
+        Some((min, max)) => {
+            let size = u32::from_be_bytes(buf[4..8].try_into().unwrap()) as usize;
+            (size, min, max)
+        }
+        None => {
+            let size: usize = u16::from_be_bytes([buf[2], buf[3]]).into();
+            (size, FRAME_HEADER_LEN, MAX_FRAME_LEN)
+        }
+    };
+    if size < min || size > max {
+        return Err(SocketError::FrameError(format!(
+            "invalid frame size {size}"
+        )));
+    }
+
+    Ok(Some(size))
+}
+
+pub fn is_control_frame(buf: &[u8]) -> bool {
+    buf[0] == CONTROL_MARKER
+}
+
+pub fn control_frame_type(buf: &[u8]) -> u8 {
+    buf[1]
+}
+
+/// Empty frame sent on idle links so that the receiver can tell a quiet peer from a dead one
+pub fn heartbeat_frame() -> [u8; FRAME_HEADER_LEN] {
+    [CONTROL_MARKER, CONTROL_HEARTBEAT, 0, FRAME_HEADER_LEN as u8]
+}
+
+/// GSO frame carrying packet, gso_size is the max TCP payload per segment
+pub fn gso_frame(gso_size: u16, packet: &[u8]) -> Vec<u8> {
+    let size = (GSO_HEADER_LEN + packet.len()) as u32;
+    let mut buf = Vec::with_capacity(size as usize);
+    buf.extend_from_slice(&[CONTROL_MARKER, CONTROL_GSO, 0, 0]);
+    buf.extend_from_slice(&size.to_be_bytes());
+    buf.extend_from_slice(&gso_size.to_be_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    buf.extend_from_slice(packet);
+    buf
+}
+
+/// gso_size and packet of a GSO frame, None for any other frame
+pub fn parse_gso_frame(frame: &[u8]) -> Option<(u16, &[u8])> {
+    if frame.len() < GSO_HEADER_LEN
+        || frame[..FRAME_HEADER_LEN] != [CONTROL_MARKER, CONTROL_GSO, 0, 0]
+    {
+        return None;
+    }
+
+    let gso_size = u16::from_be_bytes([frame[8], frame[9]]);
+    Some((gso_size, &frame[GSO_HEADER_LEN..]))
+}
+
+/// Sequenced frame carrying frame as number seq of stream, sent at sent_us
+pub fn seq_frame(stream: u32, seq: u64, sent_us: u64, frame: &[u8]) -> Vec<u8> {
+    let size = (SEQ_HEADER_LEN + frame.len()) as u32;
+    let mut buf = Vec::with_capacity(size as usize);
+    buf.extend_from_slice(&[CONTROL_MARKER, CONTROL_SEQ, 0, 0]);
+    buf.extend_from_slice(&size.to_be_bytes());
+    buf.extend_from_slice(&stream.to_be_bytes());
+    buf.extend_from_slice(&seq.to_be_bytes());
+    buf.extend_from_slice(&sent_us.to_be_bytes());
+    buf.extend_from_slice(frame);
+    buf
+}
+
+/// Stream, sequence number, send time and inner frame of a sequenced
+/// frame, None for any other frame
+pub fn parse_seq_frame(frame: &[u8]) -> Option<(u32, u64, u64, &[u8])> {
+    if frame.len() < SEQ_HEADER_LEN
+        || frame[..FRAME_HEADER_LEN] != [CONTROL_MARKER, CONTROL_SEQ, 0, 0]
+    {
+        return None;
+    }
+
+    let stream = u32::from_be_bytes(frame[8..12].try_into().unwrap());
+    let seq = u64::from_be_bytes(frame[12..20].try_into().unwrap());
+    let sent_us = u64::from_be_bytes(frame[20..28].try_into().unwrap());
+    Some((stream, seq, sent_us, &frame[SEQ_HEADER_LEN..]))
+}
+
+/// DNS frame carrying msg under tag, None if msg does not fit a frame
+pub fn dns_frame(tag: u32, msg: &[u8]) -> Option<Vec<u8>> {
+    if msg.len() > MAX_DNS_MESSAGE_LEN {
+        return None;
+    }
+
+    let size = (DNS_HEADER_LEN + msg.len()) as u16;
+    let mut buf = Vec::with_capacity(size as usize);
+    buf.extend_from_slice(&[CONTROL_MARKER, CONTROL_DNS]);
+    buf.extend_from_slice(&size.to_be_bytes());
+    buf.extend_from_slice(&tag.to_be_bytes());
+    buf.extend_from_slice(msg);
+    Some(buf)
+}
+
+/// Tag and DNS message of a DNS frame, None for any other frame
+pub fn parse_dns_frame(frame: &[u8]) -> Option<(u32, &[u8])> {
+    if frame.len() < DNS_HEADER_LEN || frame[..2] != [CONTROL_MARKER, CONTROL_DNS] {
+        return None;
+    }
+
+    let tag = u32::from_be_bytes(frame[4..8].try_into().unwrap());
+    Some((tag, &frame[DNS_HEADER_LEN..]))
+}
+
+/// Link parameters sent by the parent when a vsock connection is set up
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hello {
+    /// address the enclave uses as its own, equal to the parent's interface address
+    pub ipv4: Option<Ipv4Addr>,
+    /// whether the parent takes GSO frames on this link
+    pub gso: bool,
+    /// largest packet the parent sends out, TCP MSS is clamped to fit it
+    pub mtu: Option<u16>,
+    /// whether the parent takes sequenced frames on this link
+    pub seq: bool,
+    /// how many parallel connections the parent takes on this link
+    pub streams: Option<u8>,
+}
+
+impl Hello {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![CONTROL_MARKER, CONTROL_HELLO, 0, 0];
+
+        if let Some(ipv4) = self.ipv4 {
+            buf.extend_from_slice(&[HELLO_IPV4, 4]);
+            buf.extend_from_slice(&ipv4.octets());
+        }
+        if self.gso {
+            buf.extend_from_slice(&[HELLO_GSO, 0]);
+        }
+        if let Some(mtu) = self.mtu {
+            buf.extend_from_slice(&[HELLO_MTU, 2]);
+            buf.extend_from_slice(&mtu.to_be_bytes());
+        }
+        if self.seq {
+            buf.extend_from_slice(&[HELLO_SEQ, 0]);
+        }
+        if let Some(streams) = self.streams {
+            buf.extend_from_slice(&[HELLO_STREAMS, 1, streams]);
+        }
+
+        let size = buf.len() as u16;
+        buf[2..4].copy_from_slice(&size.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Hello, SocketError> {
+        if frame.len() < FRAME_HEADER_LEN
+            || !is_control_frame(frame)
+            || control_frame_type(frame) != CONTROL_HELLO
+        {
+            return Err(SocketError::HandshakeError(
+                "expected hello frame".to_owned(),
+            ));
+        }
+
+        let mut hello = Hello::default();
+        let mut body = &frame[FRAME_HEADER_LEN..];
+        while !body.is_empty() {
+            if body.len() < 2 || body.len() < 2 + body[1] as usize {
+                return Err(SocketError::HandshakeError(
+                    "truncated hello frame".to_owned(),
+                ));
+            }
+            let (tag, value) = (body[0], &body[2..2 + body[1] as usize]);
+
+            // unknown tags come from a newer peer and are skipped
+            match tag {
+                HELLO_IPV4 => {
+                    let octets: [u8; 4] = value.try_into().map_err(|_| {
+                        SocketError::HandshakeError(format!("invalid ipv4 length {}", value.len()))
+                    })?;
+                    hello.ipv4 = Some(Ipv4Addr::from(octets));
+                }
+                HELLO_GSO => hello.gso = true,
+                HELLO_MTU => {
+                    let mtu: [u8; 2] = value.try_into().map_err(|_| {
+                        SocketError::HandshakeError(format!("invalid mtu length {}", value.len()))
+                    })?;
+                    hello.mtu = Some(u16::from_be_bytes(mtu));
+                }
+                HELLO_SEQ => hello.seq = true,
+                HELLO_STREAMS => {
+                    let [streams] = value.try_into().map_err(|_| {
+                        SocketError::HandshakeError(format!(
+                            "invalid streams length {}",
+                            value.len()
+                        ))
+                    })?;
+                    hello.streams = Some(streams);
+                }
+                _ => {}
+            }
+
+            body = &body[2 + value.len()..];
+        }
+
+        Ok(hello)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), SocketError> {
+        writer
+            .write_all(&self.encode())
+            .map_err(SocketError::WriteError)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Hello, SocketError> {
+        let mut buf = vec![0u8; MAX_FRAME_LEN];
+        let size = read_frame(reader, &mut buf)?;
+        Hello::decode(&buf[..size])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_round_trips() {
+        let hellos = [
+            Hello::default(),
+            Hello {
+                ipv4: Some(Ipv4Addr::new(203, 0, 113, 7)),
+                gso: true,
+                mtu: Some(9001),
+                seq: true,
+                streams: Some(4),
+            },
+            Hello {
+                mtu: Some(1500),
+                ..Hello::default()
+            },
+        ];
+        for hello in hellos {
+            let frame = hello.encode();
+            assert_eq!(frame_size(&frame).unwrap(), Some(frame.len()));
+            assert_eq!(Hello::decode(&frame).unwrap(), hello);
+
+            let mut reader = std::io::Cursor::new(frame);
+            assert_eq!(Hello::read_from(&mut reader).unwrap(), hello);
+        }
+    }
+
+    #[test]
+    fn hello_skips_unknown_tlvs() {
+        let hello = Hello {
+            ipv4: Some(Ipv4Addr::new(203, 0, 113, 7)),
+            mtu: Some(9001),
+            ..Hello::default()
+        };
+        let mut frame = hello.encode();
+        // a newer peer's parameters before and after the known ones
+        frame.splice(4..4, [0x7f, 3, 1, 2, 3]);
+        frame.extend_from_slice(&[0x80, 0]);
+        let size = frame.len() as u16;
+        frame[2..4].copy_from_slice(&size.to_be_bytes());
+
+        assert_eq!(Hello::decode(&frame).unwrap(), hello);
+    }
+
+    #[test]
+    fn hello_rejects_bad_frames() {
+        // not a hello
+        assert!(Hello::decode(&heartbeat_frame()).is_err());
+        assert!(Hello::decode(&[CONTROL_MARKER]).is_err());
+
+        // value running past the end of the frame
+        let mut frame = Hello::default().encode();
+        frame.extend_from_slice(&[HELLO_MTU, 2, 0x23]);
+        assert!(Hello::decode(&frame).is_err());
+
+        // tag without its length
+        let mut frame = Hello::default().encode();
+        frame.push(0x7f);
+        assert!(Hello::decode(&frame).is_err());
+
+        // known tag with the wrong length
+        let mut frame = Hello::default().encode();
+        frame.extend_from_slice(&[HELLO_IPV4, 3, 10, 0, 0]);
+        assert!(Hello::decode(&frame).is_err());
+    }
+}
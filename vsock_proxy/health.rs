@@ -0,0 +1,611 @@
+// Health reporting for supervisors
+//
+// supervisord only knows whether a process is alive, not whether
+// packets flow. The data path records whether nfqueue is bound, whether
+// the vsock links are up and when the last frame and packet went through,
+// helper threads expose that as:
+//
+// - a key=value file rewritten every second
+// - a plain HTTP endpoint, 200 when healthy and 503 otherwise
+// - systemd sd_notify READY=1 once healthy, and WATCHDOG=1 pings
+//   while healthy so that a stalled proxy gets restarted
+//
+// A link that is down because this side listens and its peer has not
+// connected (yet or again) does not make the proxy unhealthy, there is
+// nothing a restart would fix. A connecting side that lost its link does.
+//
+// The pings come from a helper thread, which keeps running when the
+// data path hangs. So the data path marks itself busy from the return
+// of a blocking read until it is back waiting for the next one, a link
+// busy for longer than STALL_TIMEOUT makes the proxy unhealthy. An idle
+// proxy is waiting and stays healthy.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Max vsock links a proxy reports on
+pub const MAX_LINKS: usize = 64;
+
+/// How long the data path may work on a single read before it counts
+/// as stalled, above the default --peer-timeout bounding vsock writes
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Monotonic counter included in the health report
+#[derive(Clone, Debug, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Value included in the health report that may also go down
+#[derive(Clone, Debug, Default)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// Raise to value if it is larger
+    pub fn set_max(&self, value: u64) {
+        self.0.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+struct HealthState {
+    epoch: Instant,
+    // bit i set - vsock link i is up
+    vsock_connected: AtomicU64,
+    // bit i set - vsock link i is down, waiting for the peer to connect
+    vsock_listening: AtomicU64,
+    vsock_links: AtomicUsize,
+    // ms since epoch of the last frame on link i, 0 - never
+    last_vsock_frame: [AtomicU64; MAX_LINKS],
+    // ms since epoch, 0 - never
+    last_packet: AtomicU64,
+    // ms since epoch the data path of link i started on a read, 0 - waiting
+    busy_since: [AtomicU64; MAX_LINKS],
+    // None if the binary does not use nfqueue
+    nfqueue_bound: Option<AtomicBool>,
+    // counters and gauges by name, in registration order
+    values: Mutex<Vec<(&'static str, Arc<AtomicU64>)>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Health {
+    state: Arc<HealthState>,
+    // link set_vsock_connected and touch_vsock apply to
+    link: usize,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::build(None)
+    }
+
+    /// Health of a binary reading packets from nfqueue
+    pub fn with_nfqueue() -> Self {
+        Self::build(Some(AtomicBool::new(false)))
+    }
+
+    fn build(nfqueue_bound: Option<AtomicBool>) -> Self {
+        let state = Arc::new(HealthState {
+            epoch: Instant::now(),
+            vsock_connected: AtomicU64::new(0),
+            vsock_listening: AtomicU64::new(0),
+            vsock_links: AtomicUsize::new(1),
+            last_vsock_frame: [const { AtomicU64::new(0) }; MAX_LINKS],
+            last_packet: AtomicU64::new(0),
+            busy_since: [const { AtomicU64::new(0) }; MAX_LINKS],
+            nfqueue_bound,
+            values: Mutex::new(Vec::new()),
+        });
+        Health { state, link: 0 }
+    }
+
+    fn value(&self, name: &'static str) -> Arc<AtomicU64> {
+        let mut values = self.state.values.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, value)) = values.iter().find(|(n, _)| *n == name) {
+            return value.clone();
+        }
+
+        let value = Arc::new(AtomicU64::new(0));
+        values.push((name, value.clone()));
+        value
+    }
+
+    /// Get the counter reported under name, registering it on first use
+    pub fn counter(&self, name: &'static str) -> Counter {
+        Counter(self.value(name))
+    }
+
+    /// Get the gauge reported under name, registering it on first use
+    pub fn gauge(&self, name: &'static str) -> Gauge {
+        Gauge(self.value(name))
+    }
+
+    fn now_ms(&self) -> u64 {
+        // never 0, that means "never"
+        self.state.epoch.elapsed().as_millis() as u64 + 1
+    }
+
+    fn ago(&self, at: u64) -> Option<Duration> {
+        match at {
+            0 => None,
+            at => Some(Duration::from_millis(self.now_ms().saturating_sub(at))),
+        }
+    }
+
+    /// Number of vsock links that have to be up, 1 by default, at most MAX_LINKS
+    pub fn set_vsock_links(&self, links: usize) {
+        assert!(
+            (1..=MAX_LINKS).contains(&links),
+            "invalid link count {links}"
+        );
+        self.state.vsock_links.store(links, Ordering::Relaxed);
+    }
+
+    /// Same health, with set_vsock_connected and touch_vsock applying to link
+    pub fn for_link(&self, link: usize) -> Health {
+        assert!(link < MAX_LINKS, "invalid link {link}");
+        Health {
+            state: self.state.clone(),
+            link,
+        }
+    }
+
+    pub fn set_vsock_connected(&self, connected: bool) {
+        let bit = 1 << self.link;
+        self.state
+            .vsock_listening
+            .fetch_and(!bit, Ordering::Relaxed);
+        match connected {
+            true => self.state.vsock_connected.fetch_or(bit, Ordering::Relaxed),
+            false => self
+                .state
+                .vsock_connected
+                .fetch_and(!bit, Ordering::Relaxed),
+        };
+        if connected {
+            // a fresh link counts as active
+            self.touch_vsock();
+        }
+    }
+
+    /// Mark the link down and waiting for the peer to connect,
+    /// for the listening side of a link
+    pub fn set_vsock_listening(&self) {
+        let bit = 1 << self.link;
+        self.state
+            .vsock_connected
+            .fetch_and(!bit, Ordering::Relaxed);
+        self.state.vsock_listening.fetch_or(bit, Ordering::Relaxed);
+    }
+
+    // bits of all links
+    fn all_links(&self) -> u64 {
+        u64::MAX >> (64 - self.state.vsock_links.load(Ordering::Relaxed))
+    }
+
+    /// Whether all vsock links are up
+    pub fn vsock_connected(&self) -> bool {
+        let all = self.all_links();
+        self.state.vsock_connected.load(Ordering::Relaxed) & all == all
+    }
+
+    // whether every link is up or waiting for its peer
+    fn vsock_ready(&self) -> bool {
+        let all = self.all_links();
+        let ready = self.state.vsock_connected.load(Ordering::Relaxed)
+            | self.state.vsock_listening.load(Ordering::Relaxed);
+        ready & all == all
+    }
+
+    fn vsock_links_listening(&self) -> u32 {
+        self.state
+            .vsock_listening
+            .load(Ordering::Relaxed)
+            .count_ones()
+    }
+
+    fn vsock_links_connected(&self) -> u32 {
+        self.state
+            .vsock_connected
+            .load(Ordering::Relaxed)
+            .count_ones()
+    }
+
+    pub fn set_nfqueue_bound(&self, bound: bool) {
+        if let Some(nfqueue_bound) = &self.state.nfqueue_bound {
+            nfqueue_bound.store(bound, Ordering::Relaxed);
+        }
+    }
+
+    pub fn nfqueue_bound(&self) -> Option<bool> {
+        self.state
+            .nfqueue_bound
+            .as_ref()
+            .map(|bound| bound.load(Ordering::Relaxed))
+    }
+
+    /// Record a frame sent or received on this vsock link
+    pub fn touch_vsock(&self) {
+        self.state.last_vsock_frame[self.link].store(self.now_ms(), Ordering::Relaxed);
+    }
+
+    /// Time since the last frame on this vsock link, traffic on
+    /// the other links does not count
+    pub fn vsock_idle(&self) -> Option<Duration> {
+        self.ago(self.state.last_vsock_frame[self.link].load(Ordering::Relaxed))
+    }
+
+    // time since the last frame on any vsock link
+    fn any_vsock_idle(&self) -> Option<Duration> {
+        let last = self
+            .state
+            .last_vsock_frame
+            .iter()
+            .map(|last| last.load(Ordering::Relaxed))
+            .max()?;
+        self.ago(last)
+    }
+
+    /// Record a packet passing through the proxy
+    pub fn touch_packet(&self) {
+        self.state
+            .last_packet
+            .store(self.now_ms(), Ordering::Relaxed);
+    }
+
+    /// Time since the last packet passed through the proxy
+    pub fn packet_idle(&self) -> Option<Duration> {
+        self.ago(self.state.last_packet.load(Ordering::Relaxed))
+    }
+
+    /// Mark the data path of this link busy with what a read returned,
+    /// until the guard is dropped on the way back to the next read
+    pub fn busy(&self) -> Busy<'_> {
+        self.state.busy_since[self.link].store(self.now_ms(), Ordering::Relaxed);
+        Busy(self)
+    }
+
+    /// Longest time a data path has been busy with a single read
+    pub fn busy_for(&self) -> Option<Duration> {
+        let since = self
+            .state
+            .busy_since
+            .iter()
+            .map(|since| since.load(Ordering::Relaxed))
+            .filter(|since| *since != 0)
+            .min()?;
+        self.ago(since)
+    }
+
+    /// Whether a data path has been busy with a read for too long
+    pub fn stalled(&self) -> bool {
+        self.busy_for().is_some_and(|busy| busy > STALL_TIMEOUT)
+    }
+
+    /// Links are up or waiting for their peer and the data path is not stuck,
+    /// an idle proxy is still healthy
+    pub fn is_healthy(&self) -> bool {
+        self.vsock_ready() && self.nfqueue_bound().unwrap_or(true) && !self.stalled()
+    }
+
+    pub fn report(&self) -> String {
+        let ms = |d: Option<Duration>| match d {
+            Some(d) => d.as_millis().to_string(),
+            None => "never".to_owned(),
+        };
+
+        let mut report = format!(
+            "healthy={}\nvsock_connected={}\nvsock_listening={}\nlast_vsock_frame_ms_ago={}\nlast_packet_ms_ago={}\nstalled={}\n",
+            self.is_healthy() as u8,
+            self.vsock_connected() as u8,
+            self.vsock_links_listening(),
+            ms(self.any_vsock_idle()),
+            ms(self.packet_idle()),
+            self.stalled() as u8,
+        );
+        let links = self.state.vsock_links.load(Ordering::Relaxed);
+        if links > 1 {
+            report += &format!(
+                "vsock_links_connected={}/{}\n",
+                self.vsock_links_connected(),
+                links
+            );
+        }
+        if let Some(bound) = self.nfqueue_bound() {
+            report += &format!("nfqueue_bound={}\n", bound as u8);
+        }
+        for (name, value) in self
+            .state
+            .values
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+        {
+            report += &format!("{}={}\n", name, value.load(Ordering::Relaxed));
+        }
+
+        report
+    }
+}
+
+/// Data path of a link busy with a read, waiting again once dropped
+pub struct Busy<'a>(&'a Health);
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        let health = self.0;
+        health.state.busy_since[health.link].store(0, Ordering::Relaxed);
+    }
+}
+
+/// Spawn a thread rewriting path with the current state every interval
+pub fn spawn_health_file(path: PathBuf, health: Health, interval: Duration) -> JoinHandle<()> {
+    let tmp_path = path.with_extension("tmp");
+    std::thread::spawn(move || loop {
+        // write + rename so that readers never see a partial file
+        if let Err(e) = std::fs::write(&tmp_path, health.report())
+            .and_then(|_| std::fs::rename(&tmp_path, &path))
+        {
+            println!("failed to write health file {}: {:?}", path.display(), e);
+        }
+
+        std::thread::sleep(interval);
+    })
+}
+
+/// Spawn a thread answering any HTTP request on addr with the current state
+pub fn spawn_health_server(addr: SocketAddr, health: Health) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+
+            // a slow client must not block the others for long
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+
+            // the request itself does not matter, read some of it so that
+            // the client does not get a reset on close
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+
+            let status = if health.is_healthy() {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let body = health.report();
+            let _ = write!(
+                stream,
+                "HTTP/1.0 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+        }
+    }))
+}
+
+fn sd_notify(state: &str) -> std::io::Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+    } else {
+        socket.send_to(state.as_bytes(), &*path)?;
+    }
+
+    Ok(())
+}
+
+// WATCHDOG_USEC, if the watchdog is enabled for this process
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+
+    Some(Duration::from_micros(usec))
+}
+
+/// Spawn a thread notifying systemd, if started by it: READY=1 once
+/// healthy, then WATCHDOG=1 at half the watchdog interval while healthy
+pub fn spawn_sd_notify(health: Health) -> Option<JoinHandle<()>> {
+    std::env::var_os("NOTIFY_SOCKET")?;
+    let watchdog = watchdog_interval();
+
+    Some(std::thread::spawn(move || {
+        while !health.is_healthy() {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        if let Err(e) = sd_notify("READY=1") {
+            println!("sd_notify failed: {:?}", e);
+        }
+
+        let Some(watchdog) = watchdog else {
+            return;
+        };
+        loop {
+            std::thread::sleep(watchdog / 2);
+
+            // an unhealthy proxy stops pinging and gets restarted
+            if !health.is_healthy() {
+                continue;
+            }
+            if let Err(e) = sd_notify("WATCHDOG=1") {
+                println!("sd_notify failed: {:?}", e);
+            }
+        }
+    }))
+}
+
+/// Start all reporters configured on the command line
+pub fn spawn_health_reporters(
+    health: &Health,
+    file: Option<PathBuf>,
+    addr: Option<SocketAddr>,
+) -> std::io::Result<()> {
+    if let Some(file) = file {
+        spawn_health_file(file, health.clone(), Duration::from_secs(1));
+    }
+    if let Some(addr) = addr {
+        spawn_health_server(addr, health.clone())?;
+    }
+    spawn_sd_notify(health.clone());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listening_link_waits_for_peer() {
+        let health = Health::new();
+        health.set_vsock_links(2);
+
+        // not connected yet, nor listening, as on a connecting side
+        assert!(!health.is_healthy());
+        assert!(health.report().contains("vsock_connected=0\nvsock_listening=0\n"));
+
+        // a listening side waiting for its peers is healthy but not connected
+        health.for_link(0).set_vsock_listening();
+        assert!(!health.is_healthy());
+        health.for_link(1).set_vsock_listening();
+        assert!(health.is_healthy());
+        assert!(!health.vsock_connected());
+        assert!(health.report().contains("vsock_connected=0\nvsock_listening=2\n"));
+
+        // the peer connects one link after the other
+        health.for_link(0).set_vsock_connected(true);
+        assert!(health.is_healthy());
+        health.for_link(1).set_vsock_connected(true);
+        assert!(health.vsock_connected());
+        assert!(health.report().contains("vsock_connected=1\nvsock_listening=0\n"));
+
+        // and comes back after it went away, listening again in between
+        health.for_link(1).set_vsock_listening();
+        assert!(health.is_healthy());
+        assert!(!health.vsock_connected());
+        health.for_link(1).set_vsock_connected(true);
+        assert!(health.vsock_connected());
+    }
+
+    #[test]
+    fn connecting_link_reconnects() {
+        let health = Health::with_nfqueue();
+        health.set_nfqueue_bound(true);
+        health.set_vsock_links(2);
+        health.for_link(0).set_vsock_connected(true);
+        health.for_link(1).set_vsock_connected(true);
+        assert!(health.is_healthy());
+
+        // a connecting side is down while it reconnects a link
+        health.for_link(1).set_vsock_connected(false);
+        assert!(!health.is_healthy());
+        assert!(health.report().contains("vsock_links_connected=1/2\n"));
+        health.for_link(1).set_vsock_connected(true);
+        assert!(health.is_healthy());
+
+        // as it is while nfqueue is bound again
+        health.set_nfqueue_bound(false);
+        assert!(!health.is_healthy());
+        assert!(health.report().contains("nfqueue_bound=0\n"));
+    }
+
+    #[test]
+    fn links_idle_on_their_own() {
+        let mut health = Health::new();
+        // room to date frames back
+        Arc::get_mut(&mut health.state).unwrap().epoch -= Duration::from_secs(60);
+        health.set_vsock_links(2);
+        let (quiet, busy) = (health.for_link(0), health.for_link(1));
+        assert_eq!(quiet.vsock_idle(), None);
+
+        // a frame on one link leaves the other one idle, so it still gets heartbeats
+        let since = health.now_ms() - 10_000;
+        health.state.last_vsock_frame[0].store(since, Ordering::Relaxed);
+        busy.touch_vsock();
+        assert!(quiet.vsock_idle().unwrap() >= Duration::from_secs(10));
+        assert!(busy.vsock_idle().unwrap() < Duration::from_secs(10));
+
+        // the report has the most recent frame of any link
+        assert!(health.any_vsock_idle().unwrap() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn busy_data_path_stalls() {
+        let mut health = Health::new();
+        // room to date reads back
+        Arc::get_mut(&mut health.state).unwrap().epoch -= Duration::from_secs(60);
+        health.set_vsock_links(2);
+        health.for_link(0).set_vsock_connected(true);
+        health.for_link(1).set_vsock_connected(true);
+
+        // waiting for a read is not a stall, nor is a read being handled
+        assert!(health.is_healthy());
+        let link = health.for_link(1);
+        let busy = link.busy();
+        assert!(health.is_healthy());
+
+        // a read handled for too long is
+        let since = health.now_ms() - STALL_TIMEOUT.as_millis() as u64 - 1000;
+        health.state.busy_since[1].store(since, Ordering::Relaxed);
+        assert!(health.stalled());
+        assert!(!health.is_healthy());
+        assert!(health.report().contains("stalled=1\n"));
+
+        // until the data path is back waiting
+        drop(busy);
+        assert!(health.is_healthy());
+        assert_eq!(health.busy_for(), None);
+    }
+}
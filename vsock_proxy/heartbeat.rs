@@ -0,0 +1,192 @@
+// Liveness of the vsock links
+//
+// A hung peer that still holds the connection used to go unnoticed,
+// the only recovery was a write error, which may never come on a link
+// with little traffic. The sending side now writes a heartbeat frame
+// whenever the link has been idle for the heartbeat interval, and
+// limits how long a write may block. The receiving side treats a link
+// without any frame for the peer timeout as dead and goes back to the
+// existing reconnect path.
+//
+// Heartbeats are written from a helper thread while the data path is
+// blocked in nfqueue, see LinkWriter for how the writes are shared.
+
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use socket2::Socket;
+
+use crate::frame::heartbeat_frame;
+use crate::link::LinkWriter;
+use crate::{ProxyError, SocketError};
+
+/// Heartbeat interval and dead peer timeout, zero disables either
+#[derive(Clone, Copy, Debug)]
+pub struct Liveness {
+    pub heartbeat_interval: Duration,
+    pub peer_timeout: Duration,
+}
+
+impl Liveness {
+    pub fn from_secs(heartbeat_interval: u64, peer_timeout: u64) -> Self {
+        Liveness {
+            heartbeat_interval: Duration::from_secs(heartbeat_interval),
+            peer_timeout: Duration::from_secs(peer_timeout),
+        }
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        if self.peer_timeout.is_zero() {
+            None
+        } else {
+            Some(self.peer_timeout)
+        }
+    }
+
+    /// Fail reads on a link without frames for the peer timeout
+    pub fn set_read_timeout(&self, socket: &Socket) -> Result<(), ProxyError> {
+        socket
+            .set_read_timeout(self.timeout())
+            .map_err(|e| SocketError::OptionError("SO_RCVTIMEO".to_owned(), e))
+            .map_err(ProxyError::VsockError)
+    }
+
+    /// Fail writes blocked for the peer timeout
+    pub fn set_write_timeout(&self, socket: &Socket) -> Result<(), ProxyError> {
+        socket
+            .set_write_timeout(self.timeout())
+            .map_err(|e| SocketError::OptionError("SO_SNDTIMEO".to_owned(), e))
+            .map_err(ProxyError::VsockError)
+    }
+}
+
+/// Spawn a thread writing heartbeats on an idle link
+pub fn spawn_heartbeat(writer: Arc<LinkWriter>) -> Option<JoinHandle<()>> {
+    let interval = writer.liveness().heartbeat_interval;
+    if interval.is_zero() {
+        return None;
+    }
+
+    let frame = heartbeat_frame();
+    Some(std::thread::spawn(move || loop {
+        // check twice per interval so that the idle time never exceeds it by much
+        std::thread::sleep(interval / 2);
+
+        let idle = writer.health().vsock_idle().unwrap_or(interval);
+        if idle < interval {
+            continue;
+        }
+
+        // a failed heartbeat hands the link to the reconnect thread,
+        // so dead peers are noticed without traffic
+        writer.write_unbuffered(&frame);
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use socket2::{Domain, Type};
+
+    use super::*;
+    use crate::conn::VsockConn;
+    use crate::frame::FrameReader;
+    use crate::health::Health;
+    use crate::replay::{ReplayConfig, ReplayPolicy};
+
+    fn liveness(heartbeat_interval_ms: u64, peer_timeout_ms: u64) -> Liveness {
+        Liveness {
+            heartbeat_interval: Duration::from_millis(heartbeat_interval_ms),
+            peer_timeout: Duration::from_millis(peer_timeout_ms),
+        }
+    }
+
+    fn pair() -> (Socket, Socket) {
+        Socket::pair(Domain::UNIX, Type::STREAM, None).unwrap()
+    }
+
+    // link writer on socket whose peer never comes back once broken
+    fn writer(socket: Socket, liveness: Liveness) -> Arc<LinkWriter> {
+        let replay = ReplayConfig {
+            capacity: 0,
+            policy: ReplayPolicy::DropOldest,
+        };
+        LinkWriter::new(
+            VsockConn::plain(socket),
+            || Err(ProxyError::Shutdown),
+            liveness,
+            replay,
+            Health::new(),
+            false,
+        )
+    }
+
+    #[test]
+    fn heartbeats_only_when_idle() {
+        let (tx, rx) = pair();
+        let writer = writer(tx, liveness(200, 0));
+        spawn_heartbeat(writer.clone()).unwrap();
+
+        // traffic every 50ms keeps the link from going idle
+        let mut packet = vec![0x45; 60];
+        packet[2..4].copy_from_slice(&60u16.to_be_bytes());
+        for _ in 0..12 {
+            writer.write_frame(&packet).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let last_write = Instant::now();
+
+        // so the heartbeat only comes once it stops
+        let mut rx = VsockConn::plain(rx);
+        let mut reader = FrameReader::new();
+        let mut packets = 0;
+        loop {
+            let frames: Vec<Vec<u8>> = rx
+                .read_frames(&mut reader)
+                .unwrap()
+                .map(<[u8]>::to_vec)
+                .collect();
+            if let Some(index) = frames.iter().position(|f| f[..] == heartbeat_frame()) {
+                packets += index;
+                break;
+            }
+            packets += frames.len();
+        }
+        assert_eq!(packets, 12);
+        assert!(last_write.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn peer_timeout_fails_reads() {
+        let (_tx, rx) = pair();
+        liveness(0, 100).set_read_timeout(&rx).unwrap();
+        liveness(0, 100).set_write_timeout(&rx).unwrap();
+        assert_eq!(rx.read_timeout().unwrap(), Some(Duration::from_millis(100)));
+        assert_eq!(
+            rx.write_timeout().unwrap(),
+            Some(Duration::from_millis(100))
+        );
+
+        // a peer that sends nothing, not even heartbeats, is dead after the timeout
+        let start = Instant::now();
+        let mut rx = VsockConn::plain(rx);
+        assert!(matches!(
+            rx.read_frames(&mut FrameReader::new()),
+            Err(SocketError::ReadError(_))
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn zero_disables() {
+        let (tx, rx) = pair();
+        liveness(0, 0).set_read_timeout(&rx).unwrap();
+        liveness(0, 0).set_write_timeout(&rx).unwrap();
+        assert_eq!(rx.read_timeout().unwrap(), None);
+        assert_eq!(rx.write_timeout().unwrap(), None);
+
+        assert!(spawn_heartbeat(writer(tx, liveness(0, 0))).is_none());
+    }
+}
@@ -33,12 +33,30 @@
 // iptables can be used to redirect packets to a nfqueue
 // we read it here, do NAT and forward onwards
 
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
 use clap::Parser;
-use nfq::{Queue, Verdict};
-use socket2::{SockAddr, Socket};
+use nfq::{Message, Queue, Verdict};
+use socket2::SockAddr;
 
+use oyster_raw_proxy::conn::Transport;
+use oyster_raw_proxy::frame::{gso_frame, Hello};
+use oyster_raw_proxy::health::{spawn_health_reporters, Counter, Health};
+use oyster_raw_proxy::heartbeat::{spawn_heartbeat, Liveness};
+use oyster_raw_proxy::link::{LinkWriter, StripedWriter};
+use oyster_raw_proxy::packet::{complete_checksum, get_proto, TCP};
+use oyster_raw_proxy::qos::{QosArgs, Scheduler};
+use oyster_raw_proxy::replay::{ReplayConfig, ReplayPolicy};
+use oyster_raw_proxy::secure::ParentSecureArgs;
+use oyster_raw_proxy::shutdown::{
+    exit_status, log_shutdown, register_shutdown, shutdown_requested,
+};
 use oyster_raw_proxy::{
-    new_nfq_with_backoff, new_vsock_socket_with_backoff, ProxyError, SocketError, VsockAddrParser,
+    drain_nfq, get_eth_interface, new_nfq_with_backoff, new_vsock_socket_with_backoff,
+    recv_nfq_batch, ProxyError, SocketError, VsockAddrParser, MAX_BATCH,
 };
 
 #[derive(Parser)]
@@ -50,75 +68,235 @@ struct Cli {
     /// nfqueue number of the listener <num>
     #[clap(short, long, value_parser)]
     queue_num: u16,
+    /// seconds of idle link before a heartbeat is sent, 0 disables
+    #[clap(long, value_parser, default_value_t = 5)]
+    heartbeat_interval: u64,
+    /// seconds a write may block before the peer is considered dead, 0 disables
+    #[clap(long, value_parser, default_value_t = 15)]
+    peer_timeout: u64,
+    /// max bytes of frames buffered while the vsock link is re-established, 0 disables
+    #[clap(long, value_parser, default_value_t = 4 << 20)]
+    replay_buffer: usize,
+    /// which frames to drop once the replay buffer is full
+    #[clap(long, value_enum, default_value_t = ReplayPolicy::DropOldest)]
+    replay_policy: ReplayPolicy,
+    /// take packets coalesced by GRO from nfqueue and send them to the enclave whole,
+    /// it segments them or hands them to its stack with vsock-to-ip-raw-incoming --vnet-hdr
+    #[clap(long)]
+    gro: bool,
+    /// number frames and stamp them with the send time for the seq_* counters
+    /// of the enclave, it has to be recent enough to take sequenced frames
+    #[clap(long)]
+    sequence: bool,
+    /// parallel vsock connections, flows are hashed onto them, has to match the enclave
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=64), default_value_t = 1)]
+    streams: u8,
+    /// file to report the proxy state to for health checks
+    #[clap(long, value_parser)]
+    health_file: Option<PathBuf>,
+    /// address to serve the proxy state on over HTTP <ip:port>, 503 when unhealthy
+    #[clap(long, value_parser)]
+    health_addr: Option<SocketAddr>,
+    /// vsock socket type, seqpacket sends every frame as a message of its own,
+    /// has to match the enclave
+    #[clap(long, value_enum, default_value_t = Transport::Stream)]
+    transport: Transport,
+    #[clap(flatten)]
+    qos: QosArgs,
+    #[clap(flatten)]
+    secure: ParentSecureArgs,
 }
 
-fn handle_conn(conn_socket: &mut Socket, queue: &mut Queue) -> Result<(), ProxyError> {
-    loop {
-        let mut msg = queue
-            .recv()
-            .map_err(SocketError::ReadError)
-            .map_err(ProxyError::NfqError)?;
+// GSO frame for a packet coalesced by GRO, None if it has to be dropped
+fn gso_frame_for(msg: &Message) -> Option<Vec<u8>> {
+    let buf = msg.get_payload();
+    match get_proto(buf) == TCP && buf.len() <= u16::MAX.into() {
+        // segment size is up to the enclave, it knows the mtu of its device
+        true => Some(gso_frame(0, buf)),
+        false => None,
+    }
+}
 
-        let buf = msg.get_payload_mut();
-
-        // let src_addr = buf[12..16].iter().fold(String::new(), |acc, val| {
-        //   if acc != "" {
-        //       acc + "." + &val.to_string()
-        //   } else {
-        //       acc + &val.to_string()
-        //   }
-        // });
-        // println!("incoming {:?} from {:?}: {:02x?}", buf.len(), src_addr, &buf);
-
-        // send
-        let mut total_sent = 0;
-        while total_sent < buf.len() {
-            let size = conn_socket
-                .send(&buf[total_sent..])
-                .map_err(SocketError::WriteError)
-                .map_err(ProxyError::VsockError)?;
-            total_sent += size;
+fn forward_batch(
+    writer: &StripedWriter,
+    queue: &mut Queue,
+    batch: &mut Vec<Message>,
+    gso_dropped: &Counter,
+    qos: Option<&Scheduler>,
+) -> Result<(), ProxyError> {
+    // let src_addr = buf[12..16].iter().fold(String::new(), |acc, val| {
+    //   if acc != "" {
+    //       acc + "." + &val.to_string()
+    //   } else {
+    //       acc + &val.to_string()
+    //   }
+    // });
+    // println!("incoming {:?} from {:?}: {:02x?}", buf.len(), src_addr, &buf);
+
+    // with --gro checksums may be left to offload, the enclave computes
+    // them for each segment of GSO packets
+    for msg in batch.iter_mut() {
+        if !msg.is_checksum_ready() && !msg.is_seg_offloaded() {
+            complete_checksum(msg.get_payload_mut());
         }
+    }
 
-        // verdicts
+    // GSO packets are beyond the MTU, they go to the enclave in GSO frames
+    let gso_frames: Vec<Option<Vec<u8>>> = batch
+        .iter()
+        .map(|msg| match msg.is_seg_offloaded() {
+            true => gso_frame_for(msg),
+            false => None,
+        })
+        .collect();
+
+    // send, a single write for the whole batch
+    let frames: Vec<&[u8]> = batch
+        .iter()
+        .zip(&gso_frames)
+        .filter_map(|(msg, gso_frame)| match (msg.is_seg_offloaded(), gso_frame) {
+            (false, _) => Some(msg.get_payload()),
+            (true, gso_frame) => gso_frame.as_deref(),
+        })
+        .collect();
+    gso_dropped.add((batch.len() - frames.len()) as u64);
+    // with --qos control and interactive packets go first
+    let frames = match qos {
+        Some(qos) => qos.schedule(&frames),
+        None => frames,
+    };
+    writer.write_frames(&frames)?;
+
+    // verdicts
+    for mut msg in batch.drain(..) {
         msg.set_verdict(Verdict::Drop);
         queue
             .verdict(msg)
             .map_err(|e| SocketError::VerdictError(Verdict::Drop, e))
             .map_err(ProxyError::NfqError)?;
     }
+
+    Ok(())
+}
+
+fn handle_conn(
+    writer: &StripedWriter,
+    queue: &mut Queue,
+    health: &Health,
+    gso_dropped: &Counter,
+    qos: Option<&Scheduler>,
+) -> Result<(), ProxyError> {
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    loop {
+        recv_nfq_batch(queue, &mut batch)?;
+        // back to waiting with the next recv, the watchdog sees a stall otherwise
+        let _busy = health.busy();
+        health.touch_packet();
+
+        forward_batch(writer, queue, &mut batch, gso_dropped, qos)?;
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    exit_status(run(Cli::parse()))
+}
+
+fn run(cli: Cli) -> anyhow::Result<()> {
+    // stop at a frame boundary on SIGTERM/SIGINT
+    register_shutdown()?;
+
+    let health = Health::with_nfqueue();
+    spawn_health_reporters(&health, cli.health_file.clone(), cli.health_addr)?;
+    // GSO packets that cannot go to the enclave whole
+    let gso_dropped = health.counter("gso_dropped");
+    let qos = cli.qos.scheduler(&health);
 
     // nfqueue for incoming packets
     let queue_num = cli.queue_num;
-    let mut queue = new_nfq_with_backoff(queue_num);
+    let mut queue = new_nfq_with_backoff(queue_num, cli.gro)?;
+    health.set_nfqueue_bound(true);
+    // false once a shutdown cancelled binding the queue again, nothing to drain then
+    let mut bound = true;
+
+    // the enclave uses our interface address as its own
+    let (ifname, ifaddr) = get_eth_interface().context("could not get ethernet interface")?;
+    println!("detected ethernet interface: {}, {:#10x}", ifname, ifaddr);
+    let hello = Hello {
+        ipv4: Some(Ipv4Addr::from(ifaddr.to_ne_bytes())),
+        // packets only go to the enclave on this link
+        gso: false,
+        mtu: None,
+        seq: false,
+        streams: None,
+    };
 
     // get vsock socket
+    // with --secure the enclave has to attest its link key first
     let vsock_addr = &cli.vsock_addr;
-    let mut vsock_socket = new_vsock_socket_with_backoff(vsock_addr);
+    // records of the secure channel are not framed for seqpacket yet
+    if cli.secure.secure && cli.transport == Transport::Seqpacket {
+        anyhow::bail!("--secure is not supported with --transport seqpacket");
+    }
+    let secure = cli.secure.config()?;
+    let connect = {
+        let vsock_addr = vsock_addr.clone();
+        let transport = cli.transport;
+        Arc::new(move || {
+            new_vsock_socket_with_backoff(&vsock_addr, transport, &hello, secure.as_deref())
+        })
+    };
+    let liveness = Liveness::from_secs(cli.heartbeat_interval, cli.peer_timeout);
+    let replay = ReplayConfig {
+        capacity: cli.replay_buffer,
+        policy: cli.replay_policy,
+    };
+    // one link per stream, each reconnects in the background on write errors,
+    // both from the data path and heartbeats
+    let streams = usize::from(cli.streams);
+    health.set_vsock_links(streams);
+    let links = (0..streams)
+        .map(|link| {
+            let connect = connect.clone();
+            let writer = LinkWriter::new(
+                connect()?,
+                move || connect(),
+                liveness,
+                replay,
+                health.for_link(link),
+                cli.sequence,
+            );
+            spawn_heartbeat(writer.clone());
+            Ok(writer)
+        })
+        .collect::<Result<_, ProxyError>>()?;
+    let writer = StripedWriter::new(links);
 
-    loop {
+    while !shutdown_requested() {
         // do proxying
         // on errors, simply reset the erroring socket
-        match handle_conn(&mut vsock_socket, &mut queue) {
+        match handle_conn(&writer, &mut queue, &health, &gso_dropped, qos.as_ref()) {
             Ok(_) => {
                 // should never happen!
                 unreachable!("connection handler exited without error");
             }
+            Err(ProxyError::Shutdown) => break,
             Err(err @ ProxyError::NfqError(_)) => {
                 println!("{:?}", anyhow::Error::from(err));
 
                 // get nfqueue
-                queue = new_nfq_with_backoff(queue_num);
+                health.set_nfqueue_bound(false);
+                match new_nfq_with_backoff(queue_num, cli.gro) {
+                    Ok(new_queue) => queue = new_queue,
+                    Err(_) => {
+                        bound = false;
+                        break;
+                    }
+                }
+                health.set_nfqueue_bound(true);
             }
             Err(err @ ProxyError::VsockError(_)) => {
+                // the link writer reconnects and replays on its own
                 println!("{:?}", anyhow::Error::from(err));
-
-                // get vsock socket
-                vsock_socket = new_vsock_socket_with_backoff(vsock_addr);
             }
             Err(err) => {
                 // should never happen!
@@ -126,4 +304,23 @@ fn main() -> anyhow::Result<()> {
             }
         }
     }
-}
\ No newline at end of file
+
+    log_shutdown();
+
+    // forward what was already queued so that it is not lost,
+    // then let the enclave see a clean eof at a frame boundary
+    if bound {
+        drain_nfq(&mut queue, queue_num, |queue, batch| {
+            forward_batch(&writer, queue, batch, &gso_dropped, qos.as_ref())
+        })?;
+    }
+    // a link that did not come back lost what was buffered for it
+    if writer.pending() > 0 {
+        return Err(ProxyError::FramesLost(writer.pending()).into());
+    }
+    for link in writer.links() {
+        link.shutdown(std::net::Shutdown::Write)?;
+    }
+
+    Ok(())
+}
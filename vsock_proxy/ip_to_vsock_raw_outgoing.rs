@@ -28,13 +28,36 @@
 // and most applications use ports lower than ephemeral, it _is_ a breaking change
 
 use clap::Parser;
-use nfq::{Queue, Verdict};
-use socket2::{SockAddr, Socket};
-use std::net::Ipv4Addr;
+use nfq::{Message, Queue, Verdict};
+use socket2::SockAddr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::Arc;
 use byteorder::{BigEndian, ByteOrder};
 
+use anyhow::Context;
+use oyster_raw_proxy::health::{spawn_health_reporters, Counter, Health};
+use oyster_raw_proxy::heartbeat::{spawn_heartbeat, Liveness};
+use oyster_raw_proxy::link::{LinkWriter, StripedWriter};
+use oyster_raw_proxy::conn::Transport;
+use oyster_raw_proxy::dns::{watch_cache_file, DnsCache};
+use oyster_raw_proxy::frame::{gso_frame, Hello};
+use oyster_raw_proxy::packet::{
+    clamp_mss, complete_checksum, get_proto, modify_packet, ChecksumMode, TCP,
+};
+use oyster_raw_proxy::policy::{read_policy_file, watch_policy_file, Destination, Policy};
+use oyster_raw_proxy::qos::{QosArgs, Scheduler};
+use oyster_raw_proxy::replay::{ReplayConfig, ReplayPolicy};
+use oyster_raw_proxy::reload::{read_ip_file, watch_ip_file, SharedIp};
+use oyster_raw_proxy::secure::EnclaveSecureArgs;
+use oyster_raw_proxy::shaper::{read_rules_file, watch_rules_file, Direction, Shaper};
+use oyster_raw_proxy::shutdown::{
+    exit_status, log_shutdown, register_shutdown, shutdown_requested,
+};
 use oyster_raw_proxy::{
-    new_nfq_with_backoff, new_vsock_socket_with_backoff, ProxyError, SocketError, VsockAddrParser,
+    drain_nfq, new_nfq_with_backoff, new_vsock_socket_recv_hello_with_backoff, recv_nfq_batch,
+    ProxyError, SocketError, VsockAddrParser, MAX_BATCH,
 };
 
 #[derive(Parser)]
@@ -46,242 +69,429 @@ struct Cli {
     /// nfqueue number of the listener <num>
     #[clap(short, long, value_parser)]
     queue_num: u16,
+    /// file with the enclave ip, reloaded on change or SIGHUP,
+    /// overrides the ip announced by the parent
+    #[clap(long, value_parser)]
+    ip_file: Option<PathBuf>,
+    /// print the ip announced by the parent and exit
+    #[clap(long)]
+    print_ip: bool,
+    /// seconds of idle link before a heartbeat is sent, 0 disables
+    #[clap(long, value_parser, default_value_t = 5)]
+    heartbeat_interval: u64,
+    /// seconds a write may block before the peer is considered dead, 0 disables
+    #[clap(long, value_parser, default_value_t = 15)]
+    peer_timeout: u64,
+    /// max bytes of frames buffered while the vsock link is re-established, 0 disables
+    #[clap(long, value_parser, default_value_t = 4 << 20)]
+    replay_buffer: usize,
+    /// which frames to drop once the replay buffer is full
+    #[clap(long, value_enum, default_value_t = ReplayPolicy::DropOldest)]
+    replay_policy: ReplayPolicy,
+    /// how checksums of rewritten packets are fixed up
+    #[clap(long, value_enum, default_value_t = ChecksumMode::Incremental)]
+    checksum_mode: ChecksumMode,
+    /// take packets from nfqueue with checksums left to offload and complete them,
+    /// see vsock-to-ip-raw-incoming --vnet-hdr, GSO packets go to the parent whole
+    #[clap(long)]
+    checksum_offload: bool,
+    /// largest packet to reach the internet in, TCP MSS is clamped to fit the lower
+    /// of this and the mtu announced by the parent
+    #[clap(long, value_parser)]
+    mtu: Option<u16>,
+    /// number frames and stamp them with the send time, if the parent takes
+    /// sequenced frames, for its seq_* counters
+    #[clap(long)]
+    sequence: bool,
+    /// parallel vsock connections, flows are hashed onto them, capped to what the parent takes
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=64), default_value_t = 1)]
+    streams: u8,
+    /// file of upload caps per container, see shaper.rs, reloaded on change or SIGHUP
+    #[clap(long, value_parser)]
+    shape_file: Option<PathBuf>,
+    /// file of what each container may reach, see policy.rs, reloaded on change or SIGHUP
+    #[clap(long, value_parser)]
+    policy_file: Option<PathBuf>,
+    /// file of the addresses domains resolved to, for domains in the policy file,
+    /// written by dns-forwarder --dns-cache-file
+    #[clap(long, value_parser)]
+    dns_cache_file: Option<PathBuf>,
+    /// file to report the proxy state to for health checks
+    #[clap(long, value_parser)]
+    health_file: Option<PathBuf>,
+    /// address to serve the proxy state on over HTTP <ip:port>, 503 when unhealthy
+    #[clap(long, value_parser)]
+    health_addr: Option<SocketAddr>,
+    /// vsock socket type, seqpacket sends every frame as a message of its own,
+    /// has to match the parent
+    #[clap(long, value_enum, default_value_t = Transport::Stream)]
+    transport: Transport,
+    #[clap(flatten)]
+    qos: QosArgs,
+    #[clap(flatten)]
+    secure: EnclaveSecureArgs,
 }
 
-const TCP: u8 = 6;
+// source NAT of outgoing packets, only the ip changes at runtime
+struct Nat {
+    ip: SharedIp,
+    checksum_mode: ChecksumMode,
+    // max MSS of outgoing SYNs, 0 - no clamping
+    mss: Arc<AtomicU16>,
+    // whether the parent splits GSO packets, announced in its hello
+    parent_gso: Arc<AtomicBool>,
+    // unsegmented packets nfqueue hands over with --checksum-offload
+    // that cannot go to the parent whole
+    gso_dropped: Counter,
+}
 
-// Helper function to calculate the checksum for an IP header
-fn checksum_ip4(data: &[u8]) -> u16 {
-    let mut sum: u32 = 0;
+fn rewrite_msg(msg: &mut Message, ip: Ipv4Addr, mss: u16, checksum_mode: ChecksumMode) {
+    // ideally we should also read conntrack info for the
+    // packet and change all fields properly, source port in particular,
+    // that would ensure we wouldn't need source port limits per docker
+    // container that we're now forced to use, but nfq doesn't read
+    // conntrack so... one day.
+    // https://github.com/torvalds/linux/blob/master/include/uapi/linux/netfilter/nfnetlink_conntrack.h
+
+    // with --checksum-offload the transport checksum may only hold the pseudo-header sum
+    let checksum_ready = msg.is_checksum_ready();
+    // the parent computes the checksums of each segment of GSO packets
+    let seg_offloaded = msg.is_seg_offloaded();
+    let buf = msg.get_payload_mut();
+
+    let dst_ip = Ipv4Addr::from(BigEndian::read_u32(&buf[16..20]));
+    let src_ip = Ipv4Addr::from(BigEndian::read_u32(&buf[12..16]));
+
+    // println!("outgoing {:?} from {:?} to {:?}: {:02x?} ", buf.len(), src_ip, dst_ip, &buf[0..20]);
+
+    if src_ip != ip {
+      // partial checksums are computed from scratch below
+      let checksum_mode = match checksum_ready {
+          true => checksum_mode,
+          false => ChecksumMode::Incremental,
+      };
+      modify_packet(buf, ip, dst_ip, checksum_mode);
 
-    // Process each 16-bit word (2 bytes)
-    for chunk in data.chunks(2) {
-        let word = if chunk.len() == 2 {
-            BigEndian::read_u16(chunk)
-        } else {
-            (chunk[0] as u16) << 8
-        };
-        sum = sum.wrapping_add(word as u32);
+    //   let new_src_ip = Ipv4Addr::from(BigEndian::read_u32(&buf[12..16]));
+    //   println!("source_ip changed from {:?} to {:?}: {:02x?} ", src_ip, new_src_ip, &buf);
     }
 
-    // Add carry if any
-    while sum >> 16 != 0 {
-        sum = (sum & 0xFFFF) + (sum >> 16);
+    // tun0 has a larger mtu than the path to the internet, peers must
+    // not send segments that do not fit it
+    if mss > 0 {
+        clamp_mss(buf, mss);
     }
 
-    // Return the one's complement of the sum
-    !(sum as u16)
+    if !checksum_ready && !seg_offloaded {
+        complete_checksum(buf);
+    }
 }
 
-fn get_ihl(buf: &[u8]) -> u8 {
-  (buf[0] & 0x0F) * 4 // 32-words -> bytes
-}
+// MSS fitting the lower of the configured mtu and the one announced by
+// the parent, 0 if neither is known
+fn link_mss(mtu: Option<u16>, hello: &Hello) -> u16 {
+    // IPv4 and TCP headers without options
+    const HEADERS_LEN: u16 = 40;
 
-fn get_proto(buf: &[u8]) -> u8 {
-  buf[9] & 0xFF
+    match mtu.into_iter().chain(hello.mtu).min() {
+        Some(mtu) => mtu.saturating_sub(HEADERS_LEN),
+        None => 0,
+    }
 }
 
-fn checksum_tcp4(
-  tcp_segment: &[u8],
-  src_ip: Ipv4Addr,
-  dst_ip: Ipv4Addr,
-) -> u16 {
-  let mut sum: u32 = 0;
-
-  // Pseudo-header
-  for b in src_ip.octets().chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])) {
-      sum += b as u32;
-  }
-  for b in dst_ip.octets().chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])) {
-      sum += b as u32;
-  }
-
-  sum += u32::from(TCP); // Protocol number (TCP)
-  sum += (tcp_segment.len() as u32) & 0xFFFF;
-
-  // TCP header + data
-  for chunk in tcp_segment.chunks(2) {
-      let word = if chunk.len() == 2 {
-          u16::from_be_bytes([chunk[0], chunk[1]])
-      } else {
-          u16::from_be_bytes([chunk[0], 0])
-      };
-      sum += word as u32;
-  }
-
-  // Fold 32-bit sum to 16 bits
-  while (sum >> 16) != 0 {
-      sum = (sum & 0xFFFF) + (sum >> 16);
-  }
-
-  !(sum as u16)
+// GSO frame for a GSO packet, None if it has to be dropped
+fn gso_frame_for(msg: &Message, parent_gso: bool) -> Option<Vec<u8>> {
+    let buf = msg.get_payload();
+    match parent_gso && get_proto(buf) == TCP && buf.len() <= u16::MAX.into() {
+        // segment size is up to the parent, it knows the mtu
+        true => Some(gso_frame(0, buf)),
+        false => None,
+    }
 }
 
-/// Modify source IP and decrement TTL, then recalculate checksum
-fn modify_packet(
-  buf: &mut [u8],
-  src_ip: Ipv4Addr,
-  dst_ip: Ipv4Addr,
-) {
-  const SRC_IP_OFFSET: usize = 12;
-  const TTL_OFFSET: usize = 8;
-  const IP_CHECKSUM_OFFSET: usize = 10;
-  // excluding IP header
-  const TCP_CHECKSUM_OFFSET: usize = 16;
-  const MIN_IP_HEADER_LEN: usize = 20;
-  const MIN_TCP_HEADER_LEN: usize = 20;
-
-  let ip_header_length = get_ihl(buf) as usize;
-  if ip_header_length > buf.len() || ip_header_length < MIN_IP_HEADER_LEN {
-    println!("invalid IP packet len {:?}", ip_header_length);
-    return;
-  }
-
-  // TCP validate + update checksum
-  if get_proto(buf) == TCP {
-    if (ip_header_length + MIN_TCP_HEADER_LEN) > buf.len() {
-      println!("invalid TCP packet len {:?}", buf.len());
-      return;  
+fn forward_batch(
+    writer: &StripedWriter,
+    queue: &mut Queue,
+    batch: &mut Vec<Message>,
+    nat: &Nat,
+    qos: Option<&Scheduler>,
+    shaper: Option<&Shaper>,
+    policy: Option<&Policy>,
+) -> Result<(), ProxyError> {
+    // with --policy-file packets a container may not send are dropped,
+    // decided on what it sent, before NAT
+    let allowed: Vec<bool> = batch
+        .iter()
+        .map(|msg| policy.is_none_or(|policy| policy.allows(msg.get_payload(), msg.get_nfmark())))
+        .collect();
+
+    // read the current ip once per batch, it might be reloaded concurrently
+    let ip = nat.ip.get();
+    let mss = nat.mss.load(Ordering::Relaxed);
+    for msg in batch.iter_mut() {
+        rewrite_msg(msg, ip, mss, nat.checksum_mode);
     }
 
-    let offset = ip_header_length + TCP_CHECKSUM_OFFSET;
-
-    // Zero TCP checksum before recalculating
-    buf[offset..offset + 2].copy_from_slice(&[0, 0]);
-
-    // new checksum
-    let tcp_checksum_val = checksum_tcp4(&buf[ip_header_length..], src_ip, dst_ip);
-
-    // Write new checksum into header
-    buf[offset] = (tcp_checksum_val >> 8) as u8;
-    buf[offset + 1] = (tcp_checksum_val & 0xFF) as u8;
-  }
-
-  // now update IP packet
-  let new_ip_bytes = src_ip.octets();
-
-  // Decrement TTL safely
-  if buf[TTL_OFFSET] > 1 {
-      buf[TTL_OFFSET] -= 1;
-  } else {
-      buf[TTL_OFFSET] = 1; // Prevent underflow, TTL shouldn't be <= 0
-  }
+    // GSO packets are beyond the MTU, they go to the parent whole in
+    // GSO frames if it splits them and are dropped otherwise
+    let parent_gso = nat.parent_gso.load(Ordering::Relaxed);
+    let gso_frames: Vec<Option<Vec<u8>>> = batch
+        .iter()
+        .map(|msg| match msg.is_seg_offloaded() {
+            true => gso_frame_for(msg, parent_gso),
+            false => None,
+        })
+        .collect();
+
+    // send through vsock, a single write for the whole batch
+    let (frames, marks): (Vec<&[u8]>, Vec<u32>) = batch
+        .iter()
+        .zip(&gso_frames)
+        .zip(&allowed)
+        .filter(|(_, allowed)| **allowed)
+        .filter_map(|((msg, gso_frame), _)| {
+            let frame = match msg.is_seg_offloaded() {
+                false => Some(msg.get_payload()),
+                true => gso_frame.as_deref(),
+            };
+            Some((frame?, msg.get_nfmark()))
+        })
+        .unzip();
+    let forwarded = allowed.iter().filter(|allowed| **allowed).count();
+    nat.gso_dropped.add((forwarded - frames.len()) as u64);
+    // with --shape-file containers over their cap wait for the shaper
+    let frames = match shaper {
+        Some(shaper) => shaper.admit(&frames, Some(&marks)),
+        None => frames,
+    };
+    // with --qos control and interactive packets go first
+    let frames = match qos {
+        Some(qos) => qos.schedule(&frames),
+        None => frames,
+    };
+    writer.write_frames(&frames)?;
+
+    // verdicts
+    for mut msg in batch.drain(..) {
+        msg.set_verdict(Verdict::Drop);
+        queue
+            .verdict(msg)
+            .map_err(|e| SocketError::VerdictError(Verdict::Drop, e))
+            .map_err(ProxyError::NfqError)?;
+    }
 
-  // Change source IP
-  buf[SRC_IP_OFFSET..SRC_IP_OFFSET + 4].copy_from_slice(&new_ip_bytes);
+    Ok(())
+}
 
-  // Zero IP checksum before recalculating
-  buf[IP_CHECKSUM_OFFSET..IP_CHECKSUM_OFFSET + 2].copy_from_slice(&[0, 0]);
+fn handle_conn(
+    writer: &StripedWriter,
+    queue: &mut Queue,
+    nat: &Nat,
+    qos: Option<&Scheduler>,
+    shaper: Option<&Shaper>,
+    policy: Option<&Policy>,
+    health: &Health,
+) -> Result<(), ProxyError> {
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    loop {
+        recv_nfq_batch(queue, &mut batch)?;
+        // back to waiting with the next recv, the watchdog sees a stall otherwise
+        let _busy = health.busy();
+        health.touch_packet();
 
-  // Recalculate checksum over the IP header
-  let checksum_val = checksum_ip4(&buf[..ip_header_length]);
+        forward_batch(writer, queue, &mut batch, nat, qos, shaper, policy)?;
+    }
+}
 
-  // Write new checksum into header
-  buf[IP_CHECKSUM_OFFSET] = (checksum_val >> 8) as u8;
-  buf[IP_CHECKSUM_OFFSET + 1] = (checksum_val & 0xFF) as u8;
+fn main() -> anyhow::Result<()> {
+    exit_status(run(Cli::parse()))
 }
 
-fn handle_conn(conn_socket: &mut Socket, queue: &mut Queue, ip: &str) -> Result<(), ProxyError> {
-    loop {
-        let mut msg = queue
-            .recv()
-            .map_err(SocketError::ReadError)
-            .map_err(ProxyError::NfqError)?;
+fn run(cli: Cli) -> anyhow::Result<()> {
+    // stop at a frame boundary on SIGTERM/SIGINT
+    register_shutdown()?;
 
-        // ideally we should also read conntrack info for the
-        // packet and change all fields properly, source port in particular,
-        // that would ensure we wouldn't need source port limits per docker
-        // container that we're now forced to use, but nfq doesn't read
-        // conntrack so... one day.
-        // https://github.com/torvalds/linux/blob/master/include/uapi/linux/netfilter/nfnetlink_conntrack.h
-
-        let mut buf = msg.get_payload_mut();
-        let size = buf.len();
-
-        let dst_addr = buf[16..20].iter().fold(String::new(), |acc, val| {
-          if acc != "" {
-              acc + "." + &val.to_string()
-          } else {
-              acc + &val.to_string()
-          }
-        });
-
-        let src_addr = buf[12..16].iter().fold(String::new(), |acc, val| {
-          if acc != "" {
-              acc + "." + &val.to_string()
-          } else {
-              acc + &val.to_string()
-          }
-        });
-
-        // println!("outgoing {:?} from {:?} to {:?}: {:02x?} ", size, src_addr, dst_addr, &buf[0..20]);
-
-        if src_addr != ip {
-          let src_ip: Ipv4Addr = ip.parse().expect("Invalid IP address");
-          let dst_ip: Ipv4Addr = dst_addr.parse().expect("Invalid IP address");
-          modify_packet(&mut buf, src_ip, dst_ip);
-
-        //   let new_src_addr = buf[12..16].iter().fold(String::new(), |acc, val| {
-        //     if acc != "" {
-        //         acc + "." + &val.to_string()
-        //     } else {
-        //         acc + &val.to_string()
-        //     }
-        //   });  
-        //   println!("source_ip changed from {:?} to {:?}: {:02x?} ", src_addr, new_src_addr, &buf);
-        }
+    // get vsock socket, the parent announces our ip on connect
+    // with --secure our link key is attested to the parent first
+    let vsock_addr = &cli.vsock_addr;
+    // records of the secure channel are not framed for seqpacket yet
+    if cli.secure.secure && cli.transport == Transport::Seqpacket {
+        anyhow::bail!("--secure is not supported with --transport seqpacket");
+    }
+    let secure = cli.secure.config()?;
+    let (vsock_conn, hello) =
+        new_vsock_socket_recv_hello_with_backoff(vsock_addr, cli.transport, secure.as_deref())?;
+
+    if cli.print_ip {
+        let ip = hello.ipv4.context("parent did not announce an ip")?;
+        println!("{}", ip);
+        return Ok(());
+    }
 
-        // send through vsock
-        let mut total_sent = 0;
-        while total_sent < size {
-            let size = conn_socket
-                .send(&buf[total_sent..size])
-                .map_err(SocketError::WriteError)
-                .map_err(ProxyError::VsockError)?;
-            total_sent += size;
+    // enclave ip, kept up to date by the watcher thread or on reconnect
+    let ip = match &cli.ip_file {
+        Some(ip_file) => {
+            let ip = SharedIp::new(read_ip_file(ip_file)?);
+            watch_ip_file(ip_file.clone(), ip.clone())?;
+            ip
         }
+        None => SharedIp::new(hello.ipv4.context("parent did not announce an ip")?),
+    };
 
-        // verdicts
-        msg.set_verdict(Verdict::Drop);
-        queue
-            .verdict(msg)
-            .map_err(|e| SocketError::VerdictError(Verdict::Drop, e))
-            .map_err(ProxyError::NfqError)?;
+    let health = Health::with_nfqueue();
+    spawn_health_reporters(&health, cli.health_file.clone(), cli.health_addr)?;
+
+    let parent_gso = Arc::new(AtomicBool::new(hello.gso));
+    let mss = Arc::new(AtomicU16::new(link_mss(cli.mtu, &hello)));
+    match mss.load(Ordering::Relaxed) {
+        0 => println!("mtu unknown, tcp mss not clamped"),
+        mss => println!("tcp mss clamped to: {}", mss),
     }
-}
 
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    let connect = {
+        let vsock_addr = vsock_addr.clone();
+        let transport = cli.transport;
+        let ip = ip.clone();
+        let use_hello = cli.ip_file.is_none();
+        let parent_gso = parent_gso.clone();
+        let mss = mss.clone();
+        let mtu = cli.mtu;
+        Arc::new(move || {
+            let (vsock_conn, hello) =
+                new_vsock_socket_recv_hello_with_backoff(&vsock_addr, transport, secure.as_deref())?;
+            if let (true, Some(ipv4)) = (use_hello, hello.ipv4) {
+                ip.update(ipv4);
+            }
+            parent_gso.store(hello.gso, Ordering::Relaxed);
+            mss.store(link_mss(mtu, &hello), Ordering::Relaxed);
+            Ok(vsock_conn)
+        })
+    };
+    let liveness = Liveness::from_secs(cli.heartbeat_interval, cli.peer_timeout);
+    let replay = ReplayConfig {
+        capacity: cli.replay_buffer,
+        policy: cli.replay_policy,
+    };
+    let sequence = cli.sequence && hello.seq;
+    if cli.sequence && !hello.seq {
+        println!("parent does not take sequenced frames, frames are not numbered");
+    }
+    // the parent has to take as many streams, older ones take a single one
+    let streams = cli.streams.min(hello.streams.unwrap_or(1));
+    if streams < cli.streams {
+        println!("parent takes {} vsock streams only", streams);
+    }
 
-    let ip = std::fs::read_to_string("/enclaved/ip.txt")?.trim().to_owned();
+    // one link per stream, each reconnects in the background on write errors,
+    // both from the data path and heartbeats
+    let streams = usize::from(streams);
+    health.set_vsock_links(streams);
+    let mut vsock_conn = Some(vsock_conn);
+    let links = (0..streams)
+        .map(|link| {
+            let connect = connect.clone();
+            let conn = vsock_conn.take().map_or_else(|| connect(), Ok)?;
+            let writer = LinkWriter::new(
+                conn,
+                move || connect(),
+                liveness,
+                replay,
+                health.for_link(link),
+                sequence,
+            );
+            spawn_heartbeat(writer.clone());
+            Ok(writer)
+        })
+        .collect::<Result<_, ProxyError>>()?;
+    let writer = Arc::new(StripedWriter::new(links));
+
+    let nat = Nat {
+        ip,
+        checksum_mode: cli.checksum_mode,
+        mss,
+        parent_gso,
+        gso_dropped: health.counter("gso_dropped"),
+    };
+    let qos = cli.qos.scheduler(&health);
+    // queued packets go out from the shaper thread, on the same links
+    let shaper = match &cli.shape_file {
+        Some(shape_file) => {
+            let writer = writer.clone();
+            let rules = read_rules_file(shape_file)?;
+            let shaper = Shaper::new(Direction::Upload, &rules, &health, move |frames| {
+                if let Err(err) = writer.write_frames(frames) {
+                    println!("{:?}", anyhow::Error::from(err));
+                }
+            });
+            watch_rules_file(shape_file.clone(), shaper.clone())?;
+            Some(shaper)
+        }
+        None => None,
+    };
+    let policy = match &cli.policy_file {
+        Some(policy_file) => {
+            let rules = read_policy_file(policy_file)?;
+            let names = rules
+                .iter()
+                .any(|rule| matches!(rule.dst, Destination::Name(_)));
+            let dns = DnsCache::default();
+            match &cli.dns_cache_file {
+                Some(dns_cache_file) => {
+                    watch_cache_file(dns_cache_file.clone(), dns.clone())?;
+                }
+                None if names => {
+                    println!("domains in the policy file never resolve without --dns-cache-file");
+                }
+                None => {}
+            }
+            let policy = Policy::new(rules, dns, &health);
+            watch_policy_file(policy_file.clone(), policy.clone())?;
+            Some(policy)
+        }
+        None => None,
+    };
 
     // nfqueue for incoming packets
     let queue_addr = cli.queue_num;
-    let mut queue = new_nfq_with_backoff(queue_addr);
+    let mut queue = new_nfq_with_backoff(queue_addr, cli.checksum_offload)?;
+    health.set_nfqueue_bound(true);
+    // false once a shutdown cancelled binding the queue again, nothing to drain then
+    let mut bound = true;
 
-    // get vsock socket
-    let vsock_addr = &cli.vsock_addr;
-    let mut vsock_socket = new_vsock_socket_with_backoff(vsock_addr);
-
-    loop {
+    while !shutdown_requested() {
         // do proxying
         // on errors, simply reset the erroring socket
-        match handle_conn(&mut vsock_socket, &mut queue, &ip) {
+        match handle_conn(
+            &writer,
+            &mut queue,
+            &nat,
+            qos.as_ref(),
+            shaper.as_ref(),
+            policy.as_ref(),
+            &health,
+        ) {
             Ok(_) => {
                 // should never happen!
                 unreachable!("connection handler exited without error");
             }
+            Err(ProxyError::Shutdown) => break,
             Err(err @ ProxyError::NfqError(_)) => {
                 println!("{:?}", anyhow::Error::from(err));
 
                 // get nfqueue
-                queue = new_nfq_with_backoff(queue_addr);
+                health.set_nfqueue_bound(false);
+                match new_nfq_with_backoff(queue_addr, cli.checksum_offload) {
+                    Ok(new_queue) => queue = new_queue,
+                    Err(_) => {
+                        bound = false;
+                        break;
+                    }
+                }
+                health.set_nfqueue_bound(true);
             }
             Err(err @ ProxyError::VsockError(_)) => {
+                // the link writer reconnects and replays on its own
                 println!("{:?}", anyhow::Error::from(err));
-
-                // get vsock socket
-                vsock_socket = new_vsock_socket_with_backoff(vsock_addr);
             }
             Err(err) => {
                 // should never happen!
@@ -289,4 +499,35 @@ fn main() -> anyhow::Result<()> {
             }
         }
     }
-}
\ No newline at end of file
+
+    log_shutdown();
+
+    // forward what was already queued so that it is not lost,
+    // then let the parent see a clean eof at a frame boundary
+    if bound {
+        drain_nfq(&mut queue, queue_addr, |queue, batch| {
+            forward_batch(
+                &writer,
+                queue,
+                batch,
+                &nat,
+                qos.as_ref(),
+                shaper.as_ref(),
+                policy.as_ref(),
+            )
+        })?;
+    }
+    // packets waiting on their cap go out now rather than never
+    if let Some(shaper) = &shaper {
+        shaper.flush();
+    }
+    // a link that did not come back lost what was buffered for it
+    if writer.pending() > 0 {
+        return Err(ProxyError::FramesLost(writer.pending()).into());
+    }
+    for link in writer.links() {
+        link.shutdown(std::net::Shutdown::Write)?;
+    }
+
+    Ok(())
+}
@@ -1,15 +1,53 @@
 // https://raw.githubusercontent.com/marlinprotocol/oyster-monorepo/refs/heads/master/networking/raw-proxy/src/lib.rs
 
-use std::ffi::OsStr;
-use std::thread::sleep;
+use std::ffi::{CStr, OsStr};
+use std::os::fd::AsRawFd;
 use std::time::Duration;
 
+use anyhow::{anyhow, Context};
+use libc::{freeifaddrs, getifaddrs, ifaddrs, strncmp};
 use thiserror::Error;
 
 use clap::{builder::TypedValueParser, error::ErrorKind, Arg, Command};
-use nfq::{Queue, Verdict};
+use nfq::{Message, Queue, Verdict};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
+pub mod attestation;
+pub mod backoff;
+pub mod conn;
+pub mod dns;
+pub mod fragment;
+pub mod frame;
+pub mod health;
+pub mod heartbeat;
+pub mod link;
+pub mod packet;
+pub mod policy;
+pub mod qos;
+pub mod reload;
+pub mod replay;
+pub mod resolver;
+pub mod secure;
+pub mod sequence;
+pub mod shaper;
+pub mod shutdown;
+pub mod stream;
+pub mod tun;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+
+use backoff::{Backoff, BackoffError, BackoffEvent};
+use conn::{Transport, VsockConn};
+use frame::{Hello, MAX_FRAME_LEN};
+use heartbeat::Liveness;
+use secure::SecureConfig;
+
+// how long to wait for the peer's hello after the connection is set up
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Max packets handled per syscall batch, bounds the latency added to the first one
+pub const MAX_BATCH: usize = 64;
+
 #[derive(Error, Debug)]
 pub enum ProxyError {
     #[error("ip socket error")]
@@ -18,6 +56,10 @@ pub enum ProxyError {
     VsockError(#[source] SocketError),
     #[error("nfqueue error")]
     NfqError(#[source] SocketError),
+    #[error("shutdown requested")]
+    Shutdown,
+    #[error("{0} frames lost on shutdown, vsock link is down")]
+    FramesLost(usize),
 }
 
 #[derive(Error, Debug)]
@@ -74,30 +116,36 @@ pub enum SocketError {
     VerdictError(Verdict, #[source] std::io::Error),
     #[error("failed to set option {0}")]
     OptionError(String, #[source] std::io::Error),
+    #[error("invalid frame: {0}")]
+    FrameError(String),
+    #[error("handshake failed: {0}")]
+    HandshakeError(String),
 }
 
+/// Retry f until it succeeds, fails with ProxyError::Shutdown once a
+/// shutdown is requested, the caller decides what that means for the exit status
 pub fn run_with_backoff<P: Clone, R, F: Fn(P) -> Result<R, ProxyError>>(
     f: F,
     p: P,
     max_backoff: u64,
-) -> R {
-    let mut backoff = 1;
-    loop {
-        match f(p.clone()) {
-            Ok(r) => {
-                return r;
+) -> Result<R, ProxyError> {
+    let mut backoff =
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(max_backoff)).hook(|event| {
+            if let BackoffEvent::Retry { error, .. } = event {
+                println!("{:?}", anyhow::Error::from(error));
             }
-            Err(err) => {
-                println!("{:?}", anyhow::Error::from(err));
+        });
 
-                sleep(Duration::from_secs(backoff));
-                backoff = (backoff * 2).clamp(1, max_backoff);
-            }
-        };
+    match backoff.retry(|| f(p.clone())) {
+        Ok(r) => Ok(r),
+        Err(BackoffError::Cancelled { .. }) => Err(ProxyError::Shutdown),
+        Err(err @ BackoffError::GaveUp { .. }) => {
+            unreachable!("retrying without max elapsed time stopped: {err:?}")
+        }
     }
 }
 
-fn new_nfq(addr: u16) -> Result<Queue, ProxyError> {
+fn new_nfq(addr: u16, offload: bool) -> Result<Queue, ProxyError> {
     let mut queue = Queue::open()
         .map_err(SocketError::OpenError)
         .map_err(ProxyError::NfqError)?;
@@ -108,23 +156,117 @@ fn new_nfq(addr: u16) -> Result<Queue, ProxyError> {
             source: e,
         })
         .map_err(ProxyError::NfqError)?;
+    // with offload, completing checksums and segmenting packets is left to us
+    queue
+        .set_recv_gso(addr, offload)
+        .map_err(|e| SocketError::OptionError("NFQA_CFG_F_GSO".to_owned(), e))
+        .map_err(ProxyError::NfqError)?;
 
     Ok(queue)
 }
 
-pub fn new_nfq_with_backoff(addr: u16) -> Queue {
-    run_with_backoff(new_nfq, addr, 64)
+/// Bind nfqueue addr, with offload packets come with checksums left to
+/// offload (Message::is_checksum_ready) and unsegmented (Message::is_seg_offloaded)
+pub fn new_nfq_with_backoff(addr: u16, offload: bool) -> Result<Queue, ProxyError> {
+    run_with_backoff(
+        |(addr, offload)| new_nfq(addr, offload),
+        (addr, offload),
+        64,
+    )
+}
+
+// take packets already delivered to us without waiting, up to MAX_BATCH,
+// returns false once the queue is empty
+fn recv_nfq_ready(queue: &mut Queue, batch: &mut Vec<Message>) -> Result<bool, ProxyError> {
+    queue.set_nonblocking(true);
+    let result = loop {
+        if batch.len() >= MAX_BATCH {
+            break Ok(true);
+        }
+        match queue.recv() {
+            Ok(msg) => batch.push(msg),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(false),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => break Ok(true),
+            Err(e) => break Err(ProxyError::NfqError(SocketError::ReadError(e))),
+        }
+    };
+    queue.set_nonblocking(false);
+
+    result
+}
+
+/// Wait for a packet, then add it and whatever else is already queued to batch,
+/// up to MAX_BATCH. nfq reads several packets per recv, the rest costs no syscall.
+pub fn recv_nfq_batch(queue: &mut Queue, batch: &mut Vec<Message>) -> Result<(), ProxyError> {
+    let msg = queue
+        .recv()
+        .map_err(SocketError::ReadError)
+        .map_err(ProxyError::NfqError)
+        .map_err(shutdown::or_shutdown)?;
+    batch.push(msg);
+
+    // errors show up again on the next blocking recv, handle what we have
+    let _ = recv_nfq_ready(queue, batch);
+
+    Ok(())
+}
+
+/// Pass the packets already delivered to us to handler in batches and unbind
+/// the queue, packets arriving after the unbind are dropped by the kernel
+pub fn drain_nfq<F: FnMut(&mut Queue, &mut Vec<Message>) -> Result<(), ProxyError>>(
+    queue: &mut Queue,
+    addr: u16,
+    mut handler: F,
+) -> Result<(), ProxyError> {
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    loop {
+        let more = recv_nfq_ready(queue, &mut batch)?;
+        if !batch.is_empty() {
+            handler(queue, &mut batch)?;
+        }
+        if !more {
+            break;
+        }
+    }
+
+    queue
+        .unbind(addr)
+        .map_err(|e| SocketError::BindError {
+            addr: addr.to_string(),
+            source: e,
+        })
+        .map_err(ProxyError::NfqError)
 }
 
-fn new_vsock_socket(addr: &SockAddr) -> Result<Socket, ProxyError> {
-    let vsock_socket = Socket::new(Domain::VSOCK, Type::STREAM, None)
+// vsock socket of transport, stream if the kernel has no seqpacket vsock
+fn new_vsock_raw_socket(transport: Transport) -> Result<Socket, ProxyError> {
+    let r#type = transport.socket_type();
+    let vsock_socket = match Socket::new(Domain::VSOCK, r#type, None) {
+        Err(e)
+            if transport == Transport::Seqpacket
+                && matches!(
+                    e.raw_os_error(),
+                    Some(libc::ESOCKTNOSUPPORT | libc::EPROTONOSUPPORT | libc::EINVAL)
+                ) =>
+        {
+            println!("seqpacket vsock not supported, falling back to stream");
+            return new_vsock_raw_socket(Transport::Stream);
+        }
+        result => result,
+    };
+
+    vsock_socket
         .map_err(|e| SocketError::CreateError {
             domain: Domain::VSOCK,
-            r#type: Type::STREAM,
+            r#type,
             protocol: None,
             source: e,
         })
-        .map_err(ProxyError::VsockError)?;
+        .map_err(ProxyError::VsockError)
+}
+
+fn connect_vsock_socket(addr: &SockAddr, transport: Transport) -> Result<Socket, ProxyError> {
+    let vsock_socket = new_vsock_raw_socket(transport)?;
     vsock_socket
         .connect(addr)
         .map_err(|e| SocketError::ConnectError {
@@ -132,30 +274,107 @@ fn new_vsock_socket(addr: &SockAddr) -> Result<Socket, ProxyError> {
             source: e,
         })
         .map_err(ProxyError::VsockError)?;
-    vsock_socket
-        .shutdown(std::net::Shutdown::Read)
-        .map_err(|e| SocketError::ShutdownError {
-            side: std::net::Shutdown::Read,
-            source: e,
-        })
-        .map_err(ProxyError::VsockError)?;
 
     Ok(vsock_socket)
 }
 
-pub fn new_vsock_socket_with_backoff(addr: &SockAddr) -> Socket {
-    run_with_backoff(new_vsock_socket, addr, 4)
+fn shutdown_socket(socket: &Socket, side: std::net::Shutdown) -> Result<(), ProxyError> {
+    socket
+        .shutdown(side)
+        .map_err(|e| SocketError::ShutdownError { side, source: e })
+        .map_err(ProxyError::VsockError)
 }
 
-fn new_vsock_server(addr: &SockAddr) -> Result<Socket, ProxyError> {
-    let vsock_socket = Socket::new(Domain::VSOCK, Type::STREAM, None)
-        .map_err(|e| SocketError::CreateError {
-            domain: Domain::VSOCK,
-            r#type: Type::STREAM,
-            protocol: None,
-            source: e,
-        })
-        .map_err(ProxyError::VsockError)?;
+fn set_read_timeout(socket: &Socket, timeout: Option<Duration>) -> Result<(), ProxyError> {
+    socket
+        .set_read_timeout(timeout)
+        .map_err(|e| SocketError::OptionError("SO_RCVTIMEO".to_owned(), e))
+        .map_err(ProxyError::VsockError)
+}
+
+// with a secure config the handshake comes first, the hello already goes
+// over the secure channel
+fn open_vsock_conn(
+    socket: Socket,
+    secure: Option<&SecureConfig>,
+    initiator: bool,
+) -> Result<VsockConn, ProxyError> {
+    let Some(config) = secure else {
+        return Ok(VsockConn::plain(socket));
+    };
+
+    set_read_timeout(&socket, Some(HELLO_TIMEOUT))?;
+    let channel = secure::handshake(&socket, config, initiator).map_err(ProxyError::VsockError)?;
+    set_read_timeout(&socket, None)?;
+
+    Ok(VsockConn::secure(socket, channel))
+}
+
+fn send_hello(conn: &mut VsockConn, hello: &Hello) -> Result<(), ProxyError> {
+    conn.write_frame(&hello.encode())
+        .map_err(ProxyError::VsockError)
+}
+
+fn recv_hello(conn: &mut VsockConn) -> Result<Hello, ProxyError> {
+    set_read_timeout(conn.socket(), Some(HELLO_TIMEOUT))?;
+    let mut buf = vec![0u8; MAX_FRAME_LEN];
+    let size = conn.read_frame(&mut buf).map_err(ProxyError::VsockError)?;
+    let hello = Hello::decode(&buf[..size]).map_err(ProxyError::VsockError)?;
+    set_read_timeout(conn.socket(), None)?;
+
+    Ok(hello)
+}
+
+fn new_vsock_socket(
+    params: (&SockAddr, Transport, &Hello, Option<&SecureConfig>),
+) -> Result<VsockConn, ProxyError> {
+    let (addr, transport, hello, secure) = params;
+    let mut conn = open_vsock_conn(connect_vsock_socket(addr, transport)?, secure, true)?;
+    shutdown_socket(conn.socket(), std::net::Shutdown::Read)?;
+
+    // the listening side waits for our link parameters first
+    send_hello(&mut conn, hello)?;
+
+    Ok(conn)
+}
+
+/// Connect to addr over transport and announce the link parameters in
+/// hello, over a secure channel with secure
+pub fn new_vsock_socket_with_backoff(
+    addr: &SockAddr,
+    transport: Transport,
+    hello: &Hello,
+    secure: Option<&SecureConfig>,
+) -> Result<VsockConn, ProxyError> {
+    run_with_backoff(new_vsock_socket, (addr, transport, hello, secure), 4)
+}
+
+fn new_vsock_socket_recv_hello(
+    params: (&SockAddr, Transport, Option<&SecureConfig>),
+) -> Result<(VsockConn, Hello), ProxyError> {
+    let (addr, transport, secure) = params;
+    let mut conn = open_vsock_conn(connect_vsock_socket(addr, transport)?, secure, true)?;
+
+    // the listening side announces the link parameters before going write-only
+    let hello = recv_hello(&mut conn)?;
+    shutdown_socket(conn.socket(), std::net::Shutdown::Read)?;
+
+    Ok((conn, hello))
+}
+
+/// Connect to addr over transport and wait for the peer's link
+/// parameters, over a secure channel with secure
+pub fn new_vsock_socket_recv_hello_with_backoff(
+    addr: &SockAddr,
+    transport: Transport,
+    secure: Option<&SecureConfig>,
+) -> Result<(VsockConn, Hello), ProxyError> {
+    run_with_backoff(new_vsock_socket_recv_hello, (addr, transport, secure), 4)
+}
+
+fn new_vsock_server(params: (&SockAddr, Transport)) -> Result<Socket, ProxyError> {
+    let (addr, transport) = params;
+    let vsock_socket = new_vsock_raw_socket(transport)?;
     vsock_socket
         .bind(addr)
         .map_err(|e| SocketError::BindError {
@@ -174,8 +393,12 @@ fn new_vsock_server(addr: &SockAddr) -> Result<Socket, ProxyError> {
     Ok(vsock_socket)
 }
 
-pub fn new_vsock_server_with_backoff(addr: &SockAddr) -> Socket {
-    run_with_backoff(new_vsock_server, addr, 64)
+/// Listen on addr over transport, accepted connections have the same transport
+pub fn new_vsock_server_with_backoff(
+    addr: &SockAddr,
+    transport: Transport,
+) -> Result<Socket, ProxyError> {
+    run_with_backoff(new_vsock_server, (addr, transport), 64)
 }
 
 fn accept_vsock_conn(params: (&SockAddr, &Socket)) -> Result<Socket, ProxyError> {
@@ -187,19 +410,69 @@ fn accept_vsock_conn(params: (&SockAddr, &Socket)) -> Result<Socket, ProxyError>
             source: e,
         })
         .map_err(ProxyError::VsockError)?;
-    conn_socket
-        .shutdown(std::net::Shutdown::Write)
-        .map_err(|e| SocketError::ShutdownError {
-            side: std::net::Shutdown::Write,
-            source: e,
-        })
-        .map_err(ProxyError::VsockError)?;
 
     Ok(conn_socket)
 }
 
-pub fn accept_vsock_conn_with_backoff(params: (&SockAddr, &Socket)) -> Socket {
-    run_with_backoff(accept_vsock_conn, params, 64)
+fn accept_vsock_conn_send_hello(
+    params: (&SockAddr, &Socket, &Hello, &Liveness, Option<&SecureConfig>),
+) -> Result<VsockConn, ProxyError> {
+    let (addr, vsock_socket, hello, liveness, secure) = params;
+    let mut conn = open_vsock_conn(accept_vsock_conn((addr, vsock_socket))?, secure, false)?;
+
+    // announce the link parameters before going read-only
+    send_hello(&mut conn, hello)?;
+    shutdown_socket(conn.socket(), std::net::Shutdown::Write)?;
+    liveness.set_read_timeout(conn.socket())?;
+
+    Ok(conn)
+}
+
+/// Accept a connection and announce the link parameters in hello,
+/// reads on the connection time out as per liveness, with secure the
+/// peer has to complete the handshake first
+pub fn accept_vsock_conn_with_backoff(
+    params: (&SockAddr, &Socket),
+    hello: &Hello,
+    liveness: &Liveness,
+    secure: Option<&SecureConfig>,
+) -> Result<VsockConn, ProxyError> {
+    let (addr, vsock_socket) = params;
+    run_with_backoff(
+        accept_vsock_conn_send_hello,
+        (addr, vsock_socket, hello, liveness, secure),
+        64,
+    )
+}
+
+fn accept_vsock_conn_recv_hello(
+    params: (&SockAddr, &Socket, &Liveness, Option<&SecureConfig>),
+) -> Result<(VsockConn, Hello), ProxyError> {
+    let (addr, vsock_socket, liveness, secure) = params;
+    let mut conn = open_vsock_conn(accept_vsock_conn((addr, vsock_socket))?, secure, false)?;
+    shutdown_socket(conn.socket(), std::net::Shutdown::Write)?;
+
+    // the connecting side sends the link parameters as its first frame
+    let hello = recv_hello(&mut conn)?;
+    liveness.set_read_timeout(conn.socket())?;
+
+    Ok((conn, hello))
+}
+
+/// Accept a connection and wait for the peer's link parameters,
+/// reads on the connection time out as per liveness, with secure the
+/// peer has to complete the handshake first
+pub fn accept_vsock_conn_recv_hello_with_backoff(
+    params: (&SockAddr, &Socket),
+    liveness: &Liveness,
+    secure: Option<&SecureConfig>,
+) -> Result<(VsockConn, Hello), ProxyError> {
+    let (addr, vsock_socket) = params;
+    run_with_backoff(
+        accept_vsock_conn_recv_hello,
+        (addr, vsock_socket, liveness, secure),
+        64,
+    )
 }
 
 fn new_ip_socket(device: &str) -> Result<Socket, ProxyError> {
@@ -234,10 +507,110 @@ fn new_ip_socket(device: &str) -> Result<Socket, ProxyError> {
     Ok(ip_socket)
 }
 
-pub fn new_ip_socket_with_backoff(device: &str) -> Socket {
+pub fn new_ip_socket_with_backoff(device: &str) -> Result<Socket, ProxyError> {
     run_with_backoff(new_ip_socket, device, 64)
 }
 
+/// Send each packet as a datagram to addr, batched with sendmmsg
+pub fn send_ip_batch(
+    socket: &Socket,
+    packets: &[&[u8]],
+    addr: &SockAddr,
+) -> Result<(), SocketError> {
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|packet| libc::iovec {
+            iov_base: packet.as_ptr() as *mut libc::c_void,
+            iov_len: packet.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .map(|iovec| {
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = addr.len();
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    // raw sockets send whole datagrams, a short count only means
+    // the rest still has to go
+    let mut sent = 0;
+    while sent < msgs.len() {
+        let count = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs[sent..].as_mut_ptr(),
+                (msgs.len() - sent) as libc::c_uint,
+                0,
+            )
+        };
+        if count < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(SocketError::WriteError(e));
+        }
+        sent += count as usize;
+    }
+
+    Ok(())
+}
+
+/// MTU of the interface, from sysfs
+pub fn get_interface_mtu(ifname: &str) -> anyhow::Result<usize> {
+    let path = format!("/sys/class/net/{ifname}/mtu");
+    std::fs::read_to_string(&path)
+        .with_context(|| format!("could not read {path}"))?
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid mtu in {path}"))
+}
+
+/// Find the first ethernet interface with an IPv4 address,
+/// returns its name and address in network byte order
+pub fn get_eth_interface() -> anyhow::Result<(String, u32)> {
+    let mut ifap: *mut ifaddrs = std::ptr::null_mut();
+    let res = unsafe { getifaddrs(&mut ifap) };
+
+    if res < 0 {
+        return Err(anyhow!("failed to query interfaces"));
+    }
+
+    let mut ifap_iter = ifap;
+    let mut ifname = "".to_owned();
+    let mut ifaddr = 0;
+    while !ifap_iter.is_null() {
+        let name = unsafe { CStr::from_ptr((*ifap_iter).ifa_name) };
+        if (unsafe { strncmp(name.as_ptr(), "eth".as_ptr().cast(), 3) } == 0
+            || unsafe { strncmp(name.as_ptr(), "ens".as_ptr().cast(), 3) } == 0
+            || unsafe { strncmp(name.as_ptr(), "enp".as_ptr().cast(), 3) } == 0)
+            && unsafe { (*(*ifap_iter).ifa_addr).sa_family == libc::AF_INET as u16 }
+        {
+            ifname = name.to_str().context("non utf8 interface")?.to_owned();
+            ifaddr = unsafe {
+                (*(*ifap_iter).ifa_addr.cast::<libc::sockaddr_in>())
+                    .sin_addr
+                    .s_addr
+            };
+            break;
+        }
+        ifap_iter = unsafe { (*ifap_iter).ifa_next };
+    }
+
+    unsafe { freeifaddrs(ifap) };
+
+    if ifname.is_empty() {
+        Err(anyhow!("no matching interface found"))
+    } else {
+        Ok((ifname, ifaddr))
+    }
+}
+
 #[derive(Clone)]
 pub struct VsockAddrParser {}
 
@@ -267,4 +640,4 @@ impl TypedValueParser for VsockAddrParser {
 
         Ok(SockAddr::vsock(cid, port))
     }
-}
\ No newline at end of file
+}
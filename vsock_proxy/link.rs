@@ -0,0 +1,560 @@
+// Sending half of a vsock link
+//
+// Frames are written from the data path, heartbeats from a helper
+// thread, so all writes go through LinkWriter which keeps whole frames
+// from interleaving. A failed write marks the link broken and wakes a
+// reconnect thread, frames written meanwhile go to the replay buffer,
+// so the data path keeps draining nfqueue instead of sleeping in the
+// reconnect backoff. Once the new connection is up the buffered frames
+// are replayed in order before any new frame. A shutdown stops the
+// reconnect, what is still buffered then shows up in pending().
+//
+// Frames go out in batches with writev, a batch that fails half way is
+// buffered from the first frame not written completely.
+//
+// With sequence numbers frames are numbered under the lock before they
+// are written or buffered, so numbers follow the write order and a
+// replayed frame keeps its number.
+//
+// A single connection caps throughput, with --streams the transport is
+// striped over several links, each its own LinkWriter with connection,
+// replay buffer and heartbeats. StripedWriter hashes packets onto them
+// by flow, packets of a flow always take the same link and stay in
+// order, the receiver handles each link in its own thread. Heartbeats
+// are sent by each LinkWriter on its own link, other control frames
+// take the first link.
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::conn::VsockConn;
+use crate::frame::{is_control_frame, parse_gso_frame};
+use crate::health::Health;
+use crate::heartbeat::Liveness;
+use crate::packet::flow_hash;
+use crate::replay::{ReplayBuffer, ReplayConfig};
+use crate::sequence::Sequencer;
+use crate::{ProxyError, SocketError, MAX_BATCH};
+
+struct Link {
+    conn: VsockConn,
+    broken: bool,
+    pending: ReplayBuffer,
+    sequencer: Option<Sequencer>,
+}
+
+pub struct LinkWriter {
+    link: Mutex<Link>,
+    broken: Condvar,
+    connect: Box<dyn Fn() -> Result<VsockConn, ProxyError> + Send + Sync>,
+    liveness: Liveness,
+    health: Health,
+}
+
+impl LinkWriter {
+    /// Wrap an already connected link, connect is used to replace it once broken
+    /// until it fails, with sequence frames are sent as sequenced frames
+    pub fn new<F: Fn() -> Result<VsockConn, ProxyError> + Send + Sync + 'static>(
+        conn: VsockConn,
+        connect: F,
+        liveness: Liveness,
+        replay: ReplayConfig,
+        health: Health,
+        sequence: bool,
+    ) -> Arc<Self> {
+        if let Err(err) = liveness.set_write_timeout(conn.socket()) {
+            println!("{:?}", anyhow::Error::from(err));
+        }
+        health.set_vsock_connected(true);
+
+        let writer = Arc::new(LinkWriter {
+            link: Mutex::new(Link {
+                conn,
+                broken: false,
+                pending: ReplayBuffer::new(replay, &health),
+                sequencer: sequence.then(Sequencer::new),
+            }),
+            broken: Condvar::new(),
+            connect: Box::new(connect),
+            liveness,
+            health,
+        });
+
+        let reconnector = writer.clone();
+        std::thread::spawn(move || reconnector.run_reconnect());
+
+        writer
+    }
+
+    pub fn liveness(&self) -> &Liveness {
+        &self.liveness
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Link> {
+        // a panic while holding the lock aborts the process anyway
+        self.link.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn mark_broken(&self, link: &mut Link, err: SocketError) {
+        println!("{:?}", anyhow::Error::from(ProxyError::VsockError(err)));
+        link.broken = true;
+        self.health.set_vsock_connected(false);
+        self.broken.notify_one();
+    }
+
+    /// Write a whole frame, or buffer it for replay while the link is broken
+    pub fn write_frame(&self, buf: &[u8]) -> Result<(), ProxyError> {
+        self.write_frames(&[buf])
+    }
+
+    /// Write whole frames in order, or buffer them for replay while the link is broken
+    pub fn write_frames(&self, frames: &[&[u8]]) -> Result<(), ProxyError> {
+        let mut link = self.lock();
+
+        let sequenced: Option<Vec<Vec<u8>>> = link
+            .sequencer
+            .as_mut()
+            .map(|sequencer| frames.iter().map(|frame| sequencer.wrap(frame)).collect());
+        let sequenced_frames: Vec<&[u8]>;
+        let frames = match &sequenced {
+            Some(sequenced) => {
+                sequenced_frames = sequenced.iter().map(Vec::as_slice).collect();
+                &sequenced_frames[..]
+            }
+            None => frames,
+        };
+
+        if link.broken {
+            for frame in frames {
+                link.pending.push(frame);
+            }
+            return Ok(());
+        }
+
+        match link.conn.write_frames(frames) {
+            Ok(()) => self.health.touch_vsock(),
+            Err((written, err)) => {
+                // the first frame left may be partially written, it is replayed
+                // whole on the next connection which starts a new stream
+                self.mark_broken(&mut link, err);
+                for frame in &frames[written..] {
+                    link.pending.push(frame);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a frame that is useless once stale, it is never buffered,
+    /// returns false if the link is down
+    pub fn write_unbuffered(&self, buf: &[u8]) -> bool {
+        let mut link = self.lock();
+        if link.broken {
+            return false;
+        }
+
+        match link.conn.write_frame(buf) {
+            Ok(()) => {
+                self.health.touch_vsock();
+                true
+            }
+            Err(err) => {
+                self.mark_broken(&mut link, err);
+                false
+            }
+        }
+    }
+
+    /// Number of frames waiting for the link to come back
+    pub fn pending(&self) -> usize {
+        self.lock().pending.len()
+    }
+
+    pub fn shutdown(&self, side: std::net::Shutdown) -> Result<(), ProxyError> {
+        self.lock()
+            .conn
+            .socket()
+            .shutdown(side)
+            .map_err(|e| SocketError::ShutdownError { side, source: e })
+            .map_err(ProxyError::VsockError)
+    }
+
+    fn run_reconnect(&self) {
+        loop {
+            {
+                let mut link = self.lock();
+                while !link.broken {
+                    link = self.broken.wait(link).unwrap_or_else(|e| e.into_inner());
+                }
+            }
+
+            // connect without holding the lock, the data path keeps buffering,
+            // it only fails on shutdown and main counts what is left as lost
+            let Ok(conn) = (self.connect)() else {
+                return;
+            };
+            if let Err(err) = self.liveness.set_write_timeout(conn.socket()) {
+                println!("{:?}", anyhow::Error::from(err));
+            }
+
+            let mut guard = self.lock();
+            let link = &mut *guard;
+            link.conn = conn;
+
+            // replay in order, anything new waits behind the lock
+            let mut replay_error = None;
+            while !link.pending.is_empty() {
+                let frames: Vec<&[u8]> = link.pending.iter().take(MAX_BATCH).collect();
+                let written = match link.conn.write_frames(&frames) {
+                    Ok(()) => frames.len(),
+                    Err((written, err)) => {
+                        replay_error = Some(err);
+                        written
+                    }
+                };
+                for _ in 0..written {
+                    link.pending.pop();
+                }
+                if replay_error.is_some() {
+                    break;
+                }
+            }
+
+            match replay_error {
+                Some(err) => {
+                    // still broken, keep what was not replayed and try again
+                    println!("{:?}", anyhow::Error::from(ProxyError::VsockError(err)));
+                }
+                None => {
+                    link.broken = false;
+                    self.health.set_vsock_connected(true);
+                }
+            }
+        }
+    }
+}
+
+/// Sending halves of the links of a striped transport
+pub struct StripedWriter {
+    links: Vec<Arc<LinkWriter>>,
+}
+
+impl StripedWriter {
+    pub fn new(links: Vec<Arc<LinkWriter>>) -> Self {
+        assert!(!links.is_empty(), "striped transport without links");
+        StripedWriter { links }
+    }
+
+    pub fn links(&self) -> &[Arc<LinkWriter>] {
+        &self.links
+    }
+
+    // link of the packet in frame, GSO frames go by the packet they carry,
+    // other control frames have no flow and take the first link
+    fn link_for(&self, frame: &[u8]) -> usize {
+        let packet = match parse_gso_frame(frame) {
+            Some((_, packet)) => packet,
+            None if is_control_frame(frame) => return 0,
+            None => frame,
+        };
+        (flow_hash(packet) % self.links.len() as u64) as usize
+    }
+
+    /// Write frames to the links of their flows, in order per link
+    pub fn write_frames(&self, frames: &[&[u8]]) -> Result<(), ProxyError> {
+        if let [link] = &self.links[..] {
+            return link.write_frames(frames);
+        }
+
+        let mut striped = vec![Vec::new(); self.links.len()];
+        for frame in frames {
+            striped[self.link_for(frame)].push(*frame);
+        }
+        for (link, frames) in self.links.iter().zip(&striped) {
+            if !frames.is_empty() {
+                link.write_frames(frames)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Number of frames waiting for their links to come back
+    pub fn pending(&self) -> usize {
+        self.links.iter().map(|link| link.pending()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use socket2::{Domain, Socket, Type};
+
+    use super::*;
+    use crate::frame::{dns_frame, gso_frame, heartbeat_frame, FrameReader};
+    use crate::heartbeat::spawn_heartbeat;
+    use crate::replay::ReplayPolicy;
+
+    fn pair() -> (VsockConn, VsockConn) {
+        let (a, b) = Socket::pair(Domain::UNIX, Type::STREAM, None).unwrap();
+        (VsockConn::plain(a), VsockConn::plain(b))
+    }
+
+    // IPv4 packet of size bytes tagged with id
+    fn packet(id: u8, size: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; size];
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+        buf[size - 1] = id;
+        buf
+    }
+
+    // ids of the whole frames read until count or the end of the link
+    fn read_ids(rx: &mut VsockConn, count: usize) -> Vec<u8> {
+        let mut reader = FrameReader::new();
+        let mut ids = Vec::new();
+        while ids.len() < count {
+            let Ok(frames) = rx.read_frames(&mut reader) else {
+                break;
+            };
+            ids.extend(frames.map(|frame| frame[frame.len() - 1]));
+        }
+        ids
+    }
+
+    // TCP packet of flow port tagged with id
+    fn flow_packet(id: u8, port: u16) -> Vec<u8> {
+        let mut buf = packet(id, 60);
+        buf[9] = 6;
+        buf[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        buf[16..20].copy_from_slice(&[203, 0, 113, 7]);
+        buf[20..22].copy_from_slice(&port.to_be_bytes());
+        buf[22..24].copy_from_slice(&443u16.to_be_bytes());
+        buf
+    }
+
+    // striped writer over count links and the receiving ends
+    fn striped(count: usize, liveness: Liveness) -> (StripedWriter, Vec<VsockConn>) {
+        let (links, receivers) = (0..count)
+            .map(|_| {
+                let (conn, rx) = pair();
+                (writer(conn, liveness, &Health::new()).0, rx)
+            })
+            .unzip();
+        (StripedWriter::new(links), receivers)
+    }
+
+    // link writer on conn, connect hands out the connections sent on the
+    // channel and fails like on shutdown once it is dropped
+    fn writer(
+        conn: VsockConn,
+        liveness: Liveness,
+        health: &Health,
+    ) -> (Arc<LinkWriter>, mpsc::Sender<VsockConn>) {
+        let (conn_tx, conn_rx) = mpsc::channel();
+        let conn_rx = Mutex::new(conn_rx);
+        let replay = ReplayConfig {
+            capacity: 1 << 20,
+            policy: ReplayPolicy::DropOldest,
+        };
+        let writer = LinkWriter::new(
+            conn,
+            move || {
+                conn_rx
+                    .lock()
+                    .unwrap()
+                    .recv()
+                    .map_err(|_| ProxyError::Shutdown)
+            },
+            liveness,
+            replay,
+            health.clone(),
+            false,
+        );
+        (writer, conn_tx)
+    }
+
+    #[test]
+    fn replays_after_reconnect() {
+        let (conn, mut rx) = pair();
+        let health = Health::new();
+        let (writer, conn_tx) = writer(conn, Liveness::from_secs(0, 0), &health);
+
+        writer.write_frame(&packet(0, 60)).unwrap();
+        assert_eq!(read_ids(&mut rx, 1), [0]);
+
+        // the peer is gone, the failed frame and the ones after it are kept
+        drop(rx);
+        writer
+            .write_frames(&[&packet(1, 60), &packet(2, 60)])
+            .unwrap();
+        writer.write_frame(&packet(3, 60)).unwrap();
+        assert_eq!(writer.pending(), 3);
+        assert!(!health.vsock_connected());
+
+        // and go out first, in order, once the link is back
+        let (conn, mut rx) = pair();
+        conn_tx.send(conn).unwrap();
+        while !health.vsock_connected() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        writer.write_frame(&packet(4, 60)).unwrap();
+        assert_eq!(read_ids(&mut rx, 4), [1, 2, 3, 4]);
+
+        assert_eq!(writer.pending(), 0);
+        assert!(health.vsock_connected());
+        assert_eq!(health.counter("replay_replayed").get(), 3);
+    }
+
+    #[test]
+    fn resumes_batch_at_first_partial_frame() {
+        let (conn, mut rx) = pair();
+        conn.socket().set_send_buffer_size(4096).unwrap();
+        let health = Health::new();
+        // the peer does not read, the write times out part way
+        let liveness = Liveness {
+            heartbeat_interval: Duration::ZERO,
+            peer_timeout: Duration::from_millis(100),
+        };
+        let (writer, conn_tx) = writer(conn, liveness, &health);
+
+        let packets: Vec<Vec<u8>> = (0..64).map(|id| packet(id, 1000)).collect();
+        let frames: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+        writer.write_frames(&frames).unwrap();
+        assert!(!health.vsock_connected());
+        let pending = writer.pending();
+        assert!(pending > 0 && pending < frames.len());
+
+        // the new link starts with the frame that was cut, whole, and the
+        // old one ends with the last frame written completely
+        let (conn, mut new_rx) = pair();
+        conn_tx.send(conn).unwrap();
+        let new_ids = read_ids(&mut new_rx, pending);
+        let old_ids = read_ids(&mut rx, frames.len());
+        assert_eq!(old_ids.len() + new_ids.len(), frames.len());
+        assert_eq!(
+            [old_ids, new_ids].concat(),
+            (0..frames.len() as u8).collect::<Vec<_>>()
+        );
+        assert_eq!(writer.pending(), 0);
+    }
+
+    #[test]
+    fn shutdown_during_reconnect_keeps_pending() {
+        let (conn, rx) = pair();
+        let health = Health::new();
+        let (writer, conn_tx) = writer(conn, Liveness::from_secs(0, 0), &health);
+
+        // the reconnect is cancelled by a shutdown
+        drop(conn_tx);
+        drop(rx);
+        writer
+            .write_frames(&[&packet(0, 60), &packet(1, 60)])
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        // what was buffered stays for main to count as lost
+        writer.write_frame(&packet(2, 60)).unwrap();
+        assert_eq!(writer.pending(), 3);
+        assert!(!health.vsock_connected());
+    }
+
+    #[test]
+    fn stripes_by_flow() {
+        let (striped, mut receivers) = striped(4, Liveness::from_secs(0, 0));
+
+        // two batches with two packets of each of 16 flows
+        let mut expected = vec![Vec::new(); 4];
+        for batch in 0..2u8 {
+            let packets: Vec<Vec<u8>> = (0..32u8)
+                .map(|id| flow_packet(batch * 32 + id, 40000 + u16::from(id / 2)))
+                .collect();
+            for packet in &packets {
+                expected[striped.link_for(packet)].push(packet[59]);
+            }
+            let frames: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+            striped.write_frames(&frames).unwrap();
+        }
+        assert!(expected.iter().filter(|ids| !ids.is_empty()).count() > 1);
+
+        // every packet of a flow went to the same link, in order
+        for (rx, ids) in receivers.iter_mut().zip(&expected) {
+            assert_eq!(&read_ids(rx, ids.len()), ids);
+        }
+        for port in 40000..40016 {
+            let links: Vec<usize> = expected
+                .iter()
+                .enumerate()
+                .filter(|(_, ids)| ids.iter().any(|id| 40000 + u16::from(id % 32 / 2) == port))
+                .map(|(link, _)| link)
+                .collect();
+            assert_eq!(links.len(), 1);
+        }
+    }
+
+    #[test]
+    fn stripes_gso_by_packet() {
+        let (striped, mut receivers) = striped(4, Liveness::from_secs(0, 0));
+
+        for port in 40000..40016 {
+            let packet = flow_packet(port as u8, port);
+            let frame = gso_frame(1400, &packet);
+            let link = striped.link_for(&packet);
+            assert_eq!(striped.link_for(&frame), link);
+
+            striped.write_frames(&[&frame, &packet]).unwrap();
+            assert_eq!(read_ids(&mut receivers[link], 2), [port as u8; 2]);
+        }
+    }
+
+    #[test]
+    fn control_frames_take_first_link() {
+        let (striped, mut receivers) = striped(4, Liveness::from_secs(0, 0));
+
+        // a heartbeat or DNS frame has no flow to hash
+        let heartbeat = heartbeat_frame();
+        let dns = dns_frame(7, &[0; 12]).unwrap();
+        assert_eq!(striped.link_for(&heartbeat), 0);
+        assert_eq!(striped.link_for(&dns), 0);
+
+        striped.write_frames(&[&heartbeat, &dns]).unwrap();
+        let mut reader = FrameReader::new();
+        let frames: Vec<Vec<u8>> = receivers[0]
+            .read_frames(&mut reader)
+            .unwrap()
+            .map(<[u8]>::to_vec)
+            .collect();
+        assert_eq!(frames, [heartbeat.to_vec(), dns]);
+    }
+
+    #[test]
+    fn heartbeats_per_link() {
+        let liveness = Liveness {
+            heartbeat_interval: Duration::from_millis(20),
+            peer_timeout: Duration::ZERO,
+        };
+        let (striped, mut receivers) = striped(4, liveness);
+
+        // every link is kept alive by its own writer, not by striping
+        for link in striped.links() {
+            spawn_heartbeat(link.clone()).unwrap();
+        }
+        let heartbeat = heartbeat_frame();
+        for rx in &mut receivers {
+            let mut reader = FrameReader::new();
+            let frame: Vec<u8> = rx
+                .read_frames(&mut reader)
+                .unwrap()
+                .next()
+                .unwrap()
+                .to_vec();
+            assert_eq!(frame, heartbeat);
+        }
+    }
+}
@@ -0,0 +1,812 @@
+// Packet inspection and rewriting shared by the proxies
+//
+// The NAT on the enclave side rewrites the source address of outgoing
+// packets and fixes up the checksums, the parent side drops packets to
+// reserved addresses. Both live here so that they can be benchmarked
+// on their own, see benches/packet.rs.
+
+use std::net::Ipv4Addr;
+use std::ops::Range;
+
+use byteorder::{BigEndian, ByteOrder};
+use clap::ValueEnum;
+
+pub const TCP: u8 = 6;
+pub const UDP: u8 = 17;
+
+// Helper function to calculate the checksum for an IP header
+pub fn checksum_ip4(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+
+    // Process each 16-bit word (2 bytes)
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            BigEndian::read_u16(chunk)
+        } else {
+            (chunk[0] as u16) << 8
+        };
+        sum = sum.wrapping_add(word as u32);
+    }
+
+    // Add carry if any
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    // Return the one's complement of the sum
+    !(sum as u16)
+}
+
+pub fn get_ihl(buf: &[u8]) -> u8 {
+    (buf[0] & 0x0F) * 4 // 32-words -> bytes
+}
+
+pub fn get_proto(buf: &[u8]) -> u8 {
+    buf[9]
+}
+
+/// Offset of a fragment in its datagram in bytes and whether more fragments follow
+pub fn get_fragment(buf: &[u8]) -> (usize, bool) {
+    const MORE_FRAGMENTS: u16 = 0x2000;
+
+    let field = BigEndian::read_u16(&buf[6..8]);
+    (usize::from(field & 0x1FFF) * 8, field & MORE_FRAGMENTS != 0)
+}
+
+/// Whether a packet is a fragment of a larger datagram
+pub fn is_fragment(buf: &[u8]) -> bool {
+    get_fragment(buf) != (0, false)
+}
+
+/// Source and destination port of a TCP or UDP packet, None for other
+/// protocols, later fragments and packets cut short
+pub fn get_ports(buf: &[u8]) -> Option<(u16, u16)> {
+    if buf.len() < 20 || get_fragment(buf).0 > 0 || !matches!(get_proto(buf), TCP | UDP) {
+        return None;
+    }
+    let ip_header_length = get_ihl(buf) as usize;
+    let ports = buf.get(ip_header_length..ip_header_length + 4)?;
+    Some((
+        BigEndian::read_u16(&ports[0..2]),
+        BigEndian::read_u16(&ports[2..4]),
+    ))
+}
+
+/// Hash of the flow of an IPv4 packet, addresses, protocol and for TCP
+/// and UDP the ports. Fragments hash without ports, only the first one
+/// has them, so all fragments of a datagram hash the same.
+pub fn flow_hash(buf: &[u8]) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    if buf.len() < 20 {
+        return 0;
+    }
+    let ip_header_length = get_ihl(buf) as usize;
+    let proto = get_proto(buf);
+    let ports = match proto {
+        TCP | UDP if !is_fragment(buf) && buf.len() >= ip_header_length + 4 => {
+            &buf[ip_header_length..ip_header_length + 4]
+        }
+        _ => &[],
+    };
+
+    // FNV-1a, cheap and stable across processes
+    [&buf[12..20], &[proto][..], ports]
+        .concat()
+        .iter()
+        .fold(FNV_OFFSET, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+        })
+}
+
+/// Length of the IP and TCP headers of a TCP packet, None if it is
+/// anything else, a later fragment without TCP header, or cut short
+pub fn tcp_headers_len(buf: &[u8]) -> Option<usize> {
+    const MIN_IP_HEADER_LEN: usize = 20;
+    const MIN_TCP_HEADER_LEN: usize = 20;
+
+    if buf.len() < MIN_IP_HEADER_LEN || get_proto(buf) != TCP || get_fragment(buf).0 != 0 {
+        return None;
+    }
+    let ip_header_length = get_ihl(buf) as usize;
+    if ip_header_length < MIN_IP_HEADER_LEN || buf.len() < ip_header_length + MIN_TCP_HEADER_LEN {
+        return None;
+    }
+    let tcp_header_length = usize::from(buf[ip_header_length + 12] >> 4) * 4;
+    if tcp_header_length < MIN_TCP_HEADER_LEN || buf.len() < ip_header_length + tcp_header_length {
+        return None;
+    }
+
+    Some(ip_header_length + tcp_header_length)
+}
+
+pub fn checksum_tcp4(tcp_segment: &[u8], src_ip: Ipv4Addr, dst_ip: Ipv4Addr) -> u16 {
+    checksum_transport4(TCP, tcp_segment, src_ip, dst_ip)
+}
+
+// sum of the pseudo-header, not folded
+fn pseudo_header_sum(proto: u8, len: usize, src_ip: Ipv4Addr, dst_ip: Ipv4Addr) -> u32 {
+    let mut sum: u32 = 0;
+
+    for b in src_ip
+        .octets()
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+    {
+        sum += b as u32;
+    }
+    for b in dst_ip
+        .octets()
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+    {
+        sum += b as u32;
+    }
+
+    sum += u32::from(proto);
+    sum += (len as u32) & 0xFFFF;
+
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+// TCP and UDP checksums only differ in the protocol of the pseudo-header
+fn checksum_transport4(proto: u8, segment: &[u8], src_ip: Ipv4Addr, dst_ip: Ipv4Addr) -> u16 {
+    let mut sum = pseudo_header_sum(proto, segment.len(), src_ip, dst_ip);
+
+    // header + data
+    for chunk in segment.chunks(2) {
+        let word = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += word as u32;
+    }
+
+    !fold(sum)
+}
+
+// offset of the TCP or UDP checksum in the transport header
+fn transport_checksum_offset(proto: u8) -> Option<usize> {
+    match proto {
+        TCP => Some(16),
+        UDP => Some(6),
+        _ => None,
+    }
+}
+
+/// Leave the TCP or UDP checksum to offload, the field gets the
+/// pseudo-header sum like with CHECKSUM_PARTIAL. Returns the offset of
+/// the field in the transport header, None for other protocols.
+pub fn set_partial_checksum(buf: &mut [u8]) -> Option<usize> {
+    let ip_header_length = get_ihl(buf) as usize;
+    let checksum_offset = transport_checksum_offset(get_proto(buf))?;
+    let offset = ip_header_length + checksum_offset;
+    if ip_header_length < 20 || offset + 2 > buf.len() {
+        return None;
+    }
+
+    let src_ip = Ipv4Addr::from(BigEndian::read_u32(&buf[12..16]));
+    let dst_ip = Ipv4Addr::from(BigEndian::read_u32(&buf[16..20]));
+    let sum = pseudo_header_sum(get_proto(buf), buf.len() - ip_header_length, src_ip, dst_ip);
+    buf[offset..offset + 2].copy_from_slice(&fold(sum).to_be_bytes());
+
+    Some(checksum_offset)
+}
+
+/// Compute the TCP or UDP checksum of a packet that left it to offload
+/// (CHECKSUM_PARTIAL), the field only holds the pseudo-header sum then.
+/// The IP header checksum is never left to offload.
+pub fn complete_checksum(buf: &mut [u8]) {
+    const MIN_IP_HEADER_LEN: usize = 20;
+
+    let ip_header_length = get_ihl(buf) as usize;
+    let proto = get_proto(buf);
+    let Some(checksum_offset) = transport_checksum_offset(proto) else {
+        return;
+    };
+    let offset = ip_header_length + checksum_offset;
+    if ip_header_length < MIN_IP_HEADER_LEN || offset + 2 > buf.len() {
+        println!("invalid packet len {:?}", buf.len());
+        return;
+    }
+
+    let src_ip = Ipv4Addr::from(BigEndian::read_u32(&buf[12..16]));
+    let dst_ip = Ipv4Addr::from(BigEndian::read_u32(&buf[16..20]));
+    buf[offset..offset + 2].copy_from_slice(&[0, 0]);
+    let mut checksum = checksum_transport4(proto, &buf[ip_header_length..], src_ip, dst_ip);
+    // zero means no checksum for UDP
+    if proto == UDP && checksum == 0 {
+        checksum = 0xFFFF;
+    }
+    buf[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// How modify_packet fixes up the checksums of a rewritten packet
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ChecksumMode {
+    /// adjust the checksums for the rewritten fields only, RFC 1624
+    #[default]
+    Incremental,
+    /// verify the checksums first and recompute them in full if they are wrong
+    Verify,
+    /// recompute the checksums over the whole packet
+    Full,
+}
+
+/// Adjust checksum for the bytes at old becoming new, RFC 1624 eqn. 3,
+/// both must be the same whole number of 16 bit words
+pub fn checksum_adjust(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    // HC' = ~(~HC + ~m + m')
+    let mut sum = u32::from(!checksum);
+    for (old, new) in old.chunks(2).zip(new.chunks(2)) {
+        sum += u32::from(!u16::from_be_bytes([old[0], old[1]]));
+        sum += u32::from(u16::from_be_bytes([new[0], new[1]]));
+    }
+
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Whether the IP header checksum, and the TCP checksum of whole TCP packets, are correct,
+/// the TCP checksum of fragments covers the whole datagram and is not checked
+pub fn verify_checksums(buf: &[u8]) -> bool {
+    let ip_header_length = get_ihl(buf) as usize;
+    if checksum_ip4(&buf[..ip_header_length]) != 0 {
+        return false;
+    }
+    if get_proto(buf) != TCP || is_fragment(buf) {
+        return true;
+    }
+
+    let src_ip = Ipv4Addr::from(BigEndian::read_u32(&buf[12..16]));
+    let dst_ip = Ipv4Addr::from(BigEndian::read_u32(&buf[16..20]));
+    checksum_tcp4(&buf[ip_header_length..], src_ip, dst_ip) == 0
+}
+
+/// Modify source IP and decrement TTL, then fix up the checksums
+///
+/// Only the source address and TTL change, so by default the checksums
+/// are adjusted for those few words instead of summing the whole packet
+/// again. An adjusted checksum is only as right as the original one,
+/// ChecksumMode::Verify recomputes wrong ones in full.
+///
+/// The TCP checksum of a fragmented datagram is in its first fragment
+/// and covers the whole datagram, it is always adjusted there and later
+/// fragments only carry data.
+pub fn modify_packet(buf: &mut [u8], src_ip: Ipv4Addr, dst_ip: Ipv4Addr, mode: ChecksumMode) {
+    const SRC_IP_OFFSET: usize = 12;
+    const TTL_OFFSET: usize = 8;
+    const IP_CHECKSUM_OFFSET: usize = 10;
+    // excluding IP header
+    const TCP_CHECKSUM_OFFSET: usize = 16;
+    const MIN_IP_HEADER_LEN: usize = 20;
+    const MIN_TCP_HEADER_LEN: usize = 20;
+
+    let ip_header_length = get_ihl(buf) as usize;
+    if ip_header_length > buf.len() || ip_header_length < MIN_IP_HEADER_LEN {
+        println!("invalid IP packet len {:?}", ip_header_length);
+        return;
+    }
+
+    let (fragment_offset, _) = get_fragment(buf);
+    let is_tcp = get_proto(buf) == TCP && fragment_offset == 0;
+    if is_tcp && (ip_header_length + MIN_TCP_HEADER_LEN) > buf.len() {
+        println!("invalid TCP packet len {:?}", buf.len());
+        return;
+    }
+
+    let full = match mode {
+        ChecksumMode::Incremental => false,
+        ChecksumMode::Verify => !verify_checksums(buf),
+        ChecksumMode::Full => true,
+    };
+
+    // words covered by the checksums before the rewrite
+    let old_src_ip: [u8; 4] = buf[SRC_IP_OFFSET..SRC_IP_OFFSET + 4].try_into().unwrap();
+    let old_ttl_proto: [u8; 2] = buf[TTL_OFFSET..TTL_OFFSET + 2].try_into().unwrap();
+    let new_ip_bytes = src_ip.octets();
+
+    // TCP update checksum, the source address is part of the pseudo-header
+    if is_tcp {
+        let offset = ip_header_length + TCP_CHECKSUM_OFFSET;
+
+        let tcp_checksum_val = if full && !is_fragment(buf) {
+            // Zero TCP checksum before recalculating
+            buf[offset..offset + 2].copy_from_slice(&[0, 0]);
+            checksum_tcp4(&buf[ip_header_length..], src_ip, dst_ip)
+        } else {
+            let old_checksum = BigEndian::read_u16(&buf[offset..offset + 2]);
+            checksum_adjust(old_checksum, &old_src_ip, &new_ip_bytes)
+        };
+
+        // Write new checksum into header
+        buf[offset] = (tcp_checksum_val >> 8) as u8;
+        buf[offset + 1] = (tcp_checksum_val & 0xFF) as u8;
+    }
+
+    // now update IP packet
+
+    // Decrement TTL safely
+    if buf[TTL_OFFSET] > 1 {
+        buf[TTL_OFFSET] -= 1;
+    } else {
+        buf[TTL_OFFSET] = 1; // Prevent underflow, TTL shouldn't be <= 0
+    }
+
+    // Change source IP
+    buf[SRC_IP_OFFSET..SRC_IP_OFFSET + 4].copy_from_slice(&new_ip_bytes);
+
+    let checksum_val = if full {
+        // Zero IP checksum before recalculating
+        buf[IP_CHECKSUM_OFFSET..IP_CHECKSUM_OFFSET + 2].copy_from_slice(&[0, 0]);
+
+        // Recalculate checksum over the IP header
+        checksum_ip4(&buf[..ip_header_length])
+    } else {
+        let old_checksum = BigEndian::read_u16(&buf[IP_CHECKSUM_OFFSET..IP_CHECKSUM_OFFSET + 2]);
+        let checksum = checksum_adjust(
+            old_checksum,
+            &old_ttl_proto,
+            &buf[TTL_OFFSET..TTL_OFFSET + 2],
+        );
+        checksum_adjust(checksum, &old_src_ip, &new_ip_bytes)
+    };
+
+    // Write new checksum into header
+    buf[IP_CHECKSUM_OFFSET] = (checksum_val >> 8) as u8;
+    buf[IP_CHECKSUM_OFFSET + 1] = (checksum_val & 0xFF) as u8;
+}
+
+/// Lower the MSS option of a TCP SYN or SYN-ACK to at most mss and adjust
+/// the TCP checksum for it. Returns whether the packet was changed.
+pub fn clamp_mss(buf: &mut [u8], mss: u16) -> bool {
+    const SYN: u8 = 0x02;
+    const TCP_CHECKSUM_OFFSET: usize = 16;
+    const MIN_TCP_HEADER_LEN: usize = 20;
+    const OPTION_END: u8 = 0;
+    const OPTION_NOP: u8 = 1;
+    const OPTION_MSS: u8 = 2;
+
+    let Some(headers_length) = tcp_headers_len(buf) else {
+        return false;
+    };
+    let ip_header_length = get_ihl(buf) as usize;
+    if buf[ip_header_length + 13] & SYN == 0 {
+        return false;
+    }
+
+    // find the MSS option value
+    let mut offset = ip_header_length + MIN_TCP_HEADER_LEN;
+    let value_offset = loop {
+        match buf.get(offset..headers_length) {
+            Some([OPTION_END, ..]) | Some([]) | None => return false,
+            Some([OPTION_NOP, ..]) => offset += 1,
+            Some([OPTION_MSS, 4, _, _, ..]) => break offset + 2,
+            Some([_, length, ..]) if *length >= 2 => offset += usize::from(*length),
+            Some(_) => return false,
+        }
+    };
+    if BigEndian::read_u16(&buf[value_offset..value_offset + 2]) <= mss {
+        return false;
+    }
+
+    // options are not word aligned, adjust for the words around the value,
+    // the TCP header itself starts on a word
+    let words = value_offset & !1..(value_offset + 3) & !1;
+    let old = buf[words.clone()].to_vec();
+    buf[value_offset..value_offset + 2].copy_from_slice(&mss.to_be_bytes());
+
+    let offset = ip_header_length + TCP_CHECKSUM_OFFSET;
+    let checksum = BigEndian::read_u16(&buf[offset..offset + 2]);
+    let checksum = checksum_adjust(checksum, &old, &buf[words]);
+    buf[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+
+    true
+}
+
+/// Splits TCP GSO packets into segments, in a buffer reused across batches
+#[derive(Default)]
+pub struct Segmenter {
+    buf: Vec<u8>,
+    segments: Vec<Range<usize>>,
+}
+
+impl Segmenter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drop all segments, before starting on the next batch
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.segments.clear();
+    }
+
+    /// Split packet into segments of at most mss payload bytes (0 for no
+    /// limit) that fit mtu, with complete checksums. Returns the indices
+    /// of the segments, None if packet is not a whole IPv4 TCP packet.
+    pub fn segment(&mut self, packet: &[u8], mss: usize, mtu: usize) -> Option<Range<usize>> {
+        const FIN: u8 = 0x01;
+        const PSH: u8 = 0x08;
+        const CWR: u8 = 0x80;
+
+        // GSO packets beyond 64k have a zero total length
+        let packet = match packet.get(2..4).map(BigEndian::read_u16) {
+            Some(0) => packet,
+            Some(total_length) => &packet[..usize::from(total_length).min(packet.len())],
+            None => return None,
+        };
+        let header_length = tcp_headers_len(packet)?;
+        let ip_header_length = get_ihl(packet) as usize;
+        // fragments have to be reassembled first
+        if is_fragment(packet) {
+            return None;
+        }
+
+        let mss = match mss {
+            0 => usize::MAX,
+            mss => mss,
+        }
+        .min(mtu.saturating_sub(header_length));
+        if mss == 0 {
+            return None;
+        }
+
+        let (header, payload) = packet.split_at(header_length);
+        let src_ip = Ipv4Addr::from(BigEndian::read_u32(&header[12..16]));
+        let dst_ip = Ipv4Addr::from(BigEndian::read_u32(&header[16..20]));
+        let id = BigEndian::read_u16(&header[4..6]);
+        let seq = BigEndian::read_u32(&header[ip_header_length + 4..ip_header_length + 8]);
+        let flags = header[ip_header_length + 13];
+        let count = payload.len().div_ceil(mss).max(1);
+
+        let first = self.segments.len();
+        for index in 0..count {
+            let chunk =
+                &payload[(index * mss).min(payload.len())..((index + 1) * mss).min(payload.len())];
+            let start = self.buf.len();
+            self.buf.extend_from_slice(header);
+            self.buf.extend_from_slice(chunk);
+            let segment = &mut self.buf[start..];
+
+            let total_length = segment.len() as u16;
+            segment[2..4].copy_from_slice(&total_length.to_be_bytes());
+            segment[4..6].copy_from_slice(&id.wrapping_add(index as u16).to_be_bytes());
+
+            let tcp = &mut segment[ip_header_length..];
+            let seq = seq.wrapping_add((index * mss) as u32);
+            tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+            // congestion window reduced is signalled once, the end of
+            // the data and the push only with the last segment
+            let mut segment_flags = flags;
+            if index > 0 {
+                segment_flags &= !CWR;
+            }
+            if index + 1 < count {
+                segment_flags &= !(FIN | PSH);
+            }
+            tcp[13] = segment_flags;
+            tcp[16..18].copy_from_slice(&[0, 0]);
+            let tcp_checksum = checksum_tcp4(tcp, src_ip, dst_ip);
+            tcp[16..18].copy_from_slice(&tcp_checksum.to_be_bytes());
+
+            segment[10..12].copy_from_slice(&[0, 0]);
+            let ip_checksum = checksum_ip4(&segment[..ip_header_length]);
+            segment[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+            self.segments.push(start..self.buf.len());
+        }
+
+        Some(first..self.segments.len())
+    }
+
+    /// Segment at index, as returned by segment
+    pub fn get(&self, index: usize) -> &[u8] {
+        &self.buf[self.segments[index].clone()]
+    }
+}
+
+/// Whether dst_addr, in host byte order, is in a reserved range
+/// https://en.wikipedia.org/wiki/Reserved_IP_addresses
+pub fn is_reserved_addr(dst_addr: u32) -> bool {
+    // 0.0.0.0/8
+    (dst_addr & 0xff000000) == 0x00000000 ||
+        // 10.0.0.0/8
+        (dst_addr & 0xff000000) == 0x0a000000 ||
+        // 100.64.0.0/10
+        (dst_addr & 0xffc00000) == 0x64400000 ||
+        // 127.0.0.0/8
+        (dst_addr & 0xff000000) == 0x7f000000 ||
+        // 169.254.0.0/16
+        (dst_addr & 0xffff0000) == 0xa9fe0000 ||
+        // 172.16.0.0/12
+        (dst_addr & 0xfff00000) == 0xac100000 ||
+        // 192.0.0.0/24
+        (dst_addr & 0xffffff00) == 0xc0000000 ||
+        // 192.0.2.0/24
+        (dst_addr & 0xffffff00) == 0xc0000200 ||
+        // 192.88.99.0/24
+        (dst_addr & 0xffffff00) == 0xc0586300 ||
+        // 192.168.0.0/16
+        (dst_addr & 0xffff0000) == 0xc0a80000 ||
+        // 198.18.0.0/15
+        (dst_addr & 0xfffe0000) == 0xc6120000 ||
+        // 198.51.100.0/24
+        (dst_addr & 0xffffff00) == 0xc6336400 ||
+        // 203.0.113.0/24
+        (dst_addr & 0xffffff00) == 0xcb007100 ||
+        // 224.0.0.0/4
+        (dst_addr & 0xf0000000) == 0xe0000000 ||
+        // 233.252.0.0/24
+        (dst_addr & 0xffffff00) == 0xe9fc0000 ||
+        // 240.0.0.0/4
+        (dst_addr & 0xf0000000) == 0xf0000000 ||
+        // 255.255.255.255/32
+        dst_addr == 0xffffffff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    // IPv4 packet with valid checksums, options and payload are random
+    fn packet(
+        proto: u8,
+        options: Vec<u8>,
+        payload: Vec<u8>,
+        ttl: u8,
+        src_ip: Ipv4Addr,
+        dst_ip: Ipv4Addr,
+    ) -> Vec<u8> {
+        let ihl = 20 + options.len() / 4 * 4;
+        let mut buf = vec![0u8; 20];
+        buf.extend_from_slice(&options[..ihl - 20]);
+        buf.extend_from_slice(&payload);
+
+        let total_len = buf.len() as u16;
+        buf[0] = 0x40 | (ihl / 4) as u8;
+        buf[2..4].copy_from_slice(&total_len.to_be_bytes());
+        buf[8] = ttl;
+        buf[9] = proto;
+        buf[12..16].copy_from_slice(&src_ip.octets());
+        buf[16..20].copy_from_slice(&dst_ip.octets());
+
+        complete_checksum(&mut buf);
+        let checksum = checksum_ip4(&buf[..ihl]);
+        buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+        buf
+    }
+
+    fn arb_packet() -> impl Strategy<Value = Vec<u8>> {
+        (
+            prop_oneof![Just(TCP), Just(UDP), any::<u8>()],
+            prop::collection::vec(any::<u8>(), 0..=40),
+            prop::collection::vec(any::<u8>(), 20..=1500),
+            any::<u8>(),
+            any::<u32>(),
+            any::<u32>(),
+        )
+            .prop_map(|(proto, options, payload, ttl, src_ip, dst_ip)| {
+                packet(
+                    proto,
+                    options,
+                    payload,
+                    ttl,
+                    Ipv4Addr::from(src_ip),
+                    Ipv4Addr::from(dst_ip),
+                )
+            })
+    }
+
+    // TCP packet without TCP options, flags and data are random
+    fn arb_tcp_packet() -> impl Strategy<Value = Vec<u8>> {
+        (
+            prop::collection::vec(any::<u8>(), 0..=40),
+            prop::collection::vec(any::<u8>(), 20),
+            prop::collection::vec(any::<u8>(), 0..=8000),
+            any::<u32>(),
+            any::<u32>(),
+        )
+            .prop_map(|(options, mut tcp_header, data, src_ip, dst_ip)| {
+                tcp_header[12] = 0x50;
+                tcp_header.extend_from_slice(&data);
+                packet(
+                    TCP,
+                    options,
+                    tcp_header,
+                    64,
+                    Ipv4Addr::from(src_ip),
+                    Ipv4Addr::from(dst_ip),
+                )
+            })
+    }
+
+    // TCP SYN with an MSS option after some NOPs, the value may be unaligned
+    fn arb_syn_packet() -> impl Strategy<Value = (Vec<u8>, u16)> {
+        (
+            prop::collection::vec(any::<u8>(), 20),
+            0..=3usize,
+            any::<u16>(),
+            prop::collection::vec(any::<u8>(), 0..=100),
+            any::<u32>(),
+            any::<u32>(),
+        )
+            .prop_map(|(mut tcp, nops, mss, data, src_ip, dst_ip)| {
+                let mut options = vec![1u8; nops];
+                options.extend_from_slice(&[2, 4]);
+                options.extend_from_slice(&mss.to_be_bytes());
+                options.resize(8, 0);
+                tcp[12] = (((20 + options.len()) / 4) << 4) as u8;
+                tcp[13] |= 0x02;
+                tcp.extend_from_slice(&options);
+                tcp.extend_from_slice(&data);
+                let buf = packet(
+                    TCP,
+                    Vec::new(),
+                    tcp,
+                    64,
+                    Ipv4Addr::from(src_ip),
+                    Ipv4Addr::from(dst_ip),
+                );
+                (buf, mss)
+            })
+    }
+
+    fn rewrite(buf: &[u8], src_ip: Ipv4Addr, mode: ChecksumMode) -> Vec<u8> {
+        let mut buf = buf.to_vec();
+        let dst_ip = Ipv4Addr::from(BigEndian::read_u32(&buf[16..20]));
+        modify_packet(&mut buf, src_ip, dst_ip, mode);
+        buf
+    }
+
+    proptest! {
+        #[test]
+        fn completes_partial_checksums(buf in arb_packet(), partial in any::<u16>()) {
+            let offset = match get_proto(&buf) {
+                TCP => get_ihl(&buf) as usize + 16,
+                UDP => get_ihl(&buf) as usize + 6,
+                _ => return Ok(()),
+            };
+            let mut completed = buf.clone();
+            completed[offset..offset + 2].copy_from_slice(&partial.to_be_bytes());
+            complete_checksum(&mut completed);
+            prop_assert_eq!(completed, buf);
+        }
+
+        #[test]
+        fn incremental_matches_full(buf in arb_packet(), src_ip in any::<u32>()) {
+            let src_ip = Ipv4Addr::from(src_ip);
+            let incremental = rewrite(&buf, src_ip, ChecksumMode::Incremental);
+            prop_assert_eq!(&incremental, &rewrite(&buf, src_ip, ChecksumMode::Full));
+            prop_assert!(verify_checksums(&incremental));
+        }
+
+        #[test]
+        fn verify_recomputes_wrong_checksums(
+            buf in arb_packet(),
+            src_ip in any::<u32>(),
+            // the TCP checksum if there is one, else the IP checksum
+            flip in 1..=u16::MAX,
+        ) {
+            let src_ip = Ipv4Addr::from(src_ip);
+            let offset = match get_proto(&buf) {
+                TCP => get_ihl(&buf) as usize + 16,
+                _ => 10,
+            };
+            let mut broken = buf.clone();
+            let checksum = BigEndian::read_u16(&broken[offset..offset + 2]) ^ flip;
+            broken[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+            // 0x0000 and 0xffff are the same in ones' complement
+            prop_assume!(!verify_checksums(&broken));
+
+            let full = rewrite(&buf, src_ip, ChecksumMode::Full);
+            prop_assert_eq!(&rewrite(&buf, src_ip, ChecksumMode::Verify), &full);
+            prop_assert_eq!(&rewrite(&broken, src_ip, ChecksumMode::Verify), &full);
+        }
+
+        #[test]
+        fn later_fragments_keep_their_data(
+            buf in arb_packet(),
+            src_ip in any::<u32>(),
+            offset in 1..0x2000u16,
+            mode in prop_oneof![
+                Just(ChecksumMode::Incremental),
+                Just(ChecksumMode::Verify),
+                Just(ChecksumMode::Full),
+            ],
+        ) {
+            let mut fragment = buf.clone();
+            fragment[6..8].copy_from_slice(&offset.to_be_bytes());
+            let ihl = get_ihl(&fragment) as usize;
+            fragment[10..12].copy_from_slice(&[0, 0]);
+            let checksum = checksum_ip4(&fragment[..ihl]);
+            fragment[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            let rewritten = rewrite(&fragment, Ipv4Addr::from(src_ip), mode);
+            prop_assert_eq!(&rewritten[ihl..], &fragment[ihl..]);
+            prop_assert!(verify_checksums(&rewritten));
+        }
+
+        #[test]
+        fn clamps_mss((buf, mss) in arb_syn_packet(), clamp in any::<u16>()) {
+            let mut clamped = buf.clone();
+            prop_assert_eq!(clamp_mss(&mut clamped, clamp), mss > clamp);
+            prop_assert!(verify_checksums(&clamped));
+
+            // only the MSS value and the checksum change
+            let offset = clamped[40..].iter().position(|kind| *kind == 2).unwrap() + 40 + 2;
+            prop_assert_eq!(BigEndian::read_u16(&clamped[offset..offset + 2]), mss.min(clamp));
+            let mut restored = clamped.clone();
+            restored[offset..offset + 2].copy_from_slice(&buf[offset..offset + 2]);
+            restored[36..38].copy_from_slice(&buf[36..38]);
+            prop_assert_eq!(restored, buf);
+        }
+
+        #[test]
+        fn flow_hash_follows_the_flow(buf in arb_packet(), ttl in any::<u8>(), payload in any::<u8>()) {
+            let mut other = buf.clone();
+            other[8] = ttl;
+            *other.last_mut().unwrap() = payload;
+            prop_assert_eq!(flow_hash(&buf), flow_hash(&other));
+
+            // later fragments hash like the first one
+            let mut first = buf.clone();
+            first[6] = 0x20;
+            let mut later = buf.clone();
+            later[6..8].copy_from_slice(&[0x00, 0x10]);
+            let ip_header_length = get_ihl(&buf) as usize;
+            later[ip_header_length..ip_header_length + 4].copy_from_slice(&[1, 2, 3, 4]);
+            prop_assert_eq!(flow_hash(&first), flow_hash(&later));
+
+            if matches!(get_proto(&buf), TCP | UDP) {
+                let mut port = buf.clone();
+                port[ip_header_length + 1] ^= 1;
+                prop_assert_ne!(flow_hash(&buf), flow_hash(&port));
+            }
+        }
+
+        #[test]
+        fn segments_add_up(buf in arb_tcp_packet(), mss in 0..=1500usize, mtu in 100..=1500usize) {
+            let header_length = tcp_headers_len(&buf).unwrap();
+            let ip_header_length = get_ihl(&buf) as usize;
+            let seq = BigEndian::read_u32(&buf[ip_header_length + 4..ip_header_length + 8]);
+            let flags = buf[ip_header_length + 13];
+
+            let mut segmenter = Segmenter::new();
+            let segments = segmenter.segment(&buf, mss, mtu).unwrap();
+            let count = segments.len();
+            let mut data = Vec::new();
+            for (index, segment) in segments.map(|index| segmenter.get(index)).enumerate() {
+                prop_assert!(segment.len() <= mtu);
+                if mss > 0 {
+                    prop_assert!(segment.len() - header_length <= mss);
+                }
+                prop_assert!(verify_checksums(segment));
+                prop_assert_eq!(usize::from(BigEndian::read_u16(&segment[2..4])), segment.len());
+
+                let tcp = &segment[ip_header_length..];
+                prop_assert_eq!(BigEndian::read_u32(&tcp[4..8]), seq.wrapping_add(data.len() as u32));
+                // FIN and PSH only on the last segment
+                let last = index + 1 == count;
+                prop_assert_eq!(tcp[13] & 0x09, if last { flags & 0x09 } else { 0 });
+                data.extend_from_slice(&segment[header_length..]);
+            }
+            prop_assert_eq!(&data[..], &buf[header_length..]);
+        }
+    }
+}
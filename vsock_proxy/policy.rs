@@ -0,0 +1,583 @@
+// Egress policy per container on the enclave side
+//
+// Containers on docker0 and the enclaves network all leave through the
+// same ip-to-vsock-raw-outgoing with the same rights. With --policy-file
+// the containers listed there may only reach what their lines allow:
+//
+//   # name     match              proto  destination      ports
+//   relay-app  mark=0x100/0xff00  tcp    203.0.113.7      443
+//   relay-app  mark=0x100/0xff00  udp    198.51.100.0/24  53
+//   relay-app  mark=0x100/0xff00  tcp    *.nostr.example  443
+//   ln-app     ports=20000-20999  tcp    any              9735
+//
+// Containers are matched like in shaper.rs, by the packet mark restored
+// from conntrack or by the ports they were given for SNAT. The address
+// of a container is gone by the time its packets reach the queue, mark
+// its connections by address in FORWARD to key the policy on it. A
+// packet is of the container of the first line it matches and goes out
+// if any line of that container allows its protocol, destination and
+// destination port, "any" and "-" allow everything. Containers that are
+// not listed are not restricted.
+//
+// A destination may also be a domain, or *.domain for its subdomains,
+// it stands for the addresses DNS answers resolved it to (dns.rs) while
+// they are current. Flows keep the names they started with for as long
+// as they are active, so connections outlive the TTL of their answer.
+//
+// Later fragments follow the decision on the first one, like the
+// parent's filter. Denied packets are dropped and counted as
+// egress_denied, and logged, at most LOG_LIMIT a second, with the
+// destinations the container was expected to go to. The file is
+// reloaded on change and on SIGHUP.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::dns::{now_secs, DnsCache};
+use crate::fragment::FragmentTable;
+use crate::health::{Counter, Health};
+use crate::packet::{get_fragment, get_ports, get_proto, TCP, UDP};
+use crate::reload::{watch_file, ConfigError};
+use crate::shaper::{parse_selector, Selector};
+
+const ICMP: u8 = 1;
+
+/// Max flows that keep the names they started with
+pub const MAX_FLOWS: usize = 1 << 16;
+/// How long an idle flow keeps its names
+pub const FLOW_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Max denials logged a second, the others are only counted
+pub const LOG_LIMIT: u32 = 10;
+
+/// IPv4 network, addr is in host byte order with the host bits cleared
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    pub addr: u32,
+    pub prefix: u8,
+}
+
+impl Subnet {
+    fn mask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - u32::from(self.prefix))
+            .unwrap_or(0)
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        addr & self.mask() == self.addr
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.prefix {
+            0 => write!(f, "any"),
+            32 => write!(f, "{}", Ipv4Addr::from(self.addr)),
+            prefix => write!(f, "{}/{}", Ipv4Addr::from(self.addr), prefix),
+        }
+    }
+}
+
+/// Addresses a line lets a container reach
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    Subnet(Subnet),
+    // lowercase, *. in front for the subdomains
+    Name(String),
+}
+
+impl Destination {
+    // whether addr, which was resolved for names, is one of them
+    fn contains(&self, addr: u32, names: &[String]) -> bool {
+        match self {
+            Destination::Subnet(subnet) => subnet.contains(addr),
+            Destination::Name(pattern) => {
+                names.iter().any(|name| match pattern.strip_prefix('*') {
+                    Some(suffix) => name.ends_with(suffix),
+                    None => name == pattern,
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Subnet(subnet) => subnet.fmt(f),
+            Destination::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// What a line lets a container reach
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicyRule {
+    pub name: String,
+    pub selector: Selector,
+    // None is any protocol
+    pub proto: Option<u8>,
+    pub dst: Destination,
+    // destination ports, None is any
+    pub ports: Option<RangeInclusive<u16>>,
+}
+
+impl PolicyRule {
+    // names are the ones the destination of packet was resolved for
+    fn allows(&self, packet: &[u8], ports: Option<(u16, u16)>, names: &[String]) -> bool {
+        let dst_addr = u32::from_be_bytes(packet[16..20].try_into().unwrap());
+        self.proto.is_none_or(|proto| proto == get_proto(packet))
+            && self.dst.contains(dst_addr, names)
+            && match &self.ports {
+                Some(range) => ports.is_some_and(|(_, dst_port)| range.contains(&dst_port)),
+                None => true,
+            }
+    }
+}
+
+fn parse_proto(value: &str) -> Result<Option<u8>, String> {
+    match value {
+        "any" => Ok(None),
+        "tcp" => Ok(Some(TCP)),
+        "udp" => Ok(Some(UDP)),
+        "icmp" => Ok(Some(ICMP)),
+        _ => Err(format!(
+            "invalid protocol {value}, expected tcp, udp, icmp or any"
+        )),
+    }
+}
+
+fn parse_destination(value: &str) -> Result<Destination, String> {
+    if value == "any" {
+        return Ok(Destination::Subnet(Subnet { addr: 0, prefix: 0 }));
+    }
+    let invalid =
+        || format!("invalid destination {value}, expected <ip[/prefix]>, <domain> or any");
+    let (addr, prefix) = value.split_once('/').unwrap_or((value, "32"));
+    let Ok(addr) = addr.parse::<Ipv4Addr>() else {
+        let name = value.trim_end_matches('.').to_ascii_lowercase();
+        let domain = name.strip_prefix("*.").unwrap_or(&name);
+        let valid = !domain.is_empty()
+            && domain.split('.').all(|label| {
+                !label.is_empty()
+                    && label
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+        return match valid {
+            true => Ok(Destination::Name(name)),
+            false => Err(invalid()),
+        };
+    };
+    let prefix: u8 = prefix
+        .parse()
+        .ok()
+        .filter(|prefix| *prefix <= 32)
+        .ok_or_else(invalid)?;
+    let subnet = Subnet { addr: 0, prefix };
+    Ok(Destination::Subnet(Subnet {
+        addr: u32::from(addr) & subnet.mask(),
+        prefix,
+    }))
+}
+
+fn parse_ports(value: &str) -> Result<Option<RangeInclusive<u16>>, String> {
+    if value == "any" || value == "-" {
+        return Ok(None);
+    }
+    let port = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|_| format!("invalid ports {value}, expected <port[-port]> or any"))
+    };
+    let (start, end) = value.split_once('-').unwrap_or((value, value));
+    Ok(Some(port(start)?..=port(end)?))
+}
+
+fn parse_rule(line: &str) -> Result<PolicyRule, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [name, selector, proto, dst, rest @ ..] = &fields[..] else {
+        return Err("expected <name> <match> <proto> <destination> [ports]".to_owned());
+    };
+    let ports = match rest {
+        [] => None,
+        [ports] => parse_ports(ports)?,
+        _ => return Err("trailing fields".to_owned()),
+    };
+    let proto = parse_proto(proto)?;
+    if ports.is_some() && !matches!(proto, Some(TCP | UDP)) {
+        return Err("ports need tcp or udp".to_owned());
+    }
+    Ok(PolicyRule {
+        name: name.to_string(),
+        selector: parse_selector(selector)?,
+        proto,
+        dst: parse_destination(dst)?,
+        ports,
+    })
+}
+
+/// Parse policy rules, one per line, # starts a comment
+pub fn parse_policy(content: &str) -> Result<Vec<PolicyRule>, (usize, String)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.split('#').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(number, line)| parse_rule(line).map_err(|reason| (number, reason)))
+        .collect()
+}
+
+pub fn read_policy_file(path: &Path) -> Result<Vec<PolicyRule>, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(|e| ConfigError::ReadError {
+        path: path.display().to_string(),
+        source: e,
+    })?;
+    parse_policy(&content).map_err(|(line, reason)| ConfigError::RuleError {
+        path: path.display().to_string(),
+        line,
+        reason,
+    })
+}
+
+/// Spawn a thread reloading the rules of policy from path on file change or SIGHUP
+pub fn watch_policy_file(path: PathBuf, policy: Policy) -> Result<JoinHandle<()>, ConfigError> {
+    let watched = path.clone();
+    watch_file(watched, move || match read_policy_file(&path) {
+        Ok(rules) => policy.set_rules(rules),
+        Err(err) => {
+            // keep the old rules, the file might be mid-write
+            println!("{:?}", anyhow::Error::from(err));
+        }
+    })
+}
+
+// dst, proto, src port and dst port
+type FlowKey = (u32, u8, u16, u16);
+
+// names the destination of a flow was resolved for when it started
+struct Flow {
+    names: Vec<String>,
+    seen: Instant,
+}
+
+struct PolicyState {
+    rules: Vec<PolicyRule>,
+    fragments: FragmentTable,
+    flows: HashMap<FlowKey, Flow>,
+    // second of the last denial logged and how many were in it
+    logged: (u64, u32),
+}
+
+impl PolicyState {
+    fn remember_flow(&mut self, key: FlowKey, names: Vec<String>, now: Instant) {
+        if self.flows.len() >= MAX_FLOWS && !self.flows.contains_key(&key) {
+            self.flows
+                .retain(|_, flow| now.saturating_duration_since(flow.seen) < FLOW_TIMEOUT);
+            if self.flows.len() >= MAX_FLOWS {
+                return;
+            }
+        }
+        self.flows.insert(key, Flow { names, seen: now });
+    }
+
+    fn log_denial(
+        &mut self,
+        container: &str,
+        packet: &[u8],
+        ports: Option<(u16, u16)>,
+        names: &[String],
+    ) {
+        let second = now_secs();
+        match self.logged {
+            (logged, count) if logged == second && count >= LOG_LIMIT => return,
+            (logged, count) if logged == second => self.logged.1 = count + 1,
+            _ => self.logged = (second, 1),
+        }
+
+        let mut expected: Vec<String> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.name == container)
+            .map(|rule| rule.dst.to_string())
+            .collect();
+        expected.dedup();
+        let proto = match get_proto(packet) {
+            TCP => "tcp".to_owned(),
+            UDP => "udp".to_owned(),
+            ICMP => "icmp".to_owned(),
+            proto => format!("proto {proto}"),
+        };
+        let dst = Ipv4Addr::from(u32::from_be_bytes(packet[16..20].try_into().unwrap()));
+        let port = ports.map_or(String::new(), |(_, dst_port)| format!(":{dst_port}"));
+        let resolved = match names {
+            [] => "no known name".to_owned(),
+            names => format!("resolved for {}", names.join(", ")),
+        };
+        println!(
+            "egress denied for {container} to {proto} {dst}{port} ({resolved}), expected {}",
+            expected.join(", ")
+        );
+    }
+}
+
+struct PolicyInner {
+    state: Mutex<PolicyState>,
+    dns: DnsCache,
+    denied: Counter,
+}
+
+/// Egress policy of the containers, shared with the reload thread
+#[derive(Clone)]
+pub struct Policy(Arc<PolicyInner>);
+
+impl Policy {
+    /// Policy of rules, names are resolved through dns
+    pub fn new(rules: Vec<PolicyRule>, dns: DnsCache, health: &Health) -> Self {
+        let policy = Policy(Arc::new(PolicyInner {
+            state: Mutex::new(PolicyState {
+                rules: Vec::new(),
+                fragments: FragmentTable::default(),
+                flows: HashMap::new(),
+                logged: (0, 0),
+            }),
+            dns,
+            denied: health.counter("egress_denied"),
+        }));
+        policy.set_rules(rules);
+        policy
+    }
+
+    pub fn set_rules(&self, rules: Vec<PolicyRule>) {
+        let mut names: Vec<&str> = rules.iter().map(|rule| rule.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        println!("egress policy for {} containers", names.len());
+
+        self.lock().rules = rules;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PolicyState> {
+        // a panic while holding the lock aborts the process anyway
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether the container of packet, as per its mark, may send it
+    pub fn allows(&self, packet: &[u8], mark: u32) -> bool {
+        let allowed = self.check(packet, mark);
+        if !allowed {
+            self.0.denied.inc();
+        }
+        allowed
+    }
+
+    fn check(&self, packet: &[u8], mark: u32) -> bool {
+        let mut state = self.lock();
+        // the parent filters malformed packets
+        if state.rules.is_empty() || packet.len() < 20 {
+            return true;
+        }
+
+        // later fragments have no ports, they go out if the first one did
+        let now = Instant::now();
+        let (fragment_offset, more_fragments) = get_fragment(packet);
+        if fragment_offset > 0 {
+            return state.fragments.is_forwarded(packet, now);
+        }
+
+        let ports = get_ports(packet);
+        let src_port = ports.map(|(src_port, _)| src_port);
+        let Some(container) = state
+            .rules
+            .iter()
+            .find(|rule| rule.selector.matches(src_port, Some(mark)))
+            .map(|rule| rule.name.clone())
+        else {
+            return !more_fragments || state.fragments.insert(packet, now);
+        };
+        let rules = || state.rules.iter().filter(|rule| rule.name == container);
+
+        // names of the destination, current ones and those its flow started with
+        let dst_addr = u32::from_be_bytes(packet[16..20].try_into().unwrap());
+        let flow =
+            ports.map(|(src_port, dst_port)| (dst_addr, get_proto(packet), src_port, dst_port));
+        let mut names = Vec::new();
+        if rules().any(|rule| matches!(rule.dst, Destination::Name(_))) {
+            names = self.0.dns.names(Ipv4Addr::from(dst_addr), now_secs());
+            if let Some(flow) = flow.and_then(|flow| state.flows.get(&flow)) {
+                for name in &flow.names {
+                    if !names.contains(name) {
+                        names.push(name.clone());
+                    }
+                }
+            }
+        }
+
+        if !rules().any(|rule| rule.allows(packet, ports, &names)) {
+            state.log_denial(&container, packet, ports, &names);
+            return false;
+        }
+        if let (Some(flow), false) = (flow, names.is_empty()) {
+            state.remember_flow(flow, names, now);
+        }
+
+        !more_fragments || state.fragments.insert(packet, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::Answer;
+
+    // IPv4 packet of proto from src_port to dst at dst_port
+    fn packet(proto: u8, src_port: u16, dst: [u8; 4], dst_port: u16) -> Vec<u8> {
+        let mut buf = vec![0u8; 40];
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&40u16.to_be_bytes());
+        buf[9] = proto;
+        buf[16..20].copy_from_slice(&dst);
+        buf[20..22].copy_from_slice(&src_port.to_be_bytes());
+        buf[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn parses_policy() {
+        let rules = parse_policy(
+            "# name match proto destination ports\n\
+             a mark=0x100/0xff00 tcp 203.0.113.7 443 # relay\n\
+             \n\
+             b ports=20000-20999 any 198.51.100.9/24\n",
+        )
+        .unwrap();
+        assert_eq!(
+            rules,
+            [
+                PolicyRule {
+                    name: "a".to_owned(),
+                    selector: Selector::Mark {
+                        mark: 0x100,
+                        mask: 0xff00,
+                    },
+                    proto: Some(TCP),
+                    dst: Destination::Subnet(Subnet {
+                        addr: 0xcb007107,
+                        prefix: 32,
+                    }),
+                    ports: Some(443..=443),
+                },
+                PolicyRule {
+                    name: "b".to_owned(),
+                    selector: Selector::Ports(20000..=20999),
+                    proto: None,
+                    dst: Destination::Subnet(Subnet {
+                        addr: 0xc6336400,
+                        prefix: 24,
+                    }),
+                    ports: None,
+                },
+            ]
+        );
+
+        assert_eq!(parse_policy("\na mark=1 tcp\n").unwrap_err().0, 2);
+        assert!(parse_policy("a mark=1 sctp any\n").is_err());
+        assert!(parse_policy("a mark=1 tcp 1.2.3.4/33\n").is_err());
+        assert!(parse_policy("a mark=1 icmp any 443\n").is_err());
+        assert!(parse_policy("a mark=1 tcp any 443 x\n").is_err());
+
+        let rules =
+            parse_policy("a mark=1 tcp *.Example.com. 443\na mark=1 tcp relay.io\n").unwrap();
+        assert_eq!(rules[0].dst, Destination::Name("*.example.com".to_owned()));
+        assert_eq!(rules[1].dst, Destination::Name("relay.io".to_owned()));
+        assert!(parse_policy("a mark=1 tcp exa..mple.com\n").is_err());
+        assert!(parse_policy("a mark=1 tcp relay.io/24\n").is_err());
+    }
+
+    #[test]
+    fn allows_resolved_names() {
+        let rules = parse_policy(
+            "a mark=1 tcp *.example.com 443\n\
+             a mark=1 tcp relay.io 443\n",
+        )
+        .unwrap();
+        let dns = DnsCache::default();
+        let policy = Policy::new(rules, dns.clone(), &Health::new());
+        let answer = |name: &str, last: u8| Answer {
+            name: name.to_owned(),
+            addr: Ipv4Addr::new(203, 0, 113, last),
+            ttl: 300,
+        };
+        dns.learn(
+            &[
+                answer("cdn.example.com", 1),
+                answer("example.com", 2),
+                answer("relay.io", 3),
+                answer("other.org", 4),
+            ],
+            now_secs(),
+        );
+
+        assert!(policy.allows(&packet(TCP, 5000, [203, 0, 113, 1], 443), 1));
+        assert!(!policy.allows(&packet(TCP, 5000, [203, 0, 113, 2], 443), 1));
+        assert!(policy.allows(&packet(TCP, 5000, [203, 0, 113, 3], 443), 1));
+        assert!(!policy.allows(&packet(TCP, 5000, [203, 0, 113, 3], 80), 1));
+        assert!(!policy.allows(&packet(TCP, 5000, [203, 0, 113, 4], 443), 1));
+
+        // once the answers expire only flows that started before go on
+        dns.replace(Vec::new());
+        assert!(policy.allows(&packet(TCP, 5000, [203, 0, 113, 1], 443), 1));
+        assert!(!policy.allows(&packet(TCP, 5001, [203, 0, 113, 1], 443), 1));
+    }
+
+    #[test]
+    fn restricts_listed_containers() {
+        let rules = parse_policy(
+            "a mark=0x100/0xff00 tcp 203.0.113.0/24 443-444\n\
+             a mark=0x100/0xff00 udp any 53\n\
+             b ports=20000-20999 tcp 198.51.100.1 9735\n",
+        )
+        .unwrap();
+        let policy = Policy::new(rules, DnsCache::default(), &Health::new());
+
+        assert!(policy.allows(&packet(TCP, 5000, [203, 0, 113, 7], 443), 0x101));
+        assert!(policy.allows(&packet(UDP, 5000, [1, 1, 1, 1], 53), 0x101));
+        assert!(!policy.allows(&packet(TCP, 5000, [203, 0, 114, 7], 443), 0x101));
+        assert!(!policy.allows(&packet(TCP, 5000, [203, 0, 113, 7], 80), 0x101));
+        assert!(!policy.allows(&packet(ICMP, 0, [203, 0, 113, 7], 0), 0x101));
+
+        assert!(policy.allows(&packet(TCP, 20001, [198, 51, 100, 1], 9735), 1));
+        assert!(!policy.allows(&packet(TCP, 20001, [198, 51, 100, 2], 9735), 1));
+
+        // anything else is not restricted
+        assert!(policy.allows(&packet(TCP, 5000, [8, 8, 8, 8], 80), 0x201));
+        assert_eq!(policy.0.denied.get(), 4);
+
+        policy.set_rules(Vec::new());
+        assert!(policy.allows(&packet(TCP, 5000, [203, 0, 113, 7], 80), 0x101));
+    }
+
+    #[test]
+    fn fragments_follow_the_first() {
+        let rules = parse_policy("a mark=1 udp 203.0.113.7 53\n").unwrap();
+        let policy = Policy::new(rules, DnsCache::default(), &Health::new());
+
+        // id 1 to an allowed port, id 2 to a denied one
+        let fragment = |id: u16, dst_port: u16, offset: u16, more: bool| {
+            let mut buf = packet(UDP, 5000, [203, 0, 113, 7], dst_port);
+            buf[4..6].copy_from_slice(&id.to_be_bytes());
+            let flags = offset | if more { 0x2000 } else { 0 };
+            buf[6..8].copy_from_slice(&flags.to_be_bytes());
+            buf
+        };
+        assert!(policy.allows(&fragment(1, 53, 0, true), 1));
+        assert!(!policy.allows(&fragment(2, 80, 0, true), 1));
+        assert!(policy.allows(&fragment(1, 0, 3, false), 1));
+        assert!(!policy.allows(&fragment(2, 0, 3, false), 1));
+    }
+}
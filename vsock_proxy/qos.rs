@@ -0,0 +1,364 @@
+// Priority between frames of a batch on the vsock links
+//
+// All packets share the vsock link in the order nfqueue delivered them,
+// so a bulk transfer filling every batch delays the handshakes and
+// small packets of interactive connections behind it. With --qos the
+// senders sort every packet into a class before the batch is written:
+//
+// - control: TCP SYN, RST and pure ACKs, DSCP CS6/CS7
+// - interactive: DSCP EF, CS4/CS5 and AF4x, packets up to --qos-small-packet
+// - bulk: everything else, DSCP CS1/LE, fragments
+//
+// --qos-port gives the packets of a port range a class, it applies after
+// the TCP control packets. The classes are then served by deficit round
+// robin with --qos-weights, each round a class may send weight times
+// QUANTUM bytes, so bulk keeps a share instead of starving.
+//
+// Packets carrying data never overtake earlier ones of the same flow, a
+// packet is put in the lowest class seen for its flow in the batch so
+// far, TCP would take reordered segments for loss. Fragments are all
+// bulk, a later fragment must not get ahead of the first one.
+//
+// Frames are only reordered within a batch, what nfqueue delivered at
+// once, at most MAX_BATCH packets. Nothing is held back for the next
+// batch, so a class cannot get ahead of frames already written, and the
+// socket buffer and replay buffer of the link stay in arrival order. A
+// bulk transfer that keeps the link busy still delays a small packet by
+// what is queued there, QoS only helps once batches mix classes.
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::RangeInclusive;
+
+use clap::{Args, ValueEnum};
+
+use crate::frame::parse_gso_frame;
+use crate::health::{Counter, Health};
+use crate::packet::{flow_hash, get_ihl, get_proto, is_fragment, tcp_headers_len, TCP, UDP};
+
+// bytes per round and unit of weight, about a packet at the usual MTU
+const QUANTUM: usize = 1500;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+
+/// Classes in priority order
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Class {
+    Control,
+    Interactive,
+    Bulk,
+}
+
+impl Class {
+    const ALL: [Class; 3] = [Class::Control, Class::Interactive, Class::Bulk];
+
+    fn counter_name(self) -> &'static str {
+        match self {
+            Class::Control => "qos_control_frames",
+            Class::Interactive => "qos_interactive_frames",
+            Class::Bulk => "qos_bulk_frames",
+        }
+    }
+}
+
+/// Class for the packets of a port range, either end of a connection matches
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortClass {
+    pub ports: RangeInclusive<u16>,
+    pub class: Class,
+}
+
+fn parse_port_class(arg: &str) -> Result<PortClass, String> {
+    let (ports, class) = arg
+        .split_once('=')
+        .ok_or("expected <port[-port]>=<class>")?;
+    let port = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|_| format!("invalid port {port}"))
+    };
+    let ports = match ports.split_once('-') {
+        Some((start, end)) => port(start)?..=port(end)?,
+        None => port(ports)?..=port(ports)?,
+    };
+    let class = Class::from_str(class, true).map_err(|_| format!("invalid class {class}"))?;
+    Ok(PortClass { ports, class })
+}
+
+fn parse_weights(arg: &str) -> Result<[usize; 3], String> {
+    let weights = arg
+        .split(',')
+        .map(|weight| weight.trim().parse::<usize>().ok().filter(|w| *w > 0))
+        .collect::<Option<Vec<_>>>()
+        .and_then(|weights| weights.try_into().ok());
+    weights.ok_or_else(|| "expected three weights > 0 <control,interactive,bulk>".to_owned())
+}
+
+/// QoS options of the sending proxies
+#[derive(Args, Clone, Debug)]
+pub struct QosArgs {
+    /// send the frames of a batch by class, control and interactive packets ahead of bulk
+    #[clap(long)]
+    pub qos: bool,
+    /// packets up to this many bytes are interactive
+    #[clap(long, value_parser, default_value_t = 256, requires = "qos")]
+    pub qos_small_packet: usize,
+    /// class of the packets of a port range <port[-port]>=<control|interactive|bulk>,
+    /// can be repeated, the first match wins
+    #[clap(long, value_parser = parse_port_class, requires = "qos")]
+    pub qos_port: Vec<PortClass>,
+    /// share of each class per round <control,interactive,bulk>
+    #[clap(long, value_parser = parse_weights, default_value = "16,4,1", requires = "qos")]
+    pub qos_weights: [usize; 3],
+}
+
+impl QosArgs {
+    pub fn scheduler(&self, health: &Health) -> Option<Scheduler> {
+        self.qos.then(|| {
+            Scheduler::new(
+                self.qos_small_packet,
+                self.qos_port.clone(),
+                self.qos_weights,
+                health,
+            )
+        })
+    }
+}
+
+/// Orders the frames of a batch by class
+pub struct Scheduler {
+    small_packet: usize,
+    ports: Vec<PortClass>,
+    weights: [usize; 3],
+    // by Class
+    frames: Vec<Counter>,
+}
+
+impl Scheduler {
+    pub fn new(
+        small_packet: usize,
+        ports: Vec<PortClass>,
+        weights: [usize; 3],
+        health: &Health,
+    ) -> Self {
+        Scheduler {
+            small_packet,
+            ports,
+            // a class without share would never send
+            weights: weights.map(|weight| weight.max(1)),
+            frames: Class::ALL
+                .iter()
+                .map(|class| health.counter(class.counter_name()))
+                .collect(),
+        }
+    }
+
+    // class of a packet on its own, and whether it carries data
+    fn classify_packet(&self, buf: &[u8]) -> (Class, bool) {
+        if buf.len() < 20 || is_fragment(buf) {
+            return (Class::Bulk, true);
+        }
+
+        let ip_header_length = get_ihl(buf) as usize;
+        if let Some(headers_length) = tcp_headers_len(buf) {
+            let flags = buf[ip_header_length + 13];
+            let data = buf.len() > headers_length || flags & TCP_FIN != 0;
+            if flags & (TCP_SYN | TCP_RST) != 0 || !data {
+                return (Class::Control, data);
+            }
+        }
+
+        let ports = match get_proto(buf) {
+            TCP | UDP if buf.len() >= ip_header_length + 4 => {
+                let port = |at: usize| u16::from_be_bytes([buf[at], buf[at + 1]]);
+                Some((port(ip_header_length), port(ip_header_length + 2)))
+            }
+            _ => None,
+        };
+        let port_class = ports.and_then(|(src, dst)| {
+            self.ports
+                .iter()
+                .find(|rule| rule.ports.contains(&src) || rule.ports.contains(&dst))
+                .map(|rule| rule.class)
+        });
+        if let Some(class) = port_class {
+            return (class, true);
+        }
+
+        let class = match buf[1] >> 2 {
+            // CS6, CS7
+            48 | 56 => Class::Control,
+            // CS4, AF41, AF42, AF43, CS5, VA, EF
+            32 | 34 | 36 | 38 | 40 | 44 | 46 => Class::Interactive,
+            // LE, CS1
+            1 | 8 => Class::Bulk,
+            _ if buf.len() <= self.small_packet => Class::Interactive,
+            _ => Class::Bulk,
+        };
+        (class, true)
+    }
+
+    /// Class of every frame, packets carrying data stay behind earlier
+    /// ones of their flow
+    pub fn classify(&self, frames: &[&[u8]]) -> Vec<Class> {
+        let mut flows: HashMap<u64, Class> = HashMap::new();
+        frames
+            .iter()
+            .map(|frame| {
+                // GSO frames go by the packet they carry
+                let packet = parse_gso_frame(frame).map_or(*frame, |(_, packet)| packet);
+                let (class, data) = self.classify_packet(packet);
+                if !data {
+                    return class;
+                }
+
+                let flow = flows.entry(flow_hash(packet)).or_insert(class);
+                *flow = class.max(*flow);
+                *flow
+            })
+            .collect()
+    }
+
+    /// Frames of one batch in the order to write them, in order within a
+    /// class, nothing is carried over to the next batch
+    pub fn schedule<'a>(&self, frames: &[&'a [u8]]) -> Vec<&'a [u8]> {
+        let mut queues: [VecDeque<&[u8]>; 3] = Default::default();
+        for (frame, class) in frames.iter().zip(self.classify(frames)) {
+            queues[class as usize].push_back(frame);
+            self.frames[class as usize].inc();
+        }
+
+        // deficit round robin, a frame larger than a round waits for
+        // its class to save up
+        let mut deficits = [0; 3];
+        let mut scheduled = Vec::with_capacity(frames.len());
+        while scheduled.len() < frames.len() {
+            for (class, queue) in queues.iter_mut().enumerate() {
+                if queue.is_empty() {
+                    deficits[class] = 0;
+                    continue;
+                }
+                deficits[class] += self.weights[class] * QUANTUM;
+                while let Some(frame) = queue.front().filter(|f| f.len() <= deficits[class]) {
+                    deficits[class] -= frame.len();
+                    scheduled.push(*frame);
+                    queue.pop_front();
+                }
+            }
+        }
+
+        scheduled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IPv4 TCP packet of size bytes between ports
+    fn tcp(size: usize, ports: (u16, u16), flags: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; size];
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+        buf[9] = TCP;
+        buf[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        buf[16..20].copy_from_slice(&[1, 1, 1, 1]);
+        buf[20..22].copy_from_slice(&ports.0.to_be_bytes());
+        buf[22..24].copy_from_slice(&ports.1.to_be_bytes());
+        buf[32] = 5 << 4;
+        buf[33] = flags;
+        buf
+    }
+
+    fn scheduler(ports: Vec<PortClass>) -> Scheduler {
+        Scheduler::new(256, ports, [16, 4, 1], &Health::new())
+    }
+
+    #[test]
+    fn classifies() {
+        let scheduler = scheduler(vec![parse_port_class("7000-7010=interactive").unwrap()]);
+        let syn = tcp(40, (1234, 443), TCP_SYN);
+        let ack = tcp(40, (1234, 443), 0x10);
+        let small = tcp(100, (1234, 443), 0x18);
+        let bulk = tcp(1500, (1234, 443), 0x10);
+        let port = tcp(1500, (7005, 1234), 0x10);
+        let mut dscp = tcp(1500, (1236, 443), 0x10);
+        dscp[1] = 46 << 2;
+        let mut fragment = tcp(100, (1234, 443), 0x10);
+        fragment[6] = 0x20;
+
+        let frames: Vec<&[u8]> = vec![&syn, &ack, &small, &bulk, &port, &dscp, &fragment];
+        assert_eq!(
+            scheduler.classify(&frames),
+            [
+                Class::Control,
+                Class::Control,
+                Class::Interactive,
+                Class::Bulk,
+                Class::Interactive,
+                Class::Interactive,
+                Class::Bulk,
+            ]
+        );
+    }
+
+    #[test]
+    fn data_keeps_flow_order() {
+        let scheduler = scheduler(Vec::new());
+        let bulk = tcp(1500, (1234, 443), 0x10);
+        let small = tcp(100, (1234, 443), 0x18);
+        let ack = tcp(40, (1234, 443), 0x10);
+        let other = tcp(100, (1235, 443), 0x18);
+
+        let frames: Vec<&[u8]> = vec![&bulk, &small, &ack, &other];
+        assert_eq!(
+            scheduler.classify(&frames),
+            [Class::Bulk, Class::Bulk, Class::Control, Class::Interactive]
+        );
+        assert_eq!(
+            scheduler.schedule(&frames),
+            [&ack[..], &other[..], &bulk[..], &small[..]]
+        );
+    }
+
+    #[test]
+    fn weighted() {
+        let scheduler = scheduler(vec![parse_port_class("2000-2999=interactive").unwrap()]);
+        // all of bulk first, then the interactive ones by port
+        let frames: Vec<Vec<u8>> = (1000..1008)
+            .chain(2000..2008)
+            .map(|port| tcp(1500, (port, 443), 0x10))
+            .collect();
+        let frames: Vec<&[u8]> = frames.iter().map(Vec::as_slice).collect();
+
+        // 4 interactive frames per bulk one
+        let ports: Vec<u16> = scheduler
+            .schedule(&frames)
+            .iter()
+            .map(|frame| u16::from_be_bytes([frame[20], frame[21]]))
+            .collect();
+        assert_eq!(
+            ports,
+            [
+                2000, 2001, 2002, 2003, 1000, 2004, 2005, 2006, 2007, 1001, 1002, 1003, 1004, 1005,
+                1006, 1007
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_args() {
+        assert_eq!(
+            parse_port_class("443=bulk"),
+            Ok(PortClass {
+                ports: 443..=443,
+                class: Class::Bulk
+            })
+        );
+        assert!(parse_port_class("443").is_err());
+        assert!(parse_port_class("443=fast").is_err());
+        assert_eq!(parse_weights("8, 2,1"), Ok([8, 2, 1]));
+        assert!(parse_weights("8,0,1").is_err());
+        assert!(parse_weights("8,2").is_err());
+    }
+}
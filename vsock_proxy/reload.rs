@@ -0,0 +1,370 @@
+// Hot reload of the enclave IP address
+//
+// The IP used by the enclave side proxies for source rewriting and
+// destination filtering used to be read once at startup. If the parent's
+// address changes (Elastic IP reassignment, new ENI) the proxies kept
+// using the stale value until restarted, which also dropped the vsock link.
+//
+// The current address now lives in an atomic shared with the data path,
+// so reads stay lock-free and the hot loop does not have to care about
+// reloads. A watcher thread re-reads the IP source whenever the file is
+// rewritten (inotify) or the process receives SIGHUP. The shaping rules,
+// the egress policy and the DNS cache are watched the same way, every
+// watcher sees every SIGHUP.
+
+use std::ffi::CString;
+use std::net::Ipv4Addr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read {path}")]
+    ReadError {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("invalid ip address {value:?} in {path}")]
+    ParseError {
+        path: String,
+        value: String,
+        #[source]
+        source: std::net::AddrParseError,
+    },
+    #[error("failed to watch {path}")]
+    WatchError {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("failed to install handler for signal {0}")]
+    SignalError(libc::c_int, #[source] std::io::Error),
+    #[error("invalid line {line} in {path}: {reason}")]
+    RuleError {
+        path: String,
+        line: usize,
+        reason: String,
+    },
+}
+
+/// IPv4 address shared between the data path and the reload logic
+#[derive(Clone, Debug)]
+pub struct SharedIp(Arc<AtomicU32>);
+
+impl SharedIp {
+    pub fn new(ip: Ipv4Addr) -> Self {
+        SharedIp(Arc::new(AtomicU32::new(ip.into())))
+    }
+
+    pub fn get(&self) -> Ipv4Addr {
+        self.0.load(Ordering::Relaxed).into()
+    }
+
+    /// Store a new address, returns the previous one
+    pub fn set(&self, ip: Ipv4Addr) -> Ipv4Addr {
+        self.0.swap(ip.into(), Ordering::Relaxed).into()
+    }
+
+    /// Store a new address and log if it differs from the previous one
+    pub fn update(&self, ip: Ipv4Addr) {
+        let old_ip = self.set(ip);
+        if old_ip != ip {
+            println!("ip changed from {} to {}", old_ip, ip);
+        }
+    }
+}
+
+pub fn read_ip_file(path: &Path) -> Result<Ipv4Addr, ConfigError> {
+    let value = std::fs::read_to_string(path).map_err(|e| ConfigError::ReadError {
+        path: path.display().to_string(),
+        source: e,
+    })?;
+    let value = value.trim();
+
+    value.parse().map_err(|e| ConfigError::ParseError {
+        path: path.display().to_string(),
+        value: value.to_owned(),
+        source: e,
+    })
+}
+
+// bumped on every SIGHUP, each watcher compares with the last one it saw
+static RELOAD_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Install the SIGHUP handler, SA_RESTART makes sure blocking
+/// calls on the data path are not interrupted by a reload request
+pub fn register_sighup() -> Result<(), ConfigError> {
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+
+    if unsafe { libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) } < 0 {
+        return Err(ConfigError::SignalError(
+            libc::SIGHUP,
+            std::io::Error::last_os_error(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns true once per received SIGHUP for every caller with its own seen
+pub fn take_reload_request(seen: &mut u64) -> bool {
+    let generation = RELOAD_GENERATION.load(Ordering::Relaxed);
+    std::mem::replace(seen, generation) != generation
+}
+
+struct Inotify {
+    fd: libc::c_int,
+}
+
+impl Inotify {
+    // watch the directory and not the file itself so that
+    // atomic replacement via rename is also picked up
+    fn watch_dir(dir: &Path) -> std::io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let inotify = Inotify { fd };
+
+        let dir = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE;
+        if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(inotify)
+    }
+
+    /// Wait up to timeout_ms for events, returns true if any
+    /// event touched a file with the given name
+    fn wait(&self, name: &[u8], timeout_ms: libc::c_int) -> std::io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let res = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if res < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        if res == 0 {
+            return Ok(false);
+        }
+
+        let mut buf = [0u8; 4096];
+        let mut matched = false;
+        loop {
+            let size = unsafe { libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len()) };
+            if size < 0 {
+                let err = std::io::Error::last_os_error();
+                match err.kind() {
+                    std::io::ErrorKind::WouldBlock => break,
+                    // a signal handler without SA_RESTART ran, read again
+                    std::io::ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
+            }
+
+            let size = size as usize;
+            let mut offset = 0;
+            while offset + std::mem::size_of::<libc::inotify_event>() <= size {
+                let event = unsafe {
+                    std::ptr::read_unaligned(buf[offset..].as_ptr().cast::<libc::inotify_event>())
+                };
+                let name_start = offset + std::mem::size_of::<libc::inotify_event>();
+                let name_end = (name_start + event.len as usize).min(size);
+                // name is NUL padded
+                let event_name = buf[name_start..name_end]
+                    .split(|b| *b == 0)
+                    .next()
+                    .unwrap_or(&[]);
+                if event_name == name {
+                    matched = true;
+                }
+                offset = name_end;
+            }
+        }
+
+        Ok(matched)
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn reload_ip(path: &Path, ip: &SharedIp) {
+    match read_ip_file(path) {
+        Ok(new_ip) => ip.update(new_ip),
+        Err(err) => {
+            // keep the old address, the file might be mid-write
+            println!("{:?}", anyhow::Error::from(err));
+        }
+    }
+}
+
+/// Spawn a thread reloading the IP from path on file change or SIGHUP
+pub fn watch_ip_file(path: PathBuf, ip: SharedIp) -> Result<JoinHandle<()>, ConfigError> {
+    let watched = path.clone();
+    watch_file(watched, move || reload_ip(&path, &ip))
+}
+
+/// Spawn a thread calling reload whenever the file at path changes or on SIGHUP
+pub fn watch_file<F: FnMut() + Send + 'static>(
+    path: PathBuf,
+    mut reload: F,
+) -> Result<JoinHandle<()>, ConfigError> {
+    register_sighup()?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let name = path.file_name().unwrap_or_default().as_bytes().to_owned();
+    let inotify = Inotify::watch_dir(&dir).map_err(|e| ConfigError::WatchError {
+        path: dir.display().to_string(),
+        source: e,
+    })?;
+
+    let mut seen = RELOAD_GENERATION.load(Ordering::Relaxed);
+    Ok(std::thread::spawn(move || loop {
+        // SIGHUP is only checked between polls, so keep the timeout short
+        let changed = match inotify.wait(&name, 1000) {
+            Ok(changed) => changed,
+            Err(e) => {
+                println!(
+                    "{:?}",
+                    anyhow::Error::from(ConfigError::WatchError {
+                        path: dir.display().to_string(),
+                        source: e,
+                    })
+                );
+                std::thread::sleep(std::time::Duration::from_secs(1));
+                false
+            }
+        };
+
+        if take_reload_request(&mut seen) || changed {
+            reload();
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    // fresh directory per test, watchers see every file in it
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("reload-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reads_ip_file() {
+        let dir = test_dir("read");
+        let path = dir.join("ip.txt");
+
+        assert!(matches!(
+            read_ip_file(&path),
+            Err(ConfigError::ReadError { .. })
+        ));
+
+        std::fs::write(&path, " 203.0.113.7\n").unwrap();
+        assert_eq!(read_ip_file(&path).unwrap(), Ipv4Addr::new(203, 0, 113, 7));
+
+        std::fs::write(&path, "203.0.113\n").unwrap();
+        assert!(matches!(
+            read_ip_file(&path),
+            Err(ConfigError::ParseError { .. })
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reloads_on_change_and_sighup() {
+        let dir = test_dir("watch");
+        let path = dir.join("ip.txt");
+        std::fs::write(&path, "203.0.113.7\n").unwrap();
+
+        let ip = SharedIp::new(read_ip_file(&path).unwrap());
+        let (tx, rx) = mpsc::channel();
+        {
+            let ip = ip.clone();
+            let path = path.clone();
+            watch_file(path.clone(), move || {
+                reload_ip(&path, &ip);
+                let _ = tx.send(ip.get());
+            })
+            .unwrap();
+        }
+        let timeout = Duration::from_secs(5);
+
+        // a rewrite of the file is picked up
+        std::fs::write(&path, "198.51.100.9\n").unwrap();
+        assert_eq!(
+            rx.recv_timeout(timeout).unwrap(),
+            Ipv4Addr::new(198, 51, 100, 9)
+        );
+        while rx.try_recv().is_ok() {}
+
+        // as is a replacement by rename
+        let tmp = dir.join("ip.txt.tmp");
+        std::fs::write(&tmp, "192.0.2.1\n").unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+        assert_eq!(
+            rx.recv_timeout(timeout).unwrap(),
+            Ipv4Addr::new(192, 0, 2, 1)
+        );
+        while rx.try_recv().is_ok() {}
+
+        // a broken file keeps the old address
+        std::fs::write(&path, "not an ip\n").unwrap();
+        assert_eq!(
+            rx.recv_timeout(timeout).unwrap(),
+            Ipv4Addr::new(192, 0, 2, 1)
+        );
+        while rx.try_recv().is_ok() {}
+
+        // SIGHUP reloads a file written without an event, it is not closed yet
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, b"192.0.2.2\n").unwrap();
+        unsafe { libc::raise(libc::SIGHUP) };
+        assert_eq!(
+            rx.recv_timeout(timeout).unwrap(),
+            Ipv4Addr::new(192, 0, 2, 2)
+        );
+        drop(file);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+// Buffering of frames while a vsock link is being re-established
+//
+// A failed write used to lose the frame being written, and everything
+// nfqueue delivered while the reconnect backoff slept was lost or
+// verdict-failed as well. Short enclave hiccups then reset every TCP
+// connection. Frames written to a broken link are now kept in a
+// bounded buffer and replayed in order once the link is back up. When
+// the buffer is full either the oldest frames make room for new ones
+// or new frames are dropped.
+
+use std::collections::VecDeque;
+
+use clap::ValueEnum;
+
+use crate::health::{Counter, Health};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReplayPolicy {
+    /// drop the oldest buffered frames to make room
+    DropOldest,
+    /// drop frames that do not fit
+    DropNewest,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayConfig {
+    /// max bytes of buffered frames, 0 disables buffering
+    pub capacity: usize,
+    pub policy: ReplayPolicy,
+}
+
+pub struct ReplayBuffer {
+    frames: VecDeque<Vec<u8>>,
+    bytes: usize,
+    config: ReplayConfig,
+    buffered: Counter,
+    replayed: Counter,
+    dropped: Counter,
+}
+
+impl ReplayBuffer {
+    pub fn new(config: ReplayConfig, health: &Health) -> Self {
+        ReplayBuffer {
+            frames: VecDeque::new(),
+            bytes: 0,
+            config,
+            buffered: health.counter("replay_buffered"),
+            replayed: health.counter("replay_replayed"),
+            dropped: health.counter("replay_dropped"),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Buffer a copy of frame, returns false if it was dropped
+    pub fn push(&mut self, frame: &[u8]) -> bool {
+        if frame.len() > self.config.capacity {
+            self.dropped.inc();
+            return false;
+        }
+
+        while self.bytes + frame.len() > self.config.capacity {
+            match self.config.policy {
+                ReplayPolicy::DropOldest => {
+                    let oldest = self.frames.pop_front().unwrap();
+                    self.bytes -= oldest.len();
+                    self.dropped.inc();
+                }
+                ReplayPolicy::DropNewest => {
+                    self.dropped.inc();
+                    return false;
+                }
+            }
+        }
+
+        self.bytes += frame.len();
+        self.frames.push_back(frame.to_vec());
+        self.buffered.inc();
+        true
+    }
+
+    /// Buffered frames oldest first, to be removed with pop once written
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.frames.iter().map(|frame| &frame[..])
+    }
+
+    /// Remove the oldest frame after it was replayed
+    pub fn pop(&mut self) {
+        if let Some(frame) = self.frames.pop_front() {
+            self.bytes -= frame.len();
+            self.replayed.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IPv4 packet of size bytes tagged with id
+    fn packet(id: u8, size: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; size];
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+        buf[size - 1] = id;
+        buf
+    }
+
+    fn buffered(buffer: &ReplayBuffer) -> Vec<u8> {
+        buffer.iter().map(|frame| frame[frame.len() - 1]).collect()
+    }
+
+    fn config(capacity: usize, policy: ReplayPolicy) -> ReplayConfig {
+        ReplayConfig { capacity, policy }
+    }
+
+    #[test]
+    fn drops_oldest_over_capacity() {
+        let health = Health::new();
+        let mut buffer = ReplayBuffer::new(config(250, ReplayPolicy::DropOldest), &health);
+
+        for id in 0..3 {
+            assert!(buffer.push(&packet(id, 100)));
+        }
+        // room for two only, the oldest went
+        assert_eq!(buffered(&buffer), [1, 2]);
+        // one large frame takes the place of both
+        assert!(buffer.push(&packet(3, 200)));
+        assert_eq!(buffered(&buffer), [3]);
+        // a frame over capacity is dropped whatever the policy
+        assert!(!buffer.push(&packet(4, 300)));
+        assert_eq!(buffered(&buffer), [3]);
+
+        assert_eq!(health.counter("replay_buffered").get(), 4);
+        assert_eq!(health.counter("replay_dropped").get(), 4);
+    }
+
+    #[test]
+    fn drops_newest_over_capacity() {
+        let health = Health::new();
+        let mut buffer = ReplayBuffer::new(config(250, ReplayPolicy::DropNewest), &health);
+
+        assert!(buffer.push(&packet(0, 100)));
+        assert!(buffer.push(&packet(1, 100)));
+        assert!(!buffer.push(&packet(2, 100)));
+        // a smaller frame still fits
+        assert!(buffer.push(&packet(3, 50)));
+        assert_eq!(buffered(&buffer), [0, 1, 3]);
+
+        // replayed frames make room again, oldest first
+        buffer.pop();
+        assert!(buffer.push(&packet(4, 100)));
+        assert_eq!(buffered(&buffer), [1, 3, 4]);
+        assert_eq!(health.counter("replay_replayed").get(), 1);
+        assert_eq!(health.counter("replay_dropped").get(), 1);
+
+        // nothing is buffered without capacity
+        let mut buffer = ReplayBuffer::new(config(0, ReplayPolicy::DropNewest), &health);
+        assert!(!buffer.push(&packet(5, 20)));
+        assert!(buffer.is_empty());
+    }
+}
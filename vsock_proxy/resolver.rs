@@ -0,0 +1,589 @@
+// DNS of the enclave, resolved on the parent over vsock
+//
+// UDP is not forwarded out of the enclave, so containers used to resolve
+// through a third-party DNS over HTTPS proxy. dns-forwarder now serves
+// DNS on the enclave address, UDP and TCP port 53, and hands every query
+// it cannot answer from its cache to dns-resolver on the parent in a DNS
+// frame (see frame.rs). The resolver asks the upstream server over UDP,
+// again over TCP if the answer is truncated, and sends the answer back
+// under the tag of the query.
+//
+// Queries go upstream as the client sent them, EDNS options and the DO
+// and CD bits included, so clients that validate get their DNSSEC
+// records. Answers are cached by name, type, class and those two bits,
+// for the lowest TTL of their records, negative answers for the TTL of
+// their SOA capped at MAX_NEGATIVE_TTL, and served with the TTLs counted
+// down. UDP clients get answers beyond their EDNS buffer size, 512 bytes
+// without EDNS, truncated so that they retry over TCP.
+//
+// The parent sees the queries and could forge answers, like it could
+// block the DoH server before, only validating clients can tell.
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::dns::{read_name, read_u16};
+
+/// UDP answers are cut to this size for clients without EDNS
+pub const MIN_UDP_SIZE: usize = 512;
+/// Seconds an answer is cached at most
+pub const MAX_TTL: u32 = 86400;
+/// Seconds a negative answer is cached at most
+pub const MAX_NEGATIVE_TTL: u32 = 300;
+/// Max queries waiting for an answer, more are dropped
+pub const MAX_PENDING: usize = 4096;
+
+const HEADER_LEN: usize = 12;
+const TYPE_SOA: u16 = 6;
+const TYPE_OPT: u16 = 41;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+const FLAG_CD: u16 = 0x0010;
+const OPCODE: u16 = 0x7800;
+const RCODE: u16 = 0x000f;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+// in the TTL field of the OPT record
+const EDNS_DO: u32 = 0x8000;
+
+// a resource record of a message
+struct Record {
+    record_type: u16,
+    class: u16,
+    // offset of the TTL
+    ttl_at: usize,
+    data: Range<usize>,
+}
+
+// end of the question section and the records of msg in order
+fn records(msg: &[u8]) -> Option<(usize, Vec<Record>)> {
+    let questions = read_u16(msg, 4)?;
+    let count = [6, 8, 10]
+        .iter()
+        .map(|at| read_u16(msg, *at).map(usize::from))
+        .sum::<Option<usize>>()?;
+
+    let mut at = HEADER_LEN;
+    for _ in 0..questions {
+        at = read_name(msg, at)?.1 + 4;
+    }
+    if at > msg.len() {
+        return None;
+    }
+    let question_end = at;
+
+    let mut records = Vec::with_capacity(count);
+    for _ in 0..count {
+        let next = read_name(msg, at)?.1;
+        let record_type = read_u16(msg, next)?;
+        let class = read_u16(msg, next + 2)?;
+        let data_length = read_u16(msg, next + 8)? as usize;
+        let data = next + 10..next + 10 + data_length;
+        msg.get(data.clone())?;
+        at = data.end;
+        records.push(Record {
+            record_type,
+            class,
+            ttl_at: next + 4,
+            data,
+        });
+    }
+    Some((question_end, records))
+}
+
+fn read_u32(msg: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(msg.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// What the answer to a query depends on, queries with the same key
+/// share cached answers
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct QueryKey {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+    pub dnssec_ok: bool,
+    pub checking_disabled: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query {
+    pub key: QueryKey,
+    /// largest UDP answer the client takes
+    pub udp_size: usize,
+    // end of the question section
+    question_end: usize,
+}
+
+/// Standard query of msg with a single question, None for anything else
+pub fn parse_query(msg: &[u8]) -> Option<Query> {
+    let flags = read_u16(msg, 2)?;
+    if flags & (FLAG_QR | OPCODE) != 0 || read_u16(msg, 4)? != 1 {
+        return None;
+    }
+    let (question_end, records) = records(msg)?;
+    let (name, next) = read_name(msg, HEADER_LEN)?;
+
+    let opt = records.iter().find(|record| record.record_type == TYPE_OPT);
+    let udp_size = opt.map_or(MIN_UDP_SIZE, |opt| usize::from(opt.class).max(MIN_UDP_SIZE));
+    let dnssec_ok = match opt {
+        Some(opt) => read_u32(msg, opt.ttl_at)? & EDNS_DO != 0,
+        None => false,
+    };
+
+    Some(Query {
+        key: QueryKey {
+            name,
+            qtype: read_u16(msg, next)?,
+            qclass: read_u16(msg, next + 2)?,
+            dnssec_ok,
+            checking_disabled: flags & FLAG_CD != 0,
+        },
+        udp_size,
+        question_end,
+    })
+}
+
+// seconds answer may be cached, None if it may not
+fn cache_ttl(answer: &[u8]) -> Option<u32> {
+    let flags = read_u16(answer, 2)?;
+    let answers = usize::from(read_u16(answer, 6)?);
+    let authorities = usize::from(read_u16(answer, 8)?);
+    if flags & FLAG_QR == 0 || flags & FLAG_TC != 0 {
+        return None;
+    }
+    let (_, records) = records(answer)?;
+
+    let ttl = match flags & RCODE {
+        0 if answers > 0 => records
+            .iter()
+            .filter(|record| record.record_type != TYPE_OPT)
+            .map(|record| read_u32(answer, record.ttl_at))
+            .min()??,
+        // no such name or no such record, as long as the SOA says
+        0 | RCODE_NXDOMAIN => {
+            let soa = records[answers..answers + authorities]
+                .iter()
+                .find(|record| record.record_type == TYPE_SOA)?;
+            let minimum = read_u32(answer, soa.data.end.checked_sub(4)?)?;
+            read_u32(answer, soa.ttl_at)?
+                .min(minimum)
+                .min(MAX_NEGATIVE_TTL)
+        }
+        _ => return None,
+    };
+    Some(ttl.min(MAX_TTL))
+}
+
+// header and question of msg, with no records
+fn question(msg: &[u8]) -> Vec<u8> {
+    let end = records(msg).map_or(HEADER_LEN, |(question_end, _)| question_end);
+    let mut question = msg[..end.min(msg.len())].to_vec();
+    if question.len() >= HEADER_LEN {
+        question[6..HEADER_LEN].fill(0);
+    }
+    question
+}
+
+/// Answer cut to the header and the question with the TC bit set, for
+/// UDP clients that cannot take it whole
+pub fn truncate(answer: &[u8]) -> Vec<u8> {
+    let mut truncated = question(answer);
+    if truncated.len() >= HEADER_LEN {
+        truncated[2] |= (FLAG_TC >> 8) as u8;
+    }
+    truncated
+}
+
+/// Answer to query telling the client that resolving it failed
+pub fn server_failure(query: &[u8]) -> Vec<u8> {
+    let mut failure = question(query);
+    if failure.len() >= HEADER_LEN {
+        let flags = read_u16(&failure, 2).unwrap() & (OPCODE | FLAG_RD | FLAG_CD);
+        let flags = flags | FLAG_QR | FLAG_RA | RCODE_SERVFAIL;
+        failure[2..4].copy_from_slice(&flags.to_be_bytes());
+    }
+    failure
+}
+
+struct CachedAnswer {
+    answer: Vec<u8>,
+    stored: u64,
+    expires: u64,
+}
+
+struct CacheState {
+    answers: HashMap<QueryKey, CachedAnswer>,
+    capacity: usize,
+}
+
+/// Answers by query, shared by the threads serving clients
+#[derive(Clone)]
+pub struct AnswerCache(Arc<Mutex<CacheState>>);
+
+impl AnswerCache {
+    /// Cache of at most capacity answers, 0 caches nothing
+    pub fn new(capacity: usize) -> Self {
+        AnswerCache(Arc::new(Mutex::new(CacheState {
+            answers: HashMap::new(),
+            capacity,
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        // a panic while holding the lock aborts the process anyway
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Cached answer to query msg at now, with its id and question and
+    /// the TTLs counted down
+    pub fn get(&self, msg: &[u8], query: &Query, now: u64) -> Option<Vec<u8>> {
+        let (mut answer, stored) = {
+            let state = self.lock();
+            let cached = state.answers.get(&query.key)?;
+            if cached.expires <= now {
+                return None;
+            }
+            (cached.answer.clone(), cached.stored)
+        };
+
+        let (question_end, records) = records(&answer)?;
+        // same name, but the client may have mixed its case
+        if question_end != query.question_end {
+            return None;
+        }
+        answer[..2].copy_from_slice(&msg[..2]);
+        answer[HEADER_LEN..question_end].copy_from_slice(&msg[HEADER_LEN..question_end]);
+
+        let elapsed = now.saturating_sub(stored) as u32;
+        for record in records.iter().filter(|r| r.record_type != TYPE_OPT) {
+            let ttl = read_u32(&answer, record.ttl_at)?.saturating_sub(elapsed);
+            answer[record.ttl_at..record.ttl_at + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+        Some(answer)
+    }
+
+    /// Remember answer to a query with key received at now, if it may be cached
+    pub fn insert(&self, key: QueryKey, answer: &[u8], now: u64) {
+        let Some(ttl) = cache_ttl(answer).filter(|ttl| *ttl > 0) else {
+            return;
+        };
+
+        let mut state = self.lock();
+        if state.answers.len() >= state.capacity && !state.answers.contains_key(&key) {
+            state.answers.retain(|_, cached| cached.expires > now);
+            if state.answers.len() >= state.capacity {
+                return;
+            }
+        }
+        state.answers.insert(
+            key,
+            CachedAnswer {
+                answer: answer.to_vec(),
+                stored: now,
+                expires: now + u64::from(ttl),
+            },
+        );
+    }
+}
+
+struct PendingState<T> {
+    next: u32,
+    waiting: HashMap<u32, (Instant, T)>,
+}
+
+/// Clients waiting for answers, by the tag their query went out with
+pub struct Pending<T> {
+    state: Mutex<PendingState<T>>,
+    timeout: Duration,
+}
+
+impl<T> Pending<T> {
+    /// Clients are given up on after timeout
+    pub fn new(timeout: Duration) -> Self {
+        Pending {
+            state: Mutex::new(PendingState {
+                next: 0,
+                waiting: HashMap::new(),
+            }),
+            timeout,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PendingState<T>> {
+        // a panic while holding the lock aborts the process anyway
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Tag for the query of client, None if MAX_PENDING clients wait already
+    pub fn insert(&self, client: T) -> Option<u32> {
+        let mut state = self.lock();
+        if state.waiting.len() >= MAX_PENDING {
+            return None;
+        }
+        let tag = state.next;
+        state.next = state.next.wrapping_add(1);
+        state
+            .waiting
+            .insert(tag, (Instant::now() + self.timeout, client));
+        Some(tag)
+    }
+
+    /// Client waiting for the answer under tag
+    pub fn take(&self, tag: u32) -> Option<T> {
+        self.lock().waiting.remove(&tag).map(|(_, client)| client)
+    }
+
+    /// Give up on clients waiting past their timeout at now, returns how many
+    pub fn expire(&self, now: Instant) -> usize {
+        let mut state = self.lock();
+        let waiting = state.waiting.len();
+        state.waiting.retain(|_, (deadline, _)| *deadline > now);
+        waiting - state.waiting.len()
+    }
+}
+
+fn timed_out() -> std::io::Error {
+    std::io::Error::new(ErrorKind::TimedOut, "no answer from upstream")
+}
+
+fn resolve_udp(upstream: SocketAddr, query: &[u8], deadline: Instant) -> std::io::Result<Vec<u8>> {
+    let local: SocketAddr = match upstream {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(upstream)?;
+    socket.send(query)?;
+
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let left = deadline.checked_duration_since(Instant::now());
+        socket.set_read_timeout(Some(
+            left.filter(|left| !left.is_zero()).ok_or_else(timed_out)?,
+        ))?;
+        let size = match socket.recv(&mut buf) {
+            Ok(size) => size,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(timed_out())
+            }
+            Err(e) => return Err(e),
+        };
+        // late answers to earlier queries from the same port are not ours
+        if size >= HEADER_LEN && buf[..2] == query[..2] {
+            buf.truncate(size);
+            return Ok(buf);
+        }
+    }
+}
+
+fn resolve_tcp(upstream: SocketAddr, query: &[u8], deadline: Instant) -> std::io::Result<Vec<u8>> {
+    let left = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|left| !left.is_zero())
+        .ok_or_else(timed_out)?;
+    let mut stream = TcpStream::connect_timeout(&upstream, left)?;
+    stream.set_read_timeout(Some(left))?;
+    stream.set_write_timeout(Some(left))?;
+
+    let mut buf = (query.len() as u16).to_be_bytes().to_vec();
+    buf.extend_from_slice(query);
+    stream.write_all(&buf)?;
+
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length)?;
+    let mut answer = vec![0u8; u16::from_be_bytes(length).into()];
+    stream.read_exact(&mut answer)?;
+    Ok(answer)
+}
+
+/// Ask upstream for the answer to query over UDP, and over TCP if the
+/// answer is truncated, within timeout
+pub fn resolve(upstream: SocketAddr, query: &[u8], timeout: Duration) -> std::io::Result<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    let answer = resolve_udp(upstream, query, deadline)?;
+    match read_u16(&answer, 2) {
+        Some(flags) if flags & FLAG_TC != 0 => resolve_tcp(upstream, query, deadline),
+        _ => Ok(answer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPE_A: u16 = 1;
+    const CLASS_IN: u16 = 1;
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut buf: Vec<u8> = name
+            .split('.')
+            .flat_map(|label| [&[label.len() as u8][..], label.as_bytes()].concat())
+            .collect();
+        buf.push(0);
+        buf
+    }
+
+    fn record(owner: &[u8], record_type: u16, class: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = owner.to_vec();
+        buf.extend_from_slice(&record_type.to_be_bytes());
+        buf.extend_from_slice(&class.to_be_bytes());
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    // OPT record for a 1232 byte buffer with the DO bit
+    fn opt() -> Vec<u8> {
+        record(&[0], TYPE_OPT, 1232, EDNS_DO, &[])
+    }
+
+    fn query(id: u16, owner: &str) -> Vec<u8> {
+        let mut msg = id.to_be_bytes().to_vec();
+        msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]);
+        msg.extend(name(owner));
+        msg.extend_from_slice(&[0, 1, 0, 1]);
+        msg.extend(opt());
+        msg
+    }
+
+    // answer to query with two addresses and the OPT record
+    fn answer(query: &[u8]) -> Vec<u8> {
+        let question_end = parse_query(query).unwrap().question_end;
+        let mut msg = query[..question_end].to_vec();
+        msg[2..4].copy_from_slice(&[0x81, 0x80]);
+        msg[6..12].copy_from_slice(&[0, 2, 0, 0, 0, 1]);
+        msg.extend(record(
+            &[0xc0, 12],
+            TYPE_A,
+            CLASS_IN,
+            300,
+            &[203, 0, 113, 7],
+        ));
+        msg.extend(record(&[0xc0, 12], TYPE_A, CLASS_IN, 60, &[203, 0, 113, 8]));
+        msg.extend(opt());
+        msg
+    }
+
+    fn ttls(msg: &[u8]) -> Vec<u32> {
+        let (_, records) = records(msg).unwrap();
+        records
+            .iter()
+            .map(|record| read_u32(msg, record.ttl_at).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_queries() {
+        let parsed = parse_query(&query(7, "Relay.Example.com")).unwrap();
+        assert_eq!(
+            parsed.key,
+            QueryKey {
+                name: "relay.example.com".to_owned(),
+                qtype: TYPE_A,
+                qclass: CLASS_IN,
+                dnssec_ok: true,
+                checking_disabled: false,
+            }
+        );
+        assert_eq!(parsed.udp_size, 1232);
+
+        // no EDNS, responses and short messages
+        let mut plain = query(7, "example.com");
+        plain[11] = 0;
+        plain.truncate(plain.len() - opt().len());
+        let parsed = parse_query(&plain).unwrap();
+        assert_eq!(
+            (parsed.udp_size, parsed.key.dnssec_ok),
+            (MIN_UDP_SIZE, false)
+        );
+        assert_eq!(parse_query(&answer(&plain)), None);
+        assert_eq!(parse_query(&plain[..20]), None);
+    }
+
+    #[test]
+    fn serves_cached_answers() {
+        let cache = AnswerCache::new(16);
+        let first = query(7, "example.com");
+        let parsed = parse_query(&first).unwrap();
+        cache.insert(parsed.key.clone(), &answer(&first), 1000);
+
+        // id and case of the later query, TTLs counted down, OPT untouched
+        let second = query(8, "EXAMPLE.com");
+        let parsed = parse_query(&second).unwrap();
+        let cached = cache.get(&second, &parsed, 1010).unwrap();
+        assert_eq!(cached[..2], second[..2]);
+        assert_eq!(cached[2..4], [0x81, 0x80]);
+        assert_eq!(
+            cached[12..parsed.question_end],
+            second[12..parsed.question_end]
+        );
+        assert_eq!(ttls(&cached), [290, 50, EDNS_DO]);
+
+        // kept for the lowest TTL
+        assert!(cache.get(&second, &parsed, 1059).is_some());
+        assert_eq!(cache.get(&second, &parsed, 1060), None);
+
+        // other types and DNSSEC bits are other answers
+        let mut checking_disabled = query(8, "example.com");
+        checking_disabled[3] |= FLAG_CD as u8;
+        let parsed = parse_query(&checking_disabled).unwrap();
+        assert_eq!(cache.get(&checking_disabled, &parsed, 1010), None);
+    }
+
+    #[test]
+    fn caches_negative_answers_per_soa() {
+        let cache = AnswerCache::new(16);
+        let msg = query(7, "missing.example.com");
+        let parsed = parse_query(&msg).unwrap();
+
+        let mut soa = name("ns.example.com");
+        soa.extend(name("admin.example.com"));
+        soa.extend_from_slice(&[0; 16]);
+        soa.extend_from_slice(&3600u32.to_be_bytes());
+        let mut nxdomain = msg[..parsed.question_end].to_vec();
+        nxdomain[2..4].copy_from_slice(&[0x81, 0x83]);
+        nxdomain[8..12].copy_from_slice(&[0, 1, 0, 0]);
+        nxdomain.extend(record(&name("example.com"), TYPE_SOA, CLASS_IN, 900, &soa));
+        cache.insert(parsed.key.clone(), &nxdomain, 1000);
+
+        // capped, the SOA asks for longer
+        assert!(cache.get(&msg, &parsed, 1299).is_some());
+        assert_eq!(cache.get(&msg, &parsed, 1300), None);
+
+        // server failures and truncated answers are not kept
+        let mut failure = answer(&msg);
+        failure[3] = 0x82;
+        let mut truncated = answer(&msg);
+        truncated[2] |= (FLAG_TC >> 8) as u8;
+        for answer in [failure, truncated] {
+            cache.insert(parsed.key.clone(), &answer, 2000);
+            assert_eq!(cache.get(&msg, &parsed, 2000), None);
+        }
+    }
+
+    #[test]
+    fn cuts_answers_to_the_question() {
+        let msg = query(7, "example.com");
+        let question_end = parse_query(&msg).unwrap().question_end;
+        let truncated = truncate(&answer(&msg));
+        assert_eq!(truncated.len(), question_end);
+        assert_eq!(truncated[2..12], [0x83, 0x80, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(truncated[12..], msg[12..question_end]);
+        let failure = server_failure(&msg);
+        assert_eq!(failure[2..12], [0x81, 0x82, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(failure[12..], msg[12..question_end]);
+
+        let pending = Pending::new(Duration::ZERO);
+        let tag = pending.insert("client").unwrap();
+        assert_eq!(pending.expire(Instant::now()), 1);
+        assert_eq!(pending.take(tag), None);
+    }
+}
@@ -0,0 +1,542 @@
+// Authenticated and encrypted vsock links
+//
+// The proxies used to trust whatever connected to their vsock ports, so
+// any process on the parent could inject packets into the enclave. With
+// --secure both ends of a link first run a Noise XX handshake, the
+// connecting side initiates. The enclave sends a Nitro attestation
+// document for its static key along with the key, the parent verifies
+// it (see attestation.rs) and that it attests the key the handshake
+// authenticated before any frame flows. The parent requires at least one
+// --pcr, an enclave built by anyone would pass the check otherwise, and
+// only takes any image with an explicit --allow-any-enclave. The enclave
+// pins the parent's static key with --parent-key, which --secure
+// requires, as otherwise any process on the parent could still complete
+// the handshake. The parent keeps its key across restarts with
+// --secure-key.
+//
+// After the handshake the byte stream of frames is carried in records,
+// a u16 length and a Noise transport message of up to 65535 bytes.
+// Records are authenticated and numbered by their implicit nonce, so
+// altered, replayed, reordered or dropped records fail to decrypt and
+// the link is reset like on any other read error.
+
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use clap::Args;
+use snow::{Builder, TransportState};
+use socket2::Socket;
+
+use crate::attestation::{attest, verify_attestation, AttestationPolicy};
+use crate::frame::{read_frame_part, write_frame};
+use crate::SocketError;
+
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+pub const KEY_LEN: usize = 32;
+
+const MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 2;
+// plaintext carried by a single record
+const MAX_RECORD_PAYLOAD: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+type Prove = dyn Fn(&[u8]) -> Result<Vec<u8>, String> + Send + Sync;
+type Check = dyn Fn(&[u8], &[u8]) -> Result<(), String> + Send + Sync;
+
+fn handshake_error(err: snow::Error) -> SocketError {
+    SocketError::HandshakeError(err.to_string())
+}
+
+/// Our static key, and how we prove who we are and check the peer
+pub struct SecureConfig {
+    private_key: Vec<u8>,
+    public_key: Vec<u8>,
+    // handshake payload sent with our static key
+    prove: Box<Prove>,
+    // checks the peer's handshake payload and static key
+    check: Box<Check>,
+}
+
+impl SecureConfig {
+    fn new(
+        private_key: Vec<u8>,
+        public_key: Vec<u8>,
+        prove: Box<Prove>,
+        check: Box<Check>,
+    ) -> Self {
+        SecureConfig {
+            private_key,
+            public_key,
+            prove,
+            check,
+        }
+    }
+
+    /// Enclave side, a key made once per process, the Nitro Secure Module
+    /// attests it anew on every handshake, the parent has to use parent_key
+    pub fn enclave(parent_key: [u8; KEY_LEN]) -> Result<Self, SocketError> {
+        let keypair = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .generate_keypair()
+            .map_err(handshake_error)?;
+
+        Ok(SecureConfig::new(
+            keypair.private,
+            keypair.public,
+            Box::new(|public_key| attest(public_key).map_err(|e| e.to_string())),
+            Box::new(move |_, remote_key| match parent_key[..] == remote_key[..] {
+                true => Ok(()),
+                false => Err("unexpected parent key".to_owned()),
+            }),
+        ))
+    }
+
+    /// Parent side, the enclave has to attest its key as per policy
+    pub fn parent(private_key: [u8; KEY_LEN], policy: AttestationPolicy) -> Self {
+        let secret = x25519_dalek::StaticSecret::from(private_key);
+        let public_key = x25519_dalek::PublicKey::from(&secret);
+
+        SecureConfig::new(
+            private_key.to_vec(),
+            public_key.as_bytes().to_vec(),
+            Box::new(|_| Ok(Vec::new())),
+            Box::new(move |payload, remote_key| {
+                let attested = verify_attestation(payload, &policy).map_err(|e| e.to_string())?;
+                match attested[..] == remote_key[..] {
+                    true => Ok(()),
+                    false => Err("attested key does not match".to_owned()),
+                }
+            }),
+        )
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+}
+
+fn write_message(socket: &Socket, message: &[u8]) -> Result<(), SocketError> {
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + message.len());
+    buf.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    buf.extend_from_slice(message);
+    write_frame(socket, &buf)
+}
+
+// a shutdown request is only reported before a message started, see read_frame_part
+fn read_message<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> Result<(), SocketError> {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    read_frame_part(reader, &mut header, true)?;
+    buf.resize(u16::from_be_bytes(header).into(), 0);
+    read_frame_part(reader, buf, false)
+}
+
+/// Run the handshake on a fresh connection, the connecting side initiates
+pub fn handshake(
+    socket: &Socket,
+    config: &SecureConfig,
+    initiator: bool,
+) -> Result<SecureChannel, SocketError> {
+    let builder =
+        Builder::new(NOISE_PARAMS.parse().unwrap()).local_private_key(&config.private_key);
+    let mut noise = match initiator {
+        true => builder.build_initiator(),
+        false => builder.build_responder(),
+    }
+    .map_err(handshake_error)?;
+
+    // -> e
+    // <- e, ee, s, es
+    // -> s, se
+    // payloads go with the static keys, the first message has none
+    let mut message = vec![0u8; MAX_MESSAGE_LEN];
+    let mut received = Vec::new();
+    let mut payload = Vec::new();
+    for index in 0..3 {
+        if (index % 2 == 0) == initiator {
+            let proof = match index {
+                0 => Vec::new(),
+                _ => (config.prove)(&config.public_key).map_err(SocketError::HandshakeError)?,
+            };
+            let size = noise
+                .write_message(&proof, &mut message)
+                .map_err(handshake_error)?;
+            write_message(socket, &message[..size])?;
+        } else {
+            read_message(&mut &*socket, &mut received)?;
+            let size = noise
+                .read_message(&received, &mut message)
+                .map_err(handshake_error)?;
+            payload = message[..size].to_vec();
+        }
+    }
+
+    let remote_key = noise
+        .get_remote_static()
+        .ok_or_else(|| SocketError::HandshakeError("no remote key".to_owned()))?;
+    (config.check)(&payload, remote_key).map_err(SocketError::HandshakeError)?;
+
+    let transport = noise.into_transport_mode().map_err(handshake_error)?;
+    Ok(SecureChannel::new(transport))
+}
+
+/// Transport keys of a link after the handshake
+pub struct SecureChannel {
+    transport: TransportState,
+    // decrypted bytes not read yet
+    plaintext: Vec<u8>,
+    read: usize,
+    record: Vec<u8>,
+}
+
+impl SecureChannel {
+    fn new(transport: TransportState) -> Self {
+        SecureChannel {
+            transport,
+            plaintext: Vec::new(),
+            read: 0,
+            record: Vec::new(),
+        }
+    }
+
+    /// Encrypt plaintext into records appended to out
+    pub fn seal(&mut self, plaintext: &[u8], out: &mut Vec<u8>) -> Result<(), SocketError> {
+        for chunk in plaintext.chunks(MAX_RECORD_PAYLOAD) {
+            let start = out.len();
+            out.resize(start + RECORD_HEADER_LEN + chunk.len() + TAG_LEN, 0);
+            let size = self
+                .transport
+                .write_message(chunk, &mut out[start + RECORD_HEADER_LEN..])
+                .map_err(handshake_error)?;
+            out[start..start + RECORD_HEADER_LEN].copy_from_slice(&(size as u16).to_be_bytes());
+        }
+
+        Ok(())
+    }
+
+    /// Read decrypted bytes like Read::read, a record that does not
+    /// decrypt was altered, replayed or reordered and fails the read
+    pub fn read<R: Read>(&mut self, reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.read == self.plaintext.len() {
+            match read_message(reader, &mut self.record) {
+                Ok(()) => {}
+                Err(SocketError::EofError) => return Ok(0),
+                Err(SocketError::ReadError(e)) => return Err(e),
+                Err(e) => return Err(std::io::Error::other(e)),
+            }
+
+            self.plaintext.resize(MAX_MESSAGE_LEN, 0);
+            let size = self
+                .transport
+                .read_message(&self.record, &mut self.plaintext)
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+            self.plaintext.truncate(size);
+            self.read = 0;
+        }
+
+        let size = buf.len().min(self.plaintext.len() - self.read);
+        buf[..size].copy_from_slice(&self.plaintext[self.read..self.read + size]);
+        self.read += size;
+        Ok(size)
+    }
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_key(arg: &str) -> Result<[u8; KEY_LEN], String> {
+    decode_hex(arg.trim())
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| format!("expected {KEY_LEN} hex encoded bytes"))
+}
+
+fn parse_pcr(arg: &str) -> Result<(usize, Vec<u8>), String> {
+    let (index, value) = arg.split_once('=').ok_or("expected <index>=<hex>")?;
+    let index = index
+        .parse()
+        .map_err(|_| format!("invalid pcr index {index}"))?;
+    let value = decode_hex(value).ok_or_else(|| format!("invalid pcr value {value}"))?;
+    Ok((index, value))
+}
+
+/// Secure link options of the enclave side proxies
+#[derive(Args, Clone, Debug)]
+pub struct EnclaveSecureArgs {
+    /// authenticate and encrypt the vsock link, its key is attested by the Nitro Secure Module
+    #[clap(long, requires = "parent_key")]
+    pub secure: bool,
+    /// hex public key the parent has to use on the secure link, printed by the parent
+    /// at startup, see its --secure-key
+    #[clap(long, value_parser = parse_key, requires = "secure")]
+    pub parent_key: Option<[u8; KEY_LEN]>,
+}
+
+impl EnclaveSecureArgs {
+    pub fn config(&self) -> anyhow::Result<Option<Arc<SecureConfig>>> {
+        if !self.secure {
+            return Ok(None);
+        }
+
+        // any process on the parent could complete the handshake otherwise
+        let parent_key = self.parent_key.context("--secure requires --parent-key")?;
+        let config = SecureConfig::enclave(parent_key)?;
+        Ok(Some(Arc::new(config)))
+    }
+}
+
+/// Secure link options of the parent side proxies
+#[derive(Args, Clone, Debug)]
+pub struct ParentSecureArgs {
+    /// authenticate and encrypt the vsock link, the enclave has to attest its key
+    #[clap(long)]
+    pub secure: bool,
+    /// file with the hex private key of the secure link, a new one is made on every start
+    /// otherwise, which the enclave would have to be given with --parent-key every time
+    #[clap(long, value_parser, requires = "secure")]
+    pub secure_key: Option<PathBuf>,
+    /// PCR the enclave has to attest <index>=<hex>, can be repeated, --secure
+    /// requires at least one
+    #[clap(long, value_parser = parse_pcr, requires = "secure")]
+    pub pcr: Vec<(usize, Vec<u8>)>,
+    /// accept any enclave image on the secure link, without --pcr
+    #[clap(long, requires = "secure", conflicts_with = "pcr")]
+    pub allow_any_enclave: bool,
+}
+
+impl ParentSecureArgs {
+    pub fn config(&self) -> anyhow::Result<Option<Arc<SecureConfig>>> {
+        if !self.secure {
+            return Ok(None);
+        }
+
+        // an enclave image built by anyone attests its key just as well
+        if self.pcr.is_empty() && !self.allow_any_enclave {
+            anyhow::bail!("--secure requires --pcr, or --allow-any-enclave to accept any image");
+        }
+
+        let private_key = match &self.secure_key {
+            Some(path) => {
+                let key = std::fs::read_to_string(path)
+                    .with_context(|| format!("could not read {}", path.display()))?;
+                parse_key(&key)
+                    .map_err(anyhow::Error::msg)
+                    .with_context(|| format!("invalid key in {}", path.display()))?
+            }
+            None => {
+                println!("no --secure-key given, the enclave has to pin a new --parent-key");
+                let keypair = Builder::new(NOISE_PARAMS.parse().unwrap()).generate_keypair()?;
+                keypair.private.try_into().unwrap()
+            }
+        };
+
+        let policy = AttestationPolicy {
+            pcrs: self.pcr.iter().cloned().collect(),
+            ..Default::default()
+        };
+        if self.allow_any_enclave {
+            println!("--allow-any-enclave given, any enclave image is accepted on the secure link");
+        }
+
+        let config = SecureConfig::parent(private_key, policy);
+        println!("secure link key: {}", encode_hex(config.public_key()));
+        Ok(Some(Arc::new(config)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+
+    // enclave pinning parent_key with a stand-in attestation, the attested key is the payload
+    fn enclave(parent_key: &[u8]) -> SecureConfig {
+        let mut config = SecureConfig::enclave(parent_key.try_into().unwrap()).unwrap();
+        config.prove = Box::new(|public_key| Ok(public_key.to_vec()));
+        config
+    }
+
+    fn parent(private_key: [u8; KEY_LEN]) -> SecureConfig {
+        let mut config = SecureConfig::parent(private_key, AttestationPolicy::default());
+        config.check = Box::new(|payload, remote_key| match payload == remote_key {
+            true => Ok(()),
+            false => Err("attestation".to_owned()),
+        });
+        config
+    }
+
+    fn pair() -> (Socket, Socket) {
+        let (a, b) = UnixStream::pair().unwrap();
+        (a.into(), b.into())
+    }
+
+    // enclave initiates like on the outgoing link
+    fn connect(
+        enclave: SecureConfig,
+        parent: SecureConfig,
+    ) -> (
+        Result<SecureChannel, SocketError>,
+        Result<SecureChannel, SocketError>,
+        (Socket, Socket),
+    ) {
+        let (enclave_socket, parent_socket) = pair();
+        let responder = std::thread::spawn(move || {
+            let channel = handshake(&parent_socket, &parent, false);
+            (channel, parent_socket)
+        });
+        let channel = handshake(&enclave_socket, &enclave, true);
+        // a failed side leaves the other one waiting
+        if channel.is_err() {
+            enclave_socket.shutdown(std::net::Shutdown::Both).unwrap();
+        }
+        let (parent_channel, parent_socket) = responder.join().unwrap();
+        (channel, parent_channel, (enclave_socket, parent_socket))
+    }
+
+    fn read_all(channel: &mut SecureChannel, mut reader: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            match channel.read(&mut reader, &mut buf)? {
+                0 => return Ok(out),
+                size => out.extend_from_slice(&buf[..size]),
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let parent_key = [7u8; KEY_LEN];
+        let parent_config = parent(parent_key);
+        let public_key = parent_config.public_key().to_vec();
+        let (enclave, parent, _sockets) = connect(enclave(&public_key), parent_config);
+        let (mut enclave, mut parent) = (enclave.unwrap(), parent.unwrap());
+
+        // frames larger than a record are split
+        let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        let mut sealed = Vec::new();
+        enclave.seal(&data, &mut sealed).unwrap();
+        enclave.seal(b"more", &mut sealed).unwrap();
+        let mut expected = data.clone();
+        expected.extend_from_slice(b"more");
+        assert_eq!(read_all(&mut parent, &sealed).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_tampering_and_replays() {
+        // first and second record of a fresh link
+        let records = || {
+            let parent = parent([1u8; KEY_LEN]);
+            let (enclave, parent, _sockets) = connect(enclave(parent.public_key()), parent);
+            let (mut enclave, parent) = (enclave.unwrap(), parent.unwrap());
+            let (mut first, mut second) = (Vec::new(), Vec::new());
+            enclave.seal(b"first", &mut first).unwrap();
+            enclave.seal(b"second", &mut second).unwrap();
+            (parent, first, second)
+        };
+
+        let (mut parent, first, second) = records();
+        let in_order = [first, second].concat();
+        assert_eq!(read_all(&mut parent, &in_order).unwrap(), b"firstsecond");
+
+        let (mut parent, mut first, _) = records();
+        *first.last_mut().unwrap() ^= 1;
+        assert!(read_all(&mut parent, &first).is_err());
+
+        let (mut parent, first, _) = records();
+        let replayed = [first.clone(), first].concat();
+        assert!(read_all(&mut parent, &replayed).is_err());
+
+        let (mut parent, first, second) = records();
+        let reordered = [second, first].concat();
+        assert!(read_all(&mut parent, &reordered).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_parent_key() {
+        let (enclave, _, _sockets) =
+            connect(enclave(&[0u8; KEY_LEN]), parent([2u8; KEY_LEN]));
+        assert!(matches!(enclave, Err(SocketError::HandshakeError(_))));
+    }
+
+    #[test]
+    fn rejects_unattested_enclave() {
+        let parent = parent([3u8; KEY_LEN]);
+        let mut enclave = enclave(parent.public_key());
+        enclave.prove = Box::new(|_| Ok(b"someone else".to_vec()));
+        let (_, parent, _sockets) = connect(enclave, parent);
+        assert!(matches!(parent, Err(SocketError::HandshakeError(_))));
+    }
+
+    #[test]
+    fn secure_requires_parent_key() {
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct Cli {
+            #[clap(flatten)]
+            secure: EnclaveSecureArgs,
+        }
+        let key = encode_hex(&[4u8; KEY_LEN]);
+
+        assert!(Cli::try_parse_from(["proxy", "--secure"]).is_err());
+        assert!(Cli::try_parse_from(["proxy", "--parent-key", &key]).is_err());
+        let cli = Cli::try_parse_from(["proxy", "--secure", "--parent-key", &key]).unwrap();
+        assert_eq!(cli.secure.parent_key, Some([4u8; KEY_LEN]));
+        assert!(Cli::try_parse_from(["proxy"])
+            .unwrap()
+            .secure
+            .config()
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn secure_requires_pcr() {
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct Cli {
+            #[clap(flatten)]
+            secure: ParentSecureArgs,
+        }
+        let config = |args: &[&str]| Cli::try_parse_from(args).unwrap().secure.config();
+
+        assert!(config(&["proxy", "--secure"]).is_err());
+        assert!(config(&["proxy", "--secure", "--pcr", "0=00ff"])
+            .unwrap()
+            .is_some());
+        assert!(config(&["proxy", "--secure", "--allow-any-enclave"])
+            .unwrap()
+            .is_some());
+        assert!(Cli::try_parse_from(["proxy", "--allow-any-enclave"]).is_err());
+        assert!(Cli::try_parse_from([
+            "proxy",
+            "--secure",
+            "--pcr",
+            "0=00ff",
+            "--allow-any-enclave"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(decode_hex("00ff10"), Some(vec![0x00, 0xff, 0x10]));
+        assert_eq!(encode_hex(&[0x00, 0xff, 0x10]), "00ff10");
+        assert_eq!(decode_hex("0"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(parse_pcr("0=00ff"), Ok((0, vec![0x00, 0xff])));
+    }
+}
@@ -0,0 +1,229 @@
+// Sequence numbers on the vsock links
+//
+// Frames carry no sequence information of their own, so frames lost
+// while a sender reconnects, or sent twice by the replay, go unnoticed.
+// Senders with --sequence wrap every buffered frame in a sequenced frame
+// (see frame.rs) numbered before it enters the replay buffer, so a
+// replayed frame keeps its number. Heartbeats are not numbered.
+//
+// Receivers track the numbers per stream, the sender picks a new stream
+// id on every start, and count frames skipped (gaps), seen before
+// (duplicates) and arriving after later ones (reordered), so frames lost
+// are about seq_gaps - seq_reordered. A window of the last 64 numbers
+// tells duplicates from late frames, anything older counts as duplicate.
+// Frames are forwarded either way, the counters are for diagnosis.
+//
+// The send time gives the one-way latency, which includes the time a
+// frame spent in the replay buffer. The enclave clock follows the
+// host's, a skew between both shows up as an offset and negative
+// latencies as 0.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::frame::{parse_seq_frame, seq_frame};
+use crate::health::{Counter, Gauge, Health};
+
+const WINDOW: u64 = 64;
+
+/// Current time in us since the unix epoch
+pub fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Numbers frames of a sender, in the order they are written
+pub struct Sequencer {
+    stream: u32,
+    next: u64,
+}
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sequencer {
+    pub fn new() -> Self {
+        // differs between starts, it only has to tell streams apart
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+            .unwrap_or(0);
+        Self::with_stream(nanos ^ std::process::id().rotate_left(16))
+    }
+
+    pub fn with_stream(stream: u32) -> Self {
+        Sequencer { stream, next: 0 }
+    }
+
+    /// Wrap frame in a sequenced frame with the next number
+    pub fn wrap(&mut self, frame: &[u8]) -> Vec<u8> {
+        let buf = seq_frame(self.stream, self.next, now_us(), frame);
+        self.next += 1;
+        buf
+    }
+}
+
+/// Receiver side counters of sequenced frames
+pub struct SequenceTracker {
+    stream: Option<u32>,
+    // next expected number
+    next: u64,
+    // bit i set - next - 1 - i was seen
+    window: u64,
+    frames: Counter,
+    streams: Counter,
+    gaps: Counter,
+    duplicates: Counter,
+    reordered: Counter,
+    latency: Gauge,
+    latency_max: Gauge,
+    latency_total: Counter,
+}
+
+impl SequenceTracker {
+    pub fn new(health: &Health) -> Self {
+        SequenceTracker {
+            stream: None,
+            next: 0,
+            window: 0,
+            frames: health.counter("seq_frames"),
+            streams: health.counter("seq_streams"),
+            gaps: health.counter("seq_gaps"),
+            duplicates: health.counter("seq_duplicates"),
+            reordered: health.counter("seq_reordered"),
+            latency: health.gauge("seq_latency_us"),
+            latency_max: health.gauge("seq_latency_max_us"),
+            latency_total: health.counter("seq_latency_us_total"),
+        }
+    }
+
+    /// Count a sequenced frame and return the frame it carries,
+    /// any other frame is returned as is
+    pub fn unwrap<'a>(&mut self, frame: &'a [u8], now_us: u64) -> &'a [u8] {
+        match parse_seq_frame(frame) {
+            Some((stream, seq, sent_us, inner)) => {
+                self.record(stream, seq, sent_us, now_us);
+                inner
+            }
+            None => frame,
+        }
+    }
+
+    fn record(&mut self, stream: u32, seq: u64, sent_us: u64, now_us: u64) {
+        self.frames.inc();
+
+        let latency = now_us.saturating_sub(sent_us);
+        self.latency.set(latency);
+        self.latency_max.set_max(latency);
+        self.latency_total.add(latency);
+
+        // frames before the first one seen of a stream are not counted as lost,
+        // the receiver may have started later
+        if self.stream != Some(stream) {
+            self.stream = Some(stream);
+            self.streams.inc();
+            (self.next, self.window) = (seq.saturating_add(1), 1);
+            return;
+        }
+
+        if seq >= self.next {
+            self.gaps.add(seq - self.next);
+            let shift = seq - self.next + 1;
+            self.window = if shift >= WINDOW {
+                0
+            } else {
+                self.window << shift
+            };
+            self.window |= 1;
+            self.next = seq.saturating_add(1);
+            return;
+        }
+
+        let age = self.next - 1 - seq;
+        if age >= WINDOW || self.window & (1 << age) != 0 {
+            self.duplicates.inc();
+        } else {
+            self.window |= 1 << age;
+            self.reordered.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(health: &Health) -> (u64, u64, u64, u64) {
+        (
+            health.counter("seq_frames").get(),
+            health.counter("seq_gaps").get(),
+            health.counter("seq_duplicates").get(),
+            health.counter("seq_reordered").get(),
+        )
+    }
+
+    #[test]
+    fn unwraps_and_counts() {
+        let health = Health::new();
+        let mut tracker = SequenceTracker::new(&health);
+        let mut sequencer = Sequencer::with_stream(1);
+
+        let frames: Vec<Vec<u8>> = (0..5u8).map(|i| sequencer.wrap(&[0x45, i])).collect();
+        // plain frames pass through
+        assert_eq!(tracker.unwrap(&[0x45, 9], 0), &[0x45, 9]);
+
+        // 0, 1, 3, 2, 2, 4, 0
+        for index in [0, 1, 3, 2, 2, 4, 0] {
+            let inner = tracker.unwrap(&frames[index], u64::MAX);
+            assert_eq!(inner, &[0x45, index as u8]);
+        }
+        assert_eq!(counts(&health), (7, 1, 2, 1));
+        assert!(health.gauge("seq_latency_us").get() > 0);
+    }
+
+    #[test]
+    fn new_stream_restarts() {
+        let health = Health::new();
+        let mut tracker = SequenceTracker::new(&health);
+
+        tracker.record(1, 100, 0, 0);
+        tracker.record(1, 200, 0, 0);
+        // a restarted sender counts from 0 again
+        tracker.record(2, 0, 0, 0);
+        tracker.record(2, 1, 0, 0);
+        assert_eq!(counts(&health), (4, 99, 0, 0));
+        assert_eq!(health.counter("seq_streams").get(), 2);
+    }
+
+    #[test]
+    fn old_frames_are_duplicates() {
+        let health = Health::new();
+        let mut tracker = SequenceTracker::new(&health);
+
+        tracker.record(1, 0, 0, 0);
+        tracker.record(1, 1000, 0, 0);
+        // out of the window
+        tracker.record(1, 1, 0, 0);
+        // in the window, not seen yet
+        tracker.record(1, 990, 0, 0);
+        assert_eq!(counts(&health), (4, 999, 1, 1));
+    }
+
+    #[test]
+    fn latency() {
+        let health = Health::new();
+        let mut tracker = SequenceTracker::new(&health);
+
+        tracker.record(1, 0, 1_000, 1_500);
+        tracker.record(1, 1, 1_000, 1_200);
+        // clock skew
+        tracker.record(1, 2, 2_000, 1_000);
+        assert_eq!(health.gauge("seq_latency_us").get(), 0);
+        assert_eq!(health.gauge("seq_latency_max_us").get(), 500);
+        assert_eq!(health.counter("seq_latency_us_total").get(), 700);
+    }
+}
@@ -0,0 +1,636 @@
+// Bandwidth caps per container on the enclave side
+//
+// Docker limits CPU, memory and disk per container, but all of them
+// share the vsock link. With --shape-file the enclave side proxies cap
+// the upload (ip-to-vsock-raw-outgoing) and download
+// (vsock-to-ip-raw-incoming) rate of each container listed there:
+//
+//   # name    match              upload  download  burst
+//   tenant-a  ports=20000-20999  10mbit  50mbit    256k
+//   tenant-b  mark=0x200/0xff00  5mbit   -         64k
+//
+// A container is told apart by the ports it was given for SNAT, its
+// source port on upload and destination port on download, or by the
+// packet mark on upload, optionally under a mask like iptables --mark.
+// Set it from the container address in FORWARD, save it with CONNMARK
+// --save-mark and restore it with --restore-mark before NFQUEUE, the
+// low bits are taken by the routing mark already. Downloads carry no
+// mark, so a mark line must leave download "-". Later fragments carry no
+// ports, they are only shaped by mark. The first matching line wins,
+// "-" leaves a direction uncapped.
+//
+// Every container has a token bucket filled at its rate up to burst
+// bytes. Packets go out right away while it has tokens, a GSO packet
+// may take it below zero and is paid back over time. Beyond that they
+// are queued, up to burst bytes, and a release thread sends them once
+// the bucket allows, in order. Only a full queue drops packets. The
+// file is reloaded on change and on SIGHUP, containers keep their
+// queue and tokens across reloads, on shutdown the queues are flushed.
+
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::frame::parse_gso_frame;
+use crate::health::{Counter, Gauge, Health};
+use crate::packet::get_ports;
+use crate::reload::{watch_file, ConfigError};
+
+// burst when a line does not give one
+const DEFAULT_BURST: usize = 64 << 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// How the packets of a container are recognized
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Selector {
+    Ports(RangeInclusive<u16>),
+    Mark { mark: u32, mask: u32 },
+}
+
+impl Selector {
+    /// Whether a packet with port, the one of the container, and mark, if
+    /// known, is of the container
+    pub fn matches(&self, port: Option<u16>, mark: Option<u32>) -> bool {
+        match self {
+            Selector::Ports(ports) => port.is_some_and(|port| ports.contains(&port)),
+            Selector::Mark { mark: value, mask } => mark.is_some_and(|mark| mark & mask == *value),
+        }
+    }
+}
+
+/// Caps of a container, rates in bytes per second, None is uncapped
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShapeRule {
+    pub name: String,
+    pub selector: Selector,
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    pub burst: usize,
+}
+
+// rate in bits per second with a unit, bytes per second
+fn parse_rate(value: &str) -> Result<Option<u64>, String> {
+    if value == "-" {
+        return Ok(None);
+    }
+    let units = [
+        ("gbit", 1_000_000_000),
+        ("mbit", 1_000_000),
+        ("kbit", 1_000),
+        ("bit", 1),
+    ];
+    let (number, unit) = units
+        .iter()
+        .find_map(|(unit, bits)| Some((value.strip_suffix(unit)?, bits)))
+        .ok_or_else(|| format!("rate {value} needs a unit, bit, kbit, mbit or gbit"))?;
+    let bits: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid rate {value}"))?;
+    let bits = bits
+        .checked_mul(*unit)
+        .ok_or_else(|| format!("rate {value} is too large"))?;
+    match bits / 8 {
+        0 => Err(format!("rate {value} is below a byte per second")),
+        rate => Ok(Some(rate)),
+    }
+}
+
+// bytes, with an optional k or m suffix
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (number, unit) = match value.to_ascii_lowercase() {
+        v if v.ends_with('k') => (value[..v.len() - 1].to_owned(), 1 << 10),
+        v if v.ends_with('m') => (value[..v.len() - 1].to_owned(), 1 << 20),
+        _ => (value.to_owned(), 1),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .filter(|size| *size > 0)
+        .map(|size| size * unit)
+        .ok_or_else(|| format!("invalid size {value}"))
+}
+
+/// Parse ports=<port[-port]> or mark=<mark[/mask]>, marks in decimal or 0x hex
+pub fn parse_selector(value: &str) -> Result<Selector, String> {
+    let invalid =
+        || format!("invalid match {value}, expected ports=<port[-port]> or mark=<mark[/mask]>");
+    match value.split_once('=').ok_or_else(invalid)? {
+        ("ports", ports) => {
+            let port = |port: &str| port.parse::<u16>().map_err(|_| invalid());
+            let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+            Ok(Selector::Ports(port(start)?..=port(end)?))
+        }
+        ("mark", mark) => {
+            let number = |number: &str| {
+                match number.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => number.parse(),
+                }
+                .map_err(|_| invalid())
+            };
+            let (mark, mask) = match mark.split_once('/') {
+                Some((mark, mask)) => (number(mark)?, number(mask)?),
+                None => (number(mark)?, u32::MAX),
+            };
+            Ok(Selector::Mark {
+                mark: mark & mask,
+                mask,
+            })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_rule(line: &str) -> Result<ShapeRule, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [name, selector, upload, download, rest @ ..] = &fields[..] else {
+        return Err("expected <name> <match> <upload> <download> [burst]".to_owned());
+    };
+    let burst = match rest {
+        [] => DEFAULT_BURST,
+        [burst] => parse_size(burst)?,
+        _ => return Err("trailing fields".to_owned()),
+    };
+    let selector = parse_selector(selector)?;
+    let download = parse_rate(download)?;
+    // packets from the vsock link carry no mark
+    if matches!(selector, Selector::Mark { .. }) && download.is_some() {
+        return Err("a mark only matches on upload, download must be -".to_owned());
+    }
+    Ok(ShapeRule {
+        name: name.to_string(),
+        selector,
+        upload: parse_rate(upload)?,
+        download,
+        burst,
+    })
+}
+
+/// Parse shaping rules, one per line, # starts a comment
+pub fn parse_rules(content: &str) -> Result<Vec<ShapeRule>, (usize, String)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.split('#').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(number, line)| parse_rule(line).map_err(|reason| (number, reason)))
+        .collect()
+}
+
+pub fn read_rules_file(path: &Path) -> Result<Vec<ShapeRule>, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(|e| ConfigError::ReadError {
+        path: path.display().to_string(),
+        source: e,
+    })?;
+    parse_rules(&content).map_err(|(line, reason)| ConfigError::RuleError {
+        path: path.display().to_string(),
+        line,
+        reason,
+    })
+}
+
+/// Spawn a thread reloading the rules of shaper from path on file change or SIGHUP
+pub fn watch_rules_file(path: PathBuf, shaper: Shaper) -> Result<JoinHandle<()>, ConfigError> {
+    let watched = path.clone();
+    watch_file(watched, move || match read_rules_file(&path) {
+        Ok(rules) => shaper.set_rules(&rules),
+        Err(err) => {
+            // keep the old rules, the file might be mid-write
+            println!("{:?}", anyhow::Error::from(err));
+        }
+    })
+}
+
+struct Bucket {
+    name: String,
+    selector: Selector,
+    // bytes per second
+    rate: u64,
+    burst: usize,
+    // may be negative after a packet larger than what was left
+    tokens: f64,
+    last: Instant,
+    queue: VecDeque<Vec<u8>>,
+    queued: usize,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        self.last = now;
+    }
+
+    // time until the bucket is out of debt again
+    fn ready_in(&self) -> Duration {
+        Duration::from_secs_f64((-self.tokens).max(0.0) / self.rate as f64)
+    }
+}
+
+struct Counters {
+    queued: Counter,
+    dropped: Counter,
+    queue_bytes: Gauge,
+}
+
+type Sink = Box<dyn FnMut(&[&[u8]]) + Send>;
+
+struct ShaperState {
+    buckets: Vec<Bucket>,
+    // writes released packets, called with the lock held so that
+    // nothing of a container gets ahead of its queue
+    sink: Sink,
+}
+
+struct ShaperInner {
+    direction: Direction,
+    state: Mutex<ShaperState>,
+    queued: Condvar,
+    counters: Counters,
+}
+
+/// Caps the rate of the containers in a direction
+#[derive(Clone)]
+pub struct Shaper(Arc<ShaperInner>);
+
+impl Shaper {
+    /// Shape direction as per rules, queued packets are released to sink
+    /// from a thread of its own
+    pub fn new<F: FnMut(&[&[u8]]) + Send + 'static>(
+        direction: Direction,
+        rules: &[ShapeRule],
+        health: &Health,
+        sink: F,
+    ) -> Self {
+        let counters = match direction {
+            Direction::Upload => Counters {
+                queued: health.counter("upload_shaped_queued"),
+                dropped: health.counter("upload_shaped_dropped"),
+                queue_bytes: health.gauge("upload_shaped_queue_bytes"),
+            },
+            Direction::Download => Counters {
+                queued: health.counter("download_shaped_queued"),
+                dropped: health.counter("download_shaped_dropped"),
+                queue_bytes: health.gauge("download_shaped_queue_bytes"),
+            },
+        };
+        let shaper = Shaper(Arc::new(ShaperInner {
+            direction,
+            state: Mutex::new(ShaperState {
+                buckets: Vec::new(),
+                sink: Box::new(sink),
+            }),
+            queued: Condvar::new(),
+            counters,
+        }));
+        shaper.set_rules(rules);
+
+        let releaser = shaper.clone();
+        std::thread::spawn(move || releaser.run_release());
+
+        shaper
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ShaperState> {
+        // a panic while holding the lock aborts the process anyway
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Replace the rules, containers still listed keep their queue and
+    /// tokens, the queues of the others are sent right away
+    pub fn set_rules(&self, rules: &[ShapeRule]) {
+        let mut state = self.lock();
+        let now = Instant::now();
+
+        let mut old = std::mem::take(&mut state.buckets);
+        for rule in rules {
+            let rate = match self.0.direction {
+                Direction::Upload => rule.upload,
+                Direction::Download => rule.download,
+            };
+            let Some(rate) = rate else {
+                continue;
+            };
+
+            let bucket = match old.iter().position(|bucket| bucket.name == rule.name) {
+                Some(index) => {
+                    let mut bucket = old.swap_remove(index);
+                    bucket.refill(now);
+                    bucket.selector = rule.selector.clone();
+                    bucket.rate = rate;
+                    bucket.burst = rule.burst;
+                    bucket
+                }
+                None => Bucket {
+                    name: rule.name.clone(),
+                    selector: rule.selector.clone(),
+                    rate,
+                    burst: rule.burst,
+                    tokens: rule.burst as f64,
+                    last: now,
+                    queue: VecDeque::new(),
+                    queued: 0,
+                },
+            };
+            state.buckets.push(bucket);
+        }
+
+        let released: Vec<&[u8]> = old
+            .iter()
+            .flat_map(|bucket| bucket.queue.iter().map(Vec::as_slice))
+            .collect();
+        self.update_queue_bytes(&state);
+        if !released.is_empty() {
+            (state.sink)(&released);
+        }
+        println!(
+            "{:?} shaping {} containers",
+            self.0.direction,
+            state.buckets.len()
+        );
+
+        // rates may have gone up
+        self.0.queued.notify_one();
+    }
+
+    fn update_queue_bytes(&self, state: &ShaperState) {
+        let bytes = state
+            .buckets
+            .iter()
+            .map(|bucket| bucket.queued)
+            .sum::<usize>();
+        self.0.counters.queue_bytes.set(bytes as u64);
+    }
+
+    // container of a packet, by its bucket
+    fn bucket_for(&self, buckets: &[Bucket], packet: &[u8], mark: Option<u32>) -> Option<usize> {
+        let port = get_ports(packet).map(|(src_port, dst_port)| match self.0.direction {
+            Direction::Upload => src_port,
+            Direction::Download => dst_port,
+        });
+
+        buckets
+            .iter()
+            .position(|bucket| bucket.selector.matches(port, mark))
+    }
+
+    /// Frames that may go out now, in order, the others are queued for
+    /// the release thread or dropped, marks go with the frames if known
+    pub fn admit<'a>(&self, frames: &[&'a [u8]], marks: Option<&[u32]>) -> Vec<&'a [u8]> {
+        let mut state = self.lock();
+        if state.buckets.is_empty() {
+            return frames.to_vec();
+        }
+
+        let now = Instant::now();
+        let mut admitted = Vec::with_capacity(frames.len());
+        let mut queued = false;
+        for (index, frame) in frames.iter().enumerate() {
+            // GSO frames go by the packet they carry
+            let packet = parse_gso_frame(frame).map_or(*frame, |(_, packet)| packet);
+            let mark = marks.map(|marks| marks[index]);
+            let Some(bucket) = self.bucket_for(&state.buckets, packet, mark) else {
+                admitted.push(*frame);
+                continue;
+            };
+
+            let bucket = &mut state.buckets[bucket];
+            bucket.refill(now);
+            if bucket.queue.is_empty() && bucket.tokens >= 0.0 {
+                bucket.tokens -= frame.len() as f64;
+                admitted.push(*frame);
+            } else if bucket.queued + frame.len() <= bucket.burst {
+                bucket.queue.push_back(frame.to_vec());
+                bucket.queued += frame.len();
+                self.0.counters.queued.inc();
+                queued = true;
+            } else {
+                self.0.counters.dropped.inc();
+            }
+        }
+
+        if queued {
+            self.update_queue_bytes(&state);
+            self.0.queued.notify_one();
+        }
+
+        admitted
+    }
+
+    /// Send the queued frames right away, in order, for a shutdown
+    pub fn flush(&self) {
+        let mut state = self.lock();
+        let mut released = Vec::new();
+        for bucket in state.buckets.iter_mut() {
+            released.extend(bucket.queue.drain(..));
+            bucket.queued = 0;
+        }
+        self.update_queue_bytes(&state);
+        if !released.is_empty() {
+            let frames: Vec<&[u8]> = released.iter().map(Vec::as_slice).collect();
+            (state.sink)(&frames);
+        }
+    }
+
+    fn run_release(&self) {
+        let mut state = self.lock();
+        loop {
+            let now = Instant::now();
+            let mut released = Vec::new();
+            let mut wait: Option<Duration> = None;
+            for bucket in state.buckets.iter_mut() {
+                if bucket.queue.is_empty() {
+                    continue;
+                }
+                bucket.refill(now);
+                while bucket.tokens >= 0.0 {
+                    let Some(frame) = bucket.queue.pop_front() else {
+                        break;
+                    };
+                    bucket.tokens -= frame.len() as f64;
+                    bucket.queued -= frame.len();
+                    released.push(frame);
+                }
+                if !bucket.queue.is_empty() {
+                    let ready_in = bucket.ready_in();
+                    wait = Some(wait.map_or(ready_in, |wait| wait.min(ready_in)));
+                }
+            }
+
+            if !released.is_empty() {
+                self.update_queue_bytes(&state);
+                let frames: Vec<&[u8]> = released.iter().map(Vec::as_slice).collect();
+                (state.sink)(&frames);
+            }
+
+            state = match wait {
+                // at least a ms, tokens for a whole packet take that long anyway
+                Some(wait) => {
+                    let wait = wait.max(Duration::from_millis(1));
+                    self.0
+                        .queued
+                        .wait_timeout(state, wait)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self.0.queued.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::packet::UDP;
+
+    // IPv4 UDP packet of size bytes between ports
+    fn udp(size: usize, ports: (u16, u16)) -> Vec<u8> {
+        let mut buf = vec![0u8; size];
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+        buf[9] = UDP;
+        buf[20..22].copy_from_slice(&ports.0.to_be_bytes());
+        buf[22..24].copy_from_slice(&ports.1.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules = parse_rules(
+            "# name match upload download burst\n\
+             a ports=20000-20999 8mbit 80kbit 256k\n\
+             \n\
+             b mark=0x2 1gbit - # comment\n\
+             c mark=0x1ff/0xff00 1gbit -\n",
+        )
+        .unwrap();
+        assert_eq!(
+            rules[..2],
+            [
+                ShapeRule {
+                    name: "a".to_owned(),
+                    selector: Selector::Ports(20000..=20999),
+                    upload: Some(1_000_000),
+                    download: Some(10_000),
+                    burst: 256 << 10,
+                },
+                ShapeRule {
+                    name: "b".to_owned(),
+                    selector: Selector::Mark {
+                        mark: 2,
+                        mask: u32::MAX,
+                    },
+                    upload: Some(125_000_000),
+                    download: None,
+                    burst: DEFAULT_BURST,
+                },
+            ]
+        );
+
+        // bits outside the mask are ignored, like iptables --mark
+        assert_eq!(
+            rules[2].selector,
+            Selector::Mark {
+                mark: 0x100,
+                mask: 0xff00,
+            }
+        );
+        assert!(rules[2].selector.matches(None, Some(0x101)));
+        assert!(!rules[2].selector.matches(None, Some(0x201)));
+        assert!(!rules[2].selector.matches(Some(1000), None));
+
+        assert_eq!(parse_rules("a ports=1 8mbit\n").unwrap_err().0, 1);
+        assert!(parse_rules("a ports=1 8mb -\n").is_err());
+        assert!(parse_rules("a port=1 8mbit -\n").is_err());
+        assert!(parse_rules("a mark=x 8mbit -\n").is_err());
+        assert!(parse_rules("a mark=1 8mbit - 0\n").is_err());
+        assert!(parse_rules("a ports=1 - 18446744073709551615gbit\n").is_err());
+        // downloads carry no mark
+        assert!(parse_rules("a mark=1 8mbit 8mbit\n").is_err());
+    }
+
+    #[test]
+    fn queues_then_drops() {
+        let rule = parse_rule("a ports=1000-1999 8bit - 3k").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let shaper = Shaper::new(Direction::Upload, &[rule], &Health::new(), move |frames| {
+            tx.send(frames.len()).unwrap();
+        });
+
+        // 3k of tokens, the fourth packet runs into debt, then 3k of queue
+        let packets: Vec<Vec<u8>> = (0..8).map(|_| udp(1024, (1000, 443))).collect();
+        let other = udp(1024, (2000, 443));
+        let mut frames: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+        frames.push(&other);
+
+        let admitted = shaper.admit(&frames, None);
+        assert_eq!(admitted.len(), 5);
+        assert_eq!(admitted.last(), Some(&&other[..]));
+        let health = &shaper.0.counters;
+        assert_eq!(health.queued.get(), 3);
+        assert_eq!(health.dropped.get(), 1);
+        assert_eq!(health.queue_bytes.get(), 3 << 10);
+
+        // at a byte per second nothing is released, until the rule goes away
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        shaper.set_rules(&[]);
+        assert_eq!(rx.recv().unwrap(), 3);
+        assert_eq!(shaper.admit(&frames, None).len(), frames.len());
+    }
+
+    #[test]
+    fn flushes_queues() {
+        let rule = parse_rule("a ports=1000-1999 8bit - 3k").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let shaper = Shaper::new(Direction::Upload, &[rule], &Health::new(), move |frames| {
+            tx.send(frames.len()).unwrap();
+        });
+
+        let packets: Vec<Vec<u8>> = (0..6).map(|_| udp(1024, (1000, 443))).collect();
+        let frames: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+        assert_eq!(shaper.admit(&frames, None).len(), 4);
+
+        // the queue goes out at once, not at a byte per second
+        shaper.flush();
+        assert_eq!(rx.recv().unwrap(), 2);
+        assert_eq!(shaper.0.counters.queue_bytes.get(), 0);
+    }
+
+    #[test]
+    fn releases_at_rate() {
+        let rule = parse_rule("a ports=1000-1999 - 8mbit 4k").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let shaper = Shaper::new(
+            Direction::Download,
+            &[rule],
+            &Health::new(),
+            move |frames| {
+                tx.send(frames.len()).unwrap();
+            },
+        );
+
+        // packets are matched by destination port on download
+        let packets: Vec<Vec<u8>> = (0..8).map(|_| udp(1024, (443, 1000))).collect();
+        let frames: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+        assert_eq!(shaper.admit(&frames, None).len(), 5);
+
+        // 1MB/s drains the queue in a few ms, the gauge is updated before
+        // the frames are sent
+        let mut released = 0;
+        while released < 3 {
+            released += rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        }
+        assert_eq!(shaper.0.counters.queue_bytes.get(), 0);
+    }
+}
@@ -0,0 +1,115 @@
+// Graceful shutdown on SIGTERM/SIGINT
+//
+// The handler only records the request. It is installed without
+// SA_RESTART so that the blocking nfqueue/vsock/accept calls of the
+// main thread return EINTR and the data path gets a chance to stop at
+// a frame boundary. If the signal lands on a helper thread it is
+// forwarded to the main thread, otherwise the main thread would sleep
+// through it, and to the other threads of the data path registered with
+// register_shutdown_thread.
+
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::reload::ConfigError;
+use crate::ProxyError;
+
+// 0 - no shutdown requested, otherwise the signal number
+static SHUTDOWN_SIGNAL: AtomicI32 = AtomicI32::new(0);
+static MAIN_THREAD: AtomicU64 = AtomicU64::new(0);
+
+// data path threads besides the main one, a fixed array since the
+// handler must not lock
+const MAX_THREADS: usize = 64;
+static THREADS: [AtomicU64; MAX_THREADS] = [const { AtomicU64::new(0) }; MAX_THREADS];
+static THREAD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_shutdown(signal: libc::c_int) {
+    // forwarded signals land here again, only the first one is forwarded
+    if SHUTDOWN_SIGNAL.swap(signal, Ordering::Relaxed) != 0 {
+        return;
+    }
+
+    let current = unsafe { libc::pthread_self() };
+    let count = THREAD_COUNT.load(Ordering::Relaxed).min(MAX_THREADS);
+    let threads = THREADS[..count]
+        .iter()
+        .map(|thread| thread.load(Ordering::Relaxed));
+    for thread in std::iter::once(MAIN_THREAD.load(Ordering::Relaxed)).chain(threads) {
+        let thread = thread as libc::pthread_t;
+        if thread != 0 && thread != current {
+            unsafe { libc::pthread_kill(thread, signal) };
+        }
+    }
+}
+
+/// Install SIGTERM/SIGINT handlers, must be called from the main thread
+pub fn register_shutdown() -> Result<(), ConfigError> {
+    MAIN_THREAD.store(unsafe { libc::pthread_self() } as u64, Ordering::Relaxed);
+
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = on_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // no SA_RESTART, blocking calls must be interrupted
+    action.sa_flags = 0;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } < 0 {
+            return Err(ConfigError::SignalError(
+                signal,
+                std::io::Error::last_os_error(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Have the shutdown interrupt the blocking calls of the current thread
+/// too, for threads of the data path besides the main one
+pub fn register_shutdown_thread() {
+    let index = THREAD_COUNT.fetch_add(1, Ordering::Relaxed);
+    assert!(index < MAX_THREADS, "too many shutdown threads");
+    THREADS[index].store(unsafe { libc::pthread_self() } as u64, Ordering::Relaxed);
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_SIGNAL.load(Ordering::Relaxed) != 0
+}
+
+/// Signal that requested the shutdown, if any
+pub fn shutdown_signal() -> Option<libc::c_int> {
+    match SHUTDOWN_SIGNAL.load(Ordering::Relaxed) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+pub fn log_shutdown() {
+    println!(
+        "shutting down on signal {}",
+        shutdown_signal().unwrap_or_default()
+    );
+}
+
+/// Errors caused by the shutdown interrupting a blocking call
+/// are reported as a shutdown and not as a broken socket
+pub fn or_shutdown(err: ProxyError) -> ProxyError {
+    if shutdown_requested() {
+        ProxyError::Shutdown
+    } else {
+        err
+    }
+}
+
+/// Exit status of main for what it ran, a shutdown that cancelled a
+/// (re)connect exits cleanly, nothing was in flight then. Senders check
+/// for frames lost on shutdown themselves, after draining.
+pub fn exit_status(result: anyhow::Result<()>) -> anyhow::Result<()> {
+    match result {
+        Err(err) if matches!(err.downcast_ref(), Some(ProxyError::Shutdown)) => {
+            log_shutdown();
+            Ok(())
+        }
+        result => result,
+    }
+}
@@ -0,0 +1,554 @@
+// Byte streams between TCP and vsock, for services of the parent
+//
+// Besides the packet proxies the enclave reaches a couple of parent
+// services (its API, rclone WebDAV) as plain byte streams over vsock,
+// which used to take a socat pair per service. vsock-stream-forward
+// takes any number of mappings, `<listen> <connect>` with endpoints
+// tcp:<ip>:<port> or vsock:<cid>:<port> (cid any to listen on all), and
+// for every connection accepted on one side opens one to the other and
+// copies bytes both ways, each way in a thread of its own. An EOF is
+// passed on as a half close, the pair is done once both ways are.
+//
+// Every mapping takes at most max_conns connections at once, more are
+// closed right away. TCP sockets on both sides get keepalives so that
+// a peer gone without a FIN does not hold a connection forever. vsock
+// has no keepalives, its sockets get read and write timeouts instead and
+// a connection that moved no bytes either way for idle_timeout is shut
+// down, one way going quiet alone does not count.
+
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, SockAddr, Socket, TcpKeepalive, Type};
+
+use crate::health::{Counter, Gauge, Health};
+use crate::reload::ConfigError;
+use crate::shutdown::{register_shutdown_thread, shutdown_requested};
+use crate::{run_with_backoff, ProxyError, SocketError};
+
+// pending connections per listener
+const BACKLOG: i32 = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Vsock { cid: u32, port: u32 },
+}
+
+impl Endpoint {
+    fn addr(&self) -> SockAddr {
+        match *self {
+            Endpoint::Tcp(addr) => addr.into(),
+            Endpoint::Vsock { cid, port } => SockAddr::vsock(cid, port),
+        }
+    }
+
+    fn socket(&self) -> std::io::Result<Socket> {
+        let domain = match self {
+            Endpoint::Tcp(addr) => Domain::for_address(*addr),
+            Endpoint::Vsock { .. } => Domain::VSOCK,
+        };
+        Socket::new(domain, Type::STREAM, None)
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some(("tcp", addr)) => addr
+                .parse()
+                .map(Endpoint::Tcp)
+                .map_err(|_| format!("invalid tcp address {addr}")),
+            Some(("vsock", addr)) => {
+                let invalid = || format!("invalid vsock address {addr}");
+                let (cid, port) = addr.split_once(':').ok_or_else(invalid)?;
+                let cid = match cid {
+                    "any" => libc::VMADDR_CID_ANY,
+                    cid => cid.parse().map_err(|_| invalid())?,
+                };
+                let port = port.parse().map_err(|_| invalid())?;
+                Ok(Endpoint::Vsock { cid, port })
+            }
+            _ => Err(format!(
+                "invalid endpoint {value}, expected tcp:... or vsock:..."
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "tcp:{addr}"),
+            Endpoint::Vsock { cid, port } if *cid == libc::VMADDR_CID_ANY => {
+                write!(f, "vsock:any:{port}")
+            }
+            Endpoint::Vsock { cid, port } => write!(f, "vsock:{cid}:{port}"),
+        }
+    }
+}
+
+/// Connections accepted on listen are forwarded to connect
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    pub listen: Endpoint,
+    pub connect: Endpoint,
+}
+
+impl FromStr for Mapping {
+    type Err = String;
+
+    /// `<listen>=<connect>`, as given on the command line
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (listen, connect) = value
+            .split_once('=')
+            .ok_or_else(|| format!("invalid mapping {value}, expected <listen>=<connect>"))?;
+        Ok(Mapping {
+            listen: listen.parse()?,
+            connect: connect.parse()?,
+        })
+    }
+}
+
+fn parse_mapping(line: &str) -> Result<Mapping, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [listen, connect] = fields[..] else {
+        return Err("expected <listen> <connect>".to_owned());
+    };
+    Ok(Mapping {
+        listen: listen.parse()?,
+        connect: connect.parse()?,
+    })
+}
+
+/// Mappings of a file, one `<listen> <connect>` per line, # starts a comment
+pub fn parse_mappings(content: &str) -> Result<Vec<Mapping>, (usize, String)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.split('#').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(number, line)| parse_mapping(line).map_err(|reason| (number, reason)))
+        .collect()
+}
+
+pub fn read_mappings_file(path: &Path) -> Result<Vec<Mapping>, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(|e| ConfigError::ReadError {
+        path: path.display().to_string(),
+        source: e,
+    })?;
+    parse_mappings(&content).map_err(|(line, reason)| ConfigError::RuleError {
+        path: path.display().to_string(),
+        line,
+        reason,
+    })
+}
+
+/// Limits and metrics shared by all mappings
+#[derive(Clone)]
+pub struct StreamConfig {
+    /// connections of a mapping open at once, more are closed right away
+    pub max_conns: usize,
+    /// idle time before TCP keepalives are sent, None disables them
+    pub keepalive: Option<Duration>,
+    /// idle time after which connections over vsock are closed, None never
+    pub idle_timeout: Option<Duration>,
+    accepted: Counter,
+    rejected: Counter,
+    failed: Counter,
+    bytes_out: Counter,
+    bytes_in: Counter,
+    active: Gauge,
+    open: Arc<AtomicUsize>,
+}
+
+impl StreamConfig {
+    pub fn new(
+        max_conns: usize,
+        keepalive: Option<Duration>,
+        idle_timeout: Option<Duration>,
+        health: &Health,
+    ) -> Self {
+        StreamConfig {
+            max_conns,
+            keepalive,
+            idle_timeout,
+            accepted: health.counter("stream_accepted"),
+            rejected: health.counter("stream_rejected"),
+            failed: health.counter("stream_connect_failed"),
+            bytes_out: health.counter("stream_bytes_out"),
+            bytes_in: health.counter("stream_bytes_in"),
+            active: health.gauge("stream_active"),
+            open: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn opened(&self) {
+        let open = self.open.fetch_add(1, Ordering::Relaxed) + 1;
+        self.active.set(open as u64);
+    }
+
+    fn closed(&self) {
+        let open = self.open.fetch_sub(1, Ordering::Relaxed) - 1;
+        self.active.set(open as u64);
+    }
+
+    // keepalives for TCP, timeouts for vsock to check for idle connections
+    fn set_timeouts(&self, socket: &Socket, endpoint: &Endpoint) -> Result<(), SocketError> {
+        match (endpoint, self.keepalive, self.idle_timeout) {
+            (Endpoint::Tcp(_), Some(time), _) => {
+                let keepalive = TcpKeepalive::new().with_time(time).with_interval(time);
+                socket
+                    .set_tcp_keepalive(&keepalive)
+                    .map_err(|e| SocketError::OptionError("SO_KEEPALIVE".to_owned(), e))
+            }
+            (Endpoint::Vsock { .. }, _, Some(timeout)) => {
+                socket
+                    .set_read_timeout(Some(timeout))
+                    .map_err(|e| SocketError::OptionError("SO_RCVTIMEO".to_owned(), e))?;
+                socket
+                    .set_write_timeout(Some(timeout))
+                    .map_err(|e| SocketError::OptionError("SO_SNDTIMEO".to_owned(), e))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// when a connection last moved bytes either way
+struct Idle {
+    timeout: Option<Duration>,
+    start: Instant,
+    // ms since start
+    last: AtomicU64,
+}
+
+impl Idle {
+    fn new(timeout: Option<Duration>) -> Self {
+        Idle {
+            timeout,
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    fn expired(&self) -> bool {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.timeout
+            .is_some_and(|timeout| self.start.elapsed().saturating_sub(last) >= timeout)
+    }
+}
+
+// errors of the TCP side or the vsock side of a mapping
+fn endpoint_error(endpoint: &Endpoint, err: SocketError) -> ProxyError {
+    match endpoint {
+        Endpoint::Tcp(_) => ProxyError::IpError(err),
+        Endpoint::Vsock { .. } => ProxyError::VsockError(err),
+    }
+}
+
+fn listen(endpoint: &Endpoint) -> Result<Socket, ProxyError> {
+    let addr = endpoint.addr();
+    let socket = endpoint
+        .socket()
+        .map_err(SocketError::OpenError)
+        .map_err(|err| endpoint_error(endpoint, err))?;
+    if let Endpoint::Tcp(_) = endpoint {
+        socket
+            .set_reuse_address(true)
+            .map_err(|e| SocketError::OptionError("SO_REUSEADDR".to_owned(), e))
+            .map_err(|err| endpoint_error(endpoint, err))?;
+    }
+    socket
+        .bind(&addr)
+        .map_err(|e| SocketError::BindError {
+            addr: endpoint.to_string(),
+            source: e,
+        })
+        .map_err(|err| endpoint_error(endpoint, err))?;
+    socket
+        .listen(BACKLOG)
+        .map_err(|e| SocketError::ListenError {
+            addr: endpoint.to_string(),
+            source: e,
+        })
+        .map_err(|err| endpoint_error(endpoint, err))?;
+
+    Ok(socket)
+}
+
+/// Listen on the listen side of mapping, retried with backoff
+pub fn listen_with_backoff(mapping: &Mapping) -> Result<Socket, ProxyError> {
+    run_with_backoff(listen, &mapping.listen, 64)
+}
+
+fn connect(endpoint: &Endpoint) -> Result<Socket, ProxyError> {
+    let socket = endpoint
+        .socket()
+        .map_err(SocketError::OpenError)
+        .map_err(|err| endpoint_error(endpoint, err))?;
+    socket
+        .connect(&endpoint.addr())
+        .map_err(|e| SocketError::ConnectError {
+            addr: endpoint.to_string(),
+            source: e,
+        })
+        .map_err(|err| endpoint_error(endpoint, err))?;
+
+    Ok(socket)
+}
+
+// copy from one socket to the other till EOF, then pass the EOF on
+fn copy(from: &Socket, to: &Socket, bytes: &Counter, idle: &Idle) {
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let size = match (&mut &*from).read(&mut buf) {
+            Ok(0) => break,
+            Ok(size) => size,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // only vsock sockets time out, the other way may still be busy
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if !idle.expired() {
+                    continue;
+                }
+                // wakes up the other way too
+                let _ = from.shutdown(Shutdown::Both);
+                let _ = to.shutdown(Shutdown::Both);
+                return;
+            }
+            Err(_) => break,
+        };
+        if (&mut &*to).write_all(&buf[..size]).is_err() {
+            break;
+        }
+        bytes.add(size as u64);
+        idle.touch();
+    }
+    // the peer may have gone already
+    let _ = to.shutdown(Shutdown::Write);
+    let _ = from.shutdown(Shutdown::Read);
+}
+
+// copy both ways till both are done or the connection is idle for too long
+fn copy_both_ways(accepted: &Socket, connected: &Socket, config: &StreamConfig) {
+    let idle = Idle::new(config.idle_timeout);
+    std::thread::scope(|scope| {
+        scope.spawn(|| copy(connected, accepted, &config.bytes_in, &idle));
+        copy(accepted, connected, &config.bytes_out, &idle);
+    });
+}
+
+// forward an accepted connection, returns once both ways are done
+fn forward(accepted: Socket, mapping: &Mapping, config: &StreamConfig) -> Result<(), ProxyError> {
+    config
+        .set_timeouts(&accepted, &mapping.listen)
+        .map_err(|err| endpoint_error(&mapping.listen, err))?;
+    let connected = connect(&mapping.connect).inspect_err(|_| config.failed.inc())?;
+    config
+        .set_timeouts(&connected, &mapping.connect)
+        .map_err(|err| endpoint_error(&mapping.connect, err))?;
+
+    copy_both_ways(&accepted, &connected, config);
+    Ok(())
+}
+
+/// Spawn a thread accepting connections on listener for mapping, each
+/// forwarded in threads of its own, stops on shutdown
+pub fn spawn_mapping(listener: Socket, mapping: Mapping, config: StreamConfig) -> JoinHandle<()> {
+    // the limit is per mapping, the gauge counts all
+    let open = Arc::new(AtomicUsize::new(0));
+    std::thread::spawn(move || {
+        // woken up on shutdown like the main thread
+        register_shutdown_thread();
+        println!("forwarding {} to {}", mapping.listen, mapping.connect);
+
+        while !shutdown_requested() {
+            let accepted = match listener.accept() {
+                Ok((accepted, _)) => accepted,
+                Err(_) if shutdown_requested() => break,
+                Err(e) => {
+                    let err = SocketError::AcceptError {
+                        addr: mapping.listen.to_string(),
+                        source: e,
+                    };
+                    println!(
+                        "{:?}",
+                        anyhow::Error::from(endpoint_error(&mapping.listen, err))
+                    );
+                    continue;
+                }
+            };
+            config.accepted.inc();
+            if open.fetch_add(1, Ordering::Relaxed) >= config.max_conns {
+                open.fetch_sub(1, Ordering::Relaxed);
+                config.rejected.inc();
+                continue;
+            }
+
+            let open = open.clone();
+            let config = config.clone();
+            std::thread::spawn(move || {
+                config.opened();
+                if let Err(err) = forward(accepted, &mapping, &config) {
+                    println!("{:?}", anyhow::Error::from(err));
+                }
+                // the slot is free by the time the gauge shows it
+                open.fetch_sub(1, Ordering::Relaxed);
+                config.closed();
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn parses_mappings() {
+        let content = "\
+            # parent api\n\
+            tcp:0.0.0.0:2080 vsock:3:2080\n\
+            \n\
+            vsock:any:3080 tcp:127.0.0.1:3080 # rclone\n";
+        let mappings = parse_mappings(content).unwrap();
+        assert_eq!(
+            mappings,
+            [
+                Mapping {
+                    listen: Endpoint::Tcp("0.0.0.0:2080".parse().unwrap()),
+                    connect: Endpoint::Vsock { cid: 3, port: 2080 },
+                },
+                Mapping {
+                    listen: Endpoint::Vsock {
+                        cid: libc::VMADDR_CID_ANY,
+                        port: 3080,
+                    },
+                    connect: Endpoint::Tcp("127.0.0.1:3080".parse().unwrap()),
+                },
+            ]
+        );
+        assert_eq!(mappings[1].listen.to_string(), "vsock:any:3080");
+        assert_eq!(
+            "tcp:0.0.0.0:2080=vsock:3:2080".parse::<Mapping>(),
+            Ok(mappings[0])
+        );
+
+        for (content, line) in [
+            ("tcp:0.0.0.0:2080", 1),
+            ("# ok\ntcp:0.0.0.0:2080 udp:1.1.1.1:53", 2),
+            ("tcp:localhost:2080 vsock:3:2080", 1),
+            ("tcp:0.0.0.0:2080 vsock:3", 1),
+        ] {
+            assert_eq!(parse_mappings(content).unwrap_err().0, line, "{content}");
+        }
+    }
+
+    #[test]
+    fn forwards_both_ways() {
+        // stands in for the far side, echoes till EOF
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let connect = Endpoint::Tcp(server.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in server.incoming() {
+                let mut stream = stream.unwrap();
+                let mut received = Vec::new();
+                stream.read_to_end(&mut received).unwrap();
+                stream.write_all(&received).unwrap();
+            }
+        });
+
+        let health = Health::new();
+        let config = StreamConfig::new(1, Some(Duration::from_secs(30)), None, &health);
+        let mapping = Mapping {
+            listen: Endpoint::Tcp((Ipv4Addr::LOCALHOST, 0).into()),
+            connect,
+        };
+        let listener = listen(&mapping.listen).unwrap();
+        let listen_addr = listener.local_addr().unwrap().as_socket().unwrap();
+        spawn_mapping(listener, mapping, config.clone());
+
+        // the half close goes through, the answer comes back after it
+        let mut client = TcpStream::connect(listen_addr).unwrap();
+        client.write_all(b"hello").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        // over the limit while the first one is open
+        let mut second = TcpStream::connect(listen_addr).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(second.read(&mut [0; 8]).unwrap(), 0);
+
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).unwrap();
+        assert_eq!(answer, b"hello");
+        assert_eq!(config.bytes_out.get(), 5);
+        assert_eq!(config.rejected.get(), 1);
+
+        // the slot is free again once the first one is closed
+        drop(client);
+        while config.open.load(Ordering::Relaxed) > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(health.gauge("stream_active").get(), 0);
+        let mut third = TcpStream::connect(listen_addr).unwrap();
+        third.write_all(b"again").unwrap();
+        third.shutdown(Shutdown::Write).unwrap();
+        let mut answer = Vec::new();
+        third.read_to_end(&mut answer).unwrap();
+        assert_eq!(answer, b"again");
+        assert_eq!(config.rejected.get(), 1);
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        // unix sockets with timeouts stand in for vsock
+        let timeout = Duration::from_millis(100);
+        let pair = || Socket::pair(Domain::UNIX, Type::STREAM, None).unwrap();
+        let (client, accepted) = pair();
+        let (connected, server) = pair();
+        accepted.set_read_timeout(Some(timeout / 4)).unwrap();
+        connected.set_read_timeout(Some(timeout / 4)).unwrap();
+        let health = Health::new();
+        let config = StreamConfig::new(1, None, Some(timeout), &health);
+
+        let started = Instant::now();
+        std::thread::scope(|scope| {
+            scope.spawn(|| copy_both_ways(&accepted, &connected, &config));
+
+            // traffic one way keeps the connection open past the timeout
+            for _ in 0..8 {
+                (&mut &client).write_all(b"tick").unwrap();
+                std::thread::sleep(timeout / 4);
+            }
+            let mut received = [0u8; 32];
+            (&mut &server).read_exact(&mut received).unwrap();
+            assert!(started.elapsed() > timeout);
+
+            // and then it is closed both ways
+            let mut buf = [0u8; 8];
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            assert_eq!((&mut &client).read(&mut buf).unwrap(), 0);
+        });
+        assert_eq!(config.bytes_out.get(), 32);
+    }
+}
@@ -0,0 +1,186 @@
+// TUN device, optionally with virtio-net headers
+//
+// With IFF_VNET_HDR every packet written to the device is preceded by a
+// virtio_net_hdr describing the state of its checksum and, for GSO
+// packets, how to segment it, and the device takes the offloads set
+// with TUNSETOFFLOAD. TUN_F_CSUM lets the stack hand packets routed to
+// the device over with their checksums left to offload, nfqueue in front
+// of it passes them on as they are to ip-to-vsock-raw-outgoing run with
+// --checksum-offload, which completes them.
+//
+// GSO packets, TCP packets beyond the MTU made of several segments,
+// are written with a header describing the segments and the checksum
+// left to the kernel, which segments them only if they are forwarded
+// through a device that cannot take them whole.
+//
+// tun-tap cannot set IFF_VNET_HDR, the device is set up here directly.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, IoSlice, Write};
+use std::os::fd::{AsRawFd, RawFd};
+
+use crate::packet::{get_ihl, set_partial_checksum, tcp_headers_len};
+
+pub const VNET_HDR_LEN: usize = 10;
+
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+pub const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+
+/// struct virtio_net_hdr, in native byte order like the tun driver expects
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VnetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VnetHdr {
+    pub fn encode(&self) -> [u8; VNET_HDR_LEN] {
+        let mut buf = [0u8; VNET_HDR_LEN];
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        buf[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        buf[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        buf[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        buf[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        buf
+    }
+}
+
+pub struct Tun {
+    file: File,
+    // header written in front of every packet, empty without IFF_VNET_HDR
+    header: Vec<u8>,
+}
+
+impl Tun {
+    /// Attach to the TUN device without packet info, with virtio-net
+    /// headers and checksum offload if vnet_hdr is set
+    pub fn open(device: &str, vnet_hdr: bool) -> std::io::Result<Tun> {
+        if device.len() >= libc::IFNAMSIZ {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("device name too long: {device}"),
+            ));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+
+        let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (dst, src) in ifr.ifr_name.iter_mut().zip(device.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        let mut flags = libc::IFF_TUN | libc::IFF_NO_PI;
+        if vnet_hdr {
+            flags |= libc::IFF_VNET_HDR;
+        }
+        ifr.ifr_ifru.ifru_flags = flags as libc::c_short;
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &ifr) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        // the device outlives us, reset what an earlier run may have enabled
+        let offload = match vnet_hdr {
+            true => libc::TUN_F_CSUM | libc::TUN_F_TSO4,
+            false => 0,
+        };
+        if unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                libc::TUNSETOFFLOAD,
+                offload as libc::c_ulong,
+            )
+        } < 0
+        {
+            return Err(Error::last_os_error());
+        }
+
+        // packets from the parent carry complete checksums that nothing
+        // has verified yet, the header leaves that to the enclave stack
+        let header = match vnet_hdr {
+            true => VnetHdr::default().encode().to_vec(),
+            false => Vec::new(),
+        };
+
+        Ok(Tun { file, header })
+    }
+
+    /// Another handle on the same device, for writing from another thread
+    pub fn try_clone(&self) -> std::io::Result<Tun> {
+        Ok(Tun {
+            file: self.file.try_clone()?,
+            header: self.header.clone(),
+        })
+    }
+
+    /// Header to write in front of every packet, empty without virtio-net headers
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    pub fn has_vnet_hdr(&self) -> bool {
+        !self.header.is_empty()
+    }
+
+    /// Write a single packet, preceded by its header
+    pub fn write_packet(&mut self, packet: &[u8]) -> std::io::Result<()> {
+        write_with_header(&self.file, &self.header, packet)
+    }
+
+    /// Write a TCP GSO packet made of segments of gso_size payload bytes,
+    /// needs virtio-net headers. The TCP checksum is left to the kernel.
+    pub fn write_gso_packet(&mut self, packet: &mut [u8], gso_size: u16) -> std::io::Result<()> {
+        if !self.has_vnet_hdr() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "gso packets need virtio-net headers",
+            ));
+        }
+
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid gso packet");
+        let headers_length = tcp_headers_len(packet).ok_or_else(invalid)?;
+        let ip_header_length = get_ihl(packet) as usize;
+        let csum_offset = set_partial_checksum(packet).ok_or_else(invalid)?;
+
+        let header = VnetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV4,
+            hdr_len: headers_length as u16,
+            gso_size,
+            csum_start: ip_header_length as u16,
+            csum_offset: csum_offset as u16,
+        };
+        write_with_header(&self.file, &header.encode(), packet)
+    }
+}
+
+fn write_with_header(mut file: &File, header: &[u8], packet: &[u8]) -> std::io::Result<()> {
+    let size = loop {
+        let written = match header.is_empty() {
+            true => file.write(packet),
+            false => file.write_vectored(&[IoSlice::new(header), IoSlice::new(packet)]),
+        };
+        match written {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            written => break written?,
+        }
+    };
+
+    // tun takes whole packets or fails
+    match size == header.len() + packet.len() {
+        true => Ok(()),
+        false => Err(Error::new(ErrorKind::WriteZero, "short write to tun")),
+    }
+}
+
+impl AsRawFd for Tun {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
@@ -0,0 +1,281 @@
+// io_uring data path, built with the io-uring feature
+//
+// The blocking path costs a read per batch of frames and a write per
+// packet, all on the single thread of a direction. With io_uring the
+// frame buffer is registered with the kernel once, vsock reads go
+// straight into it and all packets of a read are written out of it to
+// the TUN device or raw socket with a single submission, without copies
+// and without a syscall per packet.
+//
+// The peer timeout set with SO_RCVTIMEO does not apply to io_uring
+// reads, it is carried over as a linked timeout on every read.
+
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use socket2::SockAddr;
+
+use crate::frame::{FrameReader, Frames};
+use crate::shutdown::shutdown_requested;
+use crate::SocketError;
+
+// max packets written per submission
+const RING_ENTRIES: u32 = 256;
+
+// index of the frame buffer among the registered buffers
+const FRAME_BUF: u16 = 0;
+
+// user_data of the read and what is linked to it, writes use their index
+const READ: u64 = u64::MAX;
+const READ_TIMEOUT: u64 = u64::MAX - 1;
+const READ_CANCEL: u64 = u64::MAX - 2;
+
+pub struct UringIo {
+    // dropped first, the registered buffer must outlive it
+    ring: RefCell<IoUring>,
+    reader: FrameReader,
+    // address range of the registered buffer
+    registered: Range<usize>,
+    read_timeout: Option<types::Timespec>,
+}
+
+impl UringIo {
+    pub fn new() -> std::io::Result<Self> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        let mut reader = FrameReader::new();
+
+        let buf = reader.buffer();
+        let registered = buf.as_ptr() as usize..buf.as_ptr() as usize + buf.len();
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // the buffer is owned by reader, which is dropped after the ring
+        unsafe { ring.submitter().register_buffers(&[iovec])? };
+
+        Ok(UringIo {
+            ring: RefCell::new(ring),
+            reader,
+            registered,
+            read_timeout: None,
+        })
+    }
+
+    /// Start reading a new connection, anything buffered from the old one is dropped
+    pub fn reset(&mut self, read_timeout: Option<Duration>) {
+        self.reader.clear();
+        self.read_timeout = read_timeout.map(|timeout| {
+            types::Timespec::new()
+                .sec(timeout.as_secs())
+                .nsec(timeout.subsec_nanos())
+        });
+    }
+
+    /// Same as FrameReader::read_frames, the frames are at the returned range
+    pub fn read_frames(&mut self, fd: RawFd) -> Result<Range<usize>, SocketError> {
+        let ring = self.ring.get_mut();
+        let timeout = self.read_timeout.as_ref();
+        self.reader
+            .read_frames_with(|buf| read_fixed(ring, fd, buf, timeout))
+    }
+
+    /// Same as FrameReader::read_messages, the frame is at the returned range
+    pub fn read_messages(&mut self, fd: RawFd) -> Result<Range<usize>, SocketError> {
+        let ring = self.ring.get_mut();
+        let timeout = self.read_timeout.as_ref();
+        self.reader
+            .read_messages_with(|buf| read_fixed(ring, fd, buf, timeout))
+    }
+
+    pub fn frames(&self, range: Range<usize>) -> Frames<'_> {
+        self.reader.frames(range)
+    }
+
+    /// Write each packet with its own write, or sendmsg to dest, preceded
+    /// by header if there is one, and wait for all of them
+    pub fn write_packets(
+        &self,
+        fd: RawFd,
+        packets: &[&[u8]],
+        dest: Option<&SockAddr>,
+        header: Option<&[u8]>,
+    ) -> std::io::Result<()> {
+        let mut ring = self.ring.borrow_mut();
+        for chunk in packets.chunks(RING_ENTRIES as usize) {
+            self.write_chunk(&mut ring, fd, chunk, dest, header)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_chunk(
+        &self,
+        ring: &mut IoUring,
+        fd: RawFd,
+        packets: &[&[u8]],
+        dest: Option<&SockAddr>,
+        header: Option<&[u8]>,
+    ) -> std::io::Result<()> {
+        // writev and sendmsg arguments have to stay put until the writes complete
+        let parts = 1 + usize::from(header.is_some());
+        let mut iovecs: Vec<libc::iovec> = Vec::new();
+        let mut msgs: Vec<libc::msghdr> = Vec::new();
+        if dest.is_some() || header.is_some() {
+            iovecs = packets
+                .iter()
+                .flat_map(|packet| header.into_iter().chain([*packet]))
+                .map(|buf| libc::iovec {
+                    iov_base: buf.as_ptr() as *mut libc::c_void,
+                    iov_len: buf.len(),
+                })
+                .collect();
+        }
+        if let Some(dest) = dest {
+            msgs = iovecs
+                .chunks_mut(parts)
+                .map(|iovecs| {
+                    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+                    msg.msg_name = dest.as_ptr() as *mut libc::c_void;
+                    msg.msg_namelen = dest.len();
+                    msg.msg_iov = iovecs.as_mut_ptr();
+                    msg.msg_iovlen = parts;
+                    msg
+                })
+                .collect();
+        }
+
+        {
+            let mut sq = ring.submission();
+            for (index, packet) in packets.iter().enumerate() {
+                let entry = match msgs.get(index) {
+                    Some(msg) => opcode::SendMsg::new(types::Fd(fd), msg).build(),
+                    None if header.is_some() => opcode::Writev::new(
+                        types::Fd(fd),
+                        iovecs[index * parts..].as_ptr(),
+                        parts as u32,
+                    )
+                    .offset(u64::MAX)
+                    .build(),
+                    None if self.is_registered(packet) => opcode::WriteFixed::new(
+                        types::Fd(fd),
+                        packet.as_ptr(),
+                        packet.len() as u32,
+                        FRAME_BUF,
+                    )
+                    .offset(u64::MAX)
+                    .build(),
+                    None => opcode::Write::new(types::Fd(fd), packet.as_ptr(), packet.len() as u32)
+                        .offset(u64::MAX)
+                        .build(),
+                };
+                // chunks never exceed the ring size and the ring is empty between calls
+                unsafe { sq.push(&entry.user_data(index as u64)) }
+                    .map_err(|_| Error::other("submission queue full"))?;
+            }
+        }
+
+        // the writes own the packets until they complete, keep
+        // waiting through signals
+        let mut error = None;
+        let mut pending = packets.len();
+        while pending > 0 {
+            match ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            for cqe in ring.completion() {
+                pending -= 1;
+                if cqe.result() < 0 && error.is_none() {
+                    error = Some(Error::from_raw_os_error(-cqe.result()));
+                }
+            }
+        }
+        drop((msgs, iovecs));
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn is_registered(&self, packet: &[u8]) -> bool {
+        let start = packet.as_ptr() as usize;
+        self.registered.contains(&start) && start + packet.len() <= self.registered.end
+    }
+}
+
+// single read into the registered buffer, behaves like read(2) on a
+// socket with SO_RCVTIMEO set to timeout
+fn read_fixed(
+    ring: &mut IoUring,
+    fd: RawFd,
+    buf: &mut [u8],
+    timeout: Option<&types::Timespec>,
+) -> std::io::Result<usize> {
+    let read = opcode::ReadFixed::new(types::Fd(fd), buf.as_mut_ptr(), buf.len() as u32, FRAME_BUF)
+        .offset(u64::MAX)
+        .build()
+        .user_data(READ);
+
+    let mut pending = 1;
+    {
+        let mut sq = ring.submission();
+        let pushed = match timeout {
+            Some(timeout) => {
+                pending += 1;
+                unsafe {
+                    sq.push_multiple(&[
+                        read.flags(squeue::Flags::IO_LINK),
+                        opcode::LinkTimeout::new(timeout)
+                            .build()
+                            .user_data(READ_TIMEOUT),
+                    ])
+                }
+            }
+            None => unsafe { sq.push(&read) },
+        };
+        pushed.map_err(|_| Error::other("submission queue full"))?;
+    }
+
+    let mut result = None;
+    let mut cancelled = false;
+    while pending > 0 {
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::Interrupted => {
+                // the read is in flight and owns the buffer, cancel it
+                // and wait for it before reporting the shutdown
+                if shutdown_requested() && !cancelled {
+                    let cancel = opcode::AsyncCancel::new(READ)
+                        .build()
+                        .user_data(READ_CANCEL);
+                    unsafe { ring.submission().push(&cancel) }
+                        .map_err(|_| Error::other("submission queue full"))?;
+                    pending += 1;
+                    cancelled = true;
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        for cqe in ring.completion() {
+            pending -= 1;
+            if cqe.user_data() == READ {
+                result = Some(cqe.result());
+            }
+        }
+    }
+
+    match result.unwrap_or(-libc::ECANCELED) {
+        size if size >= 0 => Ok(size as usize),
+        res if res == -libc::ECANCELED && cancelled => Err(Error::from(ErrorKind::Interrupted)),
+        // the linked timeout fired, same error as SO_RCVTIMEO
+        res if res == -libc::ECANCELED => Err(Error::from_raw_os_error(libc::EAGAIN)),
+        res => Err(Error::from_raw_os_error(-res)),
+    }
+}
@@ -0,0 +1,230 @@
+// Compares the blocking and io_uring data paths on loopback transports
+//
+// A producer thread writes synthetic frames to a Unix stream socket
+// standing in for the vsock link. The proxy side reads them the way the
+// vsock-to-ip proxies do and forwards every packet as a datagram, with
+// write on a connected Unix datagram socket standing in for the TUN
+// device, or sendto standing in for the raw socket. A consumer thread
+// counts what arrives.
+
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, ValueEnum};
+use socket2::{SockAddr, Socket};
+
+use oyster_raw_proxy::frame::{write_frames, FrameReader};
+use oyster_raw_proxy::uring::UringIo;
+use oyster_raw_proxy::{SocketError, MAX_BATCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Output {
+    /// write to a connected socket, like the TUN device
+    Write,
+    /// sendto an address, like the raw socket
+    Sendto,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Path {
+    Blocking,
+    Uring,
+}
+
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    /// packets per run
+    #[clap(long, value_parser, default_value_t = 1_000_000)]
+    packets: usize,
+    /// packet size in bytes
+    #[clap(long, value_parser = clap::value_parser!(u16).range(20..), default_value_t = 1400)]
+    size: u16,
+    /// outputs to run, all by default
+    #[clap(long, value_enum)]
+    output: Vec<Output>,
+    /// data paths to run, all by default
+    #[clap(long, value_enum)]
+    path: Vec<Path>,
+}
+
+// IPv4 header in front of zeros, enough for the framing
+fn packet(size: u16) -> Vec<u8> {
+    let mut packet = vec![0u8; size.into()];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&size.to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 6;
+    packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+    packet[16..20].copy_from_slice(&[1, 1, 1, 1]);
+    packet
+}
+
+struct Transport {
+    // vsock stand-in, producer and proxy ends
+    link_tx: Socket,
+    link_rx: Socket,
+    // TUN or raw socket stand-in, proxy and consumer ends
+    out: Socket,
+    dest: Option<SockAddr>,
+    sink: UnixDatagram,
+    sink_path: Option<PathBuf>,
+}
+
+impl Transport {
+    fn new(output: Output) -> std::io::Result<Self> {
+        let (link_tx, link_rx) = UnixStream::pair()?;
+
+        let (out, dest, sink, sink_path) = match output {
+            Output::Write => {
+                let (out, sink) = UnixDatagram::pair()?;
+                (out, None, sink, None)
+            }
+            Output::Sendto => {
+                let path =
+                    std::env::temp_dir().join(format!("uring-bench-{}.sock", std::process::id()));
+                let _ = std::fs::remove_file(&path);
+                let sink = UnixDatagram::bind(&path)?;
+                let dest = SockAddr::unix(&path)?;
+                (UnixDatagram::unbound()?, Some(dest), sink, Some(path))
+            }
+        };
+
+        Ok(Transport {
+            link_tx: link_tx.into(),
+            link_rx: link_rx.into(),
+            out: out.into(),
+            dest,
+            sink,
+            sink_path,
+        })
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        if let Some(path) = &self.sink_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn proxy_blocking(transport: &mut Transport) -> Result<(), SocketError> {
+    let mut reader = FrameReader::new();
+    loop {
+        let frames = match reader.read_frames(&mut transport.link_rx) {
+            Ok(frames) => frames,
+            Err(SocketError::EofError) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for frame in frames {
+            let sent = match &transport.dest {
+                Some(dest) => transport.out.send_to(frame, dest),
+                None => transport.out.send(frame),
+            };
+            sent.map_err(SocketError::WriteError)?;
+        }
+    }
+}
+
+fn proxy_uring(transport: &mut Transport, uring: &mut UringIo) -> Result<(), SocketError> {
+    uring.reset(None);
+    loop {
+        let range = match uring.read_frames(transport.link_rx.as_raw_fd()) {
+            Ok(range) => range,
+            Err(SocketError::EofError) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let packets: Vec<&[u8]> = uring.frames(range).collect();
+        uring
+            .write_packets(
+                transport.out.as_raw_fd(),
+                &packets,
+                transport.dest.as_ref(),
+                None,
+            )
+            .map_err(SocketError::WriteError)?;
+    }
+}
+
+fn run(cli: &Cli, output: Output, path: Path, uring: &mut UringIo) -> anyhow::Result<Duration> {
+    let mut transport = Transport::new(output)?;
+    let (packets, size) = (cli.packets, cli.size);
+
+    let link_tx = transport.link_tx.try_clone()?;
+    let producer = std::thread::spawn(move || {
+        let packet = packet(size);
+        let batch = vec![&packet[..]; MAX_BATCH];
+        let mut left = packets;
+        while left > 0 {
+            let count = left.min(MAX_BATCH);
+            write_frames(&link_tx, &batch[..count]).map_err(|(_, e)| e)?;
+            left -= count;
+        }
+        // eof ends the proxy loop
+        link_tx
+            .shutdown(std::net::Shutdown::Write)
+            .map_err(SocketError::WriteError)
+    });
+
+    let sink = transport.sink.try_clone()?;
+    let consumer = std::thread::spawn(move || {
+        let mut buf = vec![0u8; 65536];
+        for _ in 0..packets {
+            sink.recv(&mut buf)?;
+        }
+        std::io::Result::Ok(())
+    });
+
+    let start = Instant::now();
+    match path {
+        Path::Blocking => proxy_blocking(&mut transport)?,
+        Path::Uring => proxy_uring(&mut transport, uring)?,
+    }
+    consumer.join().unwrap()?;
+    let elapsed = start.elapsed();
+    producer.join().unwrap()?;
+
+    Ok(elapsed)
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let outputs = match cli.output.is_empty() {
+        true => vec![Output::Write, Output::Sendto],
+        false => cli.output.clone(),
+    };
+    let paths = match cli.path.is_empty() {
+        true => vec![Path::Blocking, Path::Uring],
+        false => cli.path.clone(),
+    };
+
+    let mut uring = UringIo::new()?;
+
+    println!("{} packets of {} bytes per run", cli.packets, cli.size);
+    println!(
+        "{:<8} {:<10} {:>12} {:>10}",
+        "output", "path", "packets/s", "Gbit/s"
+    );
+    for output in outputs {
+        for &path in &paths {
+            let elapsed = run(&cli, output, path, &mut uring)?.as_secs_f64();
+            let pps = cli.packets as f64 / elapsed;
+            let gbps = pps * f64::from(cli.size) * 8.0 / 1e9;
+            println!(
+                "{:<8} {:<10} {:>12.0} {:>10.2}",
+                format!("{output:?}").to_lowercase(),
+                format!("{path:?}").to_lowercase(),
+                pps,
+                gbps
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,88 @@
+// Forwards TCP connections over vsock and back, see stream.rs
+//
+// Enclave side: --map tcp:0.0.0.0:2080=vsock:3:2080
+// Parent side:  --map vsock:any:2080=tcp:127.0.0.1:2080
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+
+use oyster_raw_proxy::health::{spawn_health_reporters, Health};
+use oyster_raw_proxy::shutdown::{exit_status, log_shutdown, register_shutdown};
+use oyster_raw_proxy::stream::{
+    listen_with_backoff, read_mappings_file, spawn_mapping, Mapping, StreamConfig,
+};
+
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    /// connections to forward <listen>=<connect>, endpoints are tcp:<ip>:<port>
+    /// or vsock:<cid>:<port>, cid any listens on all, can be repeated
+    #[clap(long, value_parser)]
+    map: Vec<Mapping>,
+    /// file of mappings, one `<listen> <connect>` per line, in addition to --map
+    #[clap(long, value_parser)]
+    map_file: Option<PathBuf>,
+    /// connections of a mapping open at once, more are closed right away
+    #[clap(long, value_parser, default_value_t = 256)]
+    max_conns: usize,
+    /// seconds of idle TCP connection before keepalives are sent, 0 disables
+    #[clap(long, value_parser, default_value_t = 60)]
+    keepalive: u64,
+    /// seconds without bytes either way before a connection over vsock is
+    /// closed, 0 disables
+    #[clap(long, value_parser, default_value_t = 600)]
+    idle_timeout: u64,
+    /// file to report the forwarder state to for health checks
+    #[clap(long, value_parser)]
+    health_file: Option<PathBuf>,
+    /// address to serve the forwarder state on over HTTP <ip:port>, 503 when unhealthy
+    #[clap(long, value_parser)]
+    health_addr: Option<SocketAddr>,
+}
+
+fn main() -> anyhow::Result<()> {
+    exit_status(run(Cli::parse()))
+}
+
+fn run(cli: Cli) -> anyhow::Result<()> {
+    // stop accepting on SIGTERM/SIGINT, open connections are cut at exit
+    register_shutdown()?;
+
+    let mut mappings = cli.map.clone();
+    if let Some(map_file) = &cli.map_file {
+        mappings.extend(read_mappings_file(map_file)?);
+    }
+    if mappings.is_empty() {
+        anyhow::bail!("nothing to forward, use --map or --map-file");
+    }
+
+    let health = Health::new();
+    spawn_health_reporters(&health, cli.health_file.clone(), cli.health_addr)?;
+    let keepalive = (cli.keepalive > 0).then(|| Duration::from_secs(cli.keepalive));
+    let idle_timeout = (cli.idle_timeout > 0).then(|| Duration::from_secs(cli.idle_timeout));
+    let config = StreamConfig::new(cli.max_conns, keepalive, idle_timeout, &health);
+
+    // every mapping is listening before any is served
+    let listeners = mappings
+        .iter()
+        .map(listen_with_backoff)
+        .collect::<Result<Vec<_>, _>>()?;
+    // no lasting link, healthy once listening
+    health.set_vsock_connected(true);
+
+    let workers: Vec<_> = listeners
+        .into_iter()
+        .zip(mappings)
+        .map(|(listener, mapping)| spawn_mapping(listener, mapping, config.clone()))
+        .collect();
+    for worker in workers {
+        worker.join().expect("mapping thread panicked");
+    }
+
+    log_shutdown();
+
+    Ok(())
+}
@@ -32,18 +32,35 @@
 // tun exposes /dev/tun that can be written to as file descriptor
 // which sends the packets to kernel stack for reverse-NAT to docker
 
-use std::io::Read;
-
+use anyhow::Context;
 use clap::Parser;
-use socket2::{SockAddr, Socket};
-use tun_tap::{Mode};
-use std::fs::File;
-use std::io::Write;
+use socket2::SockAddr;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+#[cfg(feature = "io-uring")]
 use std::os::fd::AsRawFd;
-use std::os::fd::FromRawFd;
+use std::path::PathBuf;
 
+use oyster_raw_proxy::conn::{Transport, VsockConn};
+use oyster_raw_proxy::frame::{is_control_frame, parse_gso_frame};
+#[cfg(not(feature = "io-uring"))]
+use oyster_raw_proxy::frame::FrameReader;
+use oyster_raw_proxy::health::{spawn_health_reporters, Counter, Health};
+use oyster_raw_proxy::heartbeat::Liveness;
+use oyster_raw_proxy::packet::{tcp_headers_len, Segmenter};
+use oyster_raw_proxy::reload::{read_ip_file, watch_ip_file, SharedIp};
+use oyster_raw_proxy::secure::EnclaveSecureArgs;
+use oyster_raw_proxy::sequence::{now_us, SequenceTracker};
+use oyster_raw_proxy::shaper::{read_rules_file, watch_rules_file, Direction, Shaper};
+use oyster_raw_proxy::shutdown::{
+    exit_status, log_shutdown, or_shutdown, register_shutdown, register_shutdown_thread,
+    shutdown_requested,
+};
+use oyster_raw_proxy::tun::Tun;
+#[cfg(feature = "io-uring")]
+use oyster_raw_proxy::uring::UringIo;
 use oyster_raw_proxy::{
-    accept_vsock_conn_with_backoff, new_vsock_server_with_backoff,
+    accept_vsock_conn_recv_hello_with_backoff, get_interface_mtu, new_vsock_server_with_backoff,
     ProxyError, SocketError, VsockAddrParser,
 };
 
@@ -56,101 +73,448 @@ struct Cli {
     /// network device to forward packets on
     #[clap(short, long, value_parser)]
     device: String,
+    /// write packets with virtio-net headers and enable checksum and GSO offload on the device,
+    /// GSO packets are split here otherwise
+    #[clap(long)]
+    vnet_hdr: bool,
+    /// file with the enclave ip, reloaded on change or SIGHUP,
+    /// overrides the ip announced by the parent
+    #[clap(long, value_parser)]
+    ip_file: Option<PathBuf>,
+    /// seconds without frames, heartbeats included, before the peer is considered dead, 0 disables
+    #[clap(long, value_parser, default_value_t = 15)]
+    peer_timeout: u64,
+    /// file to report the proxy state to for health checks
+    #[clap(long, value_parser)]
+    health_file: Option<PathBuf>,
+    /// address to serve the proxy state on over HTTP <ip:port>, 503 when unhealthy
+    #[clap(long, value_parser)]
+    health_addr: Option<SocketAddr>,
+    /// vsock connections the parent stripes over, each served by its own thread,
+    /// has to match ip-to-vsock-raw-incoming --streams
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=64), default_value_t = 1)]
+    streams: u8,
+    /// file of download caps per container, see shaper.rs, reloaded on change or SIGHUP
+    #[clap(long, value_parser)]
+    shape_file: Option<PathBuf>,
+    /// vsock socket type, seqpacket sends every frame as a message of its own,
+    /// has to match the parent
+    #[clap(long, value_enum, default_value_t = Transport::Stream)]
+    transport: Transport,
+    #[clap(flatten)]
+    secure: EnclaveSecureArgs,
+}
+
+// whether a frame from the parent goes to the enclave
+fn should_forward(buf: &[u8], ip: Ipv4Addr) -> bool {
+    // println!("got packet from vsock, size {:?}", buf.len());
+
+    // GSO frames go by the packet they carry
+    let buf = parse_gso_frame(buf).map_or(buf, |(_, packet)| packet);
+
+    // hello is consumed on accept, heartbeats only keep the link alive
+    if is_control_frame(buf) || buf.len() < 20 {
+        return false;
+    }
+
+    // get the destination IP
+    // filter out packets not matching the expected IP
+    let dst_ip = Ipv4Addr::from(u32::from_be_bytes(buf[16..20].try_into().unwrap()));
+    // let src_ip = Ipv4Addr::from(u32::from_be_bytes(buf[12..16].try_into().unwrap()));
+    // println!("incoming {:?} from {:?}: {:02x?}", buf.len(), src_ip, buf);
+
+    dst_ip == ip
 }
 
+// writes the packets of GSO frames, whole with virtio-net headers,
+// split into segments that fit the device mtu otherwise
+struct GsoWriter {
+    mtu: usize,
+    segmenter: Segmenter,
+    // scratch copy, the checksum is rewritten before the write
+    packet: Vec<u8>,
+}
+
+impl GsoWriter {
+    fn new(mtu: usize) -> Self {
+        GsoWriter {
+            mtu,
+            segmenter: Segmenter::new(),
+            packet: Vec::new(),
+        }
+    }
+
+    // invalid packets are dropped silently, like any other the stack would not take
+    fn write(&mut self, tun: &mut Tun, gso_size: u16, packet: &[u8]) -> std::io::Result<()> {
+        if !tun.has_vnet_hdr() {
+            self.segmenter.clear();
+            let Some(segments) = self.segmenter.segment(packet, gso_size.into(), self.mtu) else {
+                return Ok(());
+            };
+            for index in segments {
+                tun.write_packet(self.segmenter.get(index))?;
+            }
+            return Ok(());
+        }
+
+        // 0 leaves the segment size to us, segments never exceed the mtu
+        let Some(headers_length) = tcp_headers_len(packet) else {
+            return Ok(());
+        };
+        let max_size = self.mtu.saturating_sub(headers_length).min(u16::MAX.into()) as u16;
+        let gso_size = match gso_size {
+            0 => max_size,
+            gso_size => gso_size.min(max_size),
+        };
+        if gso_size == 0 {
+            return Ok(());
+        }
+
+        self.packet.clear();
+        self.packet.extend_from_slice(packet);
+        match tun.write_gso_packet(&mut self.packet, gso_size) {
+            Err(e) if e.kind() == ErrorKind::InvalidData => Ok(()),
+            result => result,
+        }
+    }
+}
+
+// which frames go to the enclave and when, the ip might be reloaded concurrently
+struct Forward {
+    ip: SharedIp,
+    shaper: Option<Shaper>,
+    // packets tun did not take
+    tun_dropped: Counter,
+}
+
+// a packet tun did not take is lost like on any other device, a bad one
+// from the parent must not take the link down
+fn drop_packets(err: std::io::Error, packets: usize, tun_dropped: &Counter) {
+    let err = ProxyError::IpError(SocketError::WriteError(err));
+    println!("{:?}", anyhow::Error::from(err));
+    tun_dropped.add(packets as u64);
+}
+
+// write a frame that passed should_forward to tun
+fn write_frame(tun: &mut Tun, gso_writer: &mut GsoWriter, buf: &[u8]) -> std::io::Result<()> {
+    match parse_gso_frame(buf) {
+        Some((gso_size, packet)) => gso_writer.write(tun, gso_size, packet),
+        None => tun.write_packet(buf),
+    }
+}
+
+#[cfg(not(feature = "io-uring"))]
 fn handle_conn(
-    conn_socket: &mut Socket,
-    tun_writer: &mut File,
-    ip: &str,
+    conn: &mut VsockConn,
+    tun: &mut Tun,
+    gso_writer: &mut GsoWriter,
+    tracker: &mut SequenceTracker,
+    forward: &Forward,
+    health: &Health,
 ) -> Result<(), ProxyError> {
-    let mut buf = vec![0u8; 65535].into_boxed_slice();
+    let mut reader = FrameReader::new();
 
     loop {
-        // read till total size
-        conn_socket
-            .read_exact(&mut buf[0..4])
-            .map_err(SocketError::ReadError)
-            .map_err(ProxyError::VsockError)?;
-
-        let size: usize = u16::from_be_bytes(buf[2..4].try_into().unwrap()).into();
-        // println!("got packet from vsock, size {:?}", size);
-
-        // read till full frame
-        conn_socket
-            .read_exact(&mut buf[4..size])
-            .map_err(SocketError::ReadError)
-            .map_err(ProxyError::VsockError)?;
-
-        // get the destination IP
-        // filter out packets not matching the expected IP
-        let dst_addr = buf[16..20].iter().fold(String::new(), |acc, val| {
-            if acc != "" {
-                acc + "." + &val.to_string()
-            } else {
-                acc + &val.to_string()
+        let frames = conn
+            .read_frames(&mut reader)
+            .map_err(ProxyError::VsockError)
+            .map_err(or_shutdown)?;
+        // back to waiting with the next read, the watchdog sees a stall otherwise
+        let _busy = health.busy();
+        health.touch_vsock();
+
+        // read the current ip once per read, it might be reloaded concurrently
+        let ip = forward.ip.get();
+
+        let now = now_us();
+        let frames: Vec<&[u8]> = frames
+            .map(|buf| tracker.unwrap(buf, now))
+            .filter(|buf| should_forward(buf, ip))
+            .collect();
+        // with --shape-file containers over their cap wait for the shaper
+        let frames = match &forward.shaper {
+            Some(shaper) => shaper.admit(&frames, None),
+            None => frames,
+        };
+
+        // tun takes a single packet per write, only the reads are batched
+        for buf in frames {
+            match write_frame(tun, gso_writer, buf) {
+                Ok(()) => health.touch_packet(),
+                Err(e) => drop_packets(e, 1, &forward.tun_dropped),
             }
-        });
-        // let src_addr = buf[12..16].iter().fold(String::new(), |acc, val| {
-        //     if acc != "" {
-        //         acc + "." + &val.to_string()
-        //     } else {
-        //         acc + &val.to_string()
-        //     }
-        // });
-        // println!("incoming {:?} from {:?}: {:02x?}", size, src_addr, &buf[0..size]);
-
-        if dst_addr != ip {
-            continue;
         }
+    }
+}
 
-        tun_writer
-            .write_all(&buf[0..size])
-            .map_err(SocketError::WriteError)
-            .map_err(ProxyError::IpError)?;
+#[cfg(feature = "io-uring")]
+fn handle_conn(
+    uring: &mut UringIo,
+    conn: &mut VsockConn,
+    tun: &mut Tun,
+    gso_writer: &mut GsoWriter,
+    tracker: &mut SequenceTracker,
+    forward: &Forward,
+    health: &Health,
+) -> Result<(), ProxyError> {
+    let read_timeout = conn
+        .socket()
+        .read_timeout()
+        .map_err(|e| SocketError::OptionError("SO_RCVTIMEO".to_owned(), e))
+        .map_err(ProxyError::VsockError)?;
+    uring.reset(read_timeout);
+
+    loop {
+        let fd = conn.socket().as_raw_fd();
+        let range = match conn.transport() {
+            Transport::Stream => uring.read_frames(fd),
+            Transport::Seqpacket => uring.read_messages(fd),
+        }
+        .map_err(ProxyError::VsockError)
+        .map_err(or_shutdown)?;
+        // back to waiting with the next read, the watchdog sees a stall otherwise
+        let _busy = health.busy();
+        health.touch_vsock();
+
+        // read the current ip once per read, it might be reloaded concurrently
+        let ip = forward.ip.get();
+
+        let now = now_us();
+        let frames: Vec<&[u8]> = uring
+            .frames(range)
+            .map(|buf| tracker.unwrap(buf, now))
+            .filter(|buf| should_forward(buf, ip))
+            .collect();
+        // with --shape-file containers over their cap wait for the shaper
+        let frames = match &forward.shaper {
+            Some(shaper) => shaper.admit(&frames, None),
+            None => frames,
+        };
+
+        // all packets of a read between GSO frames go to tun with a single submission
+        let mut packets: Vec<&[u8]> = Vec::new();
+        for buf in frames {
+            match parse_gso_frame(buf) {
+                Some((gso_size, packet)) => {
+                    // keep the packets in order
+                    write_packets(uring, tun, &mut packets, forward, health);
+                    match gso_writer.write(tun, gso_size, packet) {
+                        Ok(()) => health.touch_packet(),
+                        Err(e) => drop_packets(e, 1, &forward.tun_dropped),
+                    }
+                }
+                None => packets.push(buf),
+            }
+        }
+        write_packets(uring, tun, &mut packets, forward, health);
+    }
+}
+
+#[cfg(feature = "io-uring")]
+fn write_packets(
+    uring: &UringIo,
+    tun: &Tun,
+    packets: &mut Vec<&[u8]>,
+    forward: &Forward,
+    health: &Health,
+) {
+    if packets.is_empty() {
+        return;
     }
+
+    let header = Some(tun.header()).filter(|header| !header.is_empty());
+    match uring.write_packets(tun.as_raw_fd(), packets, None, header) {
+        Ok(()) => health.touch_packet(),
+        Err(e) => drop_packets(e, packets.len(), &forward.tun_dropped),
+    }
+    packets.clear();
 }
 
 fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    exit_status(run(Cli::parse()))
+}
 
-    // get ip
-    let ip = std::fs::read_to_string("/enclaved/ip.txt")?.trim().to_owned();
+fn run(cli: Cli) -> anyhow::Result<()> {
+    // stop at a frame boundary on SIGTERM/SIGINT
+    register_shutdown()?;
 
     // get ip socket
     let device = &cli.device;
     // Open the TUN device, set IFF_NO_PI option to make sure
     // it doesn't expect 4 bytes prefix with flags and proto and just
     // accepts only raw packets
-    let iface = tun_tap::Iface::without_packet_info(device, Mode::Tun)?;
-    let tun_fd = iface.as_raw_fd();
+    let tun = Tun::open(device, cli.vnet_hdr)
+        .with_context(|| format!("could not open tun device {device}"))?;
+    let mtu = get_interface_mtu(device)?;
 
-    let mut tun_writer = unsafe { File::from_raw_fd(tun_fd) };
+    // vsock reads and tun writes go through io_uring, which reads the socket directly
+    #[cfg(feature = "io-uring")]
+    if cli.secure.secure {
+        anyhow::bail!("--secure is not supported with io-uring");
+    }
 
     // set up incoming vsock socket for incoming packets
     let vsock_addr = &cli.vsock_addr;
-    let vsock_socket = new_vsock_server_with_backoff(vsock_addr);
+    let vsock_socket = new_vsock_server_with_backoff(vsock_addr, cli.transport)?;
 
-    // get conn socket
-    let mut conn_socket = accept_vsock_conn_with_backoff((vsock_addr, &vsock_socket));
+    let health = Health::new();
+    spawn_health_reporters(&health, cli.health_file.clone(), cli.health_addr)?;
+    let streams = usize::from(cli.streams);
+    health.set_vsock_links(streams);
+    // the parent connects, waiting for it is healthy
+    (0..streams).for_each(|link| health.for_link(link).set_vsock_listening());
 
-    loop {
-        // do proxying
-        // on errors, simply reset the erroring socket
-        match handle_conn(&mut conn_socket, &mut tun_writer, &ip) {
-            Ok(_) => {
-                // should never happen!
-                unreachable!("connection handler exited without error");
-            }
-            Err(err @ ProxyError::VsockError(_)) => {
-                println!("{:?}", anyhow::Error::from(err));
+    // get conn socket, the parent announces our ip on connect
+    let liveness = Liveness::from_secs(0, cli.peer_timeout);
+    // records of the secure channel are not framed for seqpacket yet
+    if cli.secure.secure && cli.transport == Transport::Seqpacket {
+        anyhow::bail!("--secure is not supported with --transport seqpacket");
+    }
+    let secure = cli.secure.config()?;
+    let (conn, hello) = accept_vsock_conn_recv_hello_with_backoff(
+        (vsock_addr, &vsock_socket),
+        &liveness,
+        secure.as_deref(),
+    )?;
+    health.set_vsock_connected(true);
 
-                // get conn socket
-                conn_socket = accept_vsock_conn_with_backoff((vsock_addr, &vsock_socket));
-            }
-            Err(err) => {
-                // should never happen!
-                unreachable!("connection handler exited with unknown error {err:?}");
+    // get ip, kept up to date by the watcher thread or on reconnect
+    let ip = match &cli.ip_file {
+        Some(ip_file) => {
+            let ip = SharedIp::new(read_ip_file(ip_file)?);
+            watch_ip_file(ip_file.clone(), ip.clone())?;
+            ip
+        }
+        None => SharedIp::new(hello.ipv4.context("parent did not announce an ip")?),
+    };
+
+    // queued packets go out from the shaper thread, with a handle on the device of its own
+    let tun_dropped = health.counter("tun_dropped");
+    let shaper = match &cli.shape_file {
+        Some(shape_file) => {
+            let mut tun = tun
+                .try_clone()
+                .with_context(|| format!("could not open tun device {device}"))?;
+            let mut gso_writer = GsoWriter::new(mtu);
+            let tun_dropped = tun_dropped.clone();
+            let rules = read_rules_file(shape_file)?;
+            let shaper = Shaper::new(Direction::Download, &rules, &health, move |frames| {
+                for buf in frames {
+                    if let Err(e) = write_frame(&mut tun, &mut gso_writer, buf) {
+                        drop_packets(e, 1, &tun_dropped);
+                    }
+                }
+            });
+            watch_rules_file(shape_file.clone(), shaper.clone())?;
+            Some(shaper)
+        }
+        None => None,
+    };
+    let forward = Forward {
+        ip,
+        shaper,
+        tun_dropped,
+    };
+
+    // get conn socket of another link, the parent announces our ip on every one
+    let accept = |health: &Health| {
+        let (conn, hello) = accept_vsock_conn_recv_hello_with_backoff(
+            (vsock_addr, &vsock_socket),
+            &liveness,
+            secure.as_deref(),
+        )?;
+        health.set_vsock_connected(true);
+        if let (None, Some(ipv4)) = (&cli.ip_file, hello.ipv4) {
+            forward.ip.update(ipv4);
+        }
+        Ok::<_, ProxyError>(conn)
+    };
+
+    // each link is served by its own thread with its own handle on the
+    // device, packets of a flow always come in on the same link
+    let serve_link = |link: usize, mut conn: VsockConn, mut tun: Tun| -> anyhow::Result<()> {
+        let health = health.for_link(link);
+        let mut gso_writer = GsoWriter::new(mtu);
+        let mut tracker = SequenceTracker::new(&health);
+
+        #[cfg(feature = "io-uring")]
+        let mut uring = UringIo::new().context("could not set up io_uring")?;
+
+        while !shutdown_requested() {
+            // do proxying
+            // on errors, simply reset the erroring socket
+            #[cfg(not(feature = "io-uring"))]
+            let result = handle_conn(
+                &mut conn,
+                &mut tun,
+                &mut gso_writer,
+                &mut tracker,
+                &forward,
+                &health,
+            );
+            #[cfg(feature = "io-uring")]
+            let result = handle_conn(
+                &mut uring,
+                &mut conn,
+                &mut tun,
+                &mut gso_writer,
+                &mut tracker,
+                &forward,
+                &health,
+            );
+            match result {
+                Ok(_) => {
+                    // should never happen!
+                    unreachable!("connection handler exited without error");
+                }
+                Err(ProxyError::Shutdown) => break,
+                Err(err @ ProxyError::VsockError(_)) => {
+                    println!("{:?}", anyhow::Error::from(err));
+
+                    // get conn socket
+                    health.set_vsock_listening();
+                    conn = accept(&health)?;
+                }
+                Err(err) => {
+                    // should never happen!
+                    unreachable!("connection handler exited with unknown error {err:?}");
+                }
             }
         }
+
+        Ok(())
+    };
+
+    let tuns = (1..streams)
+        .map(|_| tun.try_clone())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("could not open tun device {device}"))?;
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (1..streams)
+            .zip(tuns)
+            .map(|(link, tun)| {
+                let health = health.for_link(link);
+                scope.spawn(move || {
+                    // woken up on shutdown like the main thread
+                    register_shutdown_thread();
+                    let conn = accept(&health)?;
+                    serve_link(link, conn, tun)
+                })
+            })
+            .collect();
+        let result = serve_link(0, conn, tun);
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("link thread panicked"))
+            .fold(result, Result::and)
+    })?;
+
+    // other frames are written out synchronously, only packets waiting on
+    // their cap are left
+    if let Some(shaper) = &forward.shaper {
+        shaper.flush();
     }
+    log_shutdown();
+
+    Ok(())
 }
@@ -34,20 +34,39 @@
 // iptables can be used to redirect packets to a nfqueue
 // we read it here, do NAT and forward onwards
 
-use std::ffi::CStr;
-use std::io::Read;
-use std::net::SocketAddrV4;
-use std::net::Ipv4Addr;
-
-use anyhow::{anyhow, Context};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::ops::Range;
+#[cfg(feature = "io-uring")]
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::Context;
 use clap::Parser;
-use libc::{freeifaddrs, getifaddrs, ifaddrs, strncmp};
 use socket2::{SockAddr, Socket};
 
+use oyster_raw_proxy::conn::{Transport, VsockConn};
+use oyster_raw_proxy::fragment::FragmentTable;
+use oyster_raw_proxy::frame::{is_control_frame, parse_gso_frame, Hello};
+#[cfg(not(feature = "io-uring"))]
+use oyster_raw_proxy::frame::FrameReader;
+use oyster_raw_proxy::health::{spawn_health_reporters, Counter, Health};
+use oyster_raw_proxy::heartbeat::Liveness;
+use oyster_raw_proxy::packet::{get_fragment, is_reserved_addr, Segmenter};
+use oyster_raw_proxy::secure::ParentSecureArgs;
+use oyster_raw_proxy::sequence::{now_us, SequenceTracker};
+use oyster_raw_proxy::shutdown::{
+    exit_status, log_shutdown, or_shutdown, register_shutdown, register_shutdown_thread,
+    shutdown_requested,
+};
 use oyster_raw_proxy::{
-    accept_vsock_conn_with_backoff, new_ip_socket_with_backoff, new_vsock_server_with_backoff,
-    ProxyError, SocketError, VsockAddrParser,
+    accept_vsock_conn_with_backoff, get_eth_interface, get_interface_mtu,
+    new_ip_socket_with_backoff, new_vsock_server_with_backoff, ProxyError, VsockAddrParser,
 };
+#[cfg(not(feature = "io-uring"))]
+use oyster_raw_proxy::send_ip_batch;
+#[cfg(feature = "io-uring")]
+use oyster_raw_proxy::{uring::UringIo, SocketError};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -55,186 +74,443 @@ struct Cli {
     /// vsock address to listen on <cid:port>
     #[clap(short, long, value_parser = VsockAddrParser{})]
     vsock_addr: SockAddr,
+    /// seconds without frames, heartbeats included, before the peer is considered dead, 0 disables
+    #[clap(long, value_parser, default_value_t = 15)]
+    peer_timeout: u64,
+    /// largest packet to send out, defaults to the interface mtu, announced to the
+    /// enclave to clamp TCP MSS to, set it to the path mtu if that is lower
+    #[clap(long, value_parser)]
+    mtu: Option<u16>,
+    /// file to report the proxy state to for health checks
+    #[clap(long, value_parser)]
+    health_file: Option<PathBuf>,
+    /// address to serve the proxy state on over HTTP <ip:port>, 503 when unhealthy
+    #[clap(long, value_parser)]
+    health_addr: Option<SocketAddr>,
+    /// vsock connections the enclave may stripe over, each served by its own thread
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=64), default_value_t = 1)]
+    streams: u8,
+    /// vsock socket type, seqpacket sends every frame as a message of its own,
+    /// has to match the enclave
+    #[clap(long, value_enum, default_value_t = Transport::Stream)]
+    transport: Transport,
+    #[clap(flatten)]
+    secure: ParentSecureArgs,
 }
 
-fn get_eth_interface() -> anyhow::Result<(String, u32)> {
-    let mut ifap: *mut ifaddrs = std::ptr::null_mut();
-    let res = unsafe { getifaddrs(&mut ifap) };
-
-    if res < 0 {
-        return Err(anyhow!("failed to query interfaces"));
-    }
+// why a packet from the enclave did not go out, counted as dropped_<reason>
+#[derive(Clone, Copy, Debug)]
+enum DropReason {
+    Malformed,
+    SrcAddr,
+    ReservedAddr,
+    Port,
+    // later fragment of a datagram whose first fragment did not go out
+    Fragment,
+    // first fragment of a datagram that cannot be tracked
+    FragmentTableFull,
+}
 
-    let mut ifap_iter = ifap;
-    let mut ifname = "".to_owned();
-    let mut ifaddr = 0;
-    while !ifap_iter.is_null() {
-        let name = unsafe { CStr::from_ptr((*ifap_iter).ifa_name) };
-        if (unsafe { strncmp(name.as_ptr(), "eth".as_ptr().cast(), 3) } == 0
-            || unsafe { strncmp(name.as_ptr(), "ens".as_ptr().cast(), 3) } == 0
-            || unsafe { strncmp(name.as_ptr(), "enp".as_ptr().cast(), 3) } == 0)
-            && unsafe { (*(*ifap_iter).ifa_addr).sa_family == libc::AF_INET as u16 }
-        {
-            ifname = name.to_str().context("non utf8 interface")?.to_owned();
-            ifaddr = unsafe {
-                (*(*ifap_iter).ifa_addr.cast::<libc::sockaddr_in>())
-                    .sin_addr
-                    .s_addr
-            };
-            break;
+impl DropReason {
+    const ALL: [DropReason; 6] = [
+        DropReason::Malformed,
+        DropReason::SrcAddr,
+        DropReason::ReservedAddr,
+        DropReason::Port,
+        DropReason::Fragment,
+        DropReason::FragmentTableFull,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            DropReason::Malformed => "dropped_malformed",
+            DropReason::SrcAddr => "dropped_src_addr",
+            DropReason::ReservedAddr => "dropped_reserved_addr",
+            DropReason::Port => "dropped_port",
+            DropReason::Fragment => "dropped_fragment",
+            DropReason::FragmentTableFull => "dropped_fragment_table_full",
         }
-        ifap_iter = unsafe { (*ifap_iter).ifa_next };
-    }
-
-    unsafe { freeifaddrs(ifap) };
-
-    if ifname == "" {
-        Err(anyhow!("no matching interface found"))
-    } else {
-        Ok((ifname, ifaddr))
     }
 }
 
-fn handle_conn(
-    conn_socket: &mut Socket,
-    ip_socket: &mut Socket,
+// decides which packets from the enclave may go out
+struct Filter {
     ifaddr: u32,
-) -> Result<(), ProxyError> {
-    let mut buf = vec![0u8; 65535].into_boxed_slice();
-
-    // does not matter what the address is, just has to be a publicly routed address
-    let external_addr: SockAddr = "1.1.1.1:80".parse::<SocketAddrV4>().unwrap().into();
+    fragments: FragmentTable,
+    // by DropReason
+    dropped: Vec<Counter>,
+}
 
-    loop {
-        // read till total size
-        conn_socket
-            .read_exact(&mut buf[0..4])
-            .map_err(SocketError::ReadError)
-            .map_err(ProxyError::VsockError)?;
+impl Filter {
+    fn new(ifaddr: u32, health: &Health) -> Self {
+        Filter {
+            ifaddr,
+            fragments: FragmentTable::default(),
+            dropped: DropReason::ALL
+                .iter()
+                .map(|reason| health.counter(reason.name()))
+                .collect(),
+        }
+    }
 
-        let size: usize = u16::from_be_bytes(buf[2..4].try_into().unwrap()).into();
+    fn drop(&self, reason: DropReason) {
+        self.dropped[reason as usize].inc();
+    }
 
-        // read till full frame
-        conn_socket
-            .read_exact(&mut buf[4..size])
-            .map_err(SocketError::ReadError)
-            .map_err(ProxyError::VsockError)?;
+    // whether a packet from the enclave may go out
+    fn should_forward(&mut self, buf: &[u8]) -> bool {
+        match self.check(buf) {
+            Ok(()) => true,
+            Err(reason) => {
+                self.drop(reason);
+                false
+            }
+        }
+    }
 
+    fn check(&mut self, buf: &[u8]) -> Result<(), DropReason> {
         // IMPORTANT: checks are needed here, assume packets from the enclave to be untrusted
 
+        // frames are cut to their size now, too short ones must not be indexed into
+        if buf.len() < 20 {
+            return Err(DropReason::Malformed);
+        }
+
         // get src and dst addr
         let src_addr = u32::from_ne_bytes(buf[12..16].try_into().unwrap());
         let dst_addr = u32::from_be_bytes(buf[16..20].try_into().unwrap());
 
         // ignore packets not originating from the interface address
-        if src_addr != ifaddr {
-            continue;
+        if src_addr != self.ifaddr {
+            return Err(DropReason::SrcAddr);
         }
 
-        // println!("outgoing {:?} to {:?}: {:02x?}", size, Ipv4Addr::from(dst_addr).to_string(), &buf[..size]);
-
-        // https://en.wikipedia.org/wiki/Reserved_IP_addresses
-        // ignore packets sent to
-        // 0.0.0.0/8
-        if (dst_addr & 0xff000000) == 0x00000000 ||
-            // 10.0.0.0/8
-            (dst_addr & 0xff000000) == 0x0a000000 ||
-            // 100.64.0.0/10
-            (dst_addr & 0xffc00000) == 0x64400000 ||
-            // 127.0.0.0/8
-            (dst_addr & 0xff000000) == 0x7f000000 ||
-            // 169.254.0.0/16
-            (dst_addr & 0xffff0000) == 0xa9fe0000 ||
-            // 172.16.0.0/12
-            (dst_addr & 0xfff00000) == 0xac100000 ||
-            // 192.0.0.0/24
-            (dst_addr & 0xffffff00) == 0xc0000000 ||
-            // 192.0.2.0/24
-            (dst_addr & 0xffffff00) == 0xc0000200 ||
-            // 192.88.99.0/24
-            (dst_addr & 0xffffff00) == 0xc0586300 ||
-            // 192.168.0.0/16
-            (dst_addr & 0xffff0000) == 0xc0a80000 ||
-            // 198.18.0.0/15
-            (dst_addr & 0xfffe0000) == 0xc6120000 ||
-            // 198.51.100.0/24
-            (dst_addr & 0xffffff00) == 0xc6336400 ||
-            // 203.0.113.0/24
-            (dst_addr & 0xffffff00) == 0xcb007100 ||
-            // 224.0.0.0/4
-            (dst_addr & 0xf0000000) == 0xe0000000 ||
-            // 233.252.0.0/24
-            (dst_addr & 0xffffff00) == 0xe9fc0000 ||
-            // 240.0.0.0/4
-            (dst_addr & 0xf0000000) == 0xf0000000 ||
-            // 255.255.255.255/32
-            (dst_addr & 0xffffffff) == 0xffffffff
-        {
-            continue;
+        // println!("outgoing {:?} to {:?}: {:02x?}", buf.len(), Ipv4Addr::from(dst_addr).to_string(), buf);
+
+        // ignore packets sent to reserved addresses
+        if is_reserved_addr(dst_addr) {
+            return Err(DropReason::ReservedAddr);
+        }
+
+        // later fragments have no ports, they go out if the first one did
+        let (fragment_offset, more_fragments) = get_fragment(buf);
+        if fragment_offset > 0 {
+            return match self.fragments.is_forwarded(buf, Instant::now()) {
+                true => Ok(()),
+                false => Err(DropReason::Fragment),
+            };
         }
 
         let ip_header_size = usize::from((buf[0] & 0x0f) * 4);
+        if buf.len() < ip_header_size + 2 {
+            return Err(DropReason::Malformed);
+        }
         let src_port =
             u16::from_be_bytes(buf[ip_header_size..ip_header_size + 2].try_into().unwrap());
 
-        if src_port != 80 && src_port != 443 && (src_port < 1024 || src_port > 61439) {
+        if src_port != 80 && src_port != 443 && !(1024..=61439).contains(&src_port) {
             // silently drop
+            return Err(DropReason::Port);
+        }
+
+        if more_fragments && !self.fragments.insert(buf, Instant::now()) {
+            return Err(DropReason::FragmentTableFull);
+        }
+
+        Ok(())
+    }
+}
+
+enum Outgoing<'a> {
+    Packet(&'a [u8]),
+    // indices of the segments of a GSO packet
+    Segments(Range<usize>),
+}
+
+// packets of a read that may go out, in order, GSO packets are split
+// into segments that fit the interface mtu
+fn collect_packets<'a>(
+    frames: impl Iterator<Item = &'a [u8]>,
+    filter: &mut Filter,
+    mtu: usize,
+    segmenter: &'a mut Segmenter,
+) -> Vec<&'a [u8]> {
+    segmenter.clear();
+    let outgoing: Vec<Outgoing> = frames
+        .filter_map(|frame| match parse_gso_frame(frame) {
+            Some((gso_size, packet)) if filter.should_forward(packet) => {
+                let segments = segmenter.segment(packet, gso_size.into(), mtu);
+                if segments.is_none() {
+                    filter.drop(DropReason::Malformed);
+                }
+                segments.map(Outgoing::Segments)
+            }
+            Some(_) => None,
+            // heartbeats only keep the link alive
+            None if !is_control_frame(frame) && filter.should_forward(frame) => {
+                Some(Outgoing::Packet(frame))
+            }
+            None => None,
+        })
+        .collect();
+
+    let segmenter: &'a Segmenter = segmenter;
+    let mut packets = Vec::with_capacity(outgoing.len());
+    for item in outgoing {
+        match item {
+            Outgoing::Packet(packet) => packets.push(packet),
+            Outgoing::Segments(segments) => {
+                packets.extend(segments.map(|index| segmenter.get(index)))
+            }
+        }
+    }
+
+    packets
+}
+
+#[cfg(not(feature = "io-uring"))]
+fn handle_conn(
+    conn: &mut VsockConn,
+    ip_socket: &mut Socket,
+    filter: &mut Filter,
+    tracker: &mut SequenceTracker,
+    mtu: usize,
+    health: &Health,
+) -> Result<(), ProxyError> {
+    let mut reader = FrameReader::new();
+    let mut segmenter = Segmenter::new();
+
+    // does not matter what the address is, just has to be a publicly routed address
+    let external_addr: SockAddr = "1.1.1.1:80".parse::<SocketAddrV4>().unwrap().into();
+
+    loop {
+        let frames = conn
+            .read_frames(&mut reader)
+            .map_err(ProxyError::VsockError)
+            .map_err(or_shutdown)?;
+        // back to waiting with the next read, the watchdog sees a stall otherwise
+        let _busy = health.busy();
+        health.touch_vsock();
+
+        let now = now_us();
+        let frames = frames.map(|frame| tracker.unwrap(frame, now));
+        let packets = collect_packets(frames, filter, mtu, &mut segmenter);
+        if packets.is_empty() {
             continue;
         }
 
-        // send
-        let mut total_sent = 0;
-        while total_sent < size {
-            let size = ip_socket
-                .send_to(&buf[total_sent..size], &external_addr)
-                .map_err(SocketError::WriteError)
-                .map_err(ProxyError::NfqError)?;
-            total_sent += size;
+        // send, a single syscall for all packets of a read
+        send_ip_batch(ip_socket, &packets, &external_addr).map_err(ProxyError::IpError)?;
+        health.touch_packet();
+    }
+}
+
+#[cfg(feature = "io-uring")]
+fn handle_conn(
+    uring: &mut UringIo,
+    conn: &mut VsockConn,
+    ip_socket: &mut Socket,
+    filter: &mut Filter,
+    tracker: &mut SequenceTracker,
+    mtu: usize,
+    health: &Health,
+) -> Result<(), ProxyError> {
+    let read_timeout = conn
+        .socket()
+        .read_timeout()
+        .map_err(|e| SocketError::OptionError("SO_RCVTIMEO".to_owned(), e))
+        .map_err(ProxyError::VsockError)?;
+    uring.reset(read_timeout);
+    let mut segmenter = Segmenter::new();
+
+    // does not matter what the address is, just has to be a publicly routed address
+    let external_addr: SockAddr = "1.1.1.1:80".parse::<SocketAddrV4>().unwrap().into();
+
+    loop {
+        let fd = conn.socket().as_raw_fd();
+        let range = match conn.transport() {
+            Transport::Stream => uring.read_frames(fd),
+            Transport::Seqpacket => uring.read_messages(fd),
+        }
+        .map_err(ProxyError::VsockError)
+        .map_err(or_shutdown)?;
+        // back to waiting with the next read, the watchdog sees a stall otherwise
+        let _busy = health.busy();
+        health.touch_vsock();
+
+        let now = now_us();
+        let frames = uring.frames(range).map(|frame| tracker.unwrap(frame, now));
+        let packets = collect_packets(frames, filter, mtu, &mut segmenter);
+        if packets.is_empty() {
+            continue;
         }
+
+        // send, a single submission for all packets of a read
+        uring
+            .write_packets(
+                ip_socket.as_raw_fd(),
+                &packets,
+                Some(&external_addr),
+                None,
+            )
+            .map_err(SocketError::WriteError)
+            .map_err(ProxyError::IpError)?;
+        health.touch_packet();
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    exit_status(run(Cli::parse()))
+}
+
+fn run(cli: Cli) -> anyhow::Result<()> {
+    // stop at a frame boundary on SIGTERM/SIGINT
+    register_shutdown()?;
 
     // get ethernet interface
     let (ifname, ifaddr) = get_eth_interface().context("could not get ethernet interface")?;
     println!("detected ethernet interface: {}, {:#10x}", ifname, ifaddr);
-
-    // set up ip socket for outgoing packets
-    let mut ip_socket = new_ip_socket_with_backoff(&ifname);
+    let mtu = match cli.mtu {
+        Some(mtu) => mtu.into(),
+        None => get_interface_mtu(&ifname)?,
+    };
+    println!("mtu: {}", mtu);
+
+    // vsock reads and raw socket sends go through io_uring, which reads the socket directly
+    #[cfg(feature = "io-uring")]
+    if cli.secure.secure {
+        anyhow::bail!("--secure is not supported with io-uring");
+    }
 
     // set up outgoing vsock socket for outgoing packets
     let vsock_addr = &cli.vsock_addr;
-    let vsock_socket = new_vsock_server_with_backoff(vsock_addr);
-
-    // get conn socket
-    let mut conn_socket = accept_vsock_conn_with_backoff((vsock_addr, &vsock_socket));
-
-    loop {
-        // do proxying
-        // on errors, simply reset the erroring socket
-        match handle_conn(&mut conn_socket, &mut ip_socket, ifaddr) {
-            Ok(_) => {
-                // should never happen!
-                unreachable!("connection handler exited without error");
-            }
-            Err(err @ ProxyError::IpError(_)) => {
-                println!("{:?}", anyhow::Error::from(err));
-
-                // get ip socket
-                ip_socket = new_ip_socket_with_backoff(&ifname);
-            }
-            Err(err @ ProxyError::VsockError(_)) => {
-                println!("{:?}", anyhow::Error::from(err));
-
-                // get conn socket
-                conn_socket = accept_vsock_conn_with_backoff((vsock_addr, &vsock_socket));
-            }
-            Err(err) => {
-                // should never happen!
-                unreachable!("connection handler exited with unknown error {err:?}");
+    let vsock_socket = new_vsock_server_with_backoff(vsock_addr, cli.transport)?;
+
+    // the enclave uses our interface address as its own
+    let hello = Hello {
+        ipv4: Some(Ipv4Addr::from(ifaddr.to_ne_bytes())),
+        // GSO packets are split here
+        gso: true,
+        mtu: Some(mtu.min(u16::MAX.into()) as u16),
+        // sequenced frames are counted here
+        seq: true,
+        // the enclave stripes over up to this many links
+        streams: Some(cli.streams),
+    };
+
+    let health = Health::new();
+    spawn_health_reporters(&health, cli.health_file.clone(), cli.health_addr)?;
+    let streams = usize::from(cli.streams);
+    health.set_vsock_links(streams);
+    // the parent connects, waiting for it is healthy
+    (0..streams).for_each(|link| health.for_link(link).set_vsock_listening());
+
+    let liveness = Liveness::from_secs(0, cli.peer_timeout);
+    // records of the secure channel are not framed for seqpacket yet
+    if cli.secure.secure && cli.transport == Transport::Seqpacket {
+        anyhow::bail!("--secure is not supported with --transport seqpacket");
+    }
+    let secure = cli.secure.config()?;
+
+    // each link is served by its own thread with its own sockets, a flow
+    // always comes in on the same link
+    let serve_link = |link: usize| -> anyhow::Result<()> {
+        let health = health.for_link(link);
+
+        // set up ip socket for outgoing packets
+        let mut ip_socket = new_ip_socket_with_backoff(&ifname)?;
+
+        #[cfg(feature = "io-uring")]
+        let mut uring = UringIo::new().context("could not set up io_uring")?;
+
+        let mut filter = Filter::new(ifaddr, &health);
+        let mut tracker = SequenceTracker::new(&health);
+
+        // get conn socket, with --secure the enclave has to attest its link key first
+        let mut conn = accept_vsock_conn_with_backoff(
+            (vsock_addr, &vsock_socket),
+            &hello,
+            &liveness,
+            secure.as_deref(),
+        )?;
+        health.set_vsock_connected(true);
+
+        while !shutdown_requested() {
+            // do proxying
+            // on errors, simply reset the erroring socket
+            #[cfg(not(feature = "io-uring"))]
+            let result = handle_conn(
+                &mut conn,
+                &mut ip_socket,
+                &mut filter,
+                &mut tracker,
+                mtu,
+                &health,
+            );
+            #[cfg(feature = "io-uring")]
+            let result = handle_conn(
+                &mut uring,
+                &mut conn,
+                &mut ip_socket,
+                &mut filter,
+                &mut tracker,
+                mtu,
+                &health,
+            );
+            match result {
+                Ok(_) => {
+                    // should never happen!
+                    unreachable!("connection handler exited without error");
+                }
+                Err(ProxyError::Shutdown) => break,
+                Err(err @ ProxyError::IpError(_)) => {
+                    println!("{:?}", anyhow::Error::from(err));
+
+                    // get ip socket
+                    ip_socket = new_ip_socket_with_backoff(&ifname)?;
+                }
+                Err(err @ ProxyError::VsockError(_)) => {
+                    println!("{:?}", anyhow::Error::from(err));
+
+                    // get conn socket
+                    health.set_vsock_listening();
+                    conn = accept_vsock_conn_with_backoff(
+                        (vsock_addr, &vsock_socket),
+                        &hello,
+                        &liveness,
+                        secure.as_deref(),
+                    )?;
+                    health.set_vsock_connected(true);
+                }
+                Err(err) => {
+                    // should never happen!
+                    unreachable!("connection handler exited with unknown error {err:?}");
+                }
             }
         }
-    }
-}
\ No newline at end of file
+
+        Ok(())
+    };
+
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (1..streams)
+            .map(|link| {
+                scope.spawn(move || {
+                    // woken up on shutdown like the main thread
+                    register_shutdown_thread();
+                    serve_link(link)
+                })
+            })
+            .collect();
+        let result = serve_link(0);
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("link thread panicked"))
+            .fold(result, Result::and)
+    })?;
+
+    // frames are written out synchronously, nothing left to flush
+    log_shutdown();
+
+    Ok(())
+}